@@ -23,6 +23,7 @@ use std::convert::TryFrom;
 use std::net::{AddrParseError, SocketAddr};
 use std::path::{PathBuf, Path};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::thread;
 
 use bitcoin::{Address, Network};
@@ -33,10 +34,10 @@ use log::{debug, error, info, warn, LevelFilter};
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
 
-use bdk::api::{balance, deposit_addr, init_config, start, stop, update_config, withdraw};
-use bdk::api;
+use bdk::api::{self, WalletHandle};
 use bdk::config::Config;
 use bdk::error::Error;
+use bdk::secret::Secret;
 use std::process::ChildStderr;
 use chrono::Local;
 
@@ -79,7 +80,7 @@ fn main() -> Result<(), Error> {
     println!("network: {}", network);
     println!("peers: {:?}", peers);
 
-    let init_result = api::init_config(work_dir.clone(), network, password, None);
+    let init_result = api::init_config(work_dir.clone(), network, Secret::from(password), None);
 
     match init_result {
         Ok(Some(init_result)) => {
@@ -102,7 +103,8 @@ fn main() -> Result<(), Error> {
 
     println!("peer connections: {}", connections);
 
-    let config = api::update_config(work_dir.clone(), network, peers, connections, discovery).unwrap();
+    let wallet = Arc::new(WalletHandle::new(work_dir.clone(), network));
+    let config = wallet.update_config(peers, connections, discovery).unwrap();
     debug!("config: {:?}", config);
 
     let mut rl = Editor::<()>::new();
@@ -111,9 +113,10 @@ fn main() -> Result<(), Error> {
         println!("No previous history.");
     }
 
+    let p2p_wallet = wallet.clone();
     let p2p_thread = thread::spawn(move || {
         println!("starting p2p thread");
-        api::start(work_dir.clone(), network, false);
+        p2p_wallet.start(false);
     });
 
     loop {
@@ -131,20 +134,20 @@ fn main() -> Result<(), Error> {
                                 break;
                             }
                             "balance" => {
-                                let balance_amt = api::balance().unwrap();
+                                let balance_amt = wallet.balance().unwrap();
                                 println!("balance: {}, confirmed: {}", balance_amt.balance, balance_amt.confirmed);
                             }
                             "deposit" => {
-                               let deposit_addr = api::deposit_addr();
+                                let deposit_addr = wallet.deposit_addr().unwrap();
                                 println!("deposit address: {}", deposit_addr);
                             }
                             "withdraw" => {
-                                // passphrase: String, address: Address, fee_per_vbyte: u64, amount: Option<u64>
-                                let password = a.value_of("password").unwrap().to_string();
+                                // passphrase: Secret, address: Address, fee_per_vbyte: u64, amount: Option<u64>
+                                let password = Secret::from(a.value_of("password").unwrap());
                                 let address = Address::from_str(a.value_of("address").unwrap()).unwrap();
                                 let fee = a.value_of("fee").unwrap().parse::<u64>().unwrap();
                                 let amount = Some(a.value_of("amount").unwrap().parse::<u64>().unwrap());
-                                let withdraw_tx = api::withdraw(password, address, fee, amount).unwrap();
+                                let withdraw_tx = wallet.withdraw(password, address, fee, amount).unwrap();
                                 println!("withdraw tx id: {}, fee: {}", withdraw_tx.txid, withdraw_tx.fee);
                             }
                             _ => {
@@ -173,7 +176,7 @@ fn main() -> Result<(), Error> {
     }
     rl.save_history(history_file).unwrap();
     println!("stopping");
-    api::stop();
+    wallet.stop().unwrap();
     p2p_thread.join().unwrap();
     println!("stopped");
     Ok(())
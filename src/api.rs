@@ -16,45 +16,114 @@
  */
 
 use std::{fs, time};
+use std::collections::HashSet;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
 
-use bitcoin::{Address, BitcoinHash, Network};
+use bitcoin::{Address, BitcoinHash, BlockHeader, Network, OutPoint, PrivateKey, PublicKey, Script, Transaction};
+use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::hashes::core::str::FromStr;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::address::Payload;
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin_hashes::sha256d;
 use bitcoin_wallet::account::MasterAccount;
 use futures::{executor::ThreadPoolBuilder};
 use futures_timer::Delay;
 use log::{info, warn};
-use log::{debug, error};
+use log::{debug, error, LevelFilter};
 use murmel::chaindb::ChainDB;
-use once_cell::sync::Lazy;
 
-use crate::{config, db};
+use crate::{config, consolidate, db, esplora, layout, rpc, wallet};
+use crate::bandwidth::BandwidthUsage;
+use crate::bip47::PaymentCode;
 use crate::config::Config;
-use crate::db::DB;
+use crate::consolidate::{ConsolidationEvent, ConsolidationPolicy};
+use crate::custody::{ConfigKeyStorage, KeyStorage};
+use crate::db::{DB, DbProblem, DbStats};
 use crate::error::Error;
+use crate::history::{self, ExportFormat, FiatValue};
+use crate::imported::ImportedKey;
+use crate::kdf::{self, Kdf};
+use crate::logging::{self, LogTarget};
 use crate::p2p_bitcoin::{ChainDBTrunk, P2PBitcoin};
-use crate::store::{ContentStore, SharedContentStore};
+use crate::peers::PeerInfo;
+use crate::publish::Publisher;
+use crate::rates::{self, RateProvider};
+use crate::rpc::{RpcClient, RpcTrunk};
+use crate::secret::Secret;
+use crate::sendtx::BroadcastStatus;
+#[cfg(any(test, feature = "testutil"))]
+use crate::simulation::SharedSimulation;
+use crate::store::{ContentStore, RotationEvent, SharedContentStore};
+use crate::syncstate::SyncState;
 use crate::trunk::Trunk;
-use crate::wallet::{KEY_LOOK_AHEAD, Wallet};
+use crate::txsize::{self, InputType, OutputType, TxSize};
+use crate::vault::Vault;
+use crate::wallet::{AddressInfo, DEFAULT_MAX_FEE_PERCENT, ExternalInput, FeeLimits, KEY_LOOK_AHEAD, TransactionInfo, TxOptions, Wallet};
+use crate::watch::Watched;
+use crate::webhook::Notifier;
 
 const CONFIG_FILE_NAME: &str = "bdk.cfg";
 
-static CONTENT_STORE: Lazy<Arc<RwLock<Option<SharedContentStore>>>> = Lazy::new(|| Arc::new(RwLock::new(None::<SharedContentStore>)));
+/// the passphrase stretching of a config, None if the seed is encrypted with the passphrase as is
+fn config_kdf(config: &Config) -> Result<Option<Kdf>, Error> {
+    config.kdf.as_ref().map(|k| Kdf::from_str(k.as_str())).transpose()
+}
+
+// logging
+
+/// set logging level and target of the library including the p2p layer
+pub fn set_logging(level: LevelFilter, target: LogTarget) -> Result<(), Error> {
+    logging::set_logging(level, target)
+}
 
 // load config
 
 pub fn load_config(work_dir: PathBuf, network: Network) -> Result<Config, Error> {
-    let mut file_path = PathBuf::from(work_dir);
-    file_path.push(network.to_string());
+    let mut config_path = PathBuf::from(work_dir);
+    config_path.push(network.to_string());
+    let mut file_path = config_path.clone();
     file_path.push(CONFIG_FILE_NAME);
 
+    layout::check(&config_path)?;
     config::load(&file_path)
 }
 
+/// decode a raw hex transaction without a wallet, nothing is marked as ours
+pub fn decode_tx(hex: &str, network: Network) -> Result<TransactionInfo, Error> {
+    Ok(wallet::describe(&parse_tx(hex)?, network, &HashSet::new(), |_| None))
+}
+
+fn parse_tx(hex: &str) -> Result<Transaction, Error> {
+    let bytes = hex::decode(hex.trim()).map_err(|_| Error::InvalidArgument("transaction is not hex"))?;
+    deserialize::<Transaction>(bytes.as_slice()).map_err(|_| Error::InvalidArgument("not a transaction"))
+}
+
+/// parse an address and check it against network, None if it is malformed
+pub fn validate_address(address: &str, network: Network) -> Option<AddressValidation> {
+    let address = Address::from_str(address.trim()).ok()?;
+    // base58 addresses of testnet and regtest are alike
+    let network_matches = address.network == network || match address.payload {
+        Payload::PubkeyHash(_) | Payload::ScriptHash(_) =>
+            address.network == Network::Testnet && network == Network::Regtest,
+        Payload::WitnessProgram { .. } => false
+    };
+    Some(AddressValidation {
+        address_type: address.address_type().map(|t| t.to_string()),
+        address,
+        network_matches,
+    })
+}
+
+/// estimate size and weight of a transaction spending inputs to outputs, e.g. to quote a fee before building it.
+/// Vault coins have the input type of Vault::input_type
+pub fn estimate_tx_size(inputs: &[InputType], outputs: &[OutputType]) -> TxSize {
+    txsize::estimate(inputs, outputs)
+}
+
 // remove config
 
 pub fn remove_config(work_dir: PathBuf, network: Network) -> Result<Config, Error> {
@@ -70,6 +139,7 @@ pub fn remove_config(work_dir: PathBuf, network: Network) -> Result<Config, Erro
 
 // update config
 
+/// change the peer settings of a config, WalletHandle::update_config also applies them to a running wallet
 pub fn update_config(work_dir: PathBuf, network: Network, bitcoin_peers: Vec<SocketAddr>,
                      bitcoin_connections: usize, bitcoin_discovery: bool) -> Result<Config, Error> {
     let mut config_path = PathBuf::from(work_dir);
@@ -78,7 +148,11 @@ pub fn update_config(work_dir: PathBuf, network: Network, bitcoin_peers: Vec<Soc
     file_path.push(CONFIG_FILE_NAME);
 
     let config = config::load(&file_path)?;
-    let updated_config = config.update(bitcoin_peers, bitcoin_connections, bitcoin_discovery);
+    let updated_config = config.to_builder()
+        .peers(bitcoin_peers)
+        .connections(bitcoin_connections)
+        .discovery(bitcoin_discovery)
+        .build()?;
     config::save(&config_path, &file_path, &updated_config)?;
     Ok(updated_config)
 }
@@ -99,180 +173,1130 @@ impl InitResult {
     }
 }
 
-pub fn init_config(work_dir: PathBuf, network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> Result<Option<InitResult>, Error> {
-    let mut config_path = PathBuf::from(work_dir);
-    config_path.push(network.to_string());
-    fs::create_dir_all(&config_path).expect(format!("unable to create config_path: {}", &config_path.to_str().unwrap()).as_str());
+/// init config of a new wallet protected by passphrase with the default key storage and kdf cost,
+/// returns None if a config already exists
+pub fn init_config(work_dir: PathBuf, network: Network, passphrase: Secret, pd_passphrase: Option<Secret>) -> Result<Option<InitResult>, Error> {
+    WalletHandle::new(work_dir, network).init_config(passphrase, pd_passphrase)
+}
 
-    let mut file_path = config_path.clone();
-    file_path.push(CONFIG_FILE_NAME);
+/// init config with the seed of a keystore document, protected by passphrase.
+/// Returns the deposit address or None if a config already exists
+pub fn import_keystore(work_dir: PathBuf, network: Network, document: &str, password: &str, passphrase: &str) -> Result<Option<Address>, Error> {
+    WalletHandle::new(work_dir, network).import_keystore(document, password, passphrase)
+}
 
-    if let Ok(_config) = config::load(&file_path) {
-        // do not init if a config already exists, return none
-        Ok(Option::None)
-    } else {
-        // create new wallet
-        let (mnemonic_words, deposit_address, wallet) = Wallet::new(network, passphrase, pd_passphrase);
-        let mnemonic_words = mnemonic_words.to_string();
-        let deposit_address = deposit_address;
+/// the seed as a keystore document encrypted with password, for machine-readable backups
+pub fn export_keystore(work_dir: PathBuf, network: Network, passphrase: &str, password: &str) -> Result<String, Error> {
+    WalletHandle::new(work_dir, network).export_keystore(passphrase, password)
+}
+
+/// source of blocks driving the wallet
+pub enum Backend {
+    /// the bitcoin p2p network
+    P2P,
+    /// the trusted bitcoind configured with bitcoin_rpc_url
+    Rpc,
+    /// a scripted in-memory chain
+    #[cfg(any(test, feature = "testutil"))]
+    Simulation(SharedSimulation),
+}
+
+/// the wallet of a work dir and network, several handles can run in one process.
+/// A handle is shared between the thread that runs start() and those calling it
+pub struct WalletHandle {
+    work_dir: PathBuf,
+    network: Network,
+    content_store: RwLock<Option<SharedContentStore>>,
+    p2p_bitcoin: RwLock<Option<Arc<P2PBitcoin>>>,
+    rate_provider: RwLock<Option<Arc<dyn RateProvider>>>,
+    key_storage: RwLock<Arc<dyn KeyStorage>>,
+    kdf_cost: RwLock<(u32, u32)>,
+}
+
+impl WalletHandle {
+    /// a handle of the wallet configured by init_config in work_dir for network
+    pub fn new(work_dir: PathBuf, network: Network) -> WalletHandle {
+        WalletHandle {
+            work_dir,
+            network,
+            content_store: RwLock::new(None),
+            p2p_bitcoin: RwLock::new(None),
+            rate_provider: RwLock::new(None),
+            key_storage: RwLock::new(Arc::new(ConfigKeyStorage)),
+            kdf_cost: RwLock::new((kdf::MEMORY, kdf::ITERATIONS)),
+        }
+    }
+
+    pub fn work_dir(&self) -> &Path {
+        self.work_dir.as_path()
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.content_store().is_ok()
+    }
+
+    fn config_path(&self) -> PathBuf {
+        let mut config_path = self.work_dir.clone();
+        config_path.push(self.network.to_string());
+        config_path
+    }
+
+    fn config_file_path(&self) -> PathBuf {
+        let mut file_path = self.config_path();
+        file_path.push(CONFIG_FILE_NAME);
+        file_path
+    }
+
+    pub fn load_config(&self) -> Result<Config, Error> {
+        layout::check(&self.config_path())?;
+        config::load(&self.config_file_path())
+    }
 
-        let encryptedwalletkey = hex::encode(wallet.encrypted().as_slice());
+    // key custody
+
+    /// seal the master seed with a platform key storage instead of keeping it in the config file,
+    /// set before init_config and start
+    pub fn set_key_storage(&self, storage: Arc<dyn KeyStorage>) -> Result<(), Error> {
+        *self.key_storage.write().map_err(|_| Error::Lock("key storage"))? = storage;
+        Ok(())
+    }
+
+    /// Argon2id memory in KiB and iterations stretching the passphrase of a new wallet
+    /// and of a wallet re-encrypted on unlock
+    pub fn set_kdf_cost(&self, memory: u32, iterations: u32) -> Result<(), Error> {
+        Kdf::new(memory, iterations)?;
+        *self.kdf_cost.write().map_err(|_| Error::Lock("kdf cost"))? = (memory, iterations);
+        Ok(())
+    }
+
+    fn new_kdf(&self) -> Result<Kdf, Error> {
+        let (memory, iterations) = *self.kdf_cost.read().map_err(|_| Error::Lock("kdf cost"))?;
+        Kdf::new(memory, iterations)
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        self.key_storage.read().map_err(|_| Error::Lock("key storage"))?.unseal(sealed)
+    }
+
+    // the passphrase encrypted master seed of a config
+    fn encrypted_key(&self, config: &Config) -> Result<Vec<u8>, Error> {
+        let sealed = hex::decode(&config.encryptedwalletkey).map_err(|_| Error::InvalidConfig("encryptedwalletkey is not hex"))?;
+        self.unseal(sealed.as_slice())
+    }
+
+    // the config value of a passphrase encrypted master seed
+    fn sealed_key(&self, encrypted: &[u8]) -> Result<String, Error> {
+        Ok(hex::encode(self.key_storage.read().map_err(|_| Error::Lock("key storage"))?.seal(encrypted)?))
+    }
+
+    /// init config of a new wallet protected by passphrase, returns None if a config already exists
+    pub fn init_config(&self, passphrase: Secret, pd_passphrase: Option<Secret>) -> Result<Option<InitResult>, Error> {
+        let config_path = self.config_path();
+        fs::create_dir_all(&config_path)?;
+
+        if let Ok(_config) = config::load(&self.config_file_path()) {
+            // do not init if a config already exists, return none
+            Ok(Option::None)
+        } else {
+            // create new wallet
+            let kdf = self.new_kdf()?;
+            let (mnemonic_words, deposit_address, wallet) = Wallet::new(self.network, kdf.stretch(passphrase.as_str())?.as_str(), pd_passphrase.as_ref().map(|pd| pd.as_str()));
+            let mnemonic_words = mnemonic_words.to_string();
+
+            self.save_new_wallet(&wallet, &kdf)?;
+
+            Ok(Option::from(InitResult::new(mnemonic_words, deposit_address)))
+        }
+    }
+
+    /// init config with the seed of a keystore document, protected by passphrase.
+    /// Returns the deposit address or None if a config already exists
+    pub fn import_keystore(&self, document: &str, password: &str, passphrase: &str) -> Result<Option<Address>, Error> {
+        if passphrase.len() < 8 {
+            return Err(Error::InvalidArgument("passphrase should have at least 8 characters"));
+        }
+        fs::create_dir_all(&self.config_path())?;
+
+        if let Ok(_config) = config::load(&self.config_file_path()) {
+            return Ok(None);
+        }
+        let kdf = self.new_kdf()?;
+        let (deposit_address, wallet) = Wallet::import_keystore(document, password, kdf.stretch(passphrase)?.as_str())?;
+        if wallet.master_public().network != self.network {
+            return Err(Error::InvalidArgument("keystore is for an other network"));
+        }
+        self.save_new_wallet(&wallet, &kdf)?;
+        Ok(Some(deposit_address))
+    }
+
+    /// the seed as a keystore document encrypted with password, for machine-readable backups
+    pub fn export_keystore(&self, passphrase: &str, password: &str) -> Result<String, Error> {
+        let config = self.load_config()?;
+        let encrypted = self.encrypted_key(&config)?;
+        let keyroot = ExtendedPubKey::from_str(config.keyroot.as_str()).map_err(|_| Error::InvalidConfig("keyroot is malformed"))?;
+        let passphrase = kdf::stretch(config_kdf(&config)?.as_ref(), passphrase)?;
+        Wallet::from_encrypted(encrypted.as_slice(), keyroot, config.birth).export_keystore(passphrase.as_str(), password)
+    }
+
+    fn save_new_wallet(&self, wallet: &Wallet, kdf: &Kdf) -> Result<(), Error> {
+        let config_path = self.config_path();
+        let encryptedwalletkey = self.sealed_key(wallet.encrypted().as_slice())?;
         let keyroot = wallet.master_public().to_string();
         let lookahead = KEY_LOOK_AHEAD;
         let birth = wallet.birth();
 
         // init database
         db::init(&config_path, &wallet.coins, &wallet.master);
+        layout::write(&config_path)?;
 
         // save config
-        let config = Config::new(encryptedwalletkey.as_str(),
-                                 keyroot.as_str(), lookahead, birth, network);
-        config::save(&config_path, &file_path, &config)?;
+        let config = Config::builder()
+            .encryptedwalletkey(encryptedwalletkey.as_str())
+            .kdf(Some(kdf.to_string().as_str()))
+            .keyroot(keyroot.as_str())
+            .lookahead(lookahead)
+            .birth(birth)
+            .network(self.network)
+            .build()?;
+        config::save(&config_path, &self.config_file_path(), &config)
+    }
 
-        Ok(Option::from(InitResult::new(mnemonic_words, deposit_address)))
+    /// apply the stored config to the running p2p layer without restart
+    pub fn apply_config(&self) -> Result<Config, Error> {
+        let config = self.load_config()?;
+        self.apply_running(&config);
+        Ok(config)
     }
-}
 
-pub fn start(work_dir: PathBuf, network: Network, rescan: bool) -> Result<(), Error> {
-    let p2p_bitcoin;
-    let content_store;
-
-    match CONTENT_STORE.write() {
-        Err(e) => {
-            error!("{:?}", e);
-            return Ok(());
-        }
-        Ok(mut cs) => {
-            if cs.is_some() {
-                debug!("content store exists");
-                return Ok(());
-            } else {
-                debug!("content store not initialized");
-
-                let mut config_path = PathBuf::from(work_dir);
-                config_path.push(network.to_string());
-
-                let mut config_file_path = config_path.clone();
-                config_file_path.push(CONFIG_FILE_NAME);
-
-                info!("config file path: {}", &config_file_path.to_str().unwrap());
-                let config = config::load(&config_file_path).expect("can not open config file");
-
-                let mut chain_file_path = config_path.clone();
-                chain_file_path.push("bdk.chain");
-
-                let mut chain_db = ChainDB::new(chain_file_path.as_path(), network).expect("can not open chain db");
-                chain_db.init().expect("can not initialize db");
-                let chain_db = Arc::new(RwLock::new(chain_db));
-
-                let db = open_db(&config_path);
-                let db = Arc::new(Mutex::new(db));
-
-                // get master account
-                let mut bitcoin_wallet;
-                let mut master_account = MasterAccount::from_encrypted(
-                    hex::decode(config.encryptedwalletkey).expect("encryptedwalletkey is not hex").as_slice(),
-                    ExtendedPubKey::from_str(config.keyroot.as_str()).expect("keyroot is malformed"),
-                    config.birth,
-                );
-
-                // load wallet from master account
-                {
-                    let mut db = db.lock().unwrap();
-                    let mut tx = db.transaction();
-                    let account = tx.read_account(0, 0, network, config.lookahead).expect("can not read account 0/0");
-                    master_account.add_account(account);
-                    let account = tx.read_account(0, 1, network, config.lookahead).expect("can not read account 0/1");
-                    master_account.add_account(account);
-                    let account = tx.read_account(1, 0, network, 0).expect("can not read account 1/0");
-                    master_account.add_account(account);
-                    let coins = tx.read_coins(&mut master_account).expect("can not read coins");
-                    bitcoin_wallet = Wallet::from_storage(coins, master_account);
-                }
+    /// change the peer settings and apply them if running
+    pub fn update_config(&self, bitcoin_peers: Vec<SocketAddr>, bitcoin_connections: usize, bitcoin_discovery: bool) -> Result<Config, Error> {
+        let updated_config = update_config(self.work_dir.clone(), self.network, bitcoin_peers, bitcoin_connections, bitcoin_discovery)?;
+        self.apply_running(&updated_config);
+        Ok(updated_config)
+    }
 
-                // rescan chain if requested
-                if rescan {
-                    let chain_db = chain_db.read().unwrap();
-                    let mut after = None;
-                    for cached_header in chain_db.iter_trunk_rev(None) {
-                        if (cached_header.stored.header.time as u64) < config.birth {
-                            after = Some(cached_header.bitcoin_hash());
-                            break;
-                        }
-                    }
-                    if let Some(after) = after {
-                        info!("Re-scanning after block {}", &after);
+    fn apply_running(&self, config: &Config) {
+        if let Ok(p2p_bitcoin) = self.p2p_bitcoin() {
+            p2p_bitcoin.apply_config(config);
+        }
+        if let Ok(store) = self.content_store() {
+            let mut store = store.write().unwrap();
+            store.set_fee_rate_bounds(config.min_fee_rate, config.max_fee_rate);
+            store.set_fee_limits(fee_limits(config));
+            store.set_consolidation_policy(ConsolidationPolicy::from_config(config));
+            store.set_spend_unconfirmed_change(config.spend_unconfirmed_change.unwrap_or(false));
+            store.set_storage_quota(config.max_storage);
+        }
+    }
+
+    /// header chain and scan position as a blob, the wallet must be stopped
+    pub fn export_sync_state(&self) -> Result<Vec<u8>, Error> {
+        if self.is_running() {
+            return Err(Error::Unsupported("stop the wallet to export its sync state"));
+        }
+        let chain_db = self.open_chain_db()?;
+        let mut headers = chain_db.iter_trunk_rev(None).map(|cached| cached.stored.header.clone()).collect::<Vec<_>>();
+        headers.reverse();
+        let processed = open_db(&self.config_path())?.transaction().read_processed()?
+            .and_then(|block| chain_db.pos_on_trunk(&block).map(|height| (height, block)));
+        let state = SyncState { network: self.network, processed, headers };
+        Ok(state.serialize())
+    }
+
+    /// restore headers of a sync state exported by this or an other wallet of the network, the wallet must be stopped.
+    /// Blocks are scanned from the processed block of the sync state only if that is before the birth of this wallet
+    pub fn import_sync_state(&self, blob: &[u8]) -> Result<(), Error> {
+        if self.is_running() {
+            return Err(Error::Unsupported("stop the wallet to import a sync state"));
+        }
+        let state = SyncState::deserialize(blob)?;
+        if state.network != self.network {
+            return Err(Error::InvalidArgument("sync state is for an other network"));
+        }
+        let config = self.load_config()?;
+        let mut chain_db = self.open_chain_db()?;
+        for header in state.headers.iter().skip(1) {
+            chain_db.add_header(header).map_err(|_| Error::InvalidArgument("sync state header does not extend the chain"))?;
+        }
+        chain_db.batch().map_err(|_| Error::Unsupported("can not store headers"))?;
+        chain_db.shutdown();
+
+        if let Some((height, block)) = state.processed {
+            let before_birth = state.header(height).map(|h| (h.time as u64) < config.birth).unwrap_or(false);
+            let mut db = open_db(&self.config_path())?;
+            let mut tx = db.transaction();
+            if before_birth && tx.read_processed()?.is_none() {
+                info!("resume block scan after sync state block {} {}", height, block);
+                tx.store_processed(&block)?;
+            }
+            tx.commit();
+        }
+        Ok(())
+    }
+
+    /// inconsistencies of the wallet db, the wallet must be stopped. With repair the scan state is dropped if any is
+    /// found, so the next start scans blocks from the wallet birth again
+    pub fn db_check(&self, repair: bool) -> Result<Vec<DbProblem>, Error> {
+        if self.is_running() {
+            return Err(Error::Unsupported("stop the wallet to check its db"));
+        }
+        let mut db = open_db(&self.config_path())?;
+        let mut tx = db.transaction();
+        let mut problems = tx.check()?;
+        if let Some(processed) = tx.read_processed()? {
+            if !self.is_on_trunk(&processed)? {
+                problems.push(DbProblem::ProcessedOffTrunk(processed));
+            }
+        }
+        if repair && !problems.is_empty() {
+            warn!("wallet db has {} problems, blocks are scanned again at the next start", problems.len());
+            tx.reset_scan()?;
+        }
+        tx.commit();
+        Ok(problems)
+    }
+
+    /// row counts of the tables and size of the wallet db
+    pub fn db_stats(&self) -> Result<DbStats, Error> {
+        let stats = open_db(&self.config_path())?.transaction().stats()?;
+        Ok(stats)
+    }
+
+    // is the block on the trunk of the configured backend
+    fn is_on_trunk(&self, block: &sha256d::Hash) -> Result<bool, Error> {
+        match self.configured_backend()? {
+            Backend::P2P => Ok(self.open_chain_db()?.pos_on_trunk(block).is_some()),
+            Backend::Rpc => Ok(rpc_client(&self.load_config()?)?.header(block)?.map(|h| h.on_trunk).unwrap_or(false)),
+            #[cfg(any(test, feature = "testutil"))]
+            Backend::Simulation(ref simulation) => Ok(simulation.trunk().is_on_trunk(block))
+        }
+    }
+
+    fn open_chain_db(&self) -> Result<ChainDB, Error> {
+        let mut chain_file_path = self.config_path();
+        chain_file_path.push("bdk.chain");
+        let mut chain_db = ChainDB::new(chain_file_path.as_path(), self.network).map_err(|_| Error::Unsupported("can not open chain db"))?;
+        chain_db.init().map_err(|_| Error::Unsupported("can not initialize chain db"))?;
+        Ok(chain_db)
+    }
+
+    /// start with the configured backend, bitcoind if bitcoin_rpc_url is set otherwise the p2p network
+    pub fn start(&self, rescan: bool) -> Result<(), Error> {
+        self.start_with_backend(rescan, self.configured_backend()?)
+    }
+
+    fn configured_backend(&self) -> Result<Backend, Error> {
+        Ok(if self.load_config()?.bitcoin_rpc_url.is_some() { Backend::Rpc } else { Backend::P2P })
+    }
+
+    /// start with blocks from the given backend, returns after stop()
+    pub fn start_with_backend(&self, rescan: bool, backend: Backend) -> Result<(), Error> {
+        self.run(rescan, backend, None, None).map(|_| ())
+    }
+
+    /// connect and process at most max_blocks blocks within max_duration, for platforms that
+    /// only allow short background work. Progress is persisted as with stop().
+    /// Fails with NetworkUnavailable if no peer connected and no block was processed
+    pub fn sync_once(&self, max_blocks: Option<u32>, max_duration: Duration) -> Result<SyncSummary, Error> {
+        self.sync_once_with_backend(max_blocks, max_duration, self.configured_backend()?)
+    }
+
+    pub fn sync_once_with_backend(&self, max_blocks: Option<u32>, max_duration: Duration, backend: Backend) -> Result<SyncSummary, Error> {
+        self.run(false, backend, max_blocks, Some(Instant::now() + max_duration))?
+            .ok_or(Error::Unsupported("the wallet is already running"))
+    }
+
+    // run until stopped, the block budget is used or the deadline passed, None if already running
+    fn run(&self, rescan: bool, backend: Backend, max_blocks: Option<u32>, deadline: Option<Instant>) -> Result<Option<SyncSummary>, Error> {
+        let started = Instant::now();
+        let network = self.network;
+        let mut p2p_bitcoin = None;
+        let mut rpc_backend = None;
+        let content_store;
+
+        match self.content_store.write() {
+            Err(e) => {
+                error!("{:?}", e);
+                return Ok(None);
+            }
+            Ok(mut cs) => {
+                if cs.is_some() {
+                    debug!("content store exists");
+                    return Ok(None);
+                } else {
+                    debug!("content store not initialized");
+
+                    let config_path = self.config_path();
+                    let config_file_path = self.config_file_path();
+
+                    info!("config file path: {}", &config_file_path.to_str().unwrap());
+                    layout::check(&config_path)?;
+                    let config = config::load(&config_file_path)?;
+
+                    let db = open_db(&config_path)?;
+                    let db = Arc::new(Mutex::new(db));
+
+                    // get master account
+                    let mut bitcoin_wallet;
+                    let mut master_account = MasterAccount::from_encrypted(
+                        self.encrypted_key(&config)?.as_slice(),
+                        ExtendedPubKey::from_str(config.keyroot.as_str()).map_err(|_| Error::InvalidConfig("keyroot is malformed"))?,
+                        config.birth,
+                    );
+
+                    let mut retiring_wallet = None;
+
+                    // load wallet from master account
+                    {
                         let mut db = db.lock().unwrap();
                         let mut tx = db.transaction();
-                        tx.rescan(&after).expect("can not re-scan");
+                        // tables added since the wallet was created
+                        tx.create_tables();
+                        let account = tx.read_account(0, 0, network, config.lookahead)?;
+                        master_account.add_account(account);
+                        let account = tx.read_account(0, 1, network, config.lookahead)?;
+                        master_account.add_account(account);
+                        let account = tx.read_account(1, 0, network, 0)?;
+                        master_account.add_account(account);
+                        let coins = tx.read_coins(&mut master_account)?;
+                        bitcoin_wallet = Wallet::from_storage(coins, master_account);
+                        bitcoin_wallet.imported = tx.read_imported()?;
+
+                        // the master replaced by a seed rotation, until its coins are swept
+                        if let Some((sealed, public, birth)) = tx.read_retiring_master()? {
+                            let encrypted = self.unseal(sealed.as_slice())?;
+                            let mut retiring_master = MasterAccount::from_encrypted(encrypted.as_slice(), public, birth);
+                            for (account, sub, look_ahead) in &[(0, 0, config.lookahead), (0, 1, config.lookahead), (1, 0, 0)] {
+                                let account = tx.read_retiring_account(*account, *sub, network, *look_ahead)?;
+                                retiring_master.add_account(account);
+                            }
+                            let coins = tx.read_retiring_coins(&mut retiring_master)?;
+                            retiring_wallet = Some(Wallet::from_storage(coins, retiring_master));
+                        }
                         tx.commit();
-                        bitcoin_wallet.rescan();
                     }
+
+                    let mut p2p_chain_db = None;
+                    let trunk: Arc<dyn Trunk + Send + Sync> = match backend {
+                        Backend::P2P => {
+                            let chain_db = self.open_chain_db()?;
+                            let chain_db = Arc::new(RwLock::new(chain_db));
+
+                            // rescan chain if requested
+                            if rescan {
+                                let chain_db = chain_db.read().unwrap();
+                                let mut after = None;
+                                for cached_header in chain_db.iter_trunk_rev(None) {
+                                    if (cached_header.stored.header.time as u64) < config.birth {
+                                        after = Some(cached_header.bitcoin_hash());
+                                        break;
+                                    }
+                                }
+                                if let Some(after) = after {
+                                    rescan_after(&db, &after, &mut bitcoin_wallet, &mut retiring_wallet)?;
+                                }
+                            }
+                            p2p_chain_db = Some(chain_db.clone());
+                            Arc::new(ChainDBTrunk { chaindb: chain_db })
+                        }
+                        Backend::Rpc => {
+                            let trunk = Arc::new(RpcTrunk::new(rpc_client(&config)?)?);
+                            // rescan from the block before the first one after the wallet birth
+                            if rescan {
+                                let client = trunk.client();
+                                if let Some(first) = client.first_block_after(config.birth as u32)?.filter(|h| *h > 0) {
+                                    let after = client.block_hash(first - 1)?;
+                                    rescan_after(&db, &after, &mut bitcoin_wallet, &mut retiring_wallet)?;
+                                }
+                            }
+                            rpc_backend = Some((trunk.clone(), db.clone(), config.birth));
+                            trunk
+                        }
+                        #[cfg(any(test, feature = "testutil"))]
+                        Backend::Simulation(ref simulation) => {
+                            let trunk = simulation.trunk();
+                            // rescan from the block before the first one after the wallet birth, blocks are connected again on attach
+                            if rescan {
+                                let first = (1..trunk.len()).find(|h| trunk.get_header_for_height(*h).map(|b| b.time as u64 >= config.birth).unwrap_or(false));
+                                if let Some(first) = first {
+                                    let after = trunk.get_header_for_height(first - 1).expect("below the tip").bitcoin_hash();
+                                    rescan_after(&db, &after, &mut bitcoin_wallet, &mut retiring_wallet)?;
+                                }
+                            }
+                            trunk
+                        }
+                    };
+                    info!("Wallet balance: {} satoshis {} available", bitcoin_wallet.balance(), bitcoin_wallet.available_balance(trunk.len(), |h| trunk.get_height(h)));
+
+                    let mut store = ContentStore::new(db.clone(), trunk, bitcoin_wallet)?;
+                    if let Some(retiring) = retiring_wallet {
+                        store.set_retiring(retiring);
+                    }
+                    store.set_block_budget(max_blocks);
+                    store.set_fee_rate_bounds(config.min_fee_rate, config.max_fee_rate);
+                    store.set_fee_limits(fee_limits(&config));
+                    store.set_consolidation_policy(ConsolidationPolicy::from_config(&config));
+                    store.set_spend_unconfirmed_change(config.spend_unconfirmed_change.unwrap_or(false));
+                    store.set_storage_quota(config.max_storage);
+                    store.set_notifier(config.webhook_url.clone().map(|url| Notifier::start(url, config.webhook_secret.clone())));
+                    if let Some(address) = config.event_socket {
+                        match Publisher::bind(address) {
+                            Ok(publisher) => store.set_publisher(Some(publisher)),
+                            Err(e) => warn!("can not open event socket at {}: {}", address, e)
+                        }
+                    }
+                    content_store = Arc::new(RwLock::new(store));
+
+                    *cs = Option::Some(content_store.clone());
+
+                    p2p_bitcoin = p2p_chain_db.map(|chain_db| Arc::new(P2PBitcoin::new(&config, chain_db, db.clone(), content_store.clone())));
+                }
+            }
+        }
+
+        let mut thread_pool = match ThreadPoolBuilder::new().name_prefix("futures ").create() {
+            Ok(thread_pool) => thread_pool,
+            Err(e) => {
+                *self.content_store.write().unwrap() = None;
+                return Err(e.into());
+            }
+        };
+        match backend {
+            Backend::P2P => {
+                let p2p_bitcoin = p2p_bitcoin.as_ref().expect("p2p layer is created for the p2p backend");
+                p2p_bitcoin.start(&mut thread_pool);
+                *self.p2p_bitcoin.write().unwrap() = Some(p2p_bitcoin.clone());
+                consolidate::start(content_store.clone(), self.config_file_path());
+            }
+            Backend::Rpc => {
+                let (trunk, db, birth) = rpc_backend.expect("rpc trunk is created for the rpc backend");
+                if let Err(e) = rpc::attach(trunk, db, content_store.clone(), birth) {
+                    *self.content_store.write().unwrap() = None;
+                    return Err(e);
                 }
+                consolidate::start(content_store.clone(), self.config_file_path());
+            }
+            #[cfg(any(test, feature = "testutil"))]
+            Backend::Simulation(ref simulation) => {
+                if let Err(e) = simulation.attach(content_store.clone()) {
+                    *self.content_store.write().unwrap() = None;
+                    return Err(e);
+                }
+            }
+        }
+        thread_pool.run(check_stopped(content_store.clone(), deadline));
+        let connected = p2p_bitcoin.as_ref().map(|p| p.peer_count() > 0);
+
+        {
+            // the store processes no more blocks, its last processed block is where the next start resumes
+            *self.p2p_bitcoin.write().unwrap() = None;
+            if let Some(p2p_bitcoin) = p2p_bitcoin {
+                p2p_bitcoin.shutdown()
+            }
+            let mut cs = self.content_store.write().unwrap();
+            *cs = Option::None;
+            debug!("content store set to None");
+        }
+        let store = content_store.read().unwrap();
+        // a sync window that ends without a peer and without progress tells the caller the network is down
+        if deadline.is_some() && connected == Some(false) && store.processed_blocks() == 0 {
+            return Err(Error::NetworkUnavailable("no peers connected".to_string()));
+        }
+        Ok(Some(SyncSummary {
+            blocks: store.processed_blocks(),
+            header_height: store.header_height(),
+            processed_height: store.processed_height(),
+            synced: store.is_synced(),
+            elapsed: started.elapsed(),
+        }))
+    }
+
+    /// stop after the block being processed, start() returns once peers are disconnected
+    pub fn stop(&self) -> Result<(), Error> {
+        info!("stopping");
+        let store = self.content_store()?;
+        store.write().unwrap().set_stopped(true);
+        Ok(())
+    }
+
+    fn p2p_bitcoin(&self) -> Result<Arc<P2PBitcoin>, Error> {
+        match *self.p2p_bitcoin.read().map_err(|_| Error::Lock("p2p"))? {
+            Some(ref p2p_bitcoin) => Ok(p2p_bitcoin.clone()),
+            None => Err(Error::NotRunning)
+        }
+    }
+
+    fn content_store(&self) -> Result<SharedContentStore, Error> {
+        match *self.content_store.read().map_err(|_| Error::Lock("content store"))? {
+            Some(ref store) => Ok(store.clone()),
+            None => Err(Error::NotRunning)
+        }
+    }
+
+    pub fn balance(&self) -> Result<BalanceAmt, Error> {
+        let store = self.content_store()?;
+        let bal_vec = store.read().unwrap().balance();
+        Ok(BalanceAmt::new(bal_vec[0], bal_vec[1]))
+    }
 
-                let trunk = Arc::new(ChainDBTrunk { chaindb: chain_db.clone() });
-                info!("Wallet balance: {} satoshis {} available", bitcoin_wallet.balance(), bitcoin_wallet.available_balance(trunk.len(), |h| trunk.get_height(h)));
+    pub fn deposit_addr(&self) -> Result<Address, Error> {
+        let store = self.content_store()?;
+        let addr = store.write().unwrap().deposit_address();
+        Ok(addr)
+    }
 
-                content_store =
-                    Arc::new(RwLock::new(
-                        ContentStore::new(db.clone(), trunk, bitcoin_wallet).expect("can not initialize content store")));
+    /// addresses the wallet watches in the receive and change chains of an account, with derivation path, usage and balance
+    pub fn list_addresses(&self, account: u32) -> Result<Vec<AddressInfo>, Error> {
+        let store = self.content_store()?;
+        let addresses = store.read().unwrap().list_addresses(account)?;
+        Ok(addresses)
+    }
 
-                *cs = Option::Some(content_store.clone());
+    /// raw hex and decoded view of a wallet transaction: inputs, outputs, our addresses and the net effect on our balance
+    pub fn get_transaction(&self, txid: &sha256d::Hash) -> Result<Option<TransactionInfo>, Error> {
+        let store = self.content_store()?;
+        let transaction = store.read().unwrap().get_transaction(txid)?;
+        Ok(transaction)
+    }
 
-                p2p_bitcoin = P2PBitcoin::new(config.network, config.bitcoin_connections, config.bitcoin_peers, config.bitcoin_discovery, chain_db.clone(), db.clone(),
-                                              content_store.clone(), config.birth);
+    /// label a transaction for the history export, an empty label removes it
+    pub fn set_label(&self, txid: &sha256d::Hash, label: &str) -> Result<(), Error> {
+        let store = self.content_store()?;
+        store.write().unwrap().set_label(txid, label)?;
+        Ok(())
+    }
+
+    /// confirmed transactions with block time stamps between from and to (seconds since the epoch, inclusive) as CSV or JSON,
+    /// with direction, amount, fee, running balance and label. Valued in currency at transaction time and current rates if given
+    pub fn export_history(&self, format: ExportFormat, from: Option<u32>, to: Option<u32>, currency: Option<&str>) -> Result<String, Error> {
+        let store = self.content_store()?;
+        let mut records = store.read().unwrap().history_records(from, to)?;
+        if let Some(currency) = currency {
+            let current = self.rate(currency, None)?;
+            for record in records.iter_mut() {
+                let rate = self.rate(currency, Some(record.timestamp))?;
+                let net = record.net();
+                record.fiat = Some(FiatValue {
+                    currency: currency.to_lowercase(),
+                    rate,
+                    amount: rates::fiat(net, rate),
+                    current: rates::fiat(net, current),
+                });
             }
         }
+        Ok(history::export(&records, format))
     }
 
-    let mut thread_pool = ThreadPoolBuilder::new().name_prefix("futures ").create().expect("can not start thread pool");
-    p2p_bitcoin.start(&mut thread_pool);
-    thread_pool.run(check_stopped(content_store));
+    /// value exchange rates with provider, None to stop fiat valuations
+    pub fn set_rate_provider(&self, provider: Option<Arc<dyn RateProvider>>) {
+        *self.rate_provider.write().unwrap() = provider;
+    }
 
-    {
-        let mut cs = CONTENT_STORE.write().unwrap();
-        *cs = Option::None;
-        debug!("content store set to None");
-        p2p_bitcoin.shutdown()
+    /// balance valued in currency at the current rate
+    pub fn fiat_balance(&self, currency: &str) -> Result<FiatBalance, Error> {
+        let balance = self.balance()?;
+        let rate = self.rate(currency, None)?;
+        Ok(FiatBalance {
+            currency: currency.to_lowercase(),
+            rate,
+            balance: rates::fiat(balance.balance as i64, rate),
+            confirmed: rates::fiat(balance.confirmed as i64, rate),
+        })
+    }
+
+    // price of a bitcoin in currency at time or now, cached in the db
+    fn rate(&self, currency: &str, time: Option<u32>) -> Result<f64, Error> {
+        let currency = currency.to_lowercase();
+        let store = self.content_store()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let slot = rates::rate_slot(time, now);
+        if let Some(rate) = store.read().unwrap().cached_rate(currency.as_str(), slot)? {
+            return Ok(rate);
+        }
+        let provider = self.rate_provider.read().unwrap().clone().ok_or(Error::Unsupported("no exchange rate provider is set"))?;
+        let rate = provider.rate(currency.as_str(), time)?;
+        store.write().unwrap().cache_rate(currency.as_str(), slot, rate)?;
+        Ok(rate)
+    }
+
+    /// decode a raw hex transaction, e.g. one built elsewhere before it is broadcast, marking outputs paying to us.
+    /// Nothing is marked as ours if the wallet is not running
+    pub fn decode_tx(&self, hex: &str) -> Result<TransactionInfo, Error> {
+        let transaction = parse_tx(hex)?;
+        match self.content_store() {
+            Ok(store) => store.read().unwrap().decode_tx(&transaction),
+            Err(Error::NotRunning) => decode_tx(hex, self.network),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// watch an address without keys, e.g. a cold storage, its coins are reported apart from the wallet's
+    pub fn watch_address(&self, address: &Address) -> Result<(), Error> {
+        if address.network != self.network {
+            return Err(Error::InvalidArgument("address is for an other network"));
+        }
+        let store = self.content_store()?;
+        let result = store.write().unwrap().watch_script(address.script_pubkey(), Some(address.clone()));
+        result
+    }
+
+    /// watch a script without keys, its coins are reported apart from the wallet's
+    pub fn watch_script(&self, script: &Script) -> Result<(), Error> {
+        let store = self.content_store()?;
+        let result = store.write().unwrap().watch_script(script.clone(), None);
+        result
+    }
+
+    /// watched scripts with their coins, balance and transaction history
+    pub fn watched(&self) -> Result<Vec<Watched>, Error> {
+        let store = self.content_store()?;
+        let watched = store.read().unwrap().watched();
+        Ok(watched)
+    }
+
+    /// import a WIF private key of an other wallet, compressed keys are tracked for each address type.
+    /// Their coins are reported apart from the wallet's, from the next block or from the wallet birth after a rescan
+    pub fn import_wif(&self, passphrase: Secret, wif: &str) -> Result<Vec<ImportedKey>, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let added = store.write().unwrap().import_wif(&passphrase, wif);
+        added
+    }
+
+    /// import the first receive and change keys of an Electrum standard or segwit seed
+    pub fn import_electrum_seed(&self, passphrase: Secret, words: &str, seed_passphrase: Option<&str>) -> Result<Vec<ImportedKey>, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let added = store.write().unwrap().import_electrum(&passphrase, words, seed_passphrase.unwrap_or(""));
+        added
+    }
+
+    /// import a WIF private key, e.g. of a paper wallet, and send all its coins to a deposit address.
+    /// Coins are those found by a rescan and, if a broadcast fallback is configured, those its Esplora API knows
+    pub fn sweep_wif(&self, passphrase: Secret, wif: &str, fee_per_vbyte: u64) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let key = PrivateKey::from_wif(wif).map_err(|_| Error::InvalidArgument("not a WIF private key"))?;
+        let public = key.public_key(&Secp256k1::signing_only());
+        let store = self.content_store()?;
+        let keys = {
+            let mut store = store.write().unwrap();
+            store.import_wif(&passphrase, wif)?;
+            store.imported().into_iter().filter(|k| k.public == public).collect::<Vec<_>>()
+        };
+        let mut additional = Vec::new();
+        if let Some(endpoint) = self.load_config()?.bitcoin_broadcast_fallback {
+            for key in &keys {
+                for (outpoint, value) in esplora::address_utxos(endpoint.as_str(), key.address())? {
+                    additional.push((key.watched.script.clone(), outpoint, value));
+                }
+            }
+        }
+        let (transaction, fee) = store.write().unwrap().sweep_imported(&passphrase, Some(&public), additional, fee_per_vbyte)?;
+        Ok(WithdrawTx::new(transaction.txid(), fee))
+    }
+
+    /// send coins of all imported keys to a deposit address
+    pub fn sweep_imported(&self, passphrase: Secret, fee_per_vbyte: u64) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let (transaction, fee) = store.write().unwrap().sweep_imported(&passphrase, None, Vec::new(), fee_per_vbyte)?;
+        Ok(WithdrawTx::new(transaction.txid(), fee))
+    }
+
+    /// keys imported from other wallets with their coins
+    pub fn imported_keys(&self) -> Result<Vec<ImportedKey>, Error> {
+        let store = self.content_store()?;
+        let keys = store.read().unwrap().imported();
+        Ok(keys)
+    }
+
+    /// our BIP47 payment code, to be shared instead of addresses
+    pub fn payment_code(&self, passphrase: Secret) -> Result<PaymentCode, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let code = store.read().unwrap().payment_code(passphrase.as_str());
+        code
+    }
+
+    /// the address of the index-th payment to the owner of a payment code
+    pub fn payment_address(&self, passphrase: Secret, theirs: &PaymentCode, index: u32) -> Result<Address, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let address = store.read().unwrap().payment_address(passphrase.as_str(), theirs, index);
+        address
+    }
+
+    /// send our payment code to the owner of theirs in a notification transaction, so they find our payments to payment_address
+    pub fn notify_payment_code(&self, passphrase: Secret, theirs: &PaymentCode, fee_per_vbyte: u64, options: &TxOptions) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let (t, f) = store.write().unwrap().notify_payment_code(&passphrase, theirs, fee_per_vbyte, options)?;
+        Ok(WithdrawTx::new(t.txid(), f))
+    }
+
+    /// receive payments to our payment code, imports keys for senders that notified us.
+    /// Until the wallet stops, later notifications and payments are found while processing blocks
+    pub fn receive_payment_codes(&self, passphrase: Secret) -> Result<Vec<ImportedKey>, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let added = store.write().unwrap().receive_payment_codes(&passphrase);
+        added
+    }
+
+    /// payment codes of senders that notified us
+    pub fn payment_code_senders(&self) -> Result<Vec<PaymentCode>, Error> {
+        let store = self.content_store()?;
+        let senders = store.read().unwrap().payment_code_senders();
+        Ok(senders)
+    }
+
+    /// how far the wallet is synchronized
+    pub fn sync_status(&self) -> Result<SyncStatus, Error> {
+        let store = self.content_store()?;
+        let peer_count = self.p2p_bitcoin().map(|p| p.peer_count()).unwrap_or(0);
+        let store = store.read().unwrap();
+        Ok(SyncStatus {
+            header_height: store.header_height(),
+            block_height: store.processed_height(),
+            peer_count,
+            synced: store.is_synced(),
+        })
+    }
+
+    /// the header tip the wallet follows, None before the first header
+    pub fn get_tip(&self) -> Result<Option<ChainHeader>, Error> {
+        let store = self.content_store()?;
+        let tip = store.read().unwrap().tip_header();
+        Ok(tip.map(|(height, header)| ChainHeader::new(height, &header)))
+    }
+
+    /// the header at height of the chain the wallet follows, None above the tip
+    pub fn get_header(&self, height: u32) -> Result<Option<ChainHeader>, Error> {
+        let store = self.content_store()?;
+        let header = store.read().unwrap().header_for_height(height);
+        Ok(header.map(|header| ChainHeader::new(height, &header)))
+    }
+
+    /// true if the wallet db could not be kept within the max_storage quota by pruning peer addresses
+    pub fn is_over_storage_quota(&self) -> Result<bool, Error> {
+        let store = self.content_store()?;
+        let over = store.read().unwrap().is_over_storage_quota();
+        Ok(over)
+    }
+
+    /// follow headers only, deferring block download, or download and scan blocks again. Applies until the next start,
+    /// use the headers_only config setting to start that way
+    pub fn set_headers_only(&self, headers_only: bool) -> Result<(), Error> {
+        self.p2p_bitcoin()?.set_scanning(!headers_only);
+        Ok(())
+    }
+
+    /// true if blocks are not downloaded, only headers followed
+    pub fn is_headers_only(&self) -> Result<bool, Error> {
+        Ok(!self.p2p_bitcoin()?.is_scanning())
+    }
+
+    /// connect a peer now without changing the config
+    pub fn add_peer(&self, address: SocketAddr) -> Result<(), Error> {
+        self.p2p_bitcoin()?.add_peer(address)
+    }
+
+    /// disconnect a peer now without changing the config
+    pub fn disconnect_peer(&self, address: SocketAddr) -> Result<(), Error> {
+        self.p2p_bitcoin()?.disconnect_peer(address)
+    }
+
+    /// connected peers, to diagnose a wallet that does not sync
+    pub fn list_peers(&self) -> Result<Vec<PeerInfo>, Error> {
+        Ok(self.p2p_bitcoin()?.list_peers())
+    }
+
+    /// bytes exchanged with peers since start, in total and per peer address
+    pub fn bandwidth_usage(&self) -> Result<BandwidthUsage, Error> {
+        Ok(self.p2p_bitcoin()?.bandwidth_usage())
+    }
+
+    /// how widely a transaction sent since start propagated, None if it is unknown
+    pub fn broadcast_status(&self, txid: &sha256d::Hash) -> Result<Option<BroadcastStatus>, Error> {
+        Ok(self.p2p_bitcoin()?.broadcast_status(txid))
+    }
+
+    pub fn withdraw(&self, passphrase: Secret, address: Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<WithdrawTx, Error> {
+        self.withdraw_with_options(passphrase, address, fee_per_vbyte, amount, &TxOptions::default())
+    }
+
+    /// withdraw with caller chosen lock time and sequences
+    pub fn withdraw_with_options(&self, passphrase: Secret, address: Address, fee_per_vbyte: u64, amount: Option<u64>, options: &TxOptions) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let withdraw = store.write().unwrap().withdraw(&passphrase, address, fee_per_vbyte, amount, options);
+        match withdraw {
+            Ok((t, f)) => {
+                Ok(WithdrawTx::new(t.txid(), f))
+            }
+            Err(e) => {
+                Err(e)
+            }
+        }
+    }
+
+    /// pay amount together with inputs of an other party, returns the hex of a PSBT with our inputs signed
+    pub fn collaborate(&self, passphrase: Secret, address: Address, fee_per_vbyte: u64, amount: u64, external: &[ExternalInput], options: &TxOptions) -> Result<CollaborationTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let (psbt, fee) = store.write().unwrap().collaborate(&passphrase, address, fee_per_vbyte, amount, external, options)?;
+        Ok(CollaborationTx { psbt: hex::encode(serialize(&psbt)), fee })
+    }
+
+    /// encrypt the master key with a new passphrase, the config file is replaced atomically
+    pub fn change_passphrase(&self, passphrase: Secret, new_passphrase: Secret) -> Result<(), Error> {
+        if new_passphrase.len() < 8 {
+            return Err(Error::InvalidArgument("passphrase should have at least 8 characters"));
+        }
+        let config = self.load_config()?;
+        let current = config_kdf(&config)?;
+        let passphrase = kdf::stretch(current.as_ref(), passphrase.as_str())?;
+        // a retiring seed is stretched by the kdf of the config, so an existing one is kept
+        let kdf = match current {
+            Some(kdf) => kdf,
+            None => self.new_kdf()?
+        };
+        self.reencrypt(config, passphrase.as_str(), kdf.stretch(new_passphrase.as_str())?.as_str(), &kdf)
+    }
+
+    // encrypt the seed with new_passphrase stretched by kdf, the config file is replaced atomically
+    fn reencrypt(&self, config: Config, passphrase: &str, new_passphrase: &str, kdf: &Kdf) -> Result<(), Error> {
+        let encrypted = self.encrypted_key(&config)?;
+        let keyroot = ExtendedPubKey::from_str(config.keyroot.as_str()).map_err(|_| Error::InvalidConfig("keyroot is malformed"))?;
+        let reencrypted = Wallet::reencrypt(encrypted.as_slice(), &keyroot, passphrase, new_passphrase)?;
+        let updated_config = config.to_builder()
+            .encryptedwalletkey(self.sealed_key(reencrypted.as_slice())?.as_str())
+            .kdf(Some(kdf.to_string().as_str()))
+            .build()?;
+        config::save(&self.config_path(), &self.config_file_path(), &updated_config)?;
+        // a running wallet continues with the new passphrase
+        if let Ok(store) = self.content_store() {
+            store.write().unwrap().set_encrypted(&keyroot, reencrypted.as_slice(), &Secret::from(new_passphrase));
+        }
+        Ok(())
+    }
+
+    // the passphrase as the seed is encrypted with. A seed encrypted with the passphrase as is
+    // is re-encrypted with a stretched one once the passphrase is proven right
+    fn unlock(&self, passphrase: &str) -> Result<Secret, Error> {
+        let config = self.load_config()?;
+        if let Some(kdf) = config_kdf(&config)? {
+            return kdf.stretch(passphrase);
+        }
+        let kdf = self.new_kdf()?;
+        let stretched = kdf.stretch(passphrase)?;
+        self.reencrypt(config, passphrase, stretched.as_str(), &kdf)?;
+        info!("re-encrypted the seed with a stretched passphrase");
+        Ok(stretched)
+    }
+
+    /// replace the seed by a new one protected by new_passphrase. Available coins are swept to the new seed at once,
+    /// coins of still locked term deposits once sweep_retiring is called after they became available.
+    /// The old seed is retired once it holds no coins and its vaults are closed, until then passphrase unlocks them as retiring passphrase.
+    /// The rotation stays in effect if the sweep fails.
+    pub fn rotate_seed(&self, passphrase: Secret, new_passphrase: Secret, pd_passphrase: Option<&str>, fee_per_vbyte: u64) -> Result<InitResult, Error> {
+        if new_passphrase.len() < 8 {
+            return Err(Error::InvalidArgument("passphrase should have at least 8 characters"));
+        }
+        let store = self.content_store()?;
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let config = self.load_config()?;
+        // the new seed keeps the kdf, so the passphrase of the retiring seed is stretched alike
+        let new_passphrase = kdf::stretch(config_kdf(&config)?.as_ref(), new_passphrase.as_str())?;
+        let (mnemonic_words, deposit_address, wallet) = Wallet::new(self.network, new_passphrase.as_str(), pd_passphrase);
+        let rotated_config = config.to_builder()
+            .encryptedwalletkey(self.sealed_key(wallet.encrypted().as_slice())?.as_str())
+            .keyroot(wallet.master_public().to_string().as_str())
+            .birth(wallet.birth())
+            .build()?;
+        // the config is replaced atomically before the db commits the rotation, and restored if that fails
+        let sealed = hex::decode(&config.encryptedwalletkey).map_err(|_| Error::InvalidConfig("encryptedwalletkey is not hex"))?;
+        config::save(&self.config_path(), &self.config_file_path(), &rotated_config)?;
+        if let Err(e) = store.write().unwrap().rotate(wallet, passphrase.as_str(), sealed.as_slice()) {
+            config::save(&self.config_path(), &self.config_file_path(), &config)?;
+            return Err(e);
+        }
+        store.write().unwrap().sweep_retiring(&passphrase, fee_per_vbyte)?;
+        Ok(InitResult::new(mnemonic_words.to_string(), deposit_address))
+    }
+
+    /// sweep coins of the seed retired by rotate_seed that became available since
+    pub fn sweep_retiring(&self, passphrase: Secret, fee_per_vbyte: u64) -> Result<(), Error> {
+        let store = self.content_store()?;
+        let passphrase = self.unlock_retiring(passphrase.as_str())?;
+        let swept = store.write().unwrap().sweep_retiring(&passphrase, fee_per_vbyte);
+        swept
+    }
+
+    // the passphrase of the retiring seed as it is encrypted with. It is not proven right by the wallet, so it is not upgraded
+    fn unlock_retiring(&self, passphrase: &str) -> Result<Secret, Error> {
+        kdf::stretch(config_kdf(&self.load_config()?)?.as_ref(), passphrase)
+    }
+
+    /// progress of a seed rotation since the last call
+    pub fn rotation_events(&self) -> Result<Vec<RotationEvent>, Error> {
+        let store = self.content_store()?;
+        let events = store.write().unwrap().rotation_events();
+        Ok(events)
+    }
+
+    /// consolidate small coins by the configured policy, signing with passphrase until disabled or stopped
+    pub fn enable_consolidation(&self, passphrase: Secret) -> Result<(), Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let result = store.write().unwrap().set_consolidation_passphrase(Some(passphrase));
+        result
+    }
+
+    pub fn disable_consolidation(&self) -> Result<(), Error> {
+        let store = self.content_store()?;
+        let result = store.write().unwrap().set_consolidation_passphrase(None);
+        result
+    }
+
+    /// consolidations since the last call
+    pub fn consolidation_events(&self) -> Result<Vec<ConsolidationEvent>, Error> {
+        let store = self.content_store()?;
+        let events = store.write().unwrap().consolidation_events();
+        Ok(events)
+    }
+
+    /// vaults created, including closed ones
+    pub fn vaults(&self) -> Result<Vec<Vault>, Error> {
+        let store = self.content_store()?;
+        let vaults = store.read().unwrap().vaults();
+        Ok(vaults)
+    }
+
+    /// output descriptors of open vaults, with the seed they recover the vault coins elsewhere
+    pub fn vault_descriptors(&self, passphrase: Secret) -> Result<Vec<String>, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let descriptors = store.read().unwrap().vault_descriptors(&passphrase);
+        descriptors
+    }
+
+    /// lock amount in a new vault until block height lock_time, its coins can be spent delay blocks after unvaulting.
+    /// A cancel_key kept elsewhere replaces our own key to cancel unvaulting
+    pub fn vault(&self, passphrase: Secret, amount: u64, lock_time: u32, delay: u16, fee_per_vbyte: u64, cancel_key: Option<PublicKey>) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let store = self.content_store()?;
+        let (t, f) = store.write().unwrap().vault(&passphrase, amount, lock_time, delay, fee_per_vbyte, cancel_key)?;
+        Ok(WithdrawTx::new(t.txid(), f))
+    }
+
+    /// start unvaulting a vault whose lock time passed.
+    /// A vault created before rotate_seed is unlocked with the retiring_passphrase of the retired seed
+    pub fn unvault(&self, passphrase: Secret, retiring_passphrase: Option<Secret>, vault: OutPoint, fee_per_vbyte: u64) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let retiring_passphrase = retiring_passphrase.map(|p| self.unlock_retiring(p.as_str())).transpose()?;
+        let store = self.content_store()?;
+        let (t, f) = store.write().unwrap().unvault(&passphrase, retiring_passphrase.as_ref(), &vault, fee_per_vbyte)?;
+        Ok(WithdrawTx::new(t.txid(), f))
+    }
+
+    /// send the coins of an unvaulting vault to a new vault of the current seed locked until lock_time,
+    /// cancel_key is needed if the vault was created with one.
+    /// A vault created before rotate_seed is unlocked with the retiring_passphrase of the retired seed
+    pub fn cancel_unvault(&self, passphrase: Secret, retiring_passphrase: Option<Secret>, vault: OutPoint, lock_time: u32, fee_per_vbyte: u64, cancel_key: Option<PrivateKey>) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let retiring_passphrase = retiring_passphrase.map(|p| self.unlock_retiring(p.as_str())).transpose()?;
+        let store = self.content_store()?;
+        let cancel_key = cancel_key.map(|k| k.key);
+        let (t, f) = store.write().unwrap().cancel_unvault(&passphrase, retiring_passphrase.as_ref(), &vault, lock_time, fee_per_vbyte, cancel_key.as_ref())?;
+        Ok(WithdrawTx::new(t.txid(), f))
+    }
+
+    /// spend unvaulted coins once the delay passed.
+    /// A vault created before rotate_seed is unlocked with the retiring_passphrase of the retired seed
+    pub fn withdraw_vault(&self, passphrase: Secret, retiring_passphrase: Option<Secret>, vault: OutPoint, address: Address, fee_per_vbyte: u64) -> Result<WithdrawTx, Error> {
+        let passphrase = self.unlock(passphrase.as_str())?;
+        let retiring_passphrase = retiring_passphrase.map(|p| self.unlock_retiring(p.as_str())).transpose()?;
+        let store = self.content_store()?;
+        let (t, f) = store.write().unwrap().withdraw_vault(&passphrase, retiring_passphrase.as_ref(), &vault, address, fee_per_vbyte)?;
+        Ok(WithdrawTx::new(t.txid(), f))
     }
-    Ok(())
 }
 
-async fn check_stopped(store: Arc<RwLock<ContentStore>>) -> () {
+async fn check_stopped(store: Arc<RwLock<ContentStore>>, deadline: Option<Instant>) -> () {
     info!("start check_stopped");
     let mut stopped = false;
     while !stopped {
         Delay::new(time::Duration::from_millis(100)).await.unwrap();
+        if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+            info!("sync time is up");
+            store.write().unwrap().set_stopped(true);
+        }
         stopped = store.read().unwrap().get_stopped();
     }
     warn!("stopped");
 }
 
-pub fn stop() -> () {
-    info!("stopping");
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    store.write().unwrap().set_stopped(true);
+/// a parsed address
+#[derive(Debug, Clone)]
+pub struct AddressValidation {
+    pub address: Address,
+    /// p2pkh, p2sh, p2wpkh or p2wsh, None for other witness programs
+    pub address_type: Option<String>,
+    /// true if the address is for the network it was checked against
+    pub network_matches: bool,
 }
 
+/// synchronization progress of a running wallet
 #[derive(Debug, Clone)]
-pub struct BalanceAmt { pub balance: u64, pub confirmed: u64 }
+pub struct SyncStatus {
+    pub header_height: u32,
+    /// height of the last processed block
+    pub block_height: Option<u32>,
+    pub peer_count: usize,
+    /// processed up to a recent header tip
+    pub synced: bool,
+}
 
-impl BalanceAmt {
-    fn new(balance: u64, confirmed: u64) -> BalanceAmt {
-        BalanceAmt { balance, confirmed }
+/// a header of the chain the wallet follows
+#[derive(Debug, Clone)]
+pub struct ChainHeader {
+    pub height: u32,
+    pub hash: sha256d::Hash,
+    /// block time, seconds since the unix epoch
+    pub time: u32,
+}
+
+impl ChainHeader {
+    fn new(height: u32, header: &BlockHeader) -> ChainHeader {
+        ChainHeader { height, hash: header.bitcoin_hash(), time: header.time }
     }
 }
 
-pub fn balance() -> Result<BalanceAmt, Error> {
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    let bal_vec = store.read().unwrap().balance();
-    Ok(BalanceAmt::new(bal_vec[0], bal_vec[1]))
+/// progress made by sync_once
+#[derive(Debug, Clone)]
+pub struct SyncSummary {
+    /// blocks processed
+    pub blocks: u32,
+    pub header_height: u32,
+    /// height of the last processed block
+    pub processed_height: Option<u32>,
+    /// processed up to a recent header tip
+    pub synced: bool,
+    pub elapsed: Duration,
 }
 
-pub fn deposit_addr() -> Address {
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    let addr = store.write().unwrap().deposit_address();
-    addr
+#[derive(Debug, Clone)]
+pub struct BalanceAmt { pub balance: u64, pub confirmed: u64 }
+
+impl BalanceAmt {
+    fn new(balance: u64, confirmed: u64) -> BalanceAmt {
+        BalanceAmt { balance, confirmed }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -284,23 +1308,124 @@ impl WithdrawTx {
     }
 }
 
-pub fn withdraw(passphrase: String, address: Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<WithdrawTx, Error> {
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    let withdraw = store.write().unwrap().withdraw(passphrase, address, fee_per_vbyte, amount);
-    match withdraw {
-        Ok((t, f)) => {
-            Ok(WithdrawTx::new(t.txid(), f))
-        }
-        Err(e) => {
-            Err(e)
-        }
+/// a balance valued in a fiat currency
+#[derive(Debug, Clone)]
+pub struct FiatBalance { pub currency: String, pub rate: f64, pub balance: f64, pub confirmed: f64 }
+
+#[derive(Debug, Clone)]
+pub struct CollaborationTx { pub psbt: String, pub fee: u64 }
+
+fn fee_limits(config: &Config) -> FeeLimits {
+    FeeLimits {
+        max_fee_percent: config.max_fee_percent.or(Some(DEFAULT_MAX_FEE_PERCENT)),
+        max_fee: config.max_fee,
     }
 }
 
-fn open_db(config_path: &Path) -> DB {
+// process blocks after the given one again, with coins of the wallets forgotten
+fn rescan_after(db: &Mutex<DB>, after: &sha256d::Hash, wallet: &mut Wallet, retiring: &mut Option<Wallet>) -> Result<(), Error> {
+    info!("Re-scanning after block {}", after);
+    let mut db = db.lock().unwrap();
+    let mut tx = db.transaction();
+    tx.rescan(after)?;
+    tx.commit();
+    wallet.rescan();
+    if let Some(ref mut retiring) = retiring {
+        retiring.rescan();
+    }
+    Ok(())
+}
+
+fn rpc_client(config: &Config) -> Result<RpcClient, Error> {
+    let url = config.bitcoin_rpc_url.as_ref().ok_or(Error::InvalidConfig("bitcoin_rpc_url is not set"))?;
+    Ok(RpcClient::new(url.as_str(), config.bitcoin_rpc_user.as_ref().map(|u| u.as_str()), config.bitcoin_rpc_password.as_ref().map(|p| p.as_str())))
+}
+
+fn open_db(config_path: &Path) -> Result<DB, Error> {
     let mut db_path = PathBuf::from(config_path);
     const DB_FILE_NAME: &str = "bdk.db";
     db_path.push(DB_FILE_NAME);
-    let db = DB::new(db_path.as_path()).expect(format!("Can't open DB {}", db_path.to_str().expect("can't get db_path")).as_str());
-    db
-}
\ No newline at end of file
+    DB::new(db_path.as_path())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use bitcoin::{Address, Network, Script};
+
+    use crate::custody::KeyStorage;
+    use crate::error::Error;
+    use crate::kdf;
+    use crate::secret::Secret;
+
+    use super::{validate_address, WalletHandle};
+
+    const PASSPHRASE: &str = "correct horse battery";
+
+    /// seals with a tag, as a platform storage would
+    struct Tagging;
+
+    impl KeyStorage for Tagging {
+        fn seal(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+            let mut sealed = b"sealed".to_vec();
+            sealed.extend_from_slice(encrypted);
+            Ok(sealed)
+        }
+
+        fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+            if !sealed.starts_with(b"sealed") {
+                return Err(Error::InvalidArgument("not sealed by this storage"));
+            }
+            Ok(sealed[6..].to_vec())
+        }
+    }
+
+    #[test]
+    fn validate_addresses() {
+        let segwit = Address::p2wsh(&Script::new(), Network::Testnet).to_string();
+        let validation = validate_address(segwit.as_str(), Network::Testnet).unwrap();
+        assert_eq!((validation.address_type, validation.network_matches), (Some("p2wsh".to_string()), true));
+        assert!(!validate_address(segwit.as_str(), Network::Bitcoin).unwrap().network_matches);
+        assert!(!validate_address(segwit.as_str(), Network::Regtest).unwrap().network_matches);
+
+        let legacy = Address::p2sh(&Script::new(), Network::Testnet).to_string();
+        let validation = validate_address(legacy.as_str(), Network::Regtest).unwrap();
+        assert_eq!((validation.address_type, validation.network_matches), (Some("p2sh".to_string()), true));
+        assert!(!validate_address(legacy.as_str(), Network::Bitcoin).unwrap().network_matches);
+
+        assert!(validate_address(&segwit[..segwit.len() - 1], Network::Testnet).is_none());
+        assert!(validate_address("not an address", Network::Testnet).is_none());
+    }
+
+    #[test]
+    fn handle_key_custody() {
+        let (dir_a, dir_b) = (PathBuf::from("./test-handle-a"), PathBuf::from("./test-handle-b"));
+        let a = WalletHandle::new(dir_a.clone(), Network::Regtest);
+        let b = WalletHandle::new(dir_b.clone(), Network::Regtest);
+        a.set_key_storage(Arc::new(Tagging)).unwrap();
+        a.set_kdf_cost(kdf::MIN_MEMORY, 1).unwrap();
+        b.set_kdf_cost(kdf::MIN_MEMORY, 2).unwrap();
+        assert!(b.set_kdf_cost(kdf::MIN_MEMORY - 1, 1).is_err());
+
+        a.init_config(Secret::from(PASSPHRASE), None).unwrap().unwrap();
+        b.init_config(Secret::from(PASSPHRASE), None).unwrap().unwrap();
+
+        // each handle seals and stretches with its own settings
+        let (config_a, config_b) = (a.load_config().unwrap(), b.load_config().unwrap());
+        assert!(config_a.encryptedwalletkey.starts_with(hex::encode(b"sealed").as_str()));
+        assert!(!config_b.encryptedwalletkey.starts_with(hex::encode(b"sealed").as_str()));
+        assert!(config_a.kdf.unwrap().contains(format!("m={},t=1,", kdf::MIN_MEMORY).as_str()));
+        assert!(config_b.kdf.unwrap().contains(format!("m={},t=2,", kdf::MIN_MEMORY).as_str()));
+
+        a.export_keystore(PASSPHRASE, "keystore password").unwrap();
+        b.export_keystore(PASSPHRASE, "keystore password").unwrap();
+        // a handle without the storage can not unseal the seed
+        assert!(WalletHandle::new(dir_a.clone(), Network::Regtest).export_keystore(PASSPHRASE, "keystore password").is_err());
+
+        fs::remove_dir_all(dir_a).unwrap();
+        fs::remove_dir_all(dir_b).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! bandwidth accounting and download throttling
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, mpsc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::consensus::serialize;
+use bitcoin::network::constants::Network;
+use bitcoin::network::address::Address;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_network::VersionMessage;
+use murmel::p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+
+pub type SharedBandwidth = Arc<RwLock<Bandwidth>>;
+
+/// size of the p2p message header
+const HEADER_SIZE: u64 = 24;
+/// the download rate is averaged over this window
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// bytes received and sent
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub received: u64,
+    pub sent: u64,
+}
+
+/// usage per peer address and aggregate usage since start
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BandwidthUsage {
+    pub total: Usage,
+    pub peers: Vec<(SocketAddr, Usage)>,
+}
+
+/// counts bytes of p2p messages exchanged with peers and limits the download rate
+pub struct Bandwidth {
+    network: Network,
+    max_download_rate: Option<u64>,
+    total: Usage,
+    peers: HashMap<PeerId, SocketAddr>,
+    by_address: HashMap<SocketAddr, Usage>,
+    recent: VecDeque<(Instant, u64)>,
+}
+
+impl Bandwidth {
+    pub fn new(network: Network, max_download_rate: Option<u64>) -> Bandwidth {
+        Bandwidth {
+            network,
+            max_download_rate,
+            total: Usage::default(),
+            peers: HashMap::new(),
+            by_address: HashMap::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// set the download limit in bytes per second, None for unlimited
+    pub fn set_max_download_rate(&mut self, max_download_rate: Option<u64>) {
+        self.max_download_rate = max_download_rate;
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.max_download_rate.is_some()
+    }
+
+    /// true if the recent download rate is within the limit
+    pub fn download_allowed(&mut self) -> bool {
+        if let Some(limit) = self.max_download_rate {
+            self.expire();
+            let received = self.recent.iter().map(|(_, n)| *n).sum::<u64>();
+            return received < limit * RATE_WINDOW.as_secs();
+        }
+        true
+    }
+
+    pub fn usage(&self) -> BandwidthUsage {
+        BandwidthUsage {
+            total: self.total,
+            peers: self.by_address.iter().map(|(a, u)| (*a, *u)).collect(),
+        }
+    }
+
+    pub fn connected(&mut self, peer: PeerId, address: SocketAddr) {
+        self.peers.insert(peer, address);
+    }
+
+    /// count the version and verack messages exchanged before the peer was reported connected
+    pub fn handshake(&mut self, address: Option<SocketAddr>, sent: &VersionMessage, received: &VersionMessage) {
+        self.sent(address, &NetworkMessage::Version(sent.clone()));
+        self.sent(address, &NetworkMessage::Verack);
+        self.received(address, &NetworkMessage::Version(received.clone()));
+        self.received(address, &NetworkMessage::Verack);
+    }
+
+    pub fn disconnected(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+    }
+
+    pub fn received_from_peer(&mut self, peer: PeerId, msg: &NetworkMessage) {
+        let address = self.peers.get(&peer).cloned();
+        self.received(address, msg);
+    }
+
+    pub fn sent_to_peer(&mut self, peer: PeerId, msg: &NetworkMessage) {
+        let address = self.peers.get(&peer).cloned();
+        self.sent(address, msg);
+    }
+
+    pub fn received(&mut self, address: Option<SocketAddr>, msg: &NetworkMessage) {
+        let size = self.message_size(msg);
+        self.total.received += size;
+        if let Some(address) = address {
+            self.by_address.entry(address).or_insert(Usage::default()).received += size;
+        }
+        self.recent.push_back((Instant::now(), size));
+        self.expire();
+    }
+
+    pub fn sent(&mut self, address: Option<SocketAddr>, msg: &NetworkMessage) {
+        let size = self.message_size(msg);
+        self.total.sent += size;
+        if let Some(address) = address {
+            self.by_address.entry(address).or_insert(Usage::default()).sent += size;
+        }
+    }
+
+    fn expire(&mut self) {
+        while let Some((at, _)) = self.recent.front() {
+            if at.elapsed() > RATE_WINDOW {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn message_size(&self, msg: &NetworkMessage) -> u64 {
+        HEADER_SIZE + match *msg {
+            NetworkMessage::Block(ref block) => serialize(block).len() as u64,
+            NetworkMessage::Tx(ref tx) => serialize(tx).len() as u64,
+            _ => serialize(&RawNetworkMessage { magic: self.network.magic(), payload: msg.clone() }).len() as u64 - HEADER_SIZE
+        }
+    }
+}
+
+/// dispatcher listener counting incoming messages and the handshake of new peers
+pub struct BandwidthMeter {
+    p2p: P2PControlSender<NetworkMessage>,
+    bandwidth: SharedBandwidth,
+    user_agent: String,
+}
+
+impl BandwidthMeter {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, bandwidth: SharedBandwidth, user_agent: &str) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+        let mut meter = BandwidthMeter { p2p, bandwidth, user_agent: user_agent.to_string() };
+
+        thread::Builder::new().name("bandwidth".to_string()).spawn(move || { meter.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        while let Ok(msg) = receiver.recv() {
+            match msg {
+                PeerMessage::Connected(pid, Some(address)) => {
+                    let mut bandwidth = self.bandwidth.write().unwrap();
+                    bandwidth.connected(pid, address);
+                    if let Some(received) = self.p2p.peer_version(pid) {
+                        bandwidth.handshake(Some(address), &self.sent_version(address, &received), &received);
+                    }
+                }
+                PeerMessage::Disconnected(pid, _) => self.bandwidth.write().unwrap().disconnected(pid),
+                PeerMessage::Incoming(pid, ref msg) => self.bandwidth.write().unwrap().received_from_peer(pid, msg),
+                _ => {}
+            }
+        }
+    }
+
+    /// our version message as sent to the peer, only its size matters
+    fn sent_version(&self, address: SocketAddr, received: &VersionMessage) -> VersionMessage {
+        let local = SocketAddr::from(([0, 0, 0, 0], 0));
+        VersionMessage::new(0, received.timestamp, Address::new(&address, received.services), Address::new(&local, 0),
+                            0, self.user_agent.clone(), 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use bitcoin::network::address::Address;
+    use bitcoin::network::message::NetworkMessage;
+    use bitcoin::network::message_network::VersionMessage;
+
+    use super::Bandwidth;
+
+    #[test]
+    fn count_and_limit() {
+        let mut bandwidth = Bandwidth::new(Network::Testnet, Some(100));
+        let address = Some("127.0.0.1:18333".parse().unwrap());
+        assert!(bandwidth.download_allowed());
+
+        bandwidth.received(address, &NetworkMessage::Block(genesis_block(Network::Testnet)));
+        bandwidth.sent(address, &NetworkMessage::Verack);
+        let usage = bandwidth.usage();
+        assert_eq!(usage.total.received, 24 + 285);
+        assert_eq!(usage.total.sent, 24);
+        assert_eq!(usage.peers, vec!((address.unwrap(), usage.total)));
+        assert!(bandwidth.download_allowed());
+
+        for _ in 0..4 {
+            bandwidth.received(address, &NetworkMessage::Block(genesis_block(Network::Testnet)));
+        }
+        assert!(!bandwidth.download_allowed());
+        bandwidth.set_max_download_rate(None);
+        assert!(bandwidth.download_allowed());
+    }
+
+    #[test]
+    fn count_handshake_and_pings() {
+        let mut bandwidth = Bandwidth::new(Network::Testnet, None);
+        let address = "127.0.0.1:18333".parse().unwrap();
+        let version = VersionMessage::new(0, 0, Address::new(&address, 0), Address::new(&address, 0), 0, "bdk".to_string(), 0);
+        bandwidth.handshake(Some(address), &version, &version);
+        // version with a three byte user agent, verack is the header only
+        assert_eq!(bandwidth.usage().total.sent, 24 + 89 + 24);
+        assert_eq!(bandwidth.usage().total.received, 24 + 89 + 24);
+        bandwidth.sent(Some(address), &NetworkMessage::Ping(42));
+        bandwidth.received(Some(address), &NetworkMessage::Pong(42));
+        let usage = bandwidth.usage();
+        assert_eq!(usage.total.sent, 137 + 32);
+        assert_eq!(usage.total.received, 137 + 32);
+        assert_eq!(usage.peers, vec!((address, usage.total)));
+    }
+}
@@ -0,0 +1,381 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! BIP47 reusable payment codes (version 1)
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::{Address, Network, OutPoint, PublicKey, Transaction};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::consensus::serialize;
+use bitcoin::secp256k1::{self, Secp256k1, SecretKey};
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ChainCode, ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint};
+use bitcoin_hashes::{Hash, HashEngine, Hmac, HmacEngine, sha256, sha512};
+
+use crate::error::Error;
+
+/// base58 version byte of payment codes
+const VERSION_BYTE: u8 = 0x47;
+/// length of a serialized payment code
+pub const PAYLOAD_LEN: usize = 80;
+/// position of the key x coordinate in the payload
+const X_START: usize = 3;
+/// position of the chain code in the payload
+const CHAIN_CODE_START: usize = 35;
+/// origin of the imported key of our notification address
+pub const NOTIFICATION_ORIGIN: &str = "bip47/notification";
+/// origin prefix of imported keys receiving payments, followed by the sender's code and the index
+pub const RECEIVE_ORIGIN: &str = "bip47/";
+/// keys receiving payments of a sender are imported this far beyond the last one paid to
+pub const RECEIVE_GAP: u32 = 10;
+/// value paid to the notification address by a notification transaction
+pub const NOTIFICATION_VALUE: u64 = 546;
+
+/// a payment code, the public key and chain code of a m/47'/coin'/account' key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PaymentCode {
+    pub public_key: secp256k1::PublicKey,
+    pub chain_code: ChainCode
+}
+
+impl PaymentCode {
+    pub fn from_account(account: &ExtendedPubKey) -> PaymentCode {
+        PaymentCode { public_key: account.public_key.key, chain_code: account.chain_code }
+    }
+
+    pub fn to_bytes(&self) -> [u8; PAYLOAD_LEN] {
+        let mut payload = [0u8; PAYLOAD_LEN];
+        payload[0] = 1; // version
+        payload[1] = 0; // no bitmessage notification
+        payload[2..CHAIN_CODE_START].copy_from_slice(&self.public_key.serialize()[..]);
+        payload[CHAIN_CODE_START..CHAIN_CODE_START + 32].copy_from_slice(&self.chain_code[..]);
+        payload
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<PaymentCode, Error> {
+        if payload.len() != PAYLOAD_LEN || payload[0] != 1 {
+            return Err(Error::InvalidArgument("not a version 1 payment code"));
+        }
+        let public_key = secp256k1::PublicKey::from_slice(&payload[2..CHAIN_CODE_START])
+            .map_err(|_| Error::InvalidArgument("payment code has an invalid public key"))?;
+        let chain_code = ChainCode::from(&payload[CHAIN_CODE_START..CHAIN_CODE_START + 32]);
+        Ok(PaymentCode { public_key, chain_code })
+    }
+
+    /// the public key of child index
+    pub fn child(&self, index: u32, network: Network) -> Result<secp256k1::PublicKey, Error> {
+        let secp = Secp256k1::verification_only();
+        let account = ExtendedPubKey {
+            network,
+            depth: 3,
+            parent_fingerprint: Fingerprint::default(),
+            child_number: ChildNumber::from_hardened_idx(0).expect("0 is a valid index"),
+            public_key: PublicKey { compressed: true, key: self.public_key },
+            chain_code: self.chain_code
+        };
+        let child_number = ChildNumber::from_normal_idx(index).map_err(|_| Error::InvalidArgument("payment code index is out of range"))?;
+        let child = account.ckd_pub(&secp, child_number).map_err(|_| Error::InvalidArgument("can not derive payment code key"))?;
+        Ok(child.public_key.key)
+    }
+
+    /// notifications to the owner of this code are sent to this address
+    pub fn notification_address(&self, network: Network) -> Result<Address, Error> {
+        Ok(Address::p2pkh(&PublicKey { compressed: true, key: self.child(0, network)? }, network))
+    }
+}
+
+impl fmt::Display for PaymentCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data = Vec::with_capacity(PAYLOAD_LEN + 1);
+        data.push(VERSION_BYTE);
+        data.extend_from_slice(&self.to_bytes()[..]);
+        write!(f, "{}", base58::check_encode_slice(data.as_slice()))
+    }
+}
+
+impl FromStr for PaymentCode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PaymentCode, Error> {
+        let data = base58::from_check(s).map_err(|_| Error::InvalidArgument("payment code is not base58"))?;
+        if data.len() != PAYLOAD_LEN + 1 || data[0] != VERSION_BYTE {
+            return Err(Error::InvalidArgument("not a payment code"));
+        }
+        PaymentCode::from_bytes(&data[1..])
+    }
+}
+
+/// the private key of our payment code account m/47'/coin'/0'
+pub fn account_key(master: &ExtendedPrivKey) -> Result<ExtendedPrivKey, Error> {
+    let secp = Secp256k1::signing_only();
+    let coin = if master.network == Network::Bitcoin { 0 } else { 1 };
+    let path = DerivationPath::from(vec!(
+        ChildNumber::from_hardened_idx(47).expect("valid index"),
+        ChildNumber::from_hardened_idx(coin).expect("valid index"),
+        ChildNumber::from_hardened_idx(0).expect("valid index")));
+    master.derive_priv(&secp, &path).map_err(|_| Error::Unsupported("can not derive payment code account"))
+}
+
+/// the private key of child index of our payment code account
+pub fn child_key(account: &ExtendedPrivKey, index: u32) -> Result<SecretKey, Error> {
+    let secp = Secp256k1::signing_only();
+    let child_number = ChildNumber::from_normal_idx(index).map_err(|_| Error::InvalidArgument("payment code index is out of range"))?;
+    Ok(account.ckd_priv(&secp, child_number).map_err(|_| Error::Unsupported("can not derive payment code key"))?.private_key.key)
+}
+
+/// x coordinate of the point secret * point
+fn shared_x(point: &secp256k1::PublicKey, secret: &SecretKey) -> Result<[u8; 32], Error> {
+    let secp = Secp256k1::verification_only();
+    let mut shared = point.clone();
+    shared.mul_assign(&secp, &secret[..]).map_err(|_| Error::Unsupported("invalid shared secret"))?;
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&shared.serialize()[1..33]);
+    Ok(x)
+}
+
+/// the scalar tweaking payment keys, sha256 of the shared x coordinate
+fn payment_tweak(point: &secp256k1::PublicKey, secret: &SecretKey) -> Result<[u8; 32], Error> {
+    let tweak = sha256::Hash::hash(&shared_x(point, secret)?[..]).into_inner();
+    // a hash out of the curve order is rejected as the spec requires
+    SecretKey::from_slice(&tweak[..]).map_err(|_| Error::Unsupported("payment tweak is not a valid key, use the next index"))?;
+    Ok(tweak)
+}
+
+/// the key we pay to for the index-th payment to their code,
+/// designated is the private key of child 0 of our payment code account
+pub fn send_key(designated: &SecretKey, theirs: &PaymentCode, index: u32, network: Network) -> Result<PublicKey, Error> {
+    let secp = Secp256k1::verification_only();
+    let mut key = theirs.child(index, network)?;
+    let tweak = payment_tweak(&key, designated)?;
+    key.add_exp_assign(&secp, &tweak[..]).map_err(|_| Error::Unsupported("invalid payment key"))?;
+    Ok(PublicKey { compressed: true, key })
+}
+
+/// the address we pay to for the index-th payment to their code
+pub fn send_address(designated: &SecretKey, theirs: &PaymentCode, index: u32, network: Network) -> Result<Address, Error> {
+    Ok(Address::p2pkh(&send_key(designated, theirs, index, network)?, network))
+}
+
+/// the private key receiving the index-th payment from their code,
+/// child is the private key of child index of our payment code account
+pub fn receive_key(child: &SecretKey, theirs: &PaymentCode, network: Network) -> Result<SecretKey, Error> {
+    let tweak = payment_tweak(&theirs.child(0, network)?, child)?;
+    let mut key = child.clone();
+    key.add_assign(&tweak[..]).map_err(|_| Error::Unsupported("invalid payment key"))?;
+    Ok(key)
+}
+
+/// blinding factor of a payment code in a notification transaction
+fn blinding_factor(outpoint: &OutPoint, point: &secp256k1::PublicKey, secret: &SecretKey) -> Result<[u8; 64], Error> {
+    let mut engine = HmacEngine::<sha512::Hash>::new(&serialize(outpoint)[..]);
+    engine.input(&shared_x(point, secret)?[..]);
+    Ok(Hmac::<sha512::Hash>::from_engine(engine).into_inner())
+}
+
+fn xor_payload(payload: &mut [u8; PAYLOAD_LEN], factor: &[u8; 64]) {
+    for i in 0..32 {
+        payload[X_START + i] ^= factor[i];
+        payload[CHAIN_CODE_START + i] ^= factor[32 + i];
+    }
+}
+
+/// the OP_RETURN payload of a notification transaction sending our code to the owner of theirs,
+/// designated is the private key of the first input of the notification transaction spending outpoint
+pub fn blind(ours: &PaymentCode, theirs: &PaymentCode, designated: &SecretKey, outpoint: &OutPoint, network: Network) -> Result<[u8; PAYLOAD_LEN], Error> {
+    let factor = blinding_factor(outpoint, &theirs.child(0, network)?, designated)?;
+    let mut payload = ours.to_bytes();
+    xor_payload(&mut payload, &factor);
+    Ok(payload)
+}
+
+/// the payment code of the sender of a notification transaction,
+/// designated is the public key of its first input spending outpoint
+/// and notification the private key of child 0 of our payment code account
+pub fn unblind(payload: &[u8], designated: &secp256k1::PublicKey, outpoint: &OutPoint, notification: &SecretKey) -> Result<PaymentCode, Error> {
+    if payload.len() != PAYLOAD_LEN {
+        return Err(Error::InvalidArgument("not a notification payload"));
+    }
+    let factor = blinding_factor(outpoint, designated, notification)?;
+    let mut unblinded = [0u8; PAYLOAD_LEN];
+    unblinded.copy_from_slice(payload);
+    xor_payload(&mut unblinded, &factor);
+    PaymentCode::from_bytes(&unblinded[..])
+}
+
+/// the blinded payment code of a notification transaction, pushed by its first OP_RETURN output of that size
+pub fn notification_payload(transaction: &Transaction) -> Option<Vec<u8>> {
+    transaction.output.iter().filter(|o| o.script_pubkey.is_op_return())
+        .filter_map(|o| o.script_pubkey.iter(false).find_map(|i| match i {
+            Instruction::PushBytes(data) if data.len() == PAYLOAD_LEN => Some(data.to_vec()),
+            _ => None
+        })).next()
+}
+
+/// outpoint and public key of the designated input of a notification transaction,
+/// the first input exposing a public key in its witness or script sig
+pub fn designated_input(transaction: &Transaction) -> Option<(OutPoint, secp256k1::PublicKey)> {
+    transaction.input.iter().find_map(|input| {
+        let witness_key = input.witness.last().and_then(|w| secp256k1::PublicKey::from_slice(w.as_slice()).ok());
+        let script_key = || input.script_sig.iter(false).filter_map(|i| match i {
+            Instruction::PushBytes(data) => secp256k1::PublicKey::from_slice(data).ok(),
+            _ => None
+        }).last();
+        witness_key.or_else(script_key).map(|key| (input.previous_output, key))
+    })
+}
+
+/// the payment code sent to us by a notification transaction,
+/// notification is the private key of child 0 of our payment code account
+pub fn notified_code(transaction: &Transaction, notification: &SecretKey) -> Result<PaymentCode, Error> {
+    let payload = notification_payload(transaction).ok_or(Error::InvalidArgument("not a notification transaction"))?;
+    let (outpoint, designated) = designated_input(transaction).ok_or(Error::InvalidArgument("notification transaction has no designated input"))?;
+    unblind(payload.as_slice(), &designated, &outpoint, notification)
+}
+
+/// origin of the imported key receiving the index-th payment of a sender
+pub fn receive_origin(theirs: &PaymentCode, index: u32) -> String {
+    format!("{}{}/{}", RECEIVE_ORIGIN, theirs, index)
+}
+
+/// sender and index of an imported key receiving payments to our code
+pub fn parse_receive_origin(origin: &str) -> Option<(PaymentCode, u32)> {
+    if origin == NOTIFICATION_ORIGIN || !origin.starts_with(RECEIVE_ORIGIN) {
+        return None;
+    }
+    let mut parts = origin[RECEIVE_ORIGIN.len()..].splitn(2, '/');
+    let code = PaymentCode::from_str(parts.next()?).ok()?;
+    let index = parts.next()?.parse::<u32>().ok()?;
+    Some((code, index))
+}
+
+/// the private key of an imported key of our notification address or receiving payments, derived from our seed
+pub fn origin_key(origin: &str, master: &ExtendedPrivKey) -> Result<SecretKey, Error> {
+    let account = account_key(master)?;
+    if origin == NOTIFICATION_ORIGIN {
+        return child_key(&account, 0);
+    }
+    let (sender, index) = parse_receive_origin(origin).ok_or(Error::InvalidArgument("not a payment code key"))?;
+    receive_key(&child_key(&account, index)?, &sender, master.network)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{Network, OutPoint, Script, Transaction, TxIn, TxOut};
+    use bitcoin::blockdata::opcodes::all;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::secp256k1::{self, Secp256k1};
+    use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+    use bitcoin_hashes::{Hash, sha256d};
+
+    use super::{account_key, blind, child_key, NOTIFICATION_ORIGIN, notified_code, origin_key, parse_receive_origin, PaymentCode, receive_key, receive_origin, send_key, unblind};
+
+    fn account(seed: &[u8]) -> ExtendedPrivKey {
+        account_key(&ExtendedPrivKey::new_master(Network::Testnet, seed).unwrap()).unwrap()
+    }
+
+    fn code(account: &ExtendedPrivKey) -> PaymentCode {
+        PaymentCode::from_account(&ExtendedPubKey::from_private(&Secp256k1::new(), account))
+    }
+
+    #[test]
+    fn encoding() {
+        let alice = code(&account(&[1u8; 32]));
+        let encoded = alice.to_string();
+        assert!(encoded.starts_with("PM8T"));
+        assert_eq!(PaymentCode::from_str(encoded.as_str()).unwrap(), alice);
+        assert!(PaymentCode::from_str("PM8TJTLJbPRG").is_err());
+    }
+
+    #[test]
+    fn notification_and_payments() {
+        let secp = Secp256k1::new();
+        let alice_account = account(&[1u8; 32]);
+        let bob_account = account(&[2u8; 32]);
+        let alice = code(&alice_account);
+        let bob = code(&bob_account);
+
+        // alice notifies bob from an input she owns
+        let designated = child_key(&alice_account, 7).unwrap();
+        let outpoint = OutPoint { txid: sha256d::Hash::hash(b"funding"), vout: 1 };
+        let payload = blind(&alice, &bob, &designated, &outpoint, Network::Testnet).unwrap();
+        assert_ne!(&payload[..], &alice.to_bytes()[..]);
+        let bob_notification = child_key(&bob_account, 0).unwrap();
+        let notified = unblind(&payload[..], &secp256k1::PublicKey::from_secret_key(&secp, &designated), &outpoint, &bob_notification).unwrap();
+        assert_eq!(notified, alice);
+
+        // alice pays, bob can spend
+        let alice_designated = child_key(&alice_account, 0).unwrap();
+        for index in 0..3 {
+            let paid = send_key(&alice_designated, &bob, index, Network::Testnet).unwrap();
+            let spending = receive_key(&child_key(&bob_account, index).unwrap(), &notified, Network::Testnet).unwrap();
+            assert_eq!(paid.key, secp256k1::PublicKey::from_secret_key(&secp, &spending));
+        }
+        assert_ne!(send_key(&alice_designated, &bob, 0, Network::Testnet).unwrap(), send_key(&alice_designated, &bob, 1, Network::Testnet).unwrap());
+    }
+
+    #[test]
+    fn notification_transaction() {
+        let secp = Secp256k1::new();
+        let alice_account = account(&[1u8; 32]);
+        let bob_account = account(&[2u8; 32]);
+        let alice = code(&alice_account);
+        let bob = code(&bob_account);
+
+        let designated = child_key(&alice_account, 3).unwrap();
+        let designated_public = secp256k1::PublicKey::from_secret_key(&secp, &designated);
+        let funding = OutPoint { txid: sha256d::Hash::hash(b"funding"), vout: 0 };
+        let payload = blind(&alice, &bob, &designated, &funding, Network::Testnet).unwrap();
+        let notification = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn {
+                previous_output: funding,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                // a P2WPKH input, signature and public key
+                witness: vec!(vec!(0x30u8; 71), designated_public.serialize().to_vec()),
+            }),
+            output: vec!(
+                TxOut { value: 546, script_pubkey: bob.notification_address(Network::Testnet).unwrap().script_pubkey() },
+                TxOut { value: 0, script_pubkey: Builder::new().push_opcode(all::OP_RETURN).push_slice(&payload[..]).into_script() }
+            ),
+        };
+        let bob_notification = child_key(&bob_account, 0).unwrap();
+        assert_eq!(notified_code(&notification, &bob_notification).unwrap(), alice);
+        // carol can not read it
+        let carol_notification = child_key(&account(&[3u8; 32]), 0).unwrap();
+        assert!(notified_code(&notification, &carol_notification).map(|c| c != alice).unwrap_or(true));
+
+        let mut payment = notification.clone();
+        payment.output.pop();
+        assert!(notified_code(&payment, &bob_notification).is_err());
+
+        assert_eq!(parse_receive_origin(receive_origin(&alice, 7).as_str()), Some((alice, 7)));
+        assert_eq!(parse_receive_origin("bip47/notification"), None);
+        assert_eq!(parse_receive_origin("m/0/1"), None);
+
+        // keys of origins are derived from the seed again
+        let bob_master = ExtendedPrivKey::new_master(Network::Testnet, &[2u8; 32]).unwrap();
+        assert_eq!(origin_key(NOTIFICATION_ORIGIN, &bob_master).unwrap(), bob_notification);
+        assert_eq!(origin_key(receive_origin(&alice, 7).as_str(), &bob_master).unwrap(),
+                   receive_key(&child_key(&bob_account, 7).unwrap(), &alice, Network::Testnet).unwrap());
+        assert!(origin_key("wif", &bob_master).is_err());
+    }
+}
@@ -16,7 +16,7 @@
  */
 use std::{
     collections::VecDeque,
-    sync::mpsc,
+    sync::{Arc, atomic::{AtomicBool, Ordering}, mpsc},
     thread,
     time::Duration,
 };
@@ -35,6 +35,15 @@ use murmel::error::Error;
 use murmel::p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_BLOCKS};
 use murmel::timeout::{ExpectedReply, SharedTimeout};
 
+use crate::bandwidth::SharedBandwidth;
+
+/// blocks asked at once
+const BATCH_SIZE: usize = 1000;
+/// blocks asked at once if download rate is limited
+const THROTTLED_BATCH_SIZE: usize = 10;
+/// memory assumed for a block in flight, to size batches within a memory quota
+const BLOCK_MEMORY: u64 = 2_000_000;
+
 pub struct BlockDownload {
     p2p: P2PControlSender<NetworkMessage>,
     chaindb: SharedChainDB,
@@ -43,11 +52,16 @@ pub struct BlockDownload {
     blocks_wanted: VecDeque<(sha256d::Hash, u32)>,
     blocks_asked: VecDeque<(sha256d::Hash, u32)>,
     block_download_peer: Option<PeerId>,
-    birth: u64
+    birth: u64,
+    bandwidth: SharedBandwidth,
+    /// blocks are only asked while set, headers are followed regardless
+    scanning: Arc<AtomicBool>,
+    /// blocks asked at once within the memory quota
+    max_batch_size: usize
 }
 
 impl BlockDownload {
-    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream, processed_block: Option<sha256d::Hash>, birth: u64) -> PeerMessageSender<NetworkMessage> {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream, processed_block: Option<sha256d::Hash>, birth: u64, bandwidth: SharedBandwidth, scanning: Arc<AtomicBool>, max_block_memory: Option<u64>) -> PeerMessageSender<NetworkMessage> {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
 
         let mut blocks_wanted = VecDeque::new();
@@ -72,7 +86,8 @@ impl BlockDownload {
         }
 
         let mut headerdownload = BlockDownload { chaindb, p2p, timeout, downstream: downstream,
-            blocks_wanted, blocks_asked: VecDeque::new(), block_download_peer: None, birth };
+            blocks_wanted, blocks_asked: VecDeque::new(), block_download_peer: None, birth, bandwidth, scanning,
+            max_batch_size: max_block_memory.map(|m| std::cmp::max(1, (m / BLOCK_MEMORY) as usize)).unwrap_or(BATCH_SIZE) };
 
         thread::Builder::new().name("header download".to_string()).spawn(move || { headerdownload.run(receiver) }).unwrap();
 
@@ -122,28 +137,47 @@ impl BlockDownload {
                 }
             }
             self.timeout.lock().unwrap().check(vec!(ExpectedReply::Headers, ExpectedReply::Block));
+            // resume downloads held back by the rate limit
+            if let Some(pid) = self.block_download_peer {
+                if self.blocks_asked.is_empty() && !self.blocks_wanted.is_empty() {
+                    self.ask_blocks(pid);
+                }
+            }
         }
     }
 
     fn ask_blocks (&mut self, pid: PeerId) {
+        if !self.scanning.load(Ordering::SeqCst) {
+            return;
+        }
         let mut timeout = self.timeout.lock().unwrap();
         if !timeout.is_busy_with(pid, ExpectedReply::Block) {
+            let batch_size = {
+                let mut bandwidth = self.bandwidth.write().unwrap();
+                if !bandwidth.download_allowed() {
+                    debug!("download rate limit reached, holding back block requests");
+                    return;
+                }
+                std::cmp::min(self.max_batch_size, if bandwidth.is_limited() { THROTTLED_BATCH_SIZE } else { BATCH_SIZE })
+            };
             let mut n_entries = 0;
             while let Some((hash, height)) = self.blocks_wanted.pop_front() {
                 self.blocks_asked.push_back((hash, height));
                 n_entries += 1;
-                if n_entries == 1000 {
+                if n_entries == batch_size {
                     break;
                 }
             }
             if self.blocks_asked.len() > 0 {
-                self.p2p.send_network(pid, NetworkMessage::GetData(
+                let get_data = NetworkMessage::GetData(
                     self.blocks_asked.iter().map(|(hash, _)|
                         Inventory {
                             inv_type: InvType::Block,
                             hash: hash.clone()
                         }
-                    ).collect()));
+                    ).collect());
+                self.bandwidth.write().unwrap().sent_to_peer(pid, &get_data);
+                self.p2p.send_network(pid, get_data);
                 debug!("asked {} blocks from peer={}", self.blocks_asked.len(), pid);
                 timeout.expect(pid, self.blocks_asked.len(), ExpectedReply::Block);
             }
@@ -211,7 +245,9 @@ impl BlockDownload {
                 sha256d::Hash::default()
             };
             self.timeout.lock().unwrap().expect(peer, 1, ExpectedReply::Headers);
-            self.p2p.send_network(peer, NetworkMessage::GetHeaders(GetHeadersMessage::new(locator, first)));
+            let get_headers = NetworkMessage::GetHeaders(GetHeadersMessage::new(locator, first));
+            self.bandwidth.write().unwrap().sent_to_peer(peer, &get_headers);
+            self.p2p.send_network(peer, get_headers);
         }
     }
 
@@ -20,9 +20,16 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::path::Path;
-use crate::error::Error;
+use std::str::FromStr;
 
 use bitcoin::Network;
+use bitcoin::util::bip32::ExtendedPubKey;
+
+use crate::error::Error;
+use crate::esplora;
+use crate::kdf::Kdf;
+use crate::p2p_bitcoin::{MAX_INBOUND, MAX_RECONNECT_DELAY, RECONNECT_DELAY};
+use crate::wallet::KEY_LOOK_AHEAD;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Config {
@@ -34,6 +41,61 @@ pub struct Config {
     pub bitcoin_peers: Vec<SocketAddr>,
     pub bitcoin_connections: usize,
     pub bitcoin_discovery: bool,
+    /// download limit in bytes per second
+    pub bitcoin_max_download_rate: Option<u64>,
+    /// seconds to connect and handshake with a peer
+    pub bitcoin_connect_timeout: Option<u64>,
+    /// seconds to wait before the first reconnect of a failed peer, doubled with every further failure
+    pub bitcoin_reconnect_delay: Option<u64>,
+    /// maximum seconds to wait before reconnecting a failed peer
+    pub bitcoin_max_reconnect_delay: Option<u64>,
+    /// accept inbound connections on this address
+    pub bitcoin_listen: Option<SocketAddr>,
+    /// maximum number of inbound connections
+    pub bitcoin_max_inbound: Option<usize>,
+    /// announce own transactions to this many peers, None for all connected peers
+    pub bitcoin_broadcast_peers: Option<usize>,
+    /// base url of an Esplora API to post own transactions to if no peer takes them
+    pub bitcoin_broadcast_fallback: Option<String>,
+    /// lowest fee rate in satoshi per vbyte a transaction may pay
+    pub min_fee_rate: Option<u64>,
+    /// highest fee rate in satoshi per vbyte a transaction may pay
+    pub max_fee_rate: Option<u64>,
+    /// highest fee as percentage of the amount sent, None for the default
+    pub max_fee_percent: Option<u32>,
+    /// highest fee in satoshis
+    pub max_fee: Option<u64>,
+    /// consolidate small coins if the low priority fee rate in satoshi per vbyte is at most this, None to never consolidate.
+    /// The fee rate is estimated by the esplora endpoint of bitcoin_broadcast_fallback, which must be set
+    pub consolidate_fee_rate: Option<u64>,
+    /// consolidate if there are at least this many small coins, None for the default
+    pub consolidate_min_coins: Option<u32>,
+    /// coins of at most this many satoshis are small, None for the default
+    pub consolidate_max_value: Option<u64>,
+    /// spend unconfirmed change of our own transactions if confirmed coins are insufficient, None for no
+    pub spend_unconfirmed_change: Option<bool>,
+    /// url wallet events are posted to as JSON. Events not yet posted are lost when the wallet stops
+    pub webhook_url: Option<String>,
+    /// key of the HMAC-SHA256 signature of posted events
+    pub webhook_secret: Option<String>,
+    /// publish wallet and chain events to subscribers connecting to this local address
+    pub event_socket: Option<SocketAddr>,
+    /// JSON-RPC url of a trusted bitcoind used as chain backend instead of the p2p network
+    pub bitcoin_rpc_url: Option<String>,
+    /// bitcoind rpc user
+    pub bitcoin_rpc_user: Option<String>,
+    /// bitcoind rpc password
+    pub bitcoin_rpc_password: Option<String>,
+    /// follow headers without downloading blocks until scanning is enabled, None for no
+    pub headers_only: Option<bool>,
+    /// bytes of blocks in flight, at least one block is asked at a time. None for no limit
+    pub max_block_memory: Option<u64>,
+    /// bytes of the wallet db, peer addresses are pruned beyond. None for no limit
+    pub max_storage: Option<u64>,
+    /// cap of inbound and outbound peer connections together, None for no limit
+    pub max_connections: Option<usize>,
+    /// Argon2id parameters of the passphrase, None for a wallet encrypted with the passphrase as is
+    pub kdf: Option<String>,
 }
 
 impl Config {
@@ -47,6 +109,33 @@ impl Config {
             bitcoin_peers: vec![],
             bitcoin_connections: 0,
             bitcoin_discovery: false,
+            bitcoin_max_download_rate: None,
+            bitcoin_connect_timeout: None,
+            bitcoin_reconnect_delay: None,
+            bitcoin_max_reconnect_delay: None,
+            bitcoin_listen: None,
+            bitcoin_max_inbound: None,
+            bitcoin_broadcast_peers: None,
+            bitcoin_broadcast_fallback: None,
+            min_fee_rate: None,
+            max_fee_rate: None,
+            max_fee_percent: None,
+            max_fee: None,
+            consolidate_fee_rate: None,
+            consolidate_min_coins: None,
+            consolidate_max_value: None,
+            spend_unconfirmed_change: None,
+            webhook_url: None,
+            webhook_secret: None,
+            event_socket: None,
+            bitcoin_rpc_url: None,
+            bitcoin_rpc_user: None,
+            bitcoin_rpc_password: None,
+            headers_only: None,
+            max_block_memory: None,
+            max_storage: None,
+            max_connections: None,
+            kdf: None,
         }
     }
 
@@ -60,17 +149,445 @@ impl Config {
             bitcoin_peers,
             bitcoin_connections,
             bitcoin_discovery,
+            bitcoin_max_download_rate: self.bitcoin_max_download_rate,
+            bitcoin_connect_timeout: self.bitcoin_connect_timeout,
+            bitcoin_reconnect_delay: self.bitcoin_reconnect_delay,
+            bitcoin_max_reconnect_delay: self.bitcoin_max_reconnect_delay,
+            bitcoin_listen: self.bitcoin_listen,
+            bitcoin_max_inbound: self.bitcoin_max_inbound,
+            bitcoin_broadcast_peers: self.bitcoin_broadcast_peers,
+            bitcoin_broadcast_fallback: self.bitcoin_broadcast_fallback.clone(),
+            min_fee_rate: self.min_fee_rate,
+            max_fee_rate: self.max_fee_rate,
+            max_fee_percent: self.max_fee_percent,
+            max_fee: self.max_fee,
+            consolidate_fee_rate: self.consolidate_fee_rate,
+            consolidate_min_coins: self.consolidate_min_coins,
+            consolidate_max_value: self.consolidate_max_value,
+            spend_unconfirmed_change: self.spend_unconfirmed_change,
+            webhook_url: self.webhook_url.clone(),
+            webhook_secret: self.webhook_secret.clone(),
+            event_socket: self.event_socket,
+            bitcoin_rpc_url: self.bitcoin_rpc_url.clone(),
+            bitcoin_rpc_user: self.bitcoin_rpc_user.clone(),
+            bitcoin_rpc_password: self.bitcoin_rpc_password.clone(),
+            headers_only: self.headers_only,
+            max_block_memory: self.max_block_memory,
+            max_storage: self.max_storage,
+            max_connections: self.max_connections,
+            kdf: self.kdf.clone(),
+        }
+    }
+
+    /// start building a new config
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// start building a config from this one
+    pub fn to_builder(&self) -> ConfigBuilder {
+        ConfigBuilder {
+            encryptedwalletkey: Some(self.encryptedwalletkey.clone()),
+            keyroot: Some(self.keyroot.clone()),
+            lookahead: Some(self.lookahead),
+            birth: Some(self.birth),
+            network: Some(self.network),
+            bitcoin_peers: self.bitcoin_peers.clone(),
+            bitcoin_connections: Some(self.bitcoin_connections),
+            bitcoin_discovery: self.bitcoin_discovery,
+            bitcoin_max_download_rate: self.bitcoin_max_download_rate,
+            bitcoin_connect_timeout: self.bitcoin_connect_timeout,
+            bitcoin_reconnect_delay: self.bitcoin_reconnect_delay,
+            bitcoin_max_reconnect_delay: self.bitcoin_max_reconnect_delay,
+            bitcoin_listen: self.bitcoin_listen,
+            bitcoin_max_inbound: self.bitcoin_max_inbound,
+            bitcoin_broadcast_peers: self.bitcoin_broadcast_peers,
+            bitcoin_broadcast_fallback: self.bitcoin_broadcast_fallback.clone(),
+            min_fee_rate: self.min_fee_rate,
+            max_fee_rate: self.max_fee_rate,
+            max_fee_percent: self.max_fee_percent,
+            max_fee: self.max_fee,
+            consolidate_fee_rate: self.consolidate_fee_rate,
+            consolidate_min_coins: self.consolidate_min_coins,
+            consolidate_max_value: self.consolidate_max_value,
+            spend_unconfirmed_change: self.spend_unconfirmed_change,
+            webhook_url: self.webhook_url.clone(),
+            webhook_secret: self.webhook_secret.clone(),
+            event_socket: self.event_socket,
+            bitcoin_rpc_url: self.bitcoin_rpc_url.clone(),
+            bitcoin_rpc_user: self.bitcoin_rpc_user.clone(),
+            bitcoin_rpc_password: self.bitcoin_rpc_password.clone(),
+            headers_only: self.headers_only,
+            max_block_memory: self.max_block_memory,
+            max_storage: self.max_storage,
+            max_connections: self.max_connections,
+            kdf: self.kdf.clone(),
         }
     }
 }
 
+/// build a validated config
+#[derive(Default, Debug, Clone)]
+pub struct ConfigBuilder {
+    encryptedwalletkey: Option<String>,
+    keyroot: Option<String>,
+    lookahead: Option<u32>,
+    birth: Option<u64>,
+    network: Option<Network>,
+    bitcoin_peers: Vec<SocketAddr>,
+    bitcoin_connections: Option<usize>,
+    bitcoin_discovery: bool,
+    bitcoin_max_download_rate: Option<u64>,
+    bitcoin_connect_timeout: Option<u64>,
+    bitcoin_reconnect_delay: Option<u64>,
+    bitcoin_max_reconnect_delay: Option<u64>,
+    bitcoin_listen: Option<SocketAddr>,
+    bitcoin_max_inbound: Option<usize>,
+    bitcoin_broadcast_peers: Option<usize>,
+    bitcoin_broadcast_fallback: Option<String>,
+    min_fee_rate: Option<u64>,
+    max_fee_rate: Option<u64>,
+    max_fee_percent: Option<u32>,
+    max_fee: Option<u64>,
+    consolidate_fee_rate: Option<u64>,
+    consolidate_min_coins: Option<u32>,
+    consolidate_max_value: Option<u64>,
+    spend_unconfirmed_change: Option<bool>,
+    webhook_url: Option<String>,
+    webhook_secret: Option<String>,
+    event_socket: Option<SocketAddr>,
+    bitcoin_rpc_url: Option<String>,
+    bitcoin_rpc_user: Option<String>,
+    bitcoin_rpc_password: Option<String>,
+    headers_only: Option<bool>,
+    max_block_memory: Option<u64>,
+    max_storage: Option<u64>,
+    max_connections: Option<usize>,
+    kdf: Option<String>,
+}
+
+impl ConfigBuilder {
+    pub fn encryptedwalletkey(mut self, encryptedwalletkey: &str) -> ConfigBuilder {
+        self.encryptedwalletkey = Some(encryptedwalletkey.to_string());
+        self
+    }
+
+    pub fn keyroot(mut self, keyroot: &str) -> ConfigBuilder {
+        self.keyroot = Some(keyroot.to_string());
+        self
+    }
+
+    pub fn lookahead(mut self, lookahead: u32) -> ConfigBuilder {
+        self.lookahead = Some(lookahead);
+        self
+    }
+
+    pub fn birth(mut self, birth: u64) -> ConfigBuilder {
+        self.birth = Some(birth);
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> ConfigBuilder {
+        self.network = Some(network);
+        self
+    }
+
+    /// add a bitcoin peer
+    pub fn peer(mut self, peer: SocketAddr) -> ConfigBuilder {
+        self.bitcoin_peers.push(peer);
+        self
+    }
+
+    /// replace all bitcoin peers
+    pub fn peers(mut self, peers: Vec<SocketAddr>) -> ConfigBuilder {
+        self.bitcoin_peers = peers;
+        self
+    }
+
+    pub fn connections(mut self, connections: usize) -> ConfigBuilder {
+        self.bitcoin_connections = Some(connections);
+        self
+    }
+
+    pub fn discovery(mut self, discovery: bool) -> ConfigBuilder {
+        self.bitcoin_discovery = discovery;
+        self
+    }
+
+    /// limit download to bytes per second, None for unlimited
+    pub fn max_download_rate(mut self, max_download_rate: Option<u64>) -> ConfigBuilder {
+        self.bitcoin_max_download_rate = max_download_rate;
+        self
+    }
+
+    /// seconds to connect and handshake with a peer, None for the default
+    pub fn connect_timeout(mut self, connect_timeout: Option<u64>) -> ConfigBuilder {
+        self.bitcoin_connect_timeout = connect_timeout;
+        self
+    }
+
+    /// seconds before the first and maximum seconds before any reconnect of a failed peer, None for the defaults
+    pub fn reconnect_delay(mut self, reconnect_delay: Option<u64>, max_reconnect_delay: Option<u64>) -> ConfigBuilder {
+        self.bitcoin_reconnect_delay = reconnect_delay;
+        self.bitcoin_max_reconnect_delay = max_reconnect_delay;
+        self
+    }
+
+    /// accept inbound connections on this address, None to connect outbound only
+    pub fn listen(mut self, listen: Option<SocketAddr>) -> ConfigBuilder {
+        self.bitcoin_listen = listen;
+        self
+    }
+
+    /// maximum number of inbound connections, None for the default
+    pub fn max_inbound(mut self, max_inbound: Option<usize>) -> ConfigBuilder {
+        self.bitcoin_max_inbound = max_inbound;
+        self
+    }
+
+    /// announce own transactions to this many peers, None for all connected peers
+    pub fn broadcast_peers(mut self, broadcast_peers: Option<usize>) -> ConfigBuilder {
+        self.bitcoin_broadcast_peers = broadcast_peers;
+        self
+    }
+
+    /// base url of an Esplora API e.g. https://mempool.space/api to post own transactions to
+    /// if no peer is connected or takes them, None to broadcast through peers only
+    pub fn broadcast_fallback(mut self, broadcast_fallback: Option<&str>) -> ConfigBuilder {
+        self.bitcoin_broadcast_fallback = broadcast_fallback.map(|s| s.to_string());
+        self
+    }
+
+    /// bounds of the fee rate in satoshi per vbyte of transactions, None for no bound
+    pub fn fee_rate_bounds(mut self, min_fee_rate: Option<u64>, max_fee_rate: Option<u64>) -> ConfigBuilder {
+        self.min_fee_rate = min_fee_rate;
+        self.max_fee_rate = max_fee_rate;
+        self
+    }
+
+    /// refuse to sign transactions with a fee above max_fee_percent of the amount or above max_fee satoshis,
+    /// None for the default percentage and no absolute limit
+    pub fn fee_limits(mut self, max_fee_percent: Option<u32>, max_fee: Option<u64>) -> ConfigBuilder {
+        self.max_fee_percent = max_fee_percent;
+        self.max_fee = max_fee;
+        self
+    }
+
+    /// consolidate coins of at most max_value satoshis if there are at least min_coins of them
+    /// while the low priority fee rate is at most fee_rate satoshi per vbyte. No fee_rate never consolidates,
+    /// no min_coins or max_value takes the defaults
+    pub fn consolidation(mut self, fee_rate: Option<u64>, min_coins: Option<u32>, max_value: Option<u64>) -> ConfigBuilder {
+        self.consolidate_fee_rate = fee_rate;
+        self.consolidate_min_coins = min_coins;
+        self.consolidate_max_value = max_value;
+        self
+    }
+
+    /// spend unconfirmed change of our own transactions if confirmed coins are insufficient
+    pub fn spend_unconfirmed_change(mut self, spend: bool) -> ConfigBuilder {
+        self.spend_unconfirmed_change = Some(spend);
+        self
+    }
+
+    /// post wallet events to url, signed with secret if given
+    pub fn webhook(mut self, url: Option<&str>, secret: Option<&str>) -> ConfigBuilder {
+        self.webhook_url = url.map(|u| u.to_string());
+        self.webhook_secret = secret.map(|s| s.to_string());
+        self
+    }
+
+    /// publish wallet and chain events to subscribers connecting to this local address
+    pub fn event_socket(mut self, address: Option<SocketAddr>) -> ConfigBuilder {
+        self.event_socket = address;
+        self
+    }
+
+    /// use the bitcoind at url, e.g. http://127.0.0.1:8332, as chain backend instead of the p2p network
+    pub fn rpc(mut self, url: Option<&str>, user: Option<&str>, password: Option<&str>) -> ConfigBuilder {
+        self.bitcoin_rpc_url = url.map(|u| u.to_string());
+        self.bitcoin_rpc_user = user.map(|u| u.to_string());
+        self.bitcoin_rpc_password = password.map(|p| p.to_string());
+        self
+    }
+
+    /// follow headers only and defer block download until scanning is enabled, for the p2p backend
+    pub fn headers_only(mut self, headers_only: bool) -> ConfigBuilder {
+        self.headers_only = Some(headers_only);
+        self
+    }
+
+    /// caps for constrained devices: memory of blocks downloaded at once, size of the wallet db and peer connections.
+    /// Sync slows down rather than exceeding them
+    pub fn quotas(mut self, max_block_memory: Option<u64>, max_storage: Option<u64>, max_connections: Option<usize>) -> ConfigBuilder {
+        self.max_block_memory = max_block_memory;
+        self.max_storage = max_storage;
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Argon2id parameters stretching the passphrase of encryptedwalletkey, None if it is used as is
+    pub fn kdf(mut self, kdf: Option<&str>) -> ConfigBuilder {
+        self.kdf = kdf.map(|s| s.to_string());
+        self
+    }
+
+
+    /// validate settings and build the config
+    pub fn build(self) -> Result<Config, Error> {
+        let network = self.network.ok_or(Error::InvalidConfig("network is not set"))?;
+        let encryptedwalletkey = self.encryptedwalletkey.ok_or(Error::InvalidConfig("encrypted wallet key is not set"))?;
+        if encryptedwalletkey.is_empty() || hex::decode(&encryptedwalletkey).is_err() {
+            return Err(Error::InvalidConfig("encrypted wallet key is not hex"));
+        }
+        let keyroot = self.keyroot.ok_or(Error::InvalidConfig("key root is not set"))?;
+        let master_public = ExtendedPubKey::from_str(keyroot.as_str()).map_err(|_| Error::InvalidConfig("key root is malformed"))?;
+        if (master_public.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+            return Err(Error::InvalidConfig("key root is for a different network"));
+        }
+        let mut seen = Vec::new();
+        for peer in &self.bitcoin_peers {
+            if seen.contains(peer) {
+                return Err(Error::InvalidConfig("duplicate bitcoin peer"));
+            }
+            seen.push(*peer);
+        }
+        let bitcoin_connections = self.bitcoin_connections.unwrap_or(self.bitcoin_peers.len());
+        if bitcoin_connections == 0 && (self.bitcoin_discovery || !self.bitcoin_peers.is_empty()) {
+            return Err(Error::InvalidConfig("bitcoin connections must be greater than zero"));
+        }
+        if self.bitcoin_max_download_rate == Some(0) {
+            return Err(Error::InvalidConfig("max download rate must be greater than zero"));
+        }
+        if self.bitcoin_connect_timeout == Some(0) {
+            return Err(Error::InvalidConfig("connect timeout must be greater than zero"));
+        }
+        let reconnect_delay = self.bitcoin_reconnect_delay.unwrap_or(RECONNECT_DELAY);
+        if reconnect_delay == 0 {
+            return Err(Error::InvalidConfig("reconnect delay must be greater than zero"));
+        }
+        if reconnect_delay > self.bitcoin_max_reconnect_delay.unwrap_or(MAX_RECONNECT_DELAY) {
+            return Err(Error::InvalidConfig("reconnect delay exceeds max reconnect delay"));
+        }
+        if self.bitcoin_listen.is_some() && self.bitcoin_max_inbound.unwrap_or(MAX_INBOUND) == 0 {
+            return Err(Error::InvalidConfig("max inbound must be greater than zero if listening"));
+        }
+        if self.bitcoin_broadcast_peers == Some(0) {
+            return Err(Error::InvalidConfig("broadcast peers must be greater than zero"));
+        }
+        if let Some(ref endpoint) = self.bitcoin_broadcast_fallback {
+            if !esplora::is_endpoint(endpoint) {
+                return Err(Error::InvalidConfig("broadcast fallback is not an http url"));
+            }
+        }
+        if self.min_fee_rate == Some(0) {
+            return Err(Error::InvalidConfig("min fee rate must be greater than zero"));
+        }
+        if self.min_fee_rate.map(|r| r > 100).unwrap_or(false) || self.max_fee_rate.map(|r| r > 100).unwrap_or(false) {
+            return Err(Error::InvalidConfig("fee rate bounds must not exceed 100 sat/vbyte"));
+        }
+        if let (Some(min), Some(max)) = (self.min_fee_rate, self.max_fee_rate) {
+            if min > max {
+                return Err(Error::InvalidConfig("min fee rate exceeds max fee rate"));
+            }
+        }
+        if self.max_fee_percent == Some(0) || self.max_fee_percent.map(|p| p > 100).unwrap_or(false) {
+            return Err(Error::InvalidConfig("max fee percent must be between 1 and 100"));
+        }
+        if self.max_fee == Some(0) {
+            return Err(Error::InvalidConfig("max fee must be greater than zero"));
+        }
+        if self.consolidate_fee_rate == Some(0) || self.consolidate_fee_rate.map(|r| r > 100).unwrap_or(false) {
+            return Err(Error::InvalidConfig("consolidation fee rate must be between 1 and 100"));
+        }
+        if self.consolidate_min_coins.map(|n| n < 2).unwrap_or(false) {
+            return Err(Error::InvalidConfig("consolidation needs at least two coins"));
+        }
+        if self.consolidate_max_value == Some(0) {
+            return Err(Error::InvalidConfig("consolidation max value must be greater than zero"));
+        }
+        if self.consolidate_fee_rate.is_some() && self.bitcoin_broadcast_fallback.is_none() {
+            return Err(Error::InvalidConfig("consolidation needs the esplora endpoint of broadcast fallback for fee estimates"));
+        }
+        if let Some(ref url) = self.webhook_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(Error::InvalidConfig("webhook url must be an http or https url"));
+            }
+        }
+        if self.webhook_secret.is_some() && self.webhook_url.is_none() {
+            return Err(Error::InvalidConfig("webhook secret without webhook url"));
+        }
+        if self.event_socket.map(|a| !a.ip().is_loopback()).unwrap_or(false) {
+            return Err(Error::InvalidConfig("event socket must be on a loopback address"));
+        }
+        if let Some(ref url) = self.bitcoin_rpc_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(Error::InvalidConfig("bitcoind rpc url must be an http or https url"));
+            }
+        }
+        if self.bitcoin_rpc_user.is_some() != self.bitcoin_rpc_password.is_some() {
+            return Err(Error::InvalidConfig("bitcoind rpc user and password must be set together"));
+        }
+        if self.headers_only.unwrap_or(false) && self.bitcoin_rpc_url.is_some() {
+            return Err(Error::InvalidConfig("headers only mode is for the p2p backend"));
+        }
+        if self.max_block_memory == Some(0) || self.max_storage == Some(0) || self.max_connections == Some(0) {
+            return Err(Error::InvalidConfig("resource quotas must be positive"));
+        }
+        if let Some(ref kdf) = self.kdf {
+            if Kdf::from_str(kdf).is_err() {
+                return Err(Error::InvalidConfig("kdf is malformed"));
+            }
+        }
+        Ok(Config {
+            encryptedwalletkey,
+            keyroot,
+            lookahead: self.lookahead.unwrap_or(KEY_LOOK_AHEAD),
+            birth: self.birth.unwrap_or(0),
+            network,
+            bitcoin_peers: self.bitcoin_peers,
+            bitcoin_connections,
+            bitcoin_discovery: self.bitcoin_discovery,
+            bitcoin_max_download_rate: self.bitcoin_max_download_rate,
+            bitcoin_connect_timeout: self.bitcoin_connect_timeout,
+            bitcoin_reconnect_delay: self.bitcoin_reconnect_delay,
+            bitcoin_max_reconnect_delay: self.bitcoin_max_reconnect_delay,
+            bitcoin_listen: self.bitcoin_listen,
+            bitcoin_max_inbound: self.bitcoin_max_inbound,
+            bitcoin_broadcast_peers: self.bitcoin_broadcast_peers,
+            bitcoin_broadcast_fallback: self.bitcoin_broadcast_fallback.clone(),
+            min_fee_rate: self.min_fee_rate,
+            max_fee_rate: self.max_fee_rate,
+            max_fee_percent: self.max_fee_percent,
+            max_fee: self.max_fee,
+            consolidate_fee_rate: self.consolidate_fee_rate,
+            consolidate_min_coins: self.consolidate_min_coins,
+            consolidate_max_value: self.consolidate_max_value,
+            spend_unconfirmed_change: self.spend_unconfirmed_change,
+            webhook_url: self.webhook_url.clone(),
+            webhook_secret: self.webhook_secret.clone(),
+            event_socket: self.event_socket,
+            bitcoin_rpc_url: self.bitcoin_rpc_url.clone(),
+            bitcoin_rpc_user: self.bitcoin_rpc_user.clone(),
+            bitcoin_rpc_password: self.bitcoin_rpc_password.clone(),
+            headers_only: self.headers_only,
+            max_block_memory: self.max_block_memory,
+            max_storage: self.max_storage,
+            max_connections: self.max_connections,
+            kdf: self.kdf.clone(),
+        })
+    }
+}
+
+/// write to a temporary file first, so the config is replaced atomically
 pub fn save(config_path: &Path, file_path: &Path, config: &Config) -> Result<(), Error> {
     fs::create_dir_all(&config_path)?;
-    let mut file = File::create(file_path)?;
+    let temp_path = file_path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
     let config_string = toml::to_string(config).unwrap();
 
     file.write_all(config_string.as_bytes())?;
     file.sync_all()?;
+    fs::rename(&temp_path, file_path)?;
     Ok(())
 }
 
@@ -104,6 +621,8 @@ mod test {
     use crate::config;
     use crate::config::Config;
 
+    const KEYROOT: &str = "tpubD6NzVbkrYhZ4XKz4vgwBmnnVmA7EgWhnXvimQ4krq94yUgcSSbroi4uC1xbZ3UGMxG9M2utmaPjdpMrWW2uKRY9Mj4DZWrrY8M4pry8shsK";
+
     #[test]
     fn save_load_delete() {
         let test_config = Config::new(
@@ -162,5 +681,81 @@ mod test {
         let loaded_updated = config::load(&file_path);
         assert_eq!(loaded_updated.is_ok(), false);
     }
-}
 
+    #[test]
+    fn builder() {
+        let built = Config::builder()
+            .encryptedwalletkey("0e05ba48")
+            .keyroot(KEYROOT)
+            .birth(1567260002)
+            .network(Network::Testnet)
+            .peer("127.0.0.1:18444".parse().unwrap())
+            .connections(2)
+            .discovery(true)
+            .build().unwrap();
+        assert_eq!(built.bitcoin_peers.len(), 1);
+        assert_eq!(built.bitcoin_connections, 2);
+        assert_eq!(built.bitcoin_discovery, true);
+        assert_eq!(built.to_builder().build().unwrap(), built);
+
+        let updated = built.to_builder().peers(vec!()).connections(5).build().unwrap();
+        assert_eq!(updated.bitcoin_peers.len(), 0);
+        assert_eq!(updated.keyroot, built.keyroot);
+    }
+
+    #[test]
+    fn builder_validation() {
+        let valid = Config::builder().encryptedwalletkey("0e05ba48").keyroot(KEYROOT).network(Network::Testnet);
+        assert!(valid.clone().build().is_ok());
+        assert!(Config::builder().encryptedwalletkey("0e05ba48").keyroot(KEYROOT).build().is_err());
+        assert!(valid.clone().encryptedwalletkey("not hex").build().is_err());
+        assert!(valid.clone().keyroot("keyroot").build().is_err());
+        assert!(valid.clone().network(Network::Bitcoin).build().is_err());
+        assert!(valid.clone().discovery(true).connections(0).build().is_err());
+        let peer = "127.0.0.1:18444".parse().unwrap();
+        assert!(valid.clone().peer(peer).peer(peer).build().is_err());
+        assert!(valid.clone().connect_timeout(Some(0)).build().is_err());
+        assert!(valid.clone().reconnect_delay(Some(60), Some(30)).build().is_err());
+        assert!(valid.clone().reconnect_delay(Some(1000), None).build().is_err());
+        assert!(valid.clone().reconnect_delay(Some(1), Some(60)).build().is_ok());
+        let listen = Some("0.0.0.0:18444".parse().unwrap());
+        assert!(valid.clone().listen(listen).max_inbound(Some(0)).build().is_err());
+        assert!(valid.clone().listen(listen).build().is_ok());
+        assert!(valid.clone().broadcast_peers(Some(0)).build().is_err());
+        assert!(valid.clone().broadcast_fallback(Some("mempool.space/api")).build().is_err());
+        assert!(valid.clone().broadcast_fallback(Some("https://mempool.space/testnet/api")).build().is_ok());
+        assert!(valid.clone().kdf(Some("argon2id$m=65536,t=3,p=1$00112233445566778899aabbccddeeff")).build().is_ok());
+        assert!(valid.clone().kdf(Some("scrypt$n=15$0011")).build().is_err());
+        assert!(valid.clone().fee_rate_bounds(Some(0), None).build().is_err());
+        assert!(valid.clone().fee_rate_bounds(Some(20), Some(10)).build().is_err());
+        assert!(valid.clone().fee_rate_bounds(Some(2), Some(500)).build().is_err());
+        assert!(valid.clone().fee_rate_bounds(Some(101), None).build().is_err());
+        assert!(valid.clone().fee_rate_bounds(Some(2), Some(100)).build().is_ok());
+        assert!(valid.clone().fee_limits(Some(0), None).build().is_err());
+        assert!(valid.clone().fee_limits(Some(101), None).build().is_err());
+        assert!(valid.clone().fee_limits(None, Some(0)).build().is_err());
+        assert!(valid.clone().fee_limits(Some(5), Some(100000)).build().is_ok());
+        assert!(valid.clone().consolidation(Some(0), None, None).build().is_err());
+        assert!(valid.clone().consolidation(Some(101), None, None).build().is_err());
+        assert!(valid.clone().consolidation(Some(2), Some(1), None).build().is_err());
+        assert!(valid.clone().consolidation(Some(2), None, Some(0)).build().is_err());
+        assert!(valid.clone().consolidation(Some(2), Some(10), Some(50000)).build().is_err());
+        assert!(valid.clone().consolidation(Some(2), Some(10), Some(50000)).broadcast_fallback(Some("https://mempool.space/testnet/api")).build().is_ok());
+        assert!(valid.clone().webhook(Some("example.com/hook"), None).build().is_err());
+        assert!(valid.clone().webhook(None, Some("secret")).build().is_err());
+        assert!(valid.clone().webhook(Some("https://example.com/hook"), Some("secret")).build().is_ok());
+        assert!(valid.clone().event_socket(Some("0.0.0.0:9735".parse().unwrap())).build().is_err());
+        assert!(valid.clone().event_socket(Some("127.0.0.1:9735".parse().unwrap())).build().is_ok());
+        assert!(valid.clone().event_socket(Some("192.168.1.2:9735".parse().unwrap())).build().is_err());
+        assert!(valid.clone().event_socket(Some("[::]:9735".parse().unwrap())).build().is_err());
+        assert!(valid.clone().event_socket(Some("[::1]:9735".parse().unwrap())).build().is_ok());
+        assert!(valid.clone().rpc(Some("127.0.0.1:8332"), None, None).build().is_err());
+        assert!(valid.clone().rpc(Some("http://127.0.0.1:8332"), Some("user"), None).build().is_err());
+        assert!(valid.clone().rpc(Some("http://127.0.0.1:8332"), Some("user"), Some("password")).build().is_ok());
+        assert!(valid.clone().rpc(Some("http://127.0.0.1:8332"), None, None).headers_only(true).build().is_err());
+        assert!(valid.clone().headers_only(true).build().is_ok());
+        assert!(valid.clone().quotas(Some(0), None, None).build().is_err());
+        assert!(valid.clone().quotas(None, None, Some(0)).build().is_err());
+        assert!(valid.clone().quotas(Some(8_000_000), Some(50_000_000), Some(2)).build().is_ok());
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! opportunistic consolidation of small coins
+//!
+//! small coins are merged into one while fees are low, so they do not have to be spent when fees are high
+
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::OutPoint;
+use bitcoin_hashes::sha256d;
+use log::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::esplora;
+use crate::store::SharedContentStore;
+
+/// consolidate if there are at least this many small coins
+pub const DEFAULT_MIN_COINS: u32 = 20;
+/// coins of at most this many satoshis are small
+pub const DEFAULT_MAX_VALUE: u64 = 100000;
+/// confirmation target in blocks of the low priority fee rate
+pub const LOW_PRIORITY_TARGET: u32 = 144;
+/// virtual size of spending one of our coins
+const INPUT_VSIZE: u64 = 91;
+/// keeps consolidations well below the standard transaction weight
+const MAX_INPUTS: usize = 500;
+/// fee estimates are fetched this often
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// when and what to consolidate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConsolidationPolicy {
+    /// highest low priority fee rate to consolidate at, satoshi per vbyte
+    pub fee_rate: u64,
+    pub min_coins: u32,
+    pub max_value: u64,
+}
+
+impl ConsolidationPolicy {
+    /// the policy of a config, None if consolidation is off
+    pub fn from_config(config: &Config) -> Option<ConsolidationPolicy> {
+        config.consolidate_fee_rate.map(|fee_rate| ConsolidationPolicy {
+            fee_rate,
+            min_coins: config.consolidate_min_coins.unwrap_or(DEFAULT_MIN_COINS),
+            max_value: config.consolidate_max_value.unwrap_or(DEFAULT_MAX_VALUE),
+        })
+    }
+
+    /// the small coins to merge at fee_rate, None if fees are too high or there are too few coins worth spending
+    pub fn select(&self, fee_rate: u64, small: &[(OutPoint, u64)]) -> Option<Vec<OutPoint>> {
+        if fee_rate > self.fee_rate {
+            return None;
+        }
+        let selected = small.iter()
+            .filter(|(_, value)| *value <= self.max_value && *value > fee_rate * INPUT_VSIZE)
+            .take(MAX_INPUTS)
+            .map(|(outpoint, _)| *outpoint)
+            .collect::<Vec<_>>();
+        if selected.len() < self.min_coins as usize {
+            return None;
+        }
+        Some(selected)
+    }
+}
+
+/// what a consolidation did
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConsolidationEvent {
+    /// coins were merged into one output of amount
+    Consolidated { txid: sha256d::Hash, merged: Vec<OutPoint>, amount: u64, fee: u64 },
+    /// a consolidation was due but could not be made
+    Failed { reason: String },
+}
+
+/// check fee estimates of the broadcast fallback and consolidate while the store runs
+pub fn start(store: SharedContentStore, config_file: PathBuf) {
+    thread::Builder::new().name("consolidation".to_string()).spawn(move || {
+        let mut last = None;
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            if store.read().unwrap().get_stopped() {
+                debug!("stop consolidation checks");
+                break;
+            }
+            if last.map(|l: Instant| l.elapsed() < CHECK_INTERVAL).unwrap_or(false) {
+                continue;
+            }
+            last = Some(Instant::now());
+            let endpoint = match config::load(&config_file).ok().and_then(|c| c.consolidate_fee_rate.and(c.bitcoin_broadcast_fallback)) {
+                Some(endpoint) => endpoint,
+                None => continue
+            };
+            match esplora::fee_estimate(endpoint.as_str(), LOW_PRIORITY_TARGET) {
+                Ok(fee_rate) => {
+                    debug!("low priority fee rate {}", fee_rate);
+                    if let Err(e) = store.write().unwrap().consolidate(fee_rate) {
+                        warn!("consolidation failed {}", e);
+                    }
+                }
+                Err(e) => info!("can not estimate fee rate {}", e)
+            }
+        }
+    }).expect("can not start consolidation thread");
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::OutPoint;
+
+    use super::ConsolidationPolicy;
+
+    #[test]
+    fn select() {
+        let policy = ConsolidationPolicy { fee_rate: 2, min_coins: 3, max_value: 10000 };
+        let coins = (0..4u32).map(|i| (OutPoint { txid: Default::default(), vout: i }, 1000 * (i as u64 + 1))).collect::<Vec<_>>();
+        assert_eq!(policy.select(2, coins.as_slice()).unwrap().len(), 4);
+        assert!(policy.select(3, coins.as_slice()).is_none());
+        // at 1 satoshi per vbyte a coin of 1000 is worth more than its input, at 11 it is not
+        assert!(policy.select(1, &coins[..3]).is_some());
+        let expensive = ConsolidationPolicy { fee_rate: 20, ..policy };
+        assert_eq!(expensive.select(11, coins.as_slice()).unwrap().len(), 3);
+        assert!(expensive.select(11, &coins[..3]).is_none());
+    }
+}
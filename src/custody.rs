@@ -0,0 +1,104 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! custody of the master seed
+//!
+//! the seed is always encrypted with the wallet passphrase, a key storage may seal it further,
+//! e.g. wrap it with a key of the Android Keystore or the iOS Secure Enclave, or keep it in an HSM
+//! and return a reference to it. The config only holds what seal returned.
+
+use crate::error::Error;
+
+/// seals and unseals the passphrase encrypted master seed
+pub trait KeyStorage: Send + Sync {
+    /// seal the encrypted seed, returns the value kept in the config
+    fn seal(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// the encrypted seed of a value kept in the config
+    fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// the default: the encrypted seed is kept in the config file as is
+pub struct ConfigKeyStorage;
+
+impl KeyStorage for ConfigKeyStorage {
+    fn seal(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(encrypted.to_vec())
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(sealed.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+    use bitcoin_wallet::account::Unlocker;
+
+    use crate::error::Error;
+    use crate::wallet::Wallet;
+
+    use super::{ConfigKeyStorage, KeyStorage};
+
+    const PASSPHRASE: &str = "correct horse battery";
+
+    /// seals with a tag and a key kept elsewhere, as a platform storage would
+    struct Wrapping(u8);
+
+    impl KeyStorage for Wrapping {
+        fn seal(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+            let mut sealed = b"sealed".to_vec();
+            sealed.extend(encrypted.iter().map(|b| b ^ self.0));
+            Ok(sealed)
+        }
+
+        fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+            if !sealed.starts_with(b"sealed") {
+                return Err(Error::InvalidArgument("not sealed by this storage"));
+            }
+            Ok(sealed[6..].iter().map(|b| b ^ self.0).collect())
+        }
+    }
+
+    #[test]
+    fn config_storage_round_trip() {
+        let (_, _, wallet) = Wallet::new(Network::Testnet, PASSPHRASE, None);
+        let sealed = ConfigKeyStorage.seal(wallet.encrypted().as_slice()).unwrap();
+        assert_eq!(&sealed, wallet.encrypted());
+        assert_eq!(&ConfigKeyStorage.unseal(sealed.as_slice()).unwrap(), wallet.encrypted());
+    }
+
+    #[test]
+    fn sealed_seed_unlocks() {
+        let (_, _, wallet) = Wallet::new(Network::Testnet, PASSPHRASE, None);
+        let storage = Wrapping(0x5a);
+        let sealed = storage.seal(wallet.encrypted().as_slice()).unwrap();
+        assert_ne!(&sealed, wallet.encrypted());
+
+        let unsealed = storage.unseal(sealed.as_slice()).unwrap();
+        assert_eq!(&unsealed, wallet.encrypted());
+        let restored = Wallet::from_encrypted(unsealed.as_slice(), wallet.master_public().clone(), wallet.birth());
+        assert!(Unlocker::new_for_master(&restored.master, PASSPHRASE).is_ok());
+        assert!(Unlocker::new_for_master(&restored.master, "wrong passphrase").is_err());
+
+        // an other key unseals garbage, the passphrase no longer unlocks it
+        let garbled = Wrapping(0x33).unseal(sealed.as_slice()).unwrap();
+        let wrong = Wallet::from_encrypted(garbled.as_slice(), wallet.master_public().clone(), wallet.birth());
+        assert!(Unlocker::new_for_master(&wrong.master, PASSPHRASE).is_err());
+        assert!(storage.unseal(wallet.encrypted().as_slice()).is_err());
+    }
+}
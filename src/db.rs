@@ -15,16 +15,16 @@
  * limitations under the License.
  */
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hasher;
 use std::io;
-use std::net::{Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
-use bitcoin::{Network, OutPoint, PublicKey, Script, TxOut};
+use bitcoin::{Address, Network, OutPoint, PublicKey, Script, TxOut};
 use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin_hashes::{sha256, sha256d};
@@ -41,10 +41,44 @@ use rusqlite::types::{Null, ValueRef};
 use siphasher::sip::SipHasher;
 
 use crate::error::Error;
+use crate::history::HistoryEntry;
+use crate::vault::{Vault, VaultState};
+use crate::imported::{Imported, ImportedKey};
+use crate::txsize::InputType;
+use crate::watch::{Watched, WatchedCoin};
 
 pub type SharedDB = Arc<Mutex<DB>>;
 
 const ADDRESS_SLOTS: u64 = 10000;
+const BAN_TIME: u64 = 60 * 60 * 24; // a day
+
+/// an inconsistency found by a db check
+#[derive(Clone, Debug, PartialEq)]
+pub enum DbProblem {
+    /// reported by the integrity check of sqlite
+    Integrity(String),
+    /// a coin of a table without the account, watched script or imported key it was found for
+    OrphanCoin { table: &'static str, outpoint: OutPoint },
+    /// the last processed block is not on the trunk
+    ProcessedOffTrunk(sha256d::Hash),
+}
+
+/// rows of a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableStats {
+    pub table: String,
+    pub rows: u64,
+}
+
+/// size of the db and its tables
+#[derive(Clone, Debug, PartialEq)]
+pub struct DbStats {
+    pub tables: Vec<TableStats>,
+    /// bytes
+    pub size: u64,
+    /// bytes of unused pages
+    pub free: u64,
+}
 
 pub struct DB {
     connection: Connection
@@ -129,6 +163,111 @@ impl<'db> TX<'db> {
                 id text,
                 term number
             ) without rowid;
+
+            create table if not exists notification (
+                txid text primary key,
+                tx blob
+            ) without rowid;
+
+            create table if not exists retiring_master (
+                encrypted text,
+                keyroot text,
+                birth number
+            );
+
+            create table if not exists retiring_account (
+                account number,
+                sub number,
+                address_type number,
+                master text,
+                instantiated blob,
+                primary key(account, sub)
+            ) without rowid;
+
+            create table if not exists retiring_coins (
+                txid text,
+                vout number,
+                value number,
+                script blob,
+                account number,
+                sub number,
+                kix number,
+                tweak text,
+                csv number,
+                proof blob,
+                primary key(txid, vout)
+            ) without rowid;
+
+            create table if not exists vault (
+                txid text,
+                vout number,
+                value number,
+                lock_time number,
+                delay number,
+                ix number,
+                state number,
+                unvault blob,
+                unvault_block text,
+                cancel_key text,
+                retired number,
+                primary key(txid, vout)
+            ) without rowid;
+
+            create table if not exists watched (
+                script blob primary key,
+                address text
+            );
+
+            create table if not exists watched_coin (
+                txid text,
+                vout number,
+                script blob,
+                value number,
+                height number,
+                spent_txid text,
+                spent_height number,
+                primary key(txid, vout)
+            ) without rowid;
+
+            create table if not exists imported_key (
+                script blob primary key,
+                address text,
+                public text,
+                key_type number,
+                origin text,
+                encrypted blob
+            );
+
+            create table if not exists imported_coin (
+                txid text,
+                vout number,
+                script blob,
+                value number,
+                height number,
+                spent_txid text,
+                spent_height number,
+                primary key(txid, vout)
+            ) without rowid;
+
+            create table if not exists history (
+                txid text primary key,
+                height number,
+                time number,
+                net number,
+                fee number
+            ) without rowid;
+
+            create table if not exists label (
+                txid text primary key,
+                label text
+            ) without rowid;
+
+            create table if not exists rate (
+                currency text,
+                time number,
+                rate real,
+                primary key(currency, time)
+            ) without rowid;
         "#).expect("failed to create db tables");
     }
 
@@ -136,15 +275,116 @@ impl<'db> TX<'db> {
         self.tx.execute(r#"
             update processed set block = ?1
         "#, &[&after.to_string() as &dyn ToSql])?;
+        self.drop_scanned()
+    }
+
+    /// forget the processed block and all found by scanning blocks, the next start scans from the wallet birth
+    pub fn reset_scan(&mut self) -> Result<(), Error> {
+        self.tx.execute(r#"
+            delete from processed
+        "#, NO_PARAMS)?;
+        self.drop_scanned()
+    }
+
+    fn drop_scanned(&mut self) -> Result<(), Error> {
         self.tx.execute(r#"
             delete from txout
         "#, NO_PARAMS)?;
         self.tx.execute(r#"
             delete from coins
         "#, NO_PARAMS)?;
+        self.tx.execute(r#"
+            delete from retiring_coins
+        "#, NO_PARAMS)?;
+        self.tx.execute(r#"
+            delete from watched_coin
+        "#, NO_PARAMS)?;
+        self.tx.execute(r#"
+            delete from imported_coin
+        "#, NO_PARAMS)?;
+        self.tx.execute(r#"
+            delete from history
+        "#, NO_PARAMS)?;
+        Ok(())
+    }
+
+    /// add a column to a table created by an earlier layout, unless it is there
+    pub fn add_column(&mut self, table: &str, column: &str, column_type: &str) -> Result<(), Error> {
+        let exists = {
+            let mut query = self.tx.prepare(format!("pragma table_info({})", table).as_str())?;
+            let names = query.query_map(NO_PARAMS, |r| Ok(r.get_unwrap::<usize, String>(1)))?.collect::<Result<Vec<_>, _>>()?;
+            names.iter().any(|n| n == column)
+        };
+        if !exists {
+            self.tx.execute(format!("alter table {} add column {} {}", table, column, column_type).as_str(), NO_PARAMS)?;
+        }
         Ok(())
     }
 
+    /// inconsistencies of the stored data
+    pub fn check(&self) -> Result<Vec<DbProblem>, Error> {
+        let mut problems = Vec::new();
+        let mut query = self.tx.prepare(r#"
+            pragma integrity_check
+        "#)?;
+        for message in query.query_map(NO_PARAMS, |r| Ok(r.get_unwrap::<usize, String>(0)))? {
+            let message = message?;
+            if message != "ok" {
+                problems.push(DbProblem::Integrity(message));
+            }
+        }
+        // coins must refer to the account or key they were found for
+        for &(table, sql) in [
+            ("coins", "select txid, vout from coins c where not exists (select 1 from account a where a.account = c.account and a.sub = c.sub)"),
+            ("retiring_coins", "select txid, vout from retiring_coins c where not exists (select 1 from retiring_account a where a.account = c.account and a.sub = c.sub)"),
+            ("watched_coin", "select txid, vout from watched_coin c where not exists (select 1 from watched w where w.script = c.script)"),
+            ("imported_coin", "select txid, vout from imported_coin c where not exists (select 1 from imported_key k where k.script = c.script)")].iter() {
+            let mut query = self.tx.prepare(sql)?;
+            for coin in query.query_map(NO_PARAMS, |r| Ok((r.get_unwrap::<usize, String>(0), r.get_unwrap::<usize, i64>(1))))? {
+                let (txid, vout) = coin?;
+                problems.push(DbProblem::OrphanCoin {
+                    table,
+                    outpoint: OutPoint { txid: sha256d::Hash::from_hex(txid.as_str()).map_err(|_| Error::InvalidArgument("stored txid is not hex"))?, vout: vout as u32 },
+                });
+            }
+        }
+        Ok(problems)
+    }
+
+    /// row counts of the tables and size of the db
+    pub fn stats(&self) -> Result<DbStats, Error> {
+        let mut names = Vec::new();
+        {
+            let mut query = self.tx.prepare(r#"
+                select name from sqlite_master where type = 'table' order by name
+            "#)?;
+            for name in query.query_map(NO_PARAMS, |r| Ok(r.get_unwrap::<usize, String>(0)))? {
+                names.push(name?);
+            }
+        }
+        let mut tables = Vec::new();
+        for table in names {
+            let rows = self.tx.query_row(format!("select count(*) from {}", table).as_str(), NO_PARAMS, |r| Ok(r.get_unwrap::<usize, i64>(0)))?;
+            tables.push(TableStats { table, rows: rows as u64 });
+        }
+        let pragma = |name: &str| self.tx.query_row(format!("pragma {}", name).as_str(), NO_PARAMS, |r| Ok(r.get_unwrap::<usize, i64>(0) as u64));
+        let page_size = pragma("page_size")?;
+        Ok(DbStats { tables, size: pragma("page_count")? * page_size, free: pragma("freelist_count")? * page_size })
+    }
+
+    /// bytes of the db in use, free pages excluded
+    pub fn used_size(&self) -> Result<u64, Error> {
+        let pragma = |name: &str| self.tx.query_row(format!("pragma {}", name).as_str(), NO_PARAMS, |r| Ok(r.get_unwrap::<usize, i64>(0) as u64));
+        Ok((pragma("page_count")? - pragma("freelist_count")?) * pragma("page_size")?)
+    }
+
+    /// forget peer addresses but the keep most recently seen, returns the number forgotten
+    pub fn prune_addresses(&mut self, keep: usize) -> Result<usize, Error> {
+        Ok(self.tx.execute(r#"
+            delete from address where (network, slot) not in (select network, slot from address order by last_seen desc limit ?1)
+        "#, &[&(keep as i64) as &dyn ToSql])?)
+    }
+
     pub fn store_txout(&mut self, tx: &bitcoin::Transaction, funding: Option<(&PublicKey, &sha256::Hash, u16)>) -> Result<(), Error> {
         if let Some((publisher, id, term)) = funding {
             self.tx.execute(r#"
@@ -198,6 +438,33 @@ impl<'db> TX<'db> {
         Ok(result)
     }
 
+    /// a BIP47 notification transaction paying to our notification address
+    pub fn store_notification(&mut self, tx: &bitcoin::Transaction) -> Result<(), Error> {
+        self.tx.execute(r#"
+            insert or replace into notification (txid, tx) values (?1, ?2)
+        "#, &[&tx.txid().to_string() as &dyn ToSql, &serialize(tx)])?;
+        Ok(())
+    }
+
+    pub fn read_notifications(&self) -> Result<Vec<bitcoin::Transaction>, Error> {
+        let mut query = self.tx.prepare(r#"
+            select tx from notification
+        "#)?;
+        let mut notifications = Vec::new();
+        for r in query.query_map(NO_PARAMS, |r| Ok(r.get_unwrap::<usize, Vec<u8>>(0)))? {
+            notifications.push(deserialize::<bitcoin::Transaction>(r?.as_slice()).expect("can not deserialize stored notification"));
+        }
+        Ok(notifications)
+    }
+
+    /// an own transaction, confirmed or not
+    pub fn read_txout(&self, txid: &sha256d::Hash) -> Result<Option<bitcoin::Transaction>, Error> {
+        Ok(self.tx.query_row(r#"
+            select tx from txout where txid = ?1
+        "#, &[&txid.to_string() as &dyn ToSql], |r| Ok(r.get_unwrap::<usize, Vec<u8>>(0))).optional()?
+            .map(|tx| deserialize::<bitcoin::Transaction>(tx.as_slice()).expect("can not deserialize stored transaction")))
+    }
+
     pub fn read_seed(&mut self) -> Result<(u64, u64), Error> {
         if let Some(seed) = self.tx.query_row(r#"
             select k0, k1 from seed where rowid = 1
@@ -230,13 +497,22 @@ impl<'db> TX<'db> {
     }
 
     pub fn store_coins(&mut self, coins: &Coins) -> Result<(), Error> {
-        self.tx.execute(r#"
-            delete from coins;
-        "#, NO_PARAMS)?;
-        let mut statement = self.tx.prepare(r#"
-            insert into coins (txid, vout, value, script, account, sub, kix, tweak, csv, proof)
+        self.store_coins_in("coins", coins)
+    }
+
+    /// coins of the master retired by a seed rotation
+    pub fn store_retiring_coins(&mut self, coins: &Coins) -> Result<(), Error> {
+        self.store_coins_in("retiring_coins", coins)
+    }
+
+    fn store_coins_in(&mut self, table: &str, coins: &Coins) -> Result<(), Error> {
+        self.tx.execute(format!(r#"
+            delete from {};
+        "#, table).as_str(), NO_PARAMS)?;
+        let mut statement = self.tx.prepare(format!(r#"
+            insert into {} (txid, vout, value, script, account, sub, kix, tweak, csv, proof)
             values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-        "#)?;
+        "#, table).as_str())?;
         let proofs = coins.proofs();
         for (outpoint, coin) in coins.confirmed() {
             let proof = proofs.get(&outpoint.txid).expect("inconsistent wallet, missing proof");
@@ -271,11 +547,303 @@ impl<'db> TX<'db> {
         Ok(())
     }
 
-    pub fn read_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error> {
-        // read confirmed
+    pub fn store_vault(&mut self, vault: &Vault) -> Result<(), Error> {
+        let unvault = vault.unvault.as_ref().map(|tx| serialize(tx));
+        let unvault_block = vault.unvault_block.map(|block| block.to_string());
+        let cancel_key = vault.cancel_key.map(|key| key.to_string());
+        self.tx.execute(r#"
+            insert or replace into vault (txid, vout, value, lock_time, delay, ix, state, unvault, unvault_block, cancel_key, retired)
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+        "#, &[&vault.outpoint.txid.to_string() as &dyn ToSql, &vault.outpoint.vout,
+            &(vault.value as i64), &vault.lock_time, &vault.delay, &vault.index, &vault.state.as_u32(),
+            if let Some(ref unvault) = unvault {
+                unvault as &dyn ToSql
+            } else {
+                &Null
+            },
+            if let Some(ref block) = unvault_block {
+                block as &dyn ToSql
+            } else {
+                &Null
+            },
+            if let Some(ref key) = cancel_key {
+                key as &dyn ToSql
+            } else {
+                &Null
+            },
+            &vault.retired])?;
+        Ok(())
+    }
+
+    pub fn read_vaults(&mut self) -> Result<Vec<Vault>, Error> {
         let mut query = self.tx.prepare(r#"
-            select txid, vout, value, script, account, sub, kix, tweak, csv, proof from coins
+            select txid, vout, value, lock_time, delay, ix, state, unvault, unvault_block, cancel_key, retired from vault
         "#)?;
+        let mut vaults = Vec::new();
+        for r in query.query_map(NO_PARAMS, |r| {
+            Ok(Vault {
+                outpoint: OutPoint {
+                    txid: sha256d::Hash::from_hex(r.get_unwrap::<usize, String>(0).as_str()).expect("transaction id not hex"),
+                    vout: r.get_unwrap::<usize, u32>(1),
+                },
+                value: r.get_unwrap::<usize, i64>(2) as u64,
+                lock_time: r.get_unwrap::<usize, u32>(3),
+                delay: r.get_unwrap::<usize, u16>(4),
+                index: r.get_unwrap::<usize, u32>(5),
+                cancel_key: r.get_unwrap::<usize, Option<String>>(9)
+                    .map(|key| PublicKey::from_str(key.as_str()).expect("stored cancel key is not a public key")),
+                retired: r.get_unwrap::<usize, Option<bool>>(10).unwrap_or(false),
+                state: VaultState::from_u32(r.get_unwrap::<usize, u32>(6)).expect("unknown vault state"),
+                unvault: match r.get_raw(7) {
+                    ValueRef::Null => None,
+                    ValueRef::Blob(tx) => Some(deserialize::<bitcoin::Transaction>(tx).expect("can not deserialize stored unvault transaction")),
+                    _ => panic!("unexpected unvault type")
+                },
+                unvault_block: match r.get_raw(8) {
+                    ValueRef::Null => None,
+                    ValueRef::Text(block) => Some(sha256d::Hash::from_hex(std::str::from_utf8(block).unwrap()).expect("stored block id not hex")),
+                    _ => panic!("unexpected unvault block type")
+                },
+            })
+        })? {
+            vaults.push(r?);
+        }
+        Ok(vaults)
+    }
+
+    pub fn store_watched(&mut self, watched: &Watched) -> Result<(), Error> {
+        let script = watched.script.to_bytes();
+        let address = watched.address.as_ref().map(|a| a.to_string());
+        self.tx.execute(r#"
+            insert or replace into watched (script, address) values (?1, ?2)
+        "#, &[&script as &dyn ToSql,
+            if let Some(ref address) = address {
+                address as &dyn ToSql
+            } else {
+                &Null
+            }])?;
+        self.store_watched_coins_in("watched_coin", watched)
+    }
+
+    fn store_watched_coins_in(&mut self, table: &str, watched: &Watched) -> Result<(), Error> {
+        let script = watched.script.to_bytes();
+        self.tx.execute(format!(r#"
+            delete from {} where script = ?1
+        "#, table).as_str(), &[&script as &dyn ToSql])?;
+        let mut statement = self.tx.prepare(format!(r#"
+            insert into {} (txid, vout, script, value, height, spent_txid, spent_height) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        "#, table).as_str())?;
+        for coin in &watched.coins {
+            let spent_txid = coin.spent.map(|(txid, _)| txid.to_string());
+            let spent_height = coin.spent.map(|(_, height)| height);
+            statement.execute(&[&coin.outpoint.txid.to_string() as &dyn ToSql, &coin.outpoint.vout, &script,
+                &(coin.value as i64), &coin.height,
+                if let Some(ref txid) = spent_txid {
+                    txid as &dyn ToSql
+                } else {
+                    &Null
+                },
+                if let Some(ref height) = spent_height {
+                    height as &dyn ToSql
+                } else {
+                    &Null
+                }])?;
+        }
+        Ok(())
+    }
+
+    pub fn read_watched(&mut self) -> Result<Vec<Watched>, Error> {
+        let mut query = self.tx.prepare(r#"
+            select script, address from watched
+        "#)?;
+        let mut watched = Vec::new();
+        for r in query.query_map(NO_PARAMS, |r| {
+            Ok(Watched::new(
+                Script::from(r.get_unwrap::<usize, Vec<u8>>(0)),
+                match r.get_raw(1) {
+                    ValueRef::Null => None,
+                    ValueRef::Text(address) => Some(Address::from_str(std::str::from_utf8(address).unwrap()).expect("stored address is invalid")),
+                    _ => panic!("unexpected watched address type")
+                }))
+        })? {
+            watched.push(r?);
+        }
+        for (script, coin) in self.read_watched_coins_in("watched_coin")? {
+            if let Some(w) = watched.iter_mut().find(|w| w.script == script) {
+                w.coins.push(coin);
+            }
+        }
+        Ok(watched)
+    }
+
+    fn read_watched_coins_in(&self, table: &str) -> Result<Vec<(Script, WatchedCoin)>, Error> {
+        let mut query = self.tx.prepare(format!(r#"
+            select txid, vout, script, value, height, spent_txid, spent_height from {}
+        "#, table).as_str())?;
+        let mut coins = Vec::new();
+        for r in query.query_map(NO_PARAMS, |r| {
+            Ok((Script::from(r.get_unwrap::<usize, Vec<u8>>(2)), WatchedCoin {
+                outpoint: OutPoint {
+                    txid: sha256d::Hash::from_hex(r.get_unwrap::<usize, String>(0).as_str()).expect("transaction id not hex"),
+                    vout: r.get_unwrap::<usize, u32>(1),
+                },
+                value: r.get_unwrap::<usize, i64>(3) as u64,
+                height: r.get_unwrap::<usize, u32>(4),
+                spent: match r.get_raw(5) {
+                    ValueRef::Null => None,
+                    ValueRef::Text(txid) => Some((sha256d::Hash::from_hex(std::str::from_utf8(txid).unwrap()).expect("spending transaction id not hex"),
+                                                  r.get_unwrap::<usize, u32>(6))),
+                    _ => panic!("unexpected spending transaction type")
+                },
+            }))
+        })? {
+            coins.push(r?);
+        }
+        Ok(coins)
+    }
+
+    pub fn store_history(&mut self, entry: &HistoryEntry) -> Result<(), Error> {
+        let fee = entry.fee.map(|f| f as i64);
+        self.tx.execute(r#"
+            insert or replace into history (txid, height, time, net, fee) values (?1, ?2, ?3, ?4, ?5)
+        "#, &[&entry.txid.to_string() as &dyn ToSql, &entry.height, &entry.time, &entry.net,
+            if let Some(ref fee) = fee {
+                fee as &dyn ToSql
+            } else {
+                &Null
+            }])?;
+        Ok(())
+    }
+
+    /// forget history of blocks at or above height
+    pub fn unwind_history(&mut self, height: u32) -> Result<(), Error> {
+        self.tx.execute(r#"
+            delete from history where height >= ?1
+        "#, &[&height as &dyn ToSql])?;
+        Ok(())
+    }
+
+    /// history entries, oldest first
+    pub fn read_history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let mut query = self.tx.prepare(r#"
+            select txid, height, time, net, fee from history order by height
+        "#)?;
+        let mut history = Vec::new();
+        for r in query.query_map(NO_PARAMS, |r| {
+            Ok(HistoryEntry {
+                txid: sha256d::Hash::from_hex(r.get_unwrap::<usize, String>(0).as_str()).expect("transaction id not hex"),
+                height: r.get_unwrap::<usize, u32>(1),
+                time: r.get_unwrap::<usize, u32>(2),
+                net: r.get_unwrap::<usize, i64>(3),
+                fee: r.get_unwrap::<usize, Option<i64>>(4).map(|f| f as u64),
+            })
+        })? {
+            history.push(r?);
+        }
+        Ok(history)
+    }
+
+    /// label a transaction, an empty label removes it
+    pub fn store_label(&mut self, txid: &sha256d::Hash, label: &str) -> Result<(), Error> {
+        if label.is_empty() {
+            self.tx.execute(r#"
+                delete from label where txid = ?1
+            "#, &[&txid.to_string() as &dyn ToSql])?;
+        } else {
+            self.tx.execute(r#"
+                insert or replace into label (txid, label) values (?1, ?2)
+            "#, &[&txid.to_string() as &dyn ToSql, &label])?;
+        }
+        Ok(())
+    }
+
+    pub fn read_labels(&self) -> Result<HashMap<sha256d::Hash, String>, Error> {
+        let mut query = self.tx.prepare(r#"
+            select txid, label from label
+        "#)?;
+        let mut labels = HashMap::new();
+        for r in query.query_map(NO_PARAMS, |r| {
+            Ok((sha256d::Hash::from_hex(r.get_unwrap::<usize, String>(0).as_str()).expect("transaction id not hex"),
+                r.get_unwrap::<usize, String>(1)))
+        })? {
+            let (txid, label) = r?;
+            labels.insert(txid, label);
+        }
+        Ok(labels)
+    }
+
+    pub fn store_rate(&mut self, currency: &str, time: u32, rate: f64) -> Result<(), Error> {
+        self.tx.execute(r#"
+            insert or replace into rate (currency, time, rate) values (?1, ?2, ?3)
+        "#, &[&currency as &dyn ToSql, &time, &rate])?;
+        Ok(())
+    }
+
+    pub fn read_rate(&self, currency: &str, time: u32) -> Result<Option<f64>, Error> {
+        Ok(self.tx.query_row(r#"
+            select rate from rate where currency = ?1 and time = ?2
+        "#, &[&currency as &dyn ToSql, &time], |r| Ok(r.get_unwrap::<usize, f64>(0))).optional()?)
+    }
+
+    pub fn store_imported(&mut self, imported: &Imported) -> Result<(), Error> {
+        for key in &imported.keys {
+            self.tx.execute(r#"
+                insert or replace into imported_key (script, address, public, key_type, origin, encrypted) values (?1, ?2, ?3, ?4, ?5, ?6)
+            "#, &[&key.watched.script.to_bytes() as &dyn ToSql, &key.address().to_string(), &key.public.to_string(),
+                &key_type_as_u32(key.key_type), &key.origin, &key.encrypted])?;
+            self.store_watched_coins_in("imported_coin", &key.watched)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_imported(&mut self) -> Result<Imported, Error> {
+        let mut query = self.tx.prepare(r#"
+            select address, public, key_type, origin, encrypted from imported_key
+        "#)?;
+        let mut imported = Imported::new();
+        for r in query.query_map(NO_PARAMS, |r| {
+            let address = Address::from_str(r.get_unwrap::<usize, String>(0).as_str()).expect("stored address is invalid");
+            Ok(ImportedKey {
+                public: PublicKey::from_str(r.get_unwrap::<usize, String>(1).as_str()).expect("stored public key is invalid"),
+                key_type: key_type_from_u32(r.get_unwrap::<usize, u32>(2)).expect("unknown imported key type"),
+                origin: r.get_unwrap::<usize, String>(3),
+                encrypted: r.get_unwrap::<usize, Vec<u8>>(4),
+                watched: Watched::new(address.script_pubkey(), Some(address)),
+            })
+        })? {
+            imported.keys.push(r?);
+        }
+        for (script, coin) in self.read_watched_coins_in("imported_coin")? {
+            if let Some(key) = imported.keys.iter_mut().find(|k| k.watched.script == script) {
+                key.watched.coins.push(coin);
+            }
+        }
+        Ok(imported)
+    }
+
+    pub fn drop_imported(&mut self) -> Result<(), Error> {
+        self.tx.execute(r#"
+            delete from imported_key
+        "#, NO_PARAMS)?;
+        self.tx.execute(r#"
+            delete from imported_coin
+        "#, NO_PARAMS)?;
+        Ok(())
+    }
+
+    pub fn read_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error> {
+        self.read_coins_in("coins", master_account)
+    }
+
+    pub fn read_retiring_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error> {
+        self.read_coins_in("retiring_coins", master_account)
+    }
+
+    fn read_coins_in(&mut self, table: &str, master_account: &mut MasterAccount) -> Result<Coins, Error> {
+        // read confirmed
+        let mut query = self.tx.prepare(format!(r#"
+            select txid, vout, value, script, account, sub, kix, tweak, csv, proof from {}
+        "#, table).as_str())?;
         let mut coins = Coins::new();
         for r in query.query_map::<(OutPoint, Coin, ProvedTransaction), &[&dyn ToSql], _>(NO_PARAMS, |r| {
             Ok((
@@ -324,6 +892,45 @@ impl<'db> TX<'db> {
         Ok(coins)
     }
 
+    /// move accounts and coins of the current master to the retiring tables, replaced by a new master.
+    /// sealed is the encrypted seed of retiring as sealed by the key storage
+    pub fn retire_master(&mut self, retiring: &MasterAccount, sealed: &[u8]) -> Result<(), Error> {
+        self.tx.execute_batch(r#"
+            delete from retiring_master;
+            delete from retiring_account;
+            delete from retiring_coins;
+            insert into retiring_account select * from account;
+            insert into retiring_coins select * from coins;
+            delete from account;
+            delete from coins;
+        "#)?;
+        self.tx.execute(r#"
+            insert into retiring_master (encrypted, keyroot, birth) values (?1, ?2, ?3)
+        "#, &[&hex::encode(sealed) as &dyn ToSql, &retiring.master_public().to_string(), &(retiring.birth() as i64)])?;
+        Ok(())
+    }
+
+    /// sealed encrypted key, public key and birth of the retiring master
+    pub fn read_retiring_master(&mut self) -> Result<Option<(Vec<u8>, ExtendedPubKey, u64)>, Error> {
+        Ok(self.tx.query_row(r#"
+            select encrypted, keyroot, birth from retiring_master
+        "#, NO_PARAMS, |r| {
+            Ok((hex::decode(r.get_unwrap::<usize, String>(0)).expect("encrypted retiring key not hex"),
+                ExtendedPubKey::from_str(r.get_unwrap::<usize, String>(1).as_str()).expect("malformed retiring master public stored"),
+                r.get_unwrap::<usize, i64>(2) as u64))
+        }).optional()?)
+    }
+
+    /// forget the retiring master once its coins were swept
+    pub fn drop_retiring(&mut self) -> Result<(), Error> {
+        self.tx.execute_batch(r#"
+            delete from retiring_master;
+            delete from retiring_account;
+            delete from retiring_coins;
+        "#)?;
+        Ok(())
+    }
+
     pub fn store_master(&mut self, master: &MasterAccount) -> Result<usize, Error> {
         debug!("store master account");
         self.tx.execute(r#"
@@ -337,21 +944,37 @@ impl<'db> TX<'db> {
     }
 
     pub fn store_account(&mut self, account: &Account) -> Result<usize, Error> {
-        debug!("store account {}/{}", account.account_number(), account.sub_account_number());
-        Ok(self.tx.execute(r#"
-            insert or replace into account (account, address_type, sub, master, instantiated)
+        self.store_account_in("account", account)
+    }
+
+    pub fn store_retiring_account(&mut self, account: &Account) -> Result<usize, Error> {
+        self.store_account_in("retiring_account", account)
+    }
+
+    fn store_account_in(&mut self, table: &str, account: &Account) -> Result<usize, Error> {
+        debug!("store {} {}/{}", table, account.account_number(), account.sub_account_number());
+        Ok(self.tx.execute(format!(r#"
+            insert or replace into {} (account, address_type, sub, master, instantiated)
             values (?1, ?2, ?3, ?4, ?5)
-        "#, &[&account.account_number() as &dyn ToSql,
+        "#, table).as_str(), &[&account.account_number() as &dyn ToSql,
             &account.address_type().as_u32(), &account.sub_account_number(), &account.master_public().to_string(),
             &serde_cbor::ser::to_vec(&account.instantiated())?],
         )?)
     }
 
     pub fn read_account(&mut self, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error> {
-        debug!("read account {}/{}", account_number, sub);
-        Ok(self.tx.query_row(r#"
-            select address_type, master, instantiated from account where account = ?1 and sub = ?2
-        "#, &[&account_number as &dyn ToSql, &sub], |r| {
+        self.read_account_in("account", account_number, sub, network, look_ahead)
+    }
+
+    pub fn read_retiring_account(&mut self, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error> {
+        self.read_account_in("retiring_account", account_number, sub, network, look_ahead)
+    }
+
+    fn read_account_in(&mut self, table: &str, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error> {
+        debug!("read {} {}/{}", table, account_number, sub);
+        Ok(self.tx.query_row(format!(r#"
+            select address_type, master, instantiated from {} where account = ?1 and sub = ?2
+        "#, table).as_str(), &[&account_number as &dyn ToSql, &sub], |r| {
             Ok(Account::new_from_storage(
                 AccountAddressType::from_u32(r.get_unwrap::<usize, u32>(0)),
                 account_number,
@@ -419,8 +1042,6 @@ impl<'db> TX<'db> {
     // the probability to be selected is exponentially higher for those with higher last_seen time
     // TODO mark tried connections, build slots instead of storing all. Replace only if not tried for long or banned
     pub fn get_an_address(&self, network: &str, other_than: Arc<Mutex<HashSet<SocketAddr>>>) -> Result<Option<SocketAddr>, Error> {
-        const BAN_TIME: u64 = 60 * 60 * 24; // a day
-
         let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
         let mut statement = self.tx.prepare(r#"
             select ip from address where network = ?2 and banned < ?1 order by last_seen desc
@@ -447,9 +1068,42 @@ impl<'db> TX<'db> {
                 std::cmp::min(len - 1, thread_rng().sample::<f64, _>(
                     Poisson::new(len as f64 / 4.0).unwrap()) as usize)]))
     }
+
+    // true if any address with this ip was banned during the last day, ports are ignored as inbound peers use ephemeral ones
+    pub fn is_banned(&self, network: &str, ip: &IpAddr) -> Result<bool, Error> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let mut statement = self.tx.prepare(r#"
+            select ip from address where network = ?2 and banned >= ?1
+        "#)?;
+        let banned = statement.query_map::<String, _, _>(
+            &[&((now - BAN_TIME) as i64) as &dyn ToSql, &network.to_string()],
+            |row| Ok(row.get_unwrap::<usize, String>(0)))?
+            .filter_map(|s| s.ok())
+            .filter_map(|s| SocketAddr::from_str(s.as_str()).ok())
+            .any(|a| a.ip() == *ip);
+        Ok(banned)
+    }
 }
 
 
+fn key_type_as_u32(key_type: InputType) -> u32 {
+    match key_type {
+        InputType::P2pkh => 0,
+        InputType::P2shP2wpkh => 1,
+        InputType::P2wpkh => 2,
+        InputType::P2wsh { .. } => unreachable!("imported keys are single key types")
+    }
+}
+
+fn key_type_from_u32(n: u32) -> Option<InputType> {
+    match n {
+        0 => Some(InputType::P2pkh),
+        1 => Some(InputType::P2shP2wpkh),
+        2 => Some(InputType::P2wpkh),
+        _ => None
+    }
+}
+
 pub fn init(config_path: &Path, coins: &Coins, master: &MasterAccount) {
     let mut db = new(&config_path);
     {
@@ -519,4 +1173,50 @@ impl NetAddress {
         };
         Ok(NetAddress { address, port })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use bitcoin::OutPoint;
+    use rusqlite::ToSql;
+
+    use super::{DB, DbProblem};
+
+    #[test]
+    fn check_and_stats() {
+        let mut db = DB::memory().unwrap();
+        let mut tx = db.transaction();
+        tx.create_tables();
+        assert!(tx.check().unwrap().is_empty());
+
+        // a coin of an account not stored
+        let outpoint = OutPoint { txid: Default::default(), vout: 1 };
+        tx.tx.execute(r#"
+            insert into coins (txid, vout, value, account, sub) values (?1, ?2, 1000, 0, 0)
+        "#, &[&outpoint.txid.to_string() as &dyn ToSql, &outpoint.vout]).unwrap();
+        assert_eq!(tx.check().unwrap(), vec!(DbProblem::OrphanCoin { table: "coins", outpoint }));
+
+        let stats = tx.stats().unwrap();
+        assert_eq!(stats.tables.iter().find(|t| t.table == "coins").unwrap().rows, 1);
+        assert_eq!(stats.tables.iter().find(|t| t.table == "txout").unwrap().rows, 0);
+        assert!(stats.size > 0);
+
+        tx.reset_scan().unwrap();
+        assert!(tx.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_addresses() {
+        let mut db = DB::memory().unwrap();
+        let mut tx = db.transaction();
+        tx.create_tables();
+        let empty = tx.used_size().unwrap();
+        for slot in 0..3i64 {
+            tx.tx.execute(r#"
+                insert into address (network, slot, ip, connected, last_seen, banned) values ('testnet', ?1, ?2, 0, ?1, 0)
+            "#, &[&slot as &dyn ToSql, &format!("127.0.0.{}:18333", slot + 1)]).unwrap();
+        }
+        assert!(tx.used_size().unwrap() >= empty);
+        assert_eq!(tx.prune_addresses(1).unwrap(), 2);
+        assert_eq!(tx.get_an_address("testnet", Default::default()).unwrap(), Some("127.0.0.3:18333".parse().unwrap()));
+    }
+}
@@ -20,12 +20,23 @@ use std::fmt;
 use std::io;
 use bitcoin_wallet;
 use bitcoin::blockdata::script;
+#[cfg(feature = "node")]
 use rusqlite;
 
 /// An error class to offer a unified error interface upstream
 pub enum Error {
     /// Unsupported
     Unsupported(&'static str),
+    /// the passphrase does not decrypt the wallet
+    WrongPassphrase,
+    /// not enough available funds
+    InsufficientFunds,
+    /// the fee of a transaction exceeds the fee limits, it is not signed
+    FeeTooHigh(u64),
+    /// the wallet is not started
+    NotRunning,
+    /// a bitcoind RPC or Esplora endpoint is not reachable or no peer is connected
+    NetworkUnavailable(String),
     ///
     Lock(&'static str),
     /// wallet related error
@@ -33,35 +44,68 @@ pub enum Error {
     /// IO error
     IO(io::Error),
     /// DB error
+    #[cfg(feature = "node")]
     DB(rusqlite::Error),
     /// script validation error
     Script(script::Error),
     /// TOML decode error
     TomlDe(toml::de::Error),
+    /// invalid configuration
+    InvalidConfig(&'static str),
+    /// invalid argument passed by the caller
+    InvalidArgument(&'static str),
+    /// failure in the foreign function interface
+    FFI(String),
+    /// HTTP request failed
+    Http(String),
+    /// the wallet files are of a newer layout version than this library understands
+    UnsupportedVersion(u32),
 }
 
 impl std::error::Error for Error {
     fn description(&self) -> &str {
         match *self {
             Error::Unsupported(ref s) => s,
+            Error::WrongPassphrase => "wrong passphrase",
+            Error::InsufficientFunds => "insufficient funds",
+            Error::FeeTooHigh(_) => "fee exceeds the fee limits",
+            Error::NotRunning => "wallet is not running",
+            Error::NetworkUnavailable(ref s) => s.as_str(),
             Error::Lock(ref s) => s,
             Error::Wallet(ref err) => err.description(),
             Error::IO(ref err) => err.description(),
+            #[cfg(feature = "node")]
             Error::DB(ref err) => err.description(),
             Error::Script(ref err) => err.description(),
             Error::TomlDe(ref err) => err.description(),
+            Error::InvalidConfig(ref s) => s,
+            Error::InvalidArgument(ref s) => s,
+            Error::FFI(ref s) => s.as_str(),
+            Error::Http(ref s) => s.as_str(),
+            Error::UnsupportedVersion(_) => "wallet files were written by a newer library",
         }
     }
 
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             Error::Unsupported(_) => None,
+            Error::WrongPassphrase => None,
+            Error::InsufficientFunds => None,
+            Error::FeeTooHigh(_) => None,
+            Error::NotRunning => None,
+            Error::NetworkUnavailable(_) => None,
             Error::Lock(_) => None,
             Error::Wallet(ref err) => Some(err),
             Error::IO(ref err) => Some(err),
+            #[cfg(feature = "node")]
             Error::DB(ref err) => Some(err),
             Error::Script(ref err) => Some(err),
             Error::TomlDe(ref err) => Some(err),
+            Error::InvalidConfig(_) => None,
+            Error::InvalidArgument(_) => None,
+            Error::FFI(_) => None,
+            Error::Http(_) => None,
+            Error::UnsupportedVersion(_) => None,
         }
     }
 }
@@ -71,12 +115,102 @@ impl fmt::Display for Error {
         match *self {
             // underlying errors already impl `Display`, so we defer to their implementations.
             Error::Unsupported(ref s) => write!(f, "Unsupported: {}", s),
+            Error::WrongPassphrase => write!(f, "wrong passphrase"),
+            Error::InsufficientFunds => write!(f, "insufficient funds"),
+            Error::FeeTooHigh(fee) => write!(f, "fee of {} satoshis exceeds the fee limits", fee),
+            Error::NotRunning => write!(f, "wallet is not running"),
+            Error::NetworkUnavailable(ref s) => write!(f, "network unavailable: {}", s),
             Error::Lock(ref s) => write!(f, "ReadLock: {}", s),
             Error::Wallet(ref s) => write!(f, "{}", s),
             Error::IO(ref s) => write!(f, "{}", s),
+            #[cfg(feature = "node")]
             Error::DB(ref s) => write!(f, "{}", s),
             Error::Script(ref s) => write!(f, "{}", s),
             Error::TomlDe(ref s) => write!(f, "{}", s),
+            Error::InvalidConfig(ref s) => write!(f, "InvalidConfig: {}", s),
+            Error::InvalidArgument(ref s) => write!(f, "InvalidArgument: {}", s),
+            Error::FFI(ref s) => write!(f, "FFI: {}", s),
+            Error::Http(ref s) => write!(f, "HTTP: {}", s),
+            Error::UnsupportedVersion(version) => write!(f, "wallet files of version {} were written by a newer library", version),
+        }
+    }
+}
+
+/// Stable numeric error codes, offered to callers across the FFI boundary.
+/// Codes are never re-used or re-numbered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unsupported = 1,
+    Lock = 2,
+    Wallet = 3,
+    IO = 4,
+    DB = 5,
+    Script = 6,
+    TomlDe = 7,
+    InvalidConfig = 8,
+    InvalidArgument = 9,
+    FFI = 10,
+    Http = 11,
+    UnsupportedVersion = 12,
+    WrongPassphrase = 100,
+    InsufficientFunds = 101,
+    FeeTooHigh = 102,
+    NotRunning = 200,
+    NetworkUnavailable = 201,
+}
+
+/// serializable error code and message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorInfo {
+    pub code: u32,
+    pub message: String,
+}
+
+impl Error {
+    /// the stable error code of this error
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            Error::Unsupported(_) => ErrorCode::Unsupported,
+            Error::WrongPassphrase => ErrorCode::WrongPassphrase,
+            Error::InsufficientFunds => ErrorCode::InsufficientFunds,
+            Error::FeeTooHigh(_) => ErrorCode::FeeTooHigh,
+            Error::NotRunning => ErrorCode::NotRunning,
+            Error::NetworkUnavailable(_) => ErrorCode::NetworkUnavailable,
+            Error::Lock(_) => ErrorCode::Lock,
+            Error::Wallet(_) => ErrorCode::Wallet,
+            Error::IO(_) => ErrorCode::IO,
+            #[cfg(feature = "node")]
+            Error::DB(_) => ErrorCode::DB,
+            Error::Script(_) => ErrorCode::Script,
+            Error::TomlDe(_) => ErrorCode::TomlDe,
+            Error::InvalidConfig(_) => ErrorCode::InvalidConfig,
+            Error::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            Error::FFI(_) => ErrorCode::FFI,
+            Error::Http(_) => ErrorCode::Http,
+            Error::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+        }
+    }
+
+    /// error code and message in serializable form
+    pub fn info(&self) -> ErrorInfo {
+        ErrorInfo {
+            code: self.code() as u32,
+            message: self.to_string(),
+        }
+    }
+
+    /// error code and message as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.info()).expect("can not serialize error info")
+    }
+
+    /// the error of an HTTP request that got no response, unreachable hosts are NetworkUnavailable
+    #[cfg(feature = "node")]
+    pub(crate) fn from_request(err: &ureq::Error) -> Error {
+        match err {
+            ureq::Error::DnsFailed(_) | ureq::Error::ConnectionFailed(_) | ureq::Error::Io(_) =>
+                Error::NetworkUnavailable(err.to_string()),
+            _ => Error::Http(err.to_string())
         }
     }
 }
@@ -89,7 +223,10 @@ impl fmt::Debug for Error {
 
 impl convert::From<bitcoin_wallet::error::Error> for Error {
     fn from(err: bitcoin_wallet::error::Error) -> Error {
-        Error::Wallet(err)
+        match err {
+            bitcoin_wallet::error::Error::Passphrase => Error::WrongPassphrase,
+            err => Error::Wallet(err)
+        }
     }
 }
 
@@ -99,6 +236,7 @@ impl convert::From<io::Error> for Error {
     }
 }
 
+#[cfg(feature = "node")]
 impl convert::From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Error {
         Error::DB(err)
@@ -135,8 +273,38 @@ impl convert::From<script::Error> for Error {
     }
 }
 
+#[cfg(any(feature = "java", feature = "android"))]
+impl convert::From<jni::errors::Error> for Error {
+    fn from(err: jni::errors::Error) -> Error {
+        Error::FFI(err.to_string())
+    }
+}
+
 impl convert::From<toml::de::Error> for Error {
     fn from(err: toml::de::Error) -> Error {
         Error::TomlDe(err)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, ErrorCode, ErrorInfo};
+
+    #[test]
+    fn codes() {
+        assert_eq!(Error::InsufficientFunds.code(), ErrorCode::InsufficientFunds);
+        assert_eq!(Error::from(bitcoin_wallet::error::Error::Passphrase).code(), ErrorCode::WrongPassphrase);
+        let info = Error::InvalidConfig("network is not set").info();
+        assert_eq!(info.code, 8);
+        assert_eq!(Error::UnsupportedVersion(2).info().code, 12);
+        assert_eq!(serde_json::from_str::<ErrorInfo>(Error::NotRunning.to_json().as_str()).unwrap().code, 200);
+        assert_eq!(Error::NetworkUnavailable("no peers connected".to_string()).info().code, 201);
+    }
+
+    #[test]
+    #[cfg(feature = "node")]
+    fn request_codes() {
+        assert_eq!(Error::from_request(&ureq::Error::ConnectionFailed("refused".to_string())).code(), ErrorCode::NetworkUnavailable);
+        assert_eq!(Error::from_request(&ureq::Error::BadUrl("nohost".to_string())).code(), ErrorCode::Http);
+    }
+}
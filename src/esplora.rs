@@ -0,0 +1,152 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! broadcast and coin lookup through the HTTP API of an Esplora or mempool.space server
+
+use std::str::FromStr;
+
+use bitcoin::{Address, OutPoint, Transaction};
+use bitcoin::consensus::serialize;
+use bitcoin_hashes::sha256d;
+
+use crate::error::Error;
+
+/// timeout of a request in milliseconds
+const TIMEOUT: u64 = 30000;
+
+/// true if the endpoint looks like a base url of the API e.g. https://blockstream.info/api
+pub fn is_endpoint(endpoint: &str) -> bool {
+    endpoint.starts_with("https://") || endpoint.starts_with("http://")
+}
+
+/// the url transactions are posted to
+pub fn tx_url(endpoint: &str) -> String {
+    format!("{}/tx", endpoint.trim_end_matches('/'))
+}
+
+/// post a raw transaction
+pub fn post_tx(endpoint: &str, tx: &Transaction) -> Result<(), Error> {
+    let response = ureq::post(tx_url(endpoint).as_str())
+        .timeout_connect(TIMEOUT)
+        .timeout_read(TIMEOUT)
+        .send_string(hex::encode(serialize(tx)).as_str());
+    if let Some(err) = response.synthetic_error() {
+        return Err(Error::from_request(err));
+    }
+    if !response.ok() {
+        let status = response.status();
+        let message = response.into_string().unwrap_or_default();
+        return Err(Error::Http(format!("{} {}", status, message.trim())));
+    }
+    Ok(())
+}
+
+/// the url of unspent outputs of an address
+pub fn utxo_url(endpoint: &str, address: &Address) -> String {
+    format!("{}/address/{}/utxo", endpoint.trim_end_matches('/'), address)
+}
+
+/// unspent outputs of an address with their value, including unconfirmed ones
+pub fn address_utxos(endpoint: &str, address: &Address) -> Result<Vec<(OutPoint, u64)>, Error> {
+    parse_utxos(get(utxo_url(endpoint, address).as_str())?.as_str())
+}
+
+/// the url of fee estimates
+pub fn fee_url(endpoint: &str) -> String {
+    format!("{}/fee-estimates", endpoint.trim_end_matches('/'))
+}
+
+/// estimated fee rate in satoshi per vbyte to confirm within target blocks
+pub fn fee_estimate(endpoint: &str, target: u32) -> Result<u64, Error> {
+    parse_fee_estimate(get(fee_url(endpoint).as_str())?.as_str(), target)
+}
+
+fn get(url: &str) -> Result<String, Error> {
+    let response = ureq::get(url)
+        .timeout_connect(TIMEOUT)
+        .timeout_read(TIMEOUT)
+        .call();
+    if let Some(err) = response.synthetic_error() {
+        return Err(Error::from_request(err));
+    }
+    if !response.ok() {
+        return Err(Error::Http(format!("{} {}", response.status(), response.status_text())));
+    }
+    response.into_string().map_err(|e| Error::Http(e.to_string()))
+}
+
+/// the estimate of the longest target not above the requested one, rounded up
+fn parse_fee_estimate(body: &str, target: u32) -> Result<u64, Error> {
+    let invalid = || Error::Http("unexpected fee estimate response".to_string());
+    let estimates = serde_json::from_str::<serde_json::Value>(body).map_err(|_| invalid())?;
+    let mut best: Option<(u32, f64)> = None;
+    for (blocks, rate) in estimates.as_object().ok_or_else(invalid)? {
+        let blocks = blocks.parse::<u32>().map_err(|_| invalid())?;
+        let rate = rate.as_f64().ok_or_else(invalid)?;
+        if blocks <= target && best.map(|(b, _)| blocks > b).unwrap_or(true) {
+            best = Some((blocks, rate));
+        }
+    }
+    best.map(|(_, rate)| rate.ceil() as u64).ok_or_else(invalid)
+}
+
+fn parse_utxos(body: &str) -> Result<Vec<(OutPoint, u64)>, Error> {
+    let invalid = || Error::Http("unexpected utxo response".to_string());
+    let utxos = serde_json::from_str::<serde_json::Value>(body).map_err(|_| invalid())?;
+    let mut result = Vec::new();
+    for utxo in utxos.as_array().ok_or_else(invalid)? {
+        let txid = utxo["txid"].as_str().and_then(|t| sha256d::Hash::from_str(t).ok()).ok_or_else(invalid)?;
+        let vout = utxo["vout"].as_u64().ok_or_else(invalid)? as u32;
+        let value = utxo["value"].as_u64().ok_or_else(invalid)?;
+        result.push((OutPoint { txid, vout }, value));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fee_url, is_endpoint, parse_fee_estimate, parse_utxos, tx_url};
+
+    #[test]
+    fn urls() {
+        assert!(is_endpoint("https://mempool.space/api"));
+        assert!(!is_endpoint("mempool.space/api"));
+        assert_eq!(tx_url("https://blockstream.info/testnet/api/"), "https://blockstream.info/testnet/api/tx");
+        assert_eq!(tx_url("https://mempool.space/api"), "https://mempool.space/api/tx");
+        assert_eq!(fee_url("https://mempool.space/api/"), "https://mempool.space/api/fee-estimates");
+    }
+
+    #[test]
+    fn utxos() {
+        let body = r#"[{"txid":"e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468","vout":1,"status":{"confirmed":true,"block_height":170},"value":3000000000}]"#;
+        let utxos = parse_utxos(body).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].0.vout, 1);
+        assert_eq!(utxos[0].0.txid.to_string(), "e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468");
+        assert_eq!(utxos[0].1, 3000000000);
+        assert!(parse_utxos("{}").is_err());
+    }
+
+    #[test]
+    fn fee_estimates() {
+        let body = r#"{"1":87.882,"2":87.882,"6":68.285,"144":1.027,"504":1.027}"#;
+        assert_eq!(parse_fee_estimate(body, 144).unwrap(), 2);
+        assert_eq!(parse_fee_estimate(body, 100).unwrap(), 69);
+        assert_eq!(parse_fee_estimate(body, 1).unwrap(), 88);
+        assert!(parse_fee_estimate(r#"{"6":1.0}"#, 2).is_err());
+        assert!(parse_fee_estimate("[]", 2).is_err());
+    }
+}
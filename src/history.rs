@@ -0,0 +1,295 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! transaction history
+//!
+//! confirmed balance changes of the wallet and their export for accounting.
+//! Transactions of scripts watched without keys are reported apart, with their own directions and balances
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use bitcoin_hashes::sha256d;
+
+use crate::error::Error;
+use crate::watch::Watched;
+
+/// a confirmed transaction changing the wallet balance
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: sha256d::Hash,
+    pub height: u32,
+    /// time stamp of the block, seconds since the epoch
+    pub time: u32,
+    /// received by us less spent by us, in satoshis
+    pub net: i64,
+    /// fee paid by us, if we spent and the values of all spent outputs are known
+    pub fee: Option<u64>,
+}
+
+/// format of an exported history
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ExportFormat, Error> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(Error::InvalidArgument("export format is csv or json"))
+        }
+    }
+}
+
+/// a line of an exported history
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryRecord {
+    pub timestamp: u32,
+    pub txid: sha256d::Hash,
+    /// "in" or "out", "watched in" or "watched out" for watched scripts
+    pub direction: &'static str,
+    /// absolute balance change, in satoshis
+    pub amount: u64,
+    pub fee: Option<u64>,
+    /// wallet balance after the transaction, that of the watched script for watched scripts
+    pub balance: i64,
+    pub label: Option<String>,
+    /// the address or hex script of a watched script
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watched: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fiat: Option<FiatValue>,
+}
+
+/// fiat valuation of a history record
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FiatValue {
+    pub currency: String,
+    /// price of a bitcoin at the time of the transaction
+    pub rate: f64,
+    /// amount at the rate of the transaction time
+    pub amount: f64,
+    /// amount at the current rate
+    pub current: f64,
+}
+
+const CSV_HEADER: &str = "timestamp,txid,direction,amount,fee,balance,label";
+
+const CSV_FIAT_HEADER: &str = ",currency,rate,fiat_amount,fiat_current";
+
+const CSV_WATCHED_HEADER: &str = ",watched";
+
+impl HistoryRecord {
+    /// signed balance change, in satoshis
+    pub fn net(&self) -> i64 {
+        match self.direction {
+            "out" | "watched out" => -(self.amount as i64),
+            _ => self.amount as i64
+        }
+    }
+}
+
+/// records of entries with a time stamp in [from, to], the running balance accounts for all entries
+pub fn records(entries: &[HistoryEntry], labels: &HashMap<sha256d::Hash, String>, from: Option<u32>, to: Option<u32>) -> Vec<HistoryRecord> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|e| e.height);
+    let mut balance = 0i64;
+    let mut records = Vec::new();
+    for entry in entries {
+        balance += entry.net;
+        if from.map(|f| entry.time < f).unwrap_or(false) || to.map(|t| entry.time > t).unwrap_or(false) {
+            continue;
+        }
+        records.push(HistoryRecord {
+            timestamp: entry.time,
+            txid: entry.txid,
+            direction: if entry.net < 0 { "out" } else { "in" },
+            amount: entry.net.abs() as u64,
+            fee: entry.fee,
+            balance,
+            label: labels.get(&entry.txid).cloned(),
+            watched: None,
+            fiat: None,
+        });
+    }
+    records
+}
+
+/// records of transactions of a watched script with a block time stamp in [from, to],
+/// time is the time stamp of the block at a height
+pub fn watched_records<T>(watched: &Watched, time: T, labels: &HashMap<sha256d::Hash, String>, from: Option<u32>, to: Option<u32>) -> Vec<HistoryRecord>
+    where T: Fn(u32) -> Option<u32> {
+    let name = watched.address.as_ref().map(|a| a.to_string()).unwrap_or_else(|| hex::encode(watched.script.as_bytes()));
+    let mut balance = 0i64;
+    let mut records = Vec::new();
+    for entry in watched.history() {
+        let net = entry.received as i64 - entry.sent as i64;
+        balance += net;
+        let timestamp = time(entry.height).unwrap_or(0);
+        if from.map(|f| timestamp < f).unwrap_or(false) || to.map(|t| timestamp > t).unwrap_or(false) {
+            continue;
+        }
+        records.push(HistoryRecord {
+            timestamp,
+            txid: entry.txid,
+            direction: if net < 0 { "watched out" } else { "watched in" },
+            amount: net.abs() as u64,
+            fee: None,
+            balance,
+            label: labels.get(&entry.txid).cloned(),
+            watched: Some(name.clone()),
+            fiat: None,
+        });
+    }
+    records
+}
+
+/// records in the requested format, with fiat columns if any record is valued
+pub fn export(records: &[HistoryRecord], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Json => serde_json::to_string(records).expect("can not serialize history"),
+        ExportFormat::Csv => {
+            let with_fiat = records.iter().any(|r| r.fiat.is_some());
+            let with_watched = records.iter().any(|r| r.watched.is_some());
+            let mut csv = String::from(CSV_HEADER);
+            if with_fiat {
+                csv.push_str(CSV_FIAT_HEADER);
+            }
+            if with_watched {
+                csv.push_str(CSV_WATCHED_HEADER);
+            }
+            csv.push('\n');
+            for r in records {
+                csv.push_str(format!("{},{},{},{},{},{},{}", r.timestamp, r.txid, r.direction, r.amount,
+                                     r.fee.map(|f| f.to_string()).unwrap_or_default(), r.balance,
+                                     csv_field(r.label.as_ref().map(|l| l.as_str()).unwrap_or(""))).as_str());
+                if with_fiat {
+                    match r.fiat {
+                        Some(ref f) => csv.push_str(format!(",{},{},{:.2},{:.2}", f.currency, f.rate, f.amount, f.current).as_str()),
+                        None => csv.push_str(",,,,")
+                    }
+                }
+                if with_watched {
+                    csv.push(',');
+                    csv.push_str(r.watched.as_ref().map(|w| w.as_str()).unwrap_or(""));
+                }
+                csv.push('\n');
+            }
+            csv
+        }
+    }
+}
+
+// quoted if it contains a separator, quote or line break
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use bitcoin::{Address, Network, OutPoint, Script};
+    use bitcoin_hashes::{Hash, sha256d};
+
+    use crate::watch::{Watched, WatchedCoin};
+
+    use super::{export, ExportFormat, FiatValue, HistoryEntry, records, watched_records};
+
+    #[test]
+    fn export_history() {
+        let deposit = sha256d::Hash::hash(b"deposit");
+        let payment = sha256d::Hash::hash(b"payment");
+        let entries = vec![
+            HistoryEntry { txid: payment, height: 2, time: 2000, net: -30000, fee: Some(200) },
+            HistoryEntry { txid: deposit, height: 1, time: 1000, net: 100000, fee: None },
+        ];
+        let mut labels = HashMap::new();
+        labels.insert(payment, "rent, \"May\"".to_string());
+
+        let all = records(&entries, &labels, None, None);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].direction, "in");
+        assert_eq!(all[1].direction, "out");
+        assert_eq!(all[1].amount, 30000);
+        assert_eq!(all[1].balance, 70000);
+        // the balance accounts for entries before from
+        let later = records(&entries, &labels, Some(1500), None);
+        assert_eq!(later.len(), 1);
+        assert_eq!(later[0].balance, 70000);
+        assert!(records(&entries, &labels, None, Some(999)).is_empty());
+
+        let csv = export(&all, ExportFormat::Csv);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], format!("1000,{},in,100000,,100000,", deposit));
+        assert_eq!(lines[2], format!("2000,{},out,30000,200,70000,\"rent, \"\"May\"\"\"", payment));
+
+        let json = serde_json::from_str::<serde_json::Value>(export(&all, ExportFormat::Json).as_str()).unwrap();
+        assert_eq!(json[1]["label"], "rent, \"May\"");
+        assert_eq!(json[0]["fee"], serde_json::Value::Null);
+        assert!(json[0].get("fiat").is_none());
+
+        let mut valued = all.clone();
+        valued[1].fiat = Some(FiatValue { currency: "usd".to_string(), rate: 10000.0, amount: -3.0, current: -3.5 });
+        let lines = export(&valued, ExportFormat::Csv).lines().map(|l| l.to_string()).collect::<Vec<_>>();
+        assert!(lines[0].ends_with(",currency,rate,fiat_amount,fiat_current"));
+        assert!(lines[1].ends_with(",,,,"));
+        assert!(lines[2].ends_with(",usd,10000,-3.00,-3.50"));
+
+        assert_eq!("CSV".parse::<ExportFormat>().unwrap(), ExportFormat::Csv);
+        assert!("xml".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn export_watched() {
+        let cold = Address::p2wsh(&Script::new(), Network::Testnet);
+        let deposit = sha256d::Hash::hash(b"deposit");
+        let spend = sha256d::Hash::hash(b"spend");
+        let mut watched = Watched::new(cold.script_pubkey(), Some(cold.clone()));
+        watched.coins.push(WatchedCoin { outpoint: OutPoint { txid: deposit, vout: 0 }, value: 50000, height: 1, spent: Some((spend, 3)) });
+        watched.coins.push(WatchedCoin { outpoint: OutPoint { txid: deposit, vout: 1 }, value: 20000, height: 1, spent: None });
+        let time = |height| Some(1000 * height);
+
+        let all = watched_records(&watched, time, &HashMap::new(), None, None);
+        assert_eq!(all.len(), 2);
+        assert_eq!((all[0].direction, all[0].amount, all[0].balance, all[0].timestamp), ("watched in", 70000, 70000, 1000));
+        assert_eq!((all[1].direction, all[1].amount, all[1].balance, all[1].timestamp), ("watched out", 50000, 20000, 3000));
+        assert_eq!(all[1].net(), -50000);
+        assert_eq!(watched_records(&watched, time, &HashMap::new(), Some(2000), None).len(), 1);
+
+        // the wallet's records leave the watched column empty
+        let mut mixed = records(&[HistoryEntry { txid: deposit, height: 1, time: 1000, net: 1000, fee: None }], &HashMap::new(), None, None);
+        mixed.extend(all);
+        let csv = export(&mixed, ExportFormat::Csv);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert!(lines[0].ends_with(",label,watched"));
+        assert_eq!(lines[1], format!("1000,{},in,1000,,1000,,", deposit));
+        assert_eq!(lines[3], format!("3000,{},watched out,50000,,20000,,{}", spend, cold));
+        let json = serde_json::from_str::<serde_json::Value>(export(&mixed, ExportFormat::Json).as_str()).unwrap();
+        assert!(json[0].get("watched").is_none());
+        assert_eq!(json[1]["watched"], cold.to_string());
+    }
+}
@@ -0,0 +1,350 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! keys imported from other wallets
+//!
+//! WIF private keys and keys of Electrum seeds, tracked apart from the keys derived from our seed.
+//! Private keys are stored encrypted under a key derived from our seed.
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, generic_array::GenericArray, NewAead};
+use bitcoin::{Address, Block, Network, OutPoint, PrivateKey, PublicKey, Script, SigHashType, Transaction, TxIn, TxOut};
+use bitcoin::blockdata::script::Builder;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey};
+use bitcoin_hashes::{Hash, HashEngine, Hmac, HmacEngine, sha512};
+use rand::{RngCore, thread_rng};
+use zeroize::Zeroizing;
+
+use crate::bip47;
+use crate::error::Error;
+use crate::secret::SecretMaster;
+use crate::txsize::{self, InputType, OutputType};
+use crate::wallet::DUST;
+use crate::watch::Watched;
+
+const IMPORT_PURPOSE: u32 = 0x696d;
+const NONCE_LEN: usize = 12;
+/// keys imported of each chain of an Electrum seed
+pub const ELECTRUM_GAP: u32 = 20;
+const ELECTRUM_ROUNDS: u32 = 2048;
+/// sequence of sweeps, signals replace-by-fee
+const RBF: u32 = 0xffffffff - 2;
+
+/// an imported key and the coins of its address
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportedKey {
+    pub public: PublicKey,
+    pub key_type: InputType,
+    /// "wif" or the derivation path in an Electrum seed
+    pub origin: String,
+    /// nonce and encrypted private key, empty for keys of payment codes derived from our seed
+    pub encrypted: Vec<u8>,
+    pub watched: Watched,
+}
+
+impl ImportedKey {
+    pub fn address(&self) -> &Address {
+        self.watched.address.as_ref().expect("imported keys have an address")
+    }
+
+    /// the private key, master is our master private key
+    pub fn private_key(&self, master: &ExtendedPrivKey) -> Result<PrivateKey, Error> {
+        if self.encrypted.is_empty() {
+            let key = bip47::origin_key(self.origin.as_str(), master)?;
+            let private = PrivateKey { compressed: true, network: self.address().network, key };
+            if private.public_key(&Secp256k1::signing_only()) != self.public {
+                return Err(Error::WrongPassphrase);
+            }
+            return Ok(private);
+        }
+        if self.encrypted.len() < NONCE_LEN {
+            return Err(Error::InvalidArgument("imported key is not encrypted"));
+        }
+        let plain = Zeroizing::new(cipher(master)?
+            .decrypt(GenericArray::from_slice(&self.encrypted[..NONCE_LEN]), &self.encrypted[NONCE_LEN..])
+            .map_err(|_| Error::WrongPassphrase)?);
+        let key = bitcoin::secp256k1::SecretKey::from_slice(plain.as_slice()).map_err(|_| Error::InvalidArgument("invalid imported key"))?;
+        Ok(PrivateKey { compressed: self.public.compressed, network: self.address().network, key })
+    }
+}
+
+/// keys imported from other wallets
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Imported {
+    pub keys: Vec<ImportedKey>,
+}
+
+impl Imported {
+    pub fn new() -> Imported {
+        Imported { keys: Vec::new() }
+    }
+
+    /// add keys not yet imported, returns those added
+    pub fn add(&mut self, keys: Vec<ImportedKey>) -> Vec<ImportedKey> {
+        let added = keys.into_iter()
+            .filter(|k| !self.keys.iter().any(|o| o.watched.script == k.watched.script))
+            .collect::<Vec<_>>();
+        self.keys.extend(added.iter().cloned());
+        added
+    }
+
+    /// value of confirmed unspent coins of imported keys
+    pub fn balance(&self) -> u64 {
+        self.keys.iter().map(|k| k.watched.balance()).sum()
+    }
+
+    /// track coins of imported keys in a block, true if any changed
+    pub fn process(&mut self, block: &Block, height: u32) -> bool {
+        let mut changed = false;
+        for key in self.keys.iter_mut() {
+            changed |= key.watched.process(block, height);
+        }
+        changed
+    }
+
+    /// forget what the block at height did, true if any changed
+    pub fn unwind(&mut self, height: u32) -> bool {
+        let mut changed = false;
+        for key in self.keys.iter_mut() {
+            changed |= key.watched.unwind(height);
+        }
+        changed
+    }
+}
+
+/// a transaction spending coins of imported keys to address, paying fee_per_vbyte on its estimated size.
+/// Coins are the key, outpoint and value
+pub fn sweep(coins: &[(ImportedKey, OutPoint, u64)], master: &ExtendedPrivKey, address: &Address, fee_per_vbyte: u64) -> Result<(Transaction, u64), Error> {
+    if coins.is_empty() {
+        return Err(Error::InvalidArgument("no coins to sweep"));
+    }
+    let output = OutputType::from_address(address).ok_or(Error::InvalidArgument("can not sweep to this address type"))?;
+    let total = coins.iter().map(|(_, _, value)| *value).sum::<u64>();
+    let fee = txsize::estimate(coins.iter().map(|(k, _, _)| k.key_type).collect::<Vec<_>>().as_slice(), &[output]).fee(fee_per_vbyte);
+    if total < fee + DUST {
+        return Err(Error::InvalidArgument("coins do not pay the fee of the sweep"));
+    }
+    let mut tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: coins.iter().map(|(_, outpoint, _)| TxIn { previous_output: *outpoint, script_sig: Script::new(), sequence: RBF, witness: vec!() }).collect(),
+        output: vec!(TxOut { value: total - fee, script_pubkey: address.script_pubkey() }),
+    };
+    let secp = Secp256k1::signing_only();
+    let unsigned = tx.clone();
+    let components = SighashComponents::new(&unsigned);
+    for (index, (key, _, value)) in coins.iter().enumerate() {
+        let private = key.private_key(master)?;
+        let network = key.address().network;
+        let sighash = match key.key_type {
+            InputType::P2pkh => unsigned.signature_hash(index, &key.watched.script, SigHashType::All as u32),
+            InputType::P2shP2wpkh | InputType::P2wpkh =>
+                components.sighash_all(&unsigned.input[index], &Address::p2pkh(&key.public, network).script_pubkey(), *value),
+            InputType::P2wsh { .. } => return Err(Error::Unsupported("imported keys are single key types"))
+        };
+        let mut signature = secp.sign(&Message::from_slice(&sighash[..]).expect("sighash is 32 bytes"), &private.key).serialize_der().to_vec();
+        signature.push(SigHashType::All as u8);
+        let input = &mut tx.input[index];
+        match key.key_type {
+            InputType::P2pkh => {
+                input.script_sig = Builder::new().push_slice(signature.as_slice()).push_key(&key.public).into_script();
+            }
+            InputType::P2shP2wpkh => {
+                input.script_sig = Builder::new().push_slice(Address::p2wpkh(&key.public, network).script_pubkey().as_bytes()).into_script();
+                input.witness = vec!(signature, key.public.to_bytes());
+            }
+            InputType::P2wpkh => {
+                input.witness = vec!(signature, key.public.to_bytes());
+            }
+            InputType::P2wsh { .. } => unreachable!("not signed")
+        }
+    }
+    Ok((tx, fee))
+}
+
+/// keys of a WIF private key, compressed keys are imported for each address type
+pub fn import_wif(wif: &str, master: &ExtendedPrivKey) -> Result<Vec<ImportedKey>, Error> {
+    let key = PrivateKey::from_wif(wif).map_err(|_| Error::InvalidArgument("not a WIF private key"))?;
+    if (key.network == Network::Bitcoin) != (master.network == Network::Bitcoin) {
+        return Err(Error::InvalidArgument("private key is for an other network"));
+    }
+    let types = if key.compressed {
+        vec!(InputType::P2wpkh, InputType::P2shP2wpkh, InputType::P2pkh)
+    } else {
+        vec!(InputType::P2pkh)
+    };
+    types.into_iter().map(|t| imported_key(&key, t, "wif".to_string(), master)).collect()
+}
+
+/// the first ELECTRUM_GAP receive and change keys of an Electrum seed
+pub fn import_electrum(words: &str, passphrase: &str, master: &ExtendedPrivKey) -> Result<Vec<ImportedKey>, Error> {
+    let words = words.split_whitespace().map(|w| w.to_lowercase()).collect::<Vec<_>>().join(" ");
+    let version = hex::encode(&hmac_sha512(b"Seed version", words.as_bytes())[..]);
+    let (key_type, account) = if version.starts_with("01") {
+        (InputType::P2pkh, vec!())
+    } else if version.starts_with("100") {
+        (InputType::P2wpkh, vec!(ChildNumber::from_hardened_idx(0).expect("valid index")))
+    } else {
+        return Err(Error::Unsupported("not an Electrum standard or segwit seed"));
+    };
+    let salt = format!("electrum{}", passphrase.to_lowercase());
+    let seed = pbkdf2_sha512(words.as_bytes(), salt.as_bytes(), ELECTRUM_ROUNDS);
+    let secp = Secp256k1::signing_only();
+    let root = SecretMaster::from(ExtendedPrivKey::new_master(master.network, &seed[..]).map_err(|_| Error::InvalidArgument("invalid Electrum seed"))?);
+    let mut keys = Vec::new();
+    for chain in 0..2 {
+        for index in 0..ELECTRUM_GAP {
+            let mut path = account.clone();
+            path.push(ChildNumber::from_normal_idx(chain).expect("valid index"));
+            path.push(ChildNumber::from_normal_idx(index).expect("valid index"));
+            let path = DerivationPath::from(path);
+            let key = root.derive_priv(&secp, &path).map_err(|_| Error::Unsupported("can not derive Electrum key"))?.private_key;
+            keys.push(imported_key(&key, key_type, path.to_string(), master)?);
+        }
+    }
+    Ok(keys)
+}
+
+/// an imported key of key_type with its private key encrypted for master
+pub fn imported_key(key: &PrivateKey, key_type: InputType, origin: String, master: &ExtendedPrivKey) -> Result<ImportedKey, Error> {
+    let public = key.public_key(&Secp256k1::signing_only());
+    let address = match key_type {
+        InputType::P2pkh => Address::p2pkh(&public, master.network),
+        InputType::P2shP2wpkh => Address::p2shwpkh(&public, master.network),
+        InputType::P2wpkh => Address::p2wpkh(&public, master.network),
+        InputType::P2wsh { .. } => return Err(Error::InvalidArgument("imported keys are single key types"))
+    };
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce);
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(cipher(master)?
+        .encrypt(GenericArray::from_slice(&nonce[..]), &key.key[..])
+        .map_err(|_| Error::Unsupported("can not encrypt imported key"))?);
+    Ok(ImportedKey { public, key_type, origin, encrypted, watched: Watched::new(address.script_pubkey(), Some(address)) })
+}
+
+/// an imported key of a payment code of key_type, its private key is derived from our seed by its origin
+pub fn payment_code_key(public: PublicKey, key_type: InputType, origin: String, network: Network) -> Result<ImportedKey, Error> {
+    let address = match key_type {
+        InputType::P2pkh => Address::p2pkh(&public, network),
+        InputType::P2shP2wpkh => Address::p2shwpkh(&public, network),
+        InputType::P2wpkh => Address::p2wpkh(&public, network),
+        InputType::P2wsh { .. } => return Err(Error::InvalidArgument("imported keys are single key types"))
+    };
+    Ok(ImportedKey { public, key_type, origin, encrypted: Vec::new(), watched: Watched::new(address.script_pubkey(), Some(address)) })
+}
+
+/// the cipher of imported keys, keyed with m/purpose'/coin' of our seed
+fn cipher(master: &ExtendedPrivKey) -> Result<Aes256Gcm, Error> {
+    let coin = if master.network == Network::Bitcoin { 0 } else { 1 };
+    let path = DerivationPath::from(vec!(
+        ChildNumber::from_hardened_idx(IMPORT_PURPOSE).expect("valid index"),
+        ChildNumber::from_hardened_idx(coin).expect("valid index")));
+    let key = SecretMaster::from(master.derive_priv(&Secp256k1::signing_only(), &path).map_err(|_| Error::Unsupported("can not derive key of imported keys"))?);
+    Ok(Aes256Gcm::new(*GenericArray::from_slice(&key.private_key.key[..])))
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut engine = HmacEngine::<sha512::Hash>::new(key);
+    engine.input(data);
+    Hmac::<sha512::Hash>::from_engine(engine).into_inner()
+}
+
+/// PBKDF2 with HMAC-SHA512 for a single output block of 64 bytes
+fn pbkdf2_sha512(password: &[u8], salt: &[u8], rounds: u32) -> Zeroizing<[u8; 64]> {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = Zeroizing::new(hmac_sha512(password, block.as_slice()));
+    let mut result = Zeroizing::new(*u);
+    for _ in 1..rounds {
+        *u = hmac_sha512(password, &u[..]);
+        for (r, x) in result.iter_mut().zip(u.iter()) {
+            *r ^= x;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network, OutPoint, PrivateKey};
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::bip32::ExtendedPrivKey;
+
+    use crate::txsize::InputType;
+
+    use super::{ELECTRUM_GAP, import_electrum, import_wif, sweep};
+
+    #[test]
+    fn wif() {
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let other = ExtendedPrivKey::new_master(Network::Testnet, &[2u8; 32]).unwrap();
+        let key = PrivateKey { compressed: true, network: Network::Testnet, key: bitcoin::secp256k1::SecretKey::from_slice(&[3u8; 32]).unwrap() };
+        let keys = import_wif(key.to_wif().as_str(), &master).unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[0].key_type, InputType::P2wpkh);
+        assert_eq!(keys[0].public, key.public_key(&Secp256k1::signing_only()));
+        assert_eq!(keys[0].private_key(&master).unwrap().key, key.key);
+        assert!(keys[0].private_key(&other).is_err());
+
+        let uncompressed = PrivateKey { compressed: false, ..key };
+        assert_eq!(import_wif(uncompressed.to_wif().as_str(), &master).unwrap().len(), 1);
+        let mainnet = PrivateKey { network: Network::Bitcoin, ..key };
+        assert!(import_wif(mainnet.to_wif().as_str(), &master).is_err());
+        assert!(import_wif("not a key", &master).is_err());
+    }
+
+    #[test]
+    fn sweep_keys() {
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let key = PrivateKey { compressed: true, network: Network::Testnet, key: bitcoin::secp256k1::SecretKey::from_slice(&[3u8; 32]).unwrap() };
+        let keys = import_wif(key.to_wif().as_str(), &master).unwrap();
+        let to = Address::p2wpkh(&keys[0].public, Network::Testnet);
+        let coins = keys.iter().enumerate()
+            .map(|(i, k)| (k.clone(), OutPoint { txid: Default::default(), vout: i as u32 }, 100000))
+            .collect::<Vec<_>>();
+        let (tx, fee) = sweep(coins.as_slice(), &master, &to, 2).unwrap();
+        assert_eq!(tx.input.len(), 3);
+        assert_eq!(tx.output[0].value, 300000 - fee);
+        assert!(tx.input[0].script_sig.is_empty() && tx.input[0].witness.len() == 2);
+        assert!(!tx.input[1].script_sig.is_empty() && tx.input[1].witness.len() == 2);
+        assert!(!tx.input[2].script_sig.is_empty() && tx.input[2].witness.is_empty());
+        // the estimate does not undercut the signed size
+        assert!(fee * 4 >= tx.get_weight() as u64 * 2);
+        #[cfg(feature = "bitcoinconsensus")]
+        tx.verify(|o| coins.iter().find(|(_, p, _)| p == o).map(|(k, _, v)| bitcoin::TxOut { value: *v, script_pubkey: k.watched.script.clone() })).unwrap();
+
+        assert!(sweep(&[], &master, &to, 2).is_err());
+        assert!(sweep(&coins[..1], &master, &to, 1000).is_err());
+    }
+
+    #[test]
+    fn electrum() {
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[1u8; 32]).unwrap();
+        // test vector of Electrum's test_wallet_vertical
+        let keys = import_electrum("cycle rocket west magnet parrot shuffle foot correct salt library feed song", "", &master).unwrap();
+        assert_eq!(keys.len(), 2 * ELECTRUM_GAP as usize);
+        assert_eq!(keys[0].address().to_string(), "1NNkttn1YvVGdqBW4PR6zvc3Zx3H5owKRf");
+        assert_eq!(keys[ELECTRUM_GAP as usize].address().to_string(), "1KSezYMhAJMWqFbVFB2JshYg69UpmEXR4D");
+        let segwit = import_electrum("bitter grass shiver impose acquire brush forget axis eager alone wine silver", "", &master).unwrap();
+        assert_eq!(segwit[0].key_type, InputType::P2wpkh);
+        assert_eq!(segwit[0].address().to_string(), "bc1q3g5tmkmlvxryhh843v4dz026avatc0zzr6h3af");
+        assert_eq!(segwit[0].origin, "m/0'/0/0");
+        assert!(import_electrum("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about", "", &master).is_err());
+    }
+}
@@ -16,20 +16,38 @@
  */
 
 use std::convert::TryFrom;
-use std::fs;
 use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, RwLock};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use bitcoin::{Address, Network};
-use jni::JNIEnv;
-use jni::objects::{JObject, JString, JValue};
-use jni::sys::{jboolean, jint, jlong, jobject, jobjectArray};
-use log::{error, info};
+use jni::{JavaVM, JNIEnv};
+use jni::objects::{GlobalRef, JObject, JString, JThrowable, JValue};
+use jni::sys::{jboolean, jbyteArray, jint, jlong, jobject, jobjectArray};
+use log::{error, info, LevelFilter};
+use once_cell::sync::Lazy;
 
-use crate::api::{balance, BalanceAmt, deposit_addr, init_config, InitResult, load_config, remove_config, start, stop, update_config, withdraw, WithdrawTx};
+use crate::api::{AddressValidation, BalanceAmt, ChainHeader, estimate_tx_size, InitResult, load_config, remove_config, set_logging, SyncStatus, SyncSummary, validate_address, WalletHandle, WithdrawTx};
 use crate::config::Config;
+use crate::custody::{ConfigKeyStorage, KeyStorage};
+use crate::error::Error;
+use crate::logging::LogTarget;
+use crate::peers::PeerInfo;
+use crate::secret::Secret;
+use crate::txsize::{InputType, OutputType};
+
+/// wallets opened by the java side by their opaque handle, several networks can be open at once
+static WALLETS: Lazy<Mutex<Wallets>> = Lazy::new(|| Mutex::new(Wallets { next: 1, handles: HashMap::new() }));
+
+struct Wallets {
+    next: jlong,
+    handles: HashMap<jlong, Arc<WalletHandle>>,
+}
 
 // public API
 
@@ -37,31 +55,116 @@ use crate::config::Config;
 
 #[no_mangle]
 #[cfg(feature = "android")]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(_: JNIEnv, _: JObject) {
-    android_log::init("BDK").unwrap();
-    info!("android logger initialized");
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(env: JNIEnv, _: JObject) {
+    throw_on_error(&env, (), || {
+        set_logging(LevelFilter::Info, LogTarget::Logcat)?;
+        info!("android logger initialized");
+        Ok(())
+    })
 }
 
 #[no_mangle]
 #[cfg(feature = "java")]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(_: JNIEnv, _: JObject) {
-    env_logger::init();
-    info!("java logger initialized");
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(env: JNIEnv, _: JObject) {
+    throw_on_error(&env, (), || {
+        set_logging(LevelFilter::Info, LogTarget::Console)?;
+        info!("java logger initialized");
+        Ok(())
+    })
+}
+
+// void org.bdk.jni.BdkLib.setLogging(int level, String logFile, long maxSize, int maxFiles)
+// level: 0 off, 1 error, 2 warn, 3 info, 4 debug, 5 trace
+// logFile null logs to logcat on android and to stderr otherwise
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_setLogging(env: JNIEnv, _: JObject,
+                                                            j_level: jint,
+                                                            j_log_file: JString,
+                                                            j_max_size: jlong,
+                                                            j_max_files: jint) {
+    throw_on_error(&env, (), || {
+        let level = level_from_jint(j_level)?;
+        let target = match optional_string_from_jstring(&env, j_log_file)? {
+            Some(path) => LogTarget::File {
+                path: PathBuf::from(path),
+                max_size: u64_from_jlong(j_max_size, "max size must not be negative")?,
+                max_files: u32::try_from(j_max_files).map_err(|_| Error::InvalidArgument("max files must not be negative"))?,
+            },
+            #[cfg(feature = "android")]
+            None => LogTarget::Logcat,
+            #[cfg(not(feature = "android"))]
+            None => LogTarget::Console,
+        };
+        set_logging(level, target)
+    })
+}
+
+// long org.bdk.jni.BdkLib.openHandle(String workDir, int network)
+// the handle of a wallet that may not be initialized yet, to set its key storage before initConfig
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_openHandle(env: JNIEnv, _: JObject,
+                                                            j_work_dir: JString,
+                                                            j_network: jint) -> jlong {
+    throw_on_error(&env, 0, || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
+
+        open_handle(work_dir, network)
+    })
 }
 
-// Optional<Config> org.bdk.jni.BdkLib.loadConfig(String workDir, int network)
+// void org.bdk.jni.BdkLib.setKeyStorage(long handle, KeyStorage storage)
+// seeds are sealed with byte[] storage.seal(byte[] encrypted) and unsealed with byte[] storage.unseal(byte[] sealed),
+// e.g. wrapped with a key of the Android Keystore. Set before initConfig and start, null keeps seeds in the config
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_setKeyStorage(env: JNIEnv, _: JObject, j_handle: jlong, j_storage: JObject) {
+    throw_on_error(&env, (), || {
+        if j_storage.is_null() {
+            wallet(j_handle)?.set_key_storage(Arc::new(ConfigKeyStorage))
+        } else {
+            wallet(j_handle)?.set_key_storage(Arc::new(JavaKeyStorage::new(&env, j_storage)?))
+        }
+    })
+}
+
+// long org.bdk.jni.BdkLib.loadConfig(String workDir, int network)
+// the handle of an existing wallet, the same handle for repeated calls until it is released
 #[no_mangle]
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_loadConfig(env: JNIEnv, _: JObject,
                                                             j_work_dir: JString,
-                                                            j_network: jint) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
-
-    match load_config(work_dir, network) {
-        Ok(config) => j_optional_config(&env, &config),
-        Err(_err) => j_optional_empty(&env)
-    }
+                                                            j_network: jint) -> jlong {
+    throw_on_error(&env, 0, || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
+
+        load_config(work_dir.clone(), network)?;
+        open_handle(work_dir, network)
+    })
+}
+
+// Optional<Config> org.bdk.jni.BdkLib.config(long handle)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_config(env: JNIEnv, _: JObject, j_handle: jlong) -> jobject {
+    throw_on_error(&env, null(), || {
+        match wallet(j_handle)?.load_config() {
+            Ok(config) => j_optional_config(&env, &config),
+            Err(_err) => j_optional_empty(&env)
+        }
+    })
+}
+
+// void org.bdk.jni.BdkLib.releaseHandle(long handle)
+// the handle is invalid afterwards, a running wallet is stopped
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_releaseHandle(env: JNIEnv, _: JObject, j_handle: jlong) {
+    throw_on_error(&env, (), || {
+        let wallet = WALLETS.lock().map_err(|_| Error::Lock("wallets"))?.handles.remove(&j_handle)
+            .ok_or(Error::InvalidArgument("unknown wallet handle"))?;
+        if wallet.is_running() {
+            wallet.stop()?;
+        }
+        Ok(())
+    })
 }
 
 // Optional<Config> org.bdk.jni.BdkLib.removeConfig(String workDir, int network)
@@ -69,188 +172,584 @@ pub unsafe extern fn Java_org_bdk_jni_BdkLib_loadConfig(env: JNIEnv, _: JObject,
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_removeConfig(env: JNIEnv, _: JObject,
                                                               j_work_dir: JString,
                                                               j_network: jint) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
+    throw_on_error(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
 
-    match remove_config(work_dir, network) {
-        Ok(config) => j_optional_config(&env, &config),
-        Err(_err) => j_optional_empty(&env)
-    }
+        match remove_config(work_dir, network) {
+            Ok(config) => j_optional_config(&env, &config),
+            Err(_err) => j_optional_empty(&env)
+        }
+    })
 }
 
-// Optional<Config> org.bdk.jni.BdkLib.updateConfig(String workDir, int network, String[] bitcoinPeers, int bitcoinConnections, boolean bitcoinDiscovery)
+// Optional<Config> org.bdk.jni.BdkLib.updateConfig(long handle, String[] bitcoinPeers, int bitcoinConnections, boolean bitcoinDiscovery)
 #[no_mangle]
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_updateConfig(env: JNIEnv, _: JObject,
-                                                              j_work_dir: JString,
-                                                              j_network: jint,
+                                                              j_handle: jlong,
                                                               j_bitcoin_peers: jobjectArray,
                                                               j_bitcoin_connections: jint,
                                                               j_bitcoin_discovery: jboolean) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
-
-    let bitcoin_peers_length = env.get_array_length(j_bitcoin_peers)
-        .expect("error get_array_length j_bitcoin_peers");
-
-    let mut bitcoin_peers: Vec<SocketAddr> = Vec::new();
-
-    for i in 0..(bitcoin_peers_length) {
-        let bitcoin_peer = env.get_object_array_element(j_bitcoin_peers, i)
-            .expect("error get_object_array_element j_bitcoin_peers");
-        let bitcoin_peer = JString::try_from(bitcoin_peer)
-            .expect("error JString::try_from j_bitcoin_peers element");
-        let bitcoin_peer = env.get_string(bitcoin_peer)
-            .expect("error env.get_string bitcoin_peer");
-        let bitcoin_peer = bitcoin_peer.to_str()
-            .expect("error bitcoin_peer.toStr()");
-
-        let bitcoin_peer_addr = SocketAddr::from_str(bitcoin_peer)
-            .expect("error SocketAddr::from_str(bitcoin_peer)");
-
-        bitcoin_peers.push(bitcoin_peer_addr);
-    }
-
-    let bitcoin_connections = usize::try_from(j_bitcoin_connections).expect("usize::try_from(j_bitcoin_connections");
-    let bitcoin_discovery = j_bitcoin_discovery == 1;
-
-    match update_config(work_dir, network, bitcoin_peers, bitcoin_connections, bitcoin_discovery) {
-        Ok(updated_config) => j_optional_config(&env, &updated_config),
-        Err(_err) => j_optional_empty(&env)
-    }
+    throw_on_error(&env, null(), || {
+        let bitcoin_peers = parse_peers(&strings_from_jarray(&env, j_bitcoin_peers)?)?;
+        let bitcoin_connections = usize::try_from(j_bitcoin_connections)
+            .map_err(|_| Error::InvalidArgument("bitcoin connections must not be negative"))?;
+        let bitcoin_discovery = j_bitcoin_discovery == 1;
+
+        let updated_config = wallet(j_handle)?.update_config(bitcoin_peers, bitcoin_connections, bitcoin_discovery)?;
+        j_optional_config(&env, &updated_config)
+    })
 }
 
 // Optional<InitResult> org.bdk.jni.BdkLib.initConfig(String workDir, int network, String passphrase, String pdPassphrase)
+// the result carries the handle of the new wallet, use loadConfig if it exists
 #[no_mangle]
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_initConfig(env: JNIEnv, _: JObject,
                                                             j_work_dir: JString,
                                                             j_network: jint,
                                                             j_passphrase: JString,
                                                             j_pd_passphrase: JString) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
-
-    let passphrase = string_from_jstring(&env, j_passphrase);
-    let passphrase = passphrase.as_str();
-    let pd_passphrase = env.get_string(j_pd_passphrase).ok();
-    let pd_passphrase = pd_passphrase.iter()
-        .map(|pd| pd.to_str().expect("error j_pd_passphrase JavaStr.to_str()"))
-        .next();
-
-    match init_config(work_dir, network, passphrase, pd_passphrase) {
-        Ok(None) => {
+    throw_on_error(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
+        let passphrase = Secret::from(string_from_jstring(&env, j_passphrase)?);
+        let pd_passphrase = optional_string_from_jstring(&env, j_pd_passphrase)?.map(Secret::from);
+
+        // the handle of openHandle with its key storage, otherwise a new one registered only if the wallet is initialized
+        let opened = find_handle(&work_dir, network)?;
+        let wallet = match opened {
+            Some(handle) => wallet(handle)?,
+            None => Arc::new(WalletHandle::new(work_dir, network))
+        };
+        match wallet.init_config(passphrase, pd_passphrase)? {
             // do not init if a config already exists, return empty
-            j_optional_empty(&env)
-        }
-        Ok(Some(init_result)) => {
+            None => j_optional_empty(&env),
             // return config
-            j_optional_init_result(&env, init_result)
-        }
-        Err(_err) => {
-            // TODO throw java exception
-            j_optional_empty(&env)
+            Some(init_result) => {
+                let handle = match opened {
+                    Some(handle) => handle,
+                    None => add_handle(wallet)?
+                };
+                j_optional_init_result(&env, handle, init_result)
+            }
         }
-    }
+    })
+}
+
+// void org.bdk.jni.BdkLib.changePassphrase(long handle, String passphrase, String newPassphrase)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_changePassphrase(env: JNIEnv, _: JObject,
+                                                                  j_handle: jlong,
+                                                                  j_passphrase: JString,
+                                                                  j_new_passphrase: JString) {
+    throw_on_error(&env, (), || {
+        let passphrase = Secret::from(string_from_jstring(&env, j_passphrase)?);
+        let new_passphrase = Secret::from(string_from_jstring(&env, j_new_passphrase)?);
+
+        wallet(j_handle)?.change_passphrase(passphrase, new_passphrase)
+    })
 }
 
-// void org.bdk.jni.BdkLib.start(String workDir, int network, boolean rescan)
+// void org.bdk.jni.BdkLib.start(long handle, boolean rescan)
+// returns after stop(handle)
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_start(env: JNIEnv, _: JObject, j_work_dir: JString, j_network: jint, j_rescan: jboolean) {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
-    let rescan = j_rescan == 1;
-
-    match start(work_dir, network, rescan) {
-        Ok(_) => (),
-        Err(_e) => {
-            // TODO throw java exception
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_start(env: JNIEnv, _: JObject, j_handle: jlong, j_rescan: jboolean) {
+    throw_on_error(&env, (), || {
+        let rescan = j_rescan == 1;
+
+        wallet(j_handle)?.start(rescan).map_err(|err| {
             error!("Could not start wallet.");
-            ()
-        }
-    }
+            err
+        })
+    })
 }
 
-// void org.bdk.jni.BdkLib.stop()
+// void org.bdk.jni.BdkLib.stop(long handle)
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_stop(_: JNIEnv, _: JObject) {
-    stop()
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_stop(env: JNIEnv, _: JObject, j_handle: jlong) {
+    throw_on_error(&env, (), || wallet(j_handle)?.stop())
 }
 
-// Option<BalanceAmt> org.bdk.jni.BdkLib.balance()
+// SyncSummary org.bdk.jni.BdkLib.syncOnce(long handle, int maxBlocks, long maxDurationMillis)
+// a bounded sync of a stopped wallet, e.g. of a background job, maxBlocks -1 for no limit
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_balance(env: JNIEnv, _: JObject) -> jobject {
-    match balance() {
-        Ok(balance_amt) => {
-            // return wallet balance amt
-            j_optional_balance_amt_result(&env, balance_amt)
-        },
-        Err(_e) => {
-            // TODO throw java exception
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_syncOnce(env: JNIEnv, _: JObject, j_handle: jlong, j_max_blocks: jint, j_max_duration: jlong) -> jobject {
+    throw_on_error(&env, null(), || {
+        let max_blocks = if j_max_blocks < 0 { None } else { Some(j_max_blocks as u32) };
+        let max_duration = u64::try_from(j_max_duration)
+            .map_err(|_| Error::InvalidArgument("max duration must not be negative"))?;
+
+        let summary = wallet(j_handle)?.sync_once(max_blocks, Duration::from_millis(max_duration))?;
+        j_sync_summary(&env, &summary)
+    })
+}
+
+// byte[] org.bdk.jni.BdkLib.exportSyncState(long handle)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_exportSyncState(env: JNIEnv, _: JObject, j_handle: jlong) -> jbyteArray {
+    throw_on_error(&env, null(), || {
+        let blob = wallet(j_handle)?.export_sync_state()?;
+        Ok(env.byte_array_from_slice(blob.as_slice())?)
+    })
+}
+
+// void org.bdk.jni.BdkLib.importSyncState(long handle, byte[] syncState)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_importSyncState(env: JNIEnv, _: JObject, j_handle: jlong, j_sync_state: jbyteArray) {
+    throw_on_error(&env, (), || {
+        let blob = env.convert_byte_array(j_sync_state)?;
+        wallet(j_handle)?.import_sync_state(blob.as_slice())
+    })
+}
+
+// Option<BalanceAmt> org.bdk.jni.BdkLib.balance(long handle)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_balance(env: JNIEnv, _: JObject, j_handle: jlong) -> jobject {
+    throw_on_error(&env, null(), || {
+        let balance_amt = wallet(j_handle)?.balance().map_err(|err| {
             error!("Could not get wallet balance amt.");
-            j_optional_empty(&env)
+            err
+        })?;
+        // return wallet balance amt
+        j_optional_balance_amt_result(&env, balance_amt)
+    })
+}
+
+// new SyncStatus(int headerHeight, int blockHeight, int peerCount, boolean isSynced)
+// SyncStatus org.bdk.jni.BdkLib.syncStatus(long handle)
+// blockHeight is -1 before the first block is processed
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_syncStatus(env: JNIEnv, _: JObject, j_handle: jlong) -> jobject {
+    throw_on_error(&env, null(), || {
+        let sync_status = wallet(j_handle)?.sync_status()?;
+        j_sync_status(&env, &sync_status)
+    })
+}
+
+// new ChainHeader(int height, String hash, long time)
+// Optional<ChainHeader> org.bdk.jni.BdkLib.getTip(long handle)
+// time is seconds since the unix epoch, empty before the first header
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_getTip(env: JNIEnv, _: JObject, j_handle: jlong) -> jobject {
+    throw_on_error(&env, null(), || {
+        match wallet(j_handle)?.get_tip()? {
+            Some(header) => j_optional_chain_header(&env, &header),
+            None => j_optional_empty(&env)
         }
-    }
+    })
+}
+
+// Optional<ChainHeader> org.bdk.jni.BdkLib.getHeader(long handle, int height)
+// empty above the tip
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_getHeader(env: JNIEnv, _: JObject, j_handle: jlong, j_height: jint) -> jobject {
+    throw_on_error(&env, null(), || {
+        let height = u32::try_from(j_height).map_err(|_| Error::InvalidArgument("height must not be negative"))?;
+        match wallet(j_handle)?.get_header(height)? {
+            Some(header) => j_optional_chain_header(&env, &header),
+            None => j_optional_empty(&env)
+        }
+    })
+}
+
+// void org.bdk.jni.BdkLib.addPeer(long handle, String address)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_addPeer(env: JNIEnv, _: JObject, j_handle: jlong, j_address: JString) {
+    throw_on_error(&env, (), || {
+        let address = parse_peer(string_from_jstring(&env, j_address)?.as_str())?;
+        wallet(j_handle)?.add_peer(address)
+    })
+}
+
+// void org.bdk.jni.BdkLib.disconnectPeer(long handle, String address)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_disconnectPeer(env: JNIEnv, _: JObject, j_handle: jlong, j_address: JString) {
+    throw_on_error(&env, (), || {
+        let address = parse_peer(string_from_jstring(&env, j_address)?.as_str())?;
+        wallet(j_handle)?.disconnect_peer(address)
+    })
+}
+
+// new PeerInfo(String address, String userAgent, int startHeight, long pingMillis, long received, long sent)
+// PeerInfo[] org.bdk.jni.BdkLib.listPeers(long handle)
+// pingMillis is -1 before the first ping was answered
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_listPeers(env: JNIEnv, _: JObject, j_handle: jlong) -> jobjectArray {
+    throw_on_error(&env, null(), || {
+        let peers = wallet(j_handle)?.list_peers()?;
+        j_peer_info_array(&env, &peers)
+    })
 }
 
 // new Address(String address, int network, Optional<String> type)
-// Address org.bdk.jni.BdkLib.depositAddress()
+// Address org.bdk.jni.BdkLib.depositAddress(long handle)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_depositAddress(env: JNIEnv, _: JObject, j_handle: jlong) -> jobject {
+    throw_on_error(&env, null(), || {
+        let address = wallet(j_handle)?.deposit_addr()?;
+        j_address(&env, &address)
+    })
+}
+
+// TxSize org.bdk.jni.BdkLib.estimateTxSize(String[] inputs, String[] outputs)
+// inputs p2pkh, p2sh-p2wpkh, p2wpkh or p2wsh:<script length>[:branch], outputs p2pkh, p2sh, p2wpkh, p2wsh or data:<length>
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_estimateTxSize(env: JNIEnv, _: JObject,
+                                                                j_inputs: jobjectArray,
+                                                                j_outputs: jobjectArray) -> jobject {
+    throw_on_error(&env, null(), || {
+        let inputs = strings_from_jarray(&env, j_inputs)?.iter()
+            .map(|i| InputType::from_str(i.as_str())).collect::<Result<Vec<_>, _>>()?;
+        let outputs = strings_from_jarray(&env, j_outputs)?.iter()
+            .map(|o| OutputType::from_str(o.as_str())).collect::<Result<Vec<_>, _>>()?;
+        if inputs.is_empty() || outputs.is_empty() {
+            return Err(Error::InvalidArgument("a transaction has at least one input and one output"));
+        }
+        let size = estimate_tx_size(inputs.as_slice(), outputs.as_slice());
+
+        // new org.bdk.jni.TxSize(int weight, int vsize)
+        let weight = jint::try_from(size.weight).map_err(|_| Error::InvalidArgument("weight does not fit a java int"))?;
+        let vsize = jint::try_from(size.vsize).map_err(|_| Error::InvalidArgument("vsize does not fit a java int"))?;
+        Ok(env.new_object("org/bdk/jni/TxSize", "(II)V", &[JValue::Int(weight), JValue::Int(vsize)])?.into_inner())
+    })
+}
+
+// new AddressValidation(Address address, boolean networkMatches)
+// Optional<AddressValidation> org.bdk.jni.BdkLib.validateAddress(String address, int network)
+// empty if the address is malformed
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_depositAddress(env: JNIEnv, _: JObject) -> jobject {
-    let address = deposit_addr();
-    j_address(&env, &address)
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_validateAddress(env: JNIEnv, _: JObject,
+                                                                 j_address: JString,
+                                                                 j_network: jint) -> jobject {
+    throw_on_error(&env, null(), || {
+        let network = network_from_jint(j_network)?;
+        match validate_address(string_from_jstring(&env, j_address)?.as_str(), network) {
+            Some(validation) => j_optional_address_validation(&env, &validation),
+            None => j_optional_empty(&env)
+        }
+    })
 }
 
 // new WithdrawTx(String txid, long fee)
-// WithdrawTx org.bdk.jni.BdkLib.withdraw(String passphrase, String address, long feePerVbyte, long amount)
+// WithdrawTx org.bdk.jni.BdkLib.withdraw(long handle, String passphrase, String address, long feePerVbyte, long amount)
 #[no_mangle]
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_withdraw(env: JNIEnv, _: JObject,
+                                                          j_handle: jlong,
                                                           j_passphrase: JString,
                                                           j_address: JString,
                                                           j_fee_per_vbyte: jlong,
                                                           j_amount: jlong) -> jobject {
+    throw_on_error(&env, null(), || {
+        let passphrase = Secret::from(string_from_jstring(&env, j_passphrase)?);
+        let address = parse_address(string_from_jstring(&env, j_address)?.as_str())?;
+        let fee_per_vbyte = u64_from_jlong(j_fee_per_vbyte, "fee per vbyte must not be negative")?;
+        let amount = u64_from_jlong(j_amount, "amount must not be negative")?;
+
+        let withdraw_tx = wallet(j_handle)?.withdraw(passphrase, address, fee_per_vbyte, Some(amount))?;
+        j_withdraw_tx(&env, &withdraw_tx)
+    })
+}
 
-    let passphrase = string_from_jstring(&env, j_passphrase);
-    let address = string_from_jstring(&env, j_address);
-    let address = Address::from_str(address.as_str()).unwrap();
 
-    let fee_per_vbyte = u64::try_from(j_fee_per_vbyte).unwrap();
-    let amount = u64::try_from(j_amount).unwrap();
+// void org.bdk.jni.BdkLib.startAsync(long handle, boolean rescan, Callback callback)
+// returns at once, onSuccess(null) is called after stop(handle)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_startAsync(env: JNIEnv, _: JObject,
+                                                            j_handle: jlong,
+                                                            j_rescan: jboolean,
+                                                            j_callback: JObject) {
+    throw_on_error(&env, (), || {
+        let wallet = wallet(j_handle)?;
+        let rescan = j_rescan == 1;
+
+        complete_async(&env, "bdk start", j_callback, move |_| {
+            wallet.start(rescan)?;
+            Ok(null())
+        })
+    })
+}
 
-    let withdraw_tx = withdraw(passphrase, address, fee_per_vbyte, Some(amount)).unwrap();
-    j_withdraw_tx(&env, &withdraw_tx)
+// void org.bdk.jni.BdkLib.withdrawAsync(long handle, String passphrase, String address, long feePerVbyte, long amount, Callback callback)
+// onSuccess is called with the WithdrawTx
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_withdrawAsync(env: JNIEnv, _: JObject,
+                                                               j_handle: jlong,
+                                                               j_passphrase: JString,
+                                                               j_address: JString,
+                                                               j_fee_per_vbyte: jlong,
+                                                               j_amount: jlong,
+                                                               j_callback: JObject) {
+    throw_on_error(&env, (), || {
+        let wallet = wallet(j_handle)?;
+        let passphrase = Secret::from(string_from_jstring(&env, j_passphrase)?);
+        let address = parse_address(string_from_jstring(&env, j_address)?.as_str())?;
+        let fee_per_vbyte = u64_from_jlong(j_fee_per_vbyte, "fee per vbyte must not be negative")?;
+        let amount = u64_from_jlong(j_amount, "amount must not be negative")?;
+
+        complete_async(&env, "bdk withdraw", j_callback, move |env| {
+            let withdraw_tx = wallet.withdraw(passphrase, address, fee_per_vbyte, Some(amount))?;
+            j_withdraw_tx(env, &withdraw_tx)
+        })
+    })
 }
 
 
 // private functions
 
-fn string_from_jstring(env: &JNIEnv, j_string: JString) -> String {
-    let java_str = env.get_string(j_string).expect("error get_string j_string");
-    let str = java_str.to_str().expect("error java_str.to_str");
-    String::from(str)
+// the handle of the wallet of work_dir and network, a new one if it is not open
+fn open_handle(work_dir: PathBuf, network: Network) -> Result<jlong, Error> {
+    if let Some(handle) = find_handle(&work_dir, network)? {
+        return Ok(handle);
+    }
+    add_handle(Arc::new(WalletHandle::new(work_dir, network)))
+}
+
+// the handle of the wallet of work_dir and network, if it is open
+fn find_handle(work_dir: &Path, network: Network) -> Result<Option<jlong>, Error> {
+    let wallets = WALLETS.lock().map_err(|_| Error::Lock("wallets"))?;
+    Ok(wallets.handles.iter()
+        .find(|(_, wallet)| wallet.work_dir() == work_dir && wallet.network() == network)
+        .map(|(handle, _)| *handle))
+}
+
+// register wallet, unless a wallet of its work_dir and network was opened meanwhile
+fn add_handle(wallet: Arc<WalletHandle>) -> Result<jlong, Error> {
+    let mut wallets = WALLETS.lock().map_err(|_| Error::Lock("wallets"))?;
+    if let Some((handle, _)) = wallets.handles.iter()
+        .find(|(_, open)| open.work_dir() == wallet.work_dir() && open.network() == wallet.network()) {
+        return Ok(*handle);
+    }
+    let handle = wallets.next;
+    wallets.next += 1;
+    wallets.handles.insert(handle, wallet);
+    Ok(handle)
+}
+
+fn wallet(handle: jlong) -> Result<Arc<WalletHandle>, Error> {
+    let wallets = WALLETS.lock().map_err(|_| Error::Lock("wallets"))?;
+    wallets.handles.get(&handle).cloned().ok_or(Error::InvalidArgument("unknown wallet handle"))
+}
+
+// a org.bdk.jni.KeyStorage of the java side
+struct JavaKeyStorage {
+    vm: JavaVM,
+    storage: GlobalRef,
+}
+
+impl JavaKeyStorage {
+    fn new(env: &JNIEnv, j_storage: JObject) -> Result<JavaKeyStorage, Error> {
+        Ok(JavaKeyStorage { vm: env.get_java_vm()?, storage: env.new_global_ref(j_storage)? })
+    }
+
+    // call byte[] method(byte[] bytes), an exception of the storage is returned as error
+    fn call(&self, method: &str, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        // a no-op on java threads, threads of the library stay attached
+        let env = self.vm.attach_current_thread_as_daemon()?;
+        let result = env.byte_array_from_slice(bytes)
+            .and_then(|input| env.call_method(self.storage.as_obj(), method, "([B)[B", &[JValue::Object(JObject::from(input))]))
+            .and_then(|output| output.l())
+            .and_then(|output| env.convert_byte_array(output.into_inner()));
+        if result.is_err() && env.exception_check().unwrap_or(false) {
+            let _ = env.exception_clear();
+        }
+        Ok(result?)
+    }
+}
+
+impl KeyStorage for JavaKeyStorage {
+    fn seal(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        self.call("seal", encrypted)
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        self.call("unseal", sealed)
+    }
+}
+
+// run a call, throw a BdkException and return the default if it fails or panics
+fn throw_on_error<T, F>(env: &JNIEnv, default: T, f: F) -> T
+    where F: FnOnce() -> Result<T, Error> {
+    match catch_panic(f) {
+        Ok(value) => value,
+        Err(err) => {
+            throw_error(env, &err);
+            default
+        }
+    }
+}
+
+// run a call, a panic is returned as error
+fn catch_panic<T, F>(f: F) -> Result<T, Error>
+    where F: FnOnce() -> Result<T, Error> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = if let Some(s) = panic.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = panic.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            Err(Error::FFI(format!("panic: {}", message)))
+        }
+    }
+}
+
+// throw new org.bdk.jni.BdkException(int code, String message)
+fn throw_error(env: &JNIEnv, err: &Error) {
+    let info = err.info();
+    let exception = j_exception(env, err)
+        .and_then(|exception| env.throw(JThrowable::from(exception)));
+    if exception.is_err() {
+        error!("can not throw BdkException {}", info.message);
+        if env.throw_new("java/lang/RuntimeException", info.message).is_err() {
+            error!("can not throw RuntimeException");
+        }
+    }
+}
+
+// new org.bdk.jni.BdkException(int code, String message)
+fn j_exception<'a>(env: &JNIEnv<'a>, err: &Error) -> jni::errors::Result<JObject<'a>> {
+    let info = err.info();
+    let message = env.new_string(info.message.as_str())?;
+    env.new_object(
+        "org/bdk/jni/BdkException",
+        "(ILjava/lang/String;)V",
+        &[JValue::Int(info.code as jint), JValue::Object(message.into())])
+}
+
+// run a call on a new thread and complete the org.bdk.jni.Callback with its result,
+// callback.onSuccess(Object result) or callback.onError(BdkException error)
+fn complete_async<F>(env: &JNIEnv, name: &str, j_callback: JObject, f: F) -> Result<(), Error>
+    where F: FnOnce(&JNIEnv) -> Result<jobject, Error> + Send + 'static {
+    if j_callback.is_null() {
+        return Err(Error::InvalidArgument("callback must not be null"));
+    }
+    let vm = env.get_java_vm()?;
+    let callback = env.new_global_ref(j_callback)?;
+    thread::Builder::new().name(name.to_string()).spawn(move || {
+        let env = match vm.attach_current_thread() {
+            Ok(env) => env,
+            Err(e) => {
+                error!("can not attach callback thread {:?}", e);
+                return;
+            }
+        };
+        let completed = match catch_panic(|| f(&*env)) {
+            Ok(result) => env.call_method(callback.as_obj(), "onSuccess", "(Ljava/lang/Object;)V",
+                                          &[JValue::Object(JObject::from(result))]),
+            Err(err) => j_exception(&env, &err).and_then(|exception|
+                env.call_method(callback.as_obj(), "onError", "(Lorg/bdk/jni/BdkException;)V",
+                                &[JValue::Object(exception)]))
+        };
+        if completed.is_err() {
+            error!("can not complete callback");
+            // an exception thrown by the callback has nobody to catch it
+            if env.exception_check().unwrap_or(false) {
+                let _ = env.exception_clear();
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn null() -> jobject {
+    JObject::null().into_inner()
+}
+
+fn string_from_jstring(env: &JNIEnv, j_string: JString) -> Result<String, Error> {
+    if j_string.is_null() {
+        return Err(Error::InvalidArgument("string must not be null"));
+    }
+    let java_str = env.get_string(j_string)?;
+    let str = java_str.to_str().map_err(|_| Error::InvalidArgument("string is not valid UTF-8"))?;
+    Ok(String::from(str))
+}
+
+fn optional_string_from_jstring(env: &JNIEnv, j_string: JString) -> Result<Option<String>, Error> {
+    if j_string.is_null() {
+        return Ok(None);
+    }
+    string_from_jstring(env, j_string).map(Some)
 }
 
-fn j_optional_empty(env: &JNIEnv) -> jobject {
+fn strings_from_jarray(env: &JNIEnv, j_array: jobjectArray) -> Result<Vec<String>, Error> {
+    if j_array.is_null() {
+        return Err(Error::InvalidArgument("array must not be null"));
+    }
+    let length = env.get_array_length(j_array)?;
+    let mut strings = Vec::new();
+    for i in 0..length {
+        let element = env.get_object_array_element(j_array, i)?;
+        strings.push(string_from_jstring(env, JString::from(element))?);
+    }
+    Ok(strings)
+}
+
+fn parse_peers(peers: &Vec<String>) -> Result<Vec<SocketAddr>, Error> {
+    peers.iter()
+        .map(|p| parse_peer(p.as_str()))
+        .collect()
+}
+
+fn parse_peer(peer: &str) -> Result<SocketAddr, Error> {
+    SocketAddr::from_str(peer).map_err(|_| Error::InvalidArgument("malformed peer address"))
+}
+
+fn parse_address(address: &str) -> Result<Address, Error> {
+    Address::from_str(address).map_err(|_| Error::InvalidArgument("malformed bitcoin address"))
+}
+
+fn u64_from_jlong(value: jlong, message: &'static str) -> Result<u64, Error> {
+    u64::try_from(value).map_err(|_| Error::InvalidArgument(message))
+}
+
+fn jlong_from_u64(value: u64) -> Result<jlong, Error> {
+    jlong::try_from(value).map_err(|_| Error::InvalidArgument("value does not fit a java long"))
+}
+
+fn j_optional_empty(env: &JNIEnv) -> Result<jobject, Error> {
     // Optional.empty())
     let j_result = env.call_static_method(
         "java/util/Optional",
         "empty",
         "()Ljava/util/Optional;",
-        &[]).expect("error Optional.empty()")
-        .l().expect("error converting Optional.empty() jvalue to jobject");
+        &[])?.l()?;
 
-    j_result.into_inner()
+    Ok(j_result.into_inner())
 }
 
-fn network_from_jint(network_enum_ordinal: jint) -> Network {
+fn j_optional_of(env: &JNIEnv, object: JObject) -> Result<jobject, Error> {
+    // Optional.of(Object)
+    let j_result = env.call_static_method(
+        "java/util/Optional",
+        "of",
+        "(Ljava/lang/Object;)Ljava/util/Optional;",
+        &[JValue::Object(object)])?.l()?;
+
+    Ok(j_result.into_inner())
+}
+
+fn network_from_jint(network_enum_ordinal: jint) -> Result<Network, Error> {
     match network_enum_ordinal {
-        0 => Some(Network::Bitcoin),
-        1 => Some(Network::Testnet),
-        2 => Some(Network::Regtest),
-        _ => None
-    }.expect("invalid network enum ordinal")
+        0 => Ok(Network::Bitcoin),
+        1 => Ok(Network::Testnet),
+        2 => Ok(Network::Regtest),
+        _ => Err(Error::InvalidArgument("invalid network enum ordinal"))
+    }
+}
+
+fn level_from_jint(level_ordinal: jint) -> Result<LevelFilter, Error> {
+    match level_ordinal {
+        0 => Ok(LevelFilter::Off),
+        1 => Ok(LevelFilter::Error),
+        2 => Ok(LevelFilter::Warn),
+        3 => Ok(LevelFilter::Info),
+        4 => Ok(LevelFilter::Debug),
+        5 => Ok(LevelFilter::Trace),
+        _ => Err(Error::InvalidArgument("invalid log level ordinal"))
+    }
 }
 
 fn jint_from_network(network: Network) -> jint {
@@ -262,75 +761,46 @@ fn jint_from_network(network: Network) -> jint {
 }
 
 // InitResult(String mnemonicWords, Address depositAddress)
-fn j_optional_init_result(env: &JNIEnv, init_result: InitResult) -> jobject {
-    let mnemonic_words = env.new_string(init_result.mnemonic_words)
-        .expect("error new_string mnemonic_words");
-    let deposit_address: jobject = j_address(&env, &init_result.deposit_address);
+fn j_optional_init_result(env: &JNIEnv, handle: jlong, init_result: InitResult) -> Result<jobject, Error> {
+    let mnemonic_words = env.new_string(init_result.mnemonic_words)?;
+    let deposit_address: jobject = j_address(&env, &init_result.deposit_address)?;
 
     // org.bdk.jni.InitResult
-    // Optional.of(InitResult(String mnemonicWords, String depositAddress))
+    // Optional.of(InitResult(long handle, String mnemonicWords, String depositAddress))
     let j_result = env.new_object(
         "org/bdk/jni/InitResult",
-        "(Ljava/lang/String;Lorg/bdk/jni/Address;)V",
-        &[JValue::Object(mnemonic_words.into()), JValue::Object(deposit_address.into())],
-    ).expect("error new_object InitResult");
+        "(JLjava/lang/String;Lorg/bdk/jni/Address;)V",
+        &[JValue::Long(handle), JValue::Object(mnemonic_words.into()), JValue::Object(deposit_address.into())],
+    )?;
 
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_result)]).expect("error Optional.of(InitResult)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
-
-    j_result.into_inner()
+    j_optional_of(env, j_result)
 }
 
 // new BalanceAmt(long,long)
-fn j_optional_balance_amt_result(env: &JNIEnv, balance_amt: BalanceAmt) -> jobject {
-    let bal = JValue::Long(jlong::try_from(balance_amt.balance).unwrap());
-    let conf = JValue::Long(jlong::try_from(balance_amt.confirmed).unwrap());
+fn j_optional_balance_amt_result(env: &JNIEnv, balance_amt: BalanceAmt) -> Result<jobject, Error> {
+    let bal = JValue::Long(jlong_from_u64(balance_amt.balance)?);
+    let conf = JValue::Long(jlong_from_u64(balance_amt.confirmed)?);
     let j_result = env.new_object(
         "org/bdk/jni/BalanceAmt",
         "(JJ)V",
         &[bal, conf],
-    ).expect("error new_object BalanceAmt");
+    )?;
 
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_result)]).expect("error Optional.of(BalanceAmt)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
-
-    j_result.into_inner()
+    j_optional_of(env, j_result)
 }
 
 // Config(int networkEnumOrdinal, String[] bitcoinPeers, int bitcoinConnections, boolean bitcoinDiscovery)
-fn j_optional_config(env: &JNIEnv, config: &Config) -> jobject {
+fn j_optional_config(env: &JNIEnv, config: &Config) -> Result<jobject, Error> {
     let j_network_enum_ordinal: JValue = jint_from_network(config.network).into();
 
-    // return peer addresses as JString vector
-    let j_bitcoin_peer_vec: Vec<JString> = config.bitcoin_peers.iter()
-        .map(|s| s.to_string())
-        .map(|a| env.new_string(a).expect("error env.new_string(a)"))
-        .collect();
-
-    let j_bitcoin_peer_arr: jobjectArray = env.new_object_array(i32::try_from(j_bitcoin_peer_vec.len()).unwrap(),
-                                                                env.find_class("java/lang/String").expect("error env.find_class(String)"),
-                                                                env.new_string("").expect("error env.new_string()").into())
-        .expect("error env.new_object_array()");
-
-
-    for i in 0..(j_bitcoin_peer_vec.len()) {
-        env.set_object_array_element(j_bitcoin_peer_arr, i32::try_from(i).unwrap(),
-                                     j_bitcoin_peer_vec[i].into()).expect("error set_object_array_element");
-    }
+    // return peer addresses as String array
+    let peers = config.bitcoin_peers.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let j_bitcoin_peer_arr = j_string_array(env, &peers)?;
 
     let j_bitcoin_connections: JValue = jint::try_from(config.bitcoin_connections)
-        .expect("error converting bitcoin_connections to jint").into();
+        .map_err(|_| Error::InvalidArgument("bitcoin connections do not fit a java int"))?.into();
 
-    let j_bitcoin_discover: JValue = jboolean::try_from(config.bitcoin_discovery)
-        .expect("error converting bitcoin_discovery to jboolean").into();
+    let j_bitcoin_discover: JValue = (config.bitcoin_discovery as jboolean).into();
 
     // org.bdk.jni.Config
     // Optional.of(Config())
@@ -339,44 +809,36 @@ fn j_optional_config(env: &JNIEnv, config: &Config) -> jobject {
         "(I[Ljava/lang/String;IZ)V",
         &[j_network_enum_ordinal, JValue::Object(j_bitcoin_peer_arr.into()),
             j_bitcoin_connections, j_bitcoin_discover],
-    ).expect("error new_object Config");
+    )?;
 
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_result)]).expect("error Optional.of(InitResult)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
+    j_optional_of(env, j_result)
+}
 
-    j_result.into_inner()
+fn j_string_array(env: &JNIEnv, strings: &Vec<String>) -> Result<jobjectArray, Error> {
+    let length = jint::try_from(strings.len()).map_err(|_| Error::InvalidArgument("too many strings for a java array"))?;
+    let j_array = env.new_object_array(length, env.find_class("java/lang/String")?, JObject::null())?;
+    for (i, s) in strings.iter().enumerate() {
+        env.set_object_array_element(j_array, i as jint, env.new_string(s)?.into())?;
+    }
+    Ok(j_array)
 }
 
-fn j_optional_string(env: &JNIEnv, string: &String) -> jobject {
-    let j_string = env.new_string(string).unwrap();
+fn j_optional_string(env: &JNIEnv, string: &String) -> Result<jobject, Error> {
+    let j_string = env.new_string(string)?;
 
     // java.lang.String
     // Optional.of(String)
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_string.into())]).expect("error Optional.of(String)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
-
-    j_result.into_inner()
+    j_optional_of(env, j_string.into())
 }
 
 // org.bdk.jni.Address(String address, int networkEnumOrdinal, Optional<String> type)
-fn j_address(env: &JNIEnv, address: &Address) -> jobject {
-    let addr = address.to_string();
-    let addr = env.new_string(addr).unwrap();
+fn j_address(env: &JNIEnv, address: &Address) -> Result<jobject, Error> {
+    let addr = env.new_string(address.to_string())?;
     let addr = JValue::Object(addr.into());
-    let addr_network = jint_from_network(address.network);
-    let addr_network = JValue::Int(addr_network);
-    let addr_type = address.address_type().map(|t| t.to_string());
-    let addr_type: jobject = match addr_type {
-        Some(at) => j_optional_string(&env, &at),
-        None => j_optional_empty(&env)
+    let addr_network = JValue::Int(jint_from_network(address.network));
+    let addr_type: jobject = match address.address_type().map(|t| t.to_string()) {
+        Some(at) => j_optional_string(&env, &at)?,
+        None => j_optional_empty(&env)?
     };
     let addr_type = JValue::Object(addr_type.into());
 
@@ -384,22 +846,197 @@ fn j_address(env: &JNIEnv, address: &Address) -> jobject {
         "org/bdk/jni/Address",
         "(Ljava/lang/String;ILjava/util/Optional;)V",
         &[addr, addr_network, addr_type],
-    ).expect("error new_object Address");
+    )?;
+
+    Ok(j_result.into_inner())
+}
 
-    j_result.into_inner()
+// Optional.of(org.bdk.jni.AddressValidation(Address address, boolean networkMatches))
+fn j_optional_address_validation(env: &JNIEnv, validation: &AddressValidation) -> Result<jobject, Error> {
+    let address = j_address(env, &validation.address)?;
+    let j_validation = env.new_object(
+        "org/bdk/jni/AddressValidation",
+        "(Lorg/bdk/jni/Address;Z)V",
+        &[JValue::Object(address.into()), JValue::Bool(validation.network_matches as jboolean)],
+    )?;
+    j_optional_of(env, j_validation)
 }
 
 // org.bdk.jni.WithdrawTx(String txid, long fee)
-fn j_withdraw_tx(env: &JNIEnv, withdraw_tx: &WithdrawTx) -> jobject {
-    let txid = withdraw_tx.txid.to_string();
-    let txid = env.new_string(txid).unwrap();
-    let fee = i64::try_from(withdraw_tx.fee).unwrap();
+fn j_withdraw_tx(env: &JNIEnv, withdraw_tx: &WithdrawTx) -> Result<jobject, Error> {
+    let txid = env.new_string(withdraw_tx.txid.to_string())?;
+    let fee = jlong_from_u64(withdraw_tx.fee)?;
 
     let j_result = env.new_object(
         "org/bdk/jni/WithdrawTx",
         "(Ljava/lang/String;J)V",
         &[JValue::Object(txid.into()), JValue::Long(fee)],
-    ).expect("error new_object WithdrawTx");
+    )?;
 
-    j_result.into_inner()
+    Ok(j_result.into_inner())
+}
+
+fn j_sync_status(env: &JNIEnv, sync_status: &SyncStatus) -> Result<jobject, Error> {
+    let header_height = jint::try_from(sync_status.header_height)
+        .map_err(|_| Error::InvalidArgument("header height does not fit a java int"))?;
+    let block_height = match sync_status.block_height {
+        Some(height) => jint::try_from(height).map_err(|_| Error::InvalidArgument("block height does not fit a java int"))?,
+        None => -1
+    };
+    let peer_count = jint::try_from(sync_status.peer_count)
+        .map_err(|_| Error::InvalidArgument("peer count does not fit a java int"))?;
+
+    // org.bdk.jni.SyncStatus
+    let j_sync_status = env.new_object(
+        "org/bdk/jni/SyncStatus",
+        "(IIIZ)V",
+        &[JValue::Int(header_height), JValue::Int(block_height), JValue::Int(peer_count), JValue::Bool(sync_status.synced as jboolean)],
+    )?;
+    Ok(j_sync_status.into_inner())
+}
+
+// new SyncSummary(int blocks, int headerHeight, int processedHeight, boolean synced, long elapsedMillis)
+// processedHeight is -1 before the first block is processed
+fn j_sync_summary(env: &JNIEnv, summary: &SyncSummary) -> Result<jobject, Error> {
+    let blocks = jint::try_from(summary.blocks).map_err(|_| Error::InvalidArgument("blocks do not fit a java int"))?;
+    let header_height = jint::try_from(summary.header_height)
+        .map_err(|_| Error::InvalidArgument("header height does not fit a java int"))?;
+    let processed_height = match summary.processed_height {
+        Some(height) => jint::try_from(height).map_err(|_| Error::InvalidArgument("processed height does not fit a java int"))?,
+        None => -1
+    };
+    let elapsed = jlong::try_from(summary.elapsed.as_millis()).unwrap_or(jlong::max_value());
+
+    // org.bdk.jni.SyncSummary
+    let j_summary = env.new_object(
+        "org/bdk/jni/SyncSummary",
+        "(IIIZJ)V",
+        &[JValue::Int(blocks), JValue::Int(header_height), JValue::Int(processed_height), JValue::Bool(summary.synced as jboolean), JValue::Long(elapsed)],
+    )?;
+    Ok(j_summary.into_inner())
+}
+
+// Optional.of(org.bdk.jni.ChainHeader(int height, String hash, long time))
+fn j_optional_chain_header(env: &JNIEnv, header: &ChainHeader) -> Result<jobject, Error> {
+    let height = jint::try_from(header.height).map_err(|_| Error::InvalidArgument("height does not fit a java int"))?;
+    let hash = env.new_string(header.hash.to_string())?;
+    let j_header = env.new_object(
+        "org/bdk/jni/ChainHeader",
+        "(ILjava/lang/String;J)V",
+        &[JValue::Int(height), JValue::Object(hash.into()), JValue::Long(header.time as jlong)],
+    )?;
+    j_optional_of(env, j_header)
+}
+
+fn j_peer_info_array(env: &JNIEnv, peers: &Vec<PeerInfo>) -> Result<jobjectArray, Error> {
+    let length = jint::try_from(peers.len()).map_err(|_| Error::InvalidArgument("too many peers for a java array"))?;
+    let j_array = env.new_object_array(length, env.find_class("org/bdk/jni/PeerInfo")?, JObject::null())?;
+    for (i, peer) in peers.iter().enumerate() {
+        let address = env.new_string(peer.address.to_string())?;
+        let user_agent = env.new_string(peer.user_agent.as_str())?;
+        let start_height = jint::try_from(peer.start_height)
+            .map_err(|_| Error::InvalidArgument("start height does not fit a java int"))?;
+        let ping = match peer.ping {
+            Some(ping) => jlong_from_u64(ping.as_millis() as u64)?,
+            None => -1
+        };
+        let j_peer = env.new_object(
+            "org/bdk/jni/PeerInfo",
+            "(Ljava/lang/String;Ljava/lang/String;IJJJ)V",
+            &[JValue::Object(address.into()), JValue::Object(user_agent.into()), JValue::Int(start_height), JValue::Long(ping),
+                JValue::Long(jlong_from_u64(peer.usage.received)?), JValue::Long(jlong_from_u64(peer.usage.sent)?)],
+        )?;
+        env.set_object_array_element(j_array, i as jint, j_peer)?;
+    }
+    Ok(j_array)
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use std::ptr::null_mut;
+
+    use bitcoin::Network;
+    use jni::{InitArgsBuilder, JavaVM, JNIEnv};
+    use jni::objects::{JObject, JString};
+    use jni::sys::{jint, jobject};
+    use once_cell::sync::Lazy;
+
+    use crate::error::ErrorCode;
+
+    use super::{Java_org_bdk_jni_BdkLib_depositAddress, Java_org_bdk_jni_BdkLib_estimateTxSize, Java_org_bdk_jni_BdkLib_validateAddress,
+                network_from_jint, parse_address, parse_peers, u64_from_jlong};
+
+    // a JVM with the BdkException of tests/java on its class path
+    static JVM: Lazy<JavaVM> = Lazy::new(|| {
+        let classes = std::env::temp_dir().join("bdk-jni-test-classes");
+        let source = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/java/org/bdk/jni/BdkException.java");
+        let status = Command::new("javac").arg("-d").arg(&classes).arg(source).status().expect("can not run javac");
+        assert!(status.success());
+        let args = InitArgsBuilder::new()
+            .option(format!("-Djava.class.path={}", classes.display()).as_str())
+            .build().unwrap();
+        JavaVM::new(args).unwrap()
+    });
+
+    // the code of the BdkException thrown by a call returning null
+    fn thrown_code<F>(call: F) -> jint
+        where F: FnOnce(JNIEnv) -> jobject {
+        let env = JVM.attach_current_thread_as_daemon().unwrap();
+        assert!(call(JVM.get_env().unwrap()).is_null());
+        let exception = env.exception_occurred().unwrap();
+        env.exception_clear().unwrap();
+        assert!(env.is_instance_of(exception, "org/bdk/jni/BdkException").unwrap());
+        env.call_method(exception, "getCode", "()I", &[]).unwrap().i().unwrap()
+    }
+
+    #[test]
+    fn null_string() {
+        let code = thrown_code(|env| unsafe {
+            Java_org_bdk_jni_BdkLib_validateAddress(env, JObject::null(), JString::from(JObject::null()), 1)
+        });
+        assert_eq!(code, ErrorCode::InvalidArgument as jint);
+    }
+
+    #[test]
+    fn null_or_empty_array() {
+        let code = thrown_code(|env| unsafe {
+            Java_org_bdk_jni_BdkLib_estimateTxSize(env, JObject::null(), null_mut(), null_mut())
+        });
+        assert_eq!(code, ErrorCode::InvalidArgument as jint);
+        let code = thrown_code(|env| unsafe {
+            let empty = env.new_object_array(0, "java/lang/String", JObject::null()).unwrap();
+            Java_org_bdk_jni_BdkLib_estimateTxSize(env, JObject::null(), empty, empty)
+        });
+        assert_eq!(code, ErrorCode::InvalidArgument as jint);
+    }
+
+    #[test]
+    fn bad_handle() {
+        let code = thrown_code(|env| unsafe { Java_org_bdk_jni_BdkLib_depositAddress(env, JObject::null(), 0) });
+        assert_eq!(code, ErrorCode::InvalidArgument as jint);
+    }
+
+    #[test]
+    fn invalid_network() {
+        assert_eq!(network_from_jint(1).unwrap(), Network::Testnet);
+        assert_eq!(network_from_jint(3).unwrap_err().code(), ErrorCode::InvalidArgument);
+        assert_eq!(network_from_jint(-1).unwrap_err().code(), ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn invalid_peers() {
+        assert_eq!(parse_peers(&vec!("127.0.0.1:18444".to_string())).unwrap().len(), 1);
+        assert!(parse_peers(&vec!("127.0.0.1".to_string())).is_err());
+        assert!(parse_peers(&vec!("127.0.0.1:18444".to_string(), "localhost:abc".to_string())).is_err());
+        assert!(parse_peers(&vec!("".to_string())).is_err());
+    }
+
+    #[test]
+    fn invalid_amounts_and_addresses() {
+        assert_eq!(u64_from_jlong(5, "negative").unwrap(), 5);
+        assert_eq!(u64_from_jlong(-5, "negative").unwrap_err().code(), ErrorCode::InvalidArgument);
+        assert!(parse_address("not an address").is_err());
+        assert!(parse_address("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").is_ok());
+    }
 }
@@ -0,0 +1,172 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! passphrase stretching
+//!
+//! the passphrase of a wallet is stretched with Argon2id before it encrypts the master seed.
+//! The parameters are kept next to the encrypted seed in the config, wallets without them
+//! use the passphrase as is until they are re-encrypted.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rand::{RngCore, thread_rng};
+use zeroize::Zeroize;
+
+use crate::error::Error;
+use crate::secret::Secret;
+
+const NAME: &str = "argon2id";
+/// memory cost of new wallets in KiB
+pub const MEMORY: u32 = 65536;
+/// iterations of new wallets
+pub const ITERATIONS: u32 = 3;
+const PARALLELISM: u32 = 1;
+/// lowest memory cost in KiB accepted
+pub const MIN_MEMORY: u32 = 8192;
+const SALT_LEN: usize = 16;
+const HASH_LEN: u32 = 32;
+
+/// Argon2id parameters of a wallet, written as argon2id$m=<KiB>,t=<iterations>,p=<lanes>$<salt hex>
+#[derive(Clone, Debug, PartialEq)]
+pub struct Kdf {
+    /// KiB
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: Vec<u8>,
+}
+
+impl Kdf {
+    /// parameters with a fresh salt
+    pub fn new(memory: u32, iterations: u32) -> Result<Kdf, Error> {
+        let mut salt = vec![0u8; SALT_LEN];
+        thread_rng().fill_bytes(salt.as_mut_slice());
+        let kdf = Kdf { memory, iterations, parallelism: PARALLELISM, salt };
+        kdf.check()?;
+        Ok(kdf)
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.memory < MIN_MEMORY {
+            return Err(Error::InvalidArgument("kdf memory is too low"));
+        }
+        if self.iterations == 0 || self.parallelism == 0 {
+            return Err(Error::InvalidArgument("kdf iterations and parallelism must not be zero"));
+        }
+        if self.salt.len() < 8 {
+            return Err(Error::InvalidArgument("kdf salt is too short"));
+        }
+        Ok(())
+    }
+
+    /// the passphrase handed to the seed encryption
+    pub fn stretch(&self, passphrase: &str) -> Result<Secret, Error> {
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.memory,
+            time_cost: self.iterations,
+            lanes: self.parallelism,
+            hash_length: HASH_LEN,
+            ..argon2::Config::default()
+        };
+        let mut hash = argon2::hash_raw(passphrase.as_bytes(), self.salt.as_slice(), &config)
+            .map_err(|_| Error::InvalidArgument("can not stretch passphrase"))?;
+        let stretched = Secret::from(hex::encode(&hash));
+        hash.zeroize();
+        Ok(stretched)
+    }
+}
+
+impl fmt::Display for Kdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}$m={},t={},p={}${}", NAME, self.memory, self.iterations, self.parallelism, hex::encode(&self.salt))
+    }
+}
+
+impl FromStr for Kdf {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Kdf, Error> {
+        let parts = s.split('$').collect::<Vec<_>>();
+        if parts.len() != 3 || parts[0] != NAME {
+            return Err(Error::InvalidArgument("kdf is not argon2id$m=..,t=..,p=..$salt"));
+        }
+        let (mut memory, mut iterations, mut parallelism) = (None, None, None);
+        for param in parts[1].split(',') {
+            let mut kv = param.splitn(2, '=');
+            let (key, value) = (kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+            let value = value.parse::<u32>().map_err(|_| Error::InvalidArgument("kdf parameter is not a number"))?;
+            match key {
+                "m" => memory = Some(value),
+                "t" => iterations = Some(value),
+                "p" => parallelism = Some(value),
+                _ => return Err(Error::InvalidArgument("unknown kdf parameter"))
+            }
+        }
+        let kdf = Kdf {
+            memory: memory.ok_or(Error::InvalidArgument("kdf memory is missing"))?,
+            iterations: iterations.ok_or(Error::InvalidArgument("kdf iterations are missing"))?,
+            parallelism: parallelism.ok_or(Error::InvalidArgument("kdf parallelism is missing"))?,
+            salt: hex::decode(parts[2]).map_err(|_| Error::InvalidArgument("kdf salt is not hex"))?,
+        };
+        kdf.check()?;
+        Ok(kdf)
+    }
+}
+
+/// passphrase stretched by kdf, as is if there is none
+pub fn stretch(kdf: Option<&Kdf>, passphrase: &str) -> Result<Secret, Error> {
+    match kdf {
+        Some(kdf) => kdf.stretch(passphrase),
+        None => Ok(Secret::from(passphrase))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::{Kdf, MIN_MEMORY, stretch};
+
+    #[test]
+    fn parse_kdf() {
+        let kdf = Kdf::new(MIN_MEMORY, 2).unwrap();
+        assert_eq!(kdf.salt.len(), 16);
+        assert_ne!(Kdf::new(MIN_MEMORY, 2).unwrap().salt, kdf.salt);
+        assert_eq!(Kdf::from_str(kdf.to_string().as_str()).unwrap(), kdf);
+        assert_eq!(Kdf::from_str("argon2id$m=65536,t=3,p=1$00112233445566778899aabbccddeeff").unwrap().memory, 65536);
+        assert!(Kdf::from_str("argon2i$m=65536,t=3,p=1$00112233445566778899aabbccddeeff").is_err());
+        assert!(Kdf::from_str("argon2id$m=65536,t=3$00112233445566778899aabbccddeeff").is_err());
+        assert!(Kdf::from_str("argon2id$m=1024,t=3,p=1$00112233445566778899aabbccddeeff").is_err());
+        assert!(Kdf::from_str("argon2id$m=65536,t=0,p=1$00112233445566778899aabbccddeeff").is_err());
+        assert!(Kdf::from_str("argon2id$m=65536,t=3,p=1$salt").is_err());
+        assert!(Kdf::new(1024, 3).is_err());
+    }
+
+    #[test]
+    fn stretch_passphrase() {
+        let kdf = Kdf::new(MIN_MEMORY, 1).unwrap();
+        let stretched = kdf.stretch("correct horse").unwrap();
+        assert_eq!(stretched.len(), 64);
+        assert_eq!(kdf.stretch("correct horse").unwrap(), stretched);
+        assert_ne!(kdf.stretch("wrong horse").unwrap(), stretched);
+        let other = Kdf::new(MIN_MEMORY, 1).unwrap();
+        assert_ne!(other.stretch("correct horse").unwrap(), stretched);
+        assert_eq!(stretch(None, "correct horse").unwrap().as_str(), "correct horse");
+    }
+}
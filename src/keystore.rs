@@ -0,0 +1,184 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! encrypted keystore backups of the wallet seed
+//!
+//! a JSON document with the seed encrypted by AES-256-GCM under a key derived with scrypt from a password
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, generic_array::GenericArray, NewAead};
+use bitcoin::Network;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+use rand::{RngCore, thread_rng};
+use scrypt::ScryptParams;
+use zeroize::Zeroizing;
+
+use crate::error::Error;
+use crate::secret::SecretMaster;
+
+/// format version written by export
+pub const VERSION: u32 = 1;
+const KDF: &str = "scrypt";
+const CIPHER: &str = "aes-256-gcm";
+/// scrypt cost parameters of new keystores
+const LOG_N: u8 = 15;
+const R: u32 = 8;
+const P: u32 = 1;
+/// limits of scrypt cost parameters of imported keystores, so a crafted document can not exhaust memory or time
+const MAX_LOG_N: u8 = 20;
+const MAX_R: u32 = 16;
+const MAX_P: u32 = 16;
+/// memory of scrypt is 128 * r * 2^log_n bytes
+const MAX_MEMORY: u64 = 1 << 30;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub name: String,
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    /// hex
+    pub salt: String,
+}
+
+/// a versioned encrypted seed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keystore {
+    pub version: u32,
+    pub network: Network,
+    /// the public master key, to check the decrypted seed
+    pub keyroot: String,
+    /// wallet birth, unix time
+    pub birth: u64,
+    pub kdf: KdfParams,
+    pub cipher: String,
+    /// hex
+    pub nonce: String,
+    /// hex, with the authentication tag appended
+    pub ciphertext: String,
+}
+
+/// encrypt seed with password into a keystore document
+pub fn export(seed: &[u8], network: Network, birth: u64, password: &str) -> Result<String, Error> {
+    let keyroot = keyroot(seed, network)?;
+    let mut salt = [0u8; SALT_LEN];
+    thread_rng().fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce);
+    let kdf = KdfParams { name: KDF.to_string(), log_n: LOG_N, r: R, p: P, salt: hex::encode(&salt[..]) };
+    let ciphertext = cipher(password, &kdf)?
+        .encrypt(GenericArray::from_slice(&nonce[..]), seed)
+        .map_err(|_| Error::Unsupported("can not encrypt keystore"))?;
+    let keystore = Keystore {
+        version: VERSION,
+        network,
+        keyroot: keyroot.to_string(),
+        birth,
+        kdf,
+        cipher: CIPHER.to_string(),
+        nonce: hex::encode(&nonce[..]),
+        ciphertext: hex::encode(ciphertext.as_slice()),
+    };
+    Ok(serde_json::to_string(&keystore).expect("can not serialize keystore"))
+}
+
+/// decrypt the seed of a keystore document, returns seed, network, public master key and birth
+pub fn import(document: &str, password: &str) -> Result<(Zeroizing<Vec<u8>>, Network, ExtendedPubKey, u64), Error> {
+    let keystore = serde_json::from_str::<Keystore>(document).map_err(|_| Error::InvalidArgument("not a keystore"))?;
+    if keystore.version != VERSION {
+        return Err(Error::Unsupported("unknown keystore version"));
+    }
+    if keystore.kdf.name != KDF || keystore.cipher != CIPHER {
+        return Err(Error::Unsupported("unknown keystore encryption"));
+    }
+    let nonce = hex::decode(&keystore.nonce).map_err(|_| Error::InvalidArgument("keystore nonce is not hex"))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(Error::InvalidArgument("keystore nonce has wrong length"));
+    }
+    let ciphertext = hex::decode(&keystore.ciphertext).map_err(|_| Error::InvalidArgument("keystore ciphertext is not hex"))?;
+    let seed = Zeroizing::new(cipher(password, &keystore.kdf)?
+        .decrypt(GenericArray::from_slice(nonce.as_slice()), ciphertext.as_slice())
+        .map_err(|_| Error::WrongPassphrase)?);
+    let keyroot = keyroot(seed.as_slice(), keystore.network)?;
+    if keyroot.to_string() != keystore.keyroot {
+        return Err(Error::InvalidArgument("keystore seed does not match its public master key"));
+    }
+    Ok((seed, keystore.network, keyroot, keystore.birth))
+}
+
+fn cipher(password: &str, kdf: &KdfParams) -> Result<Aes256Gcm, Error> {
+    let salt = hex::decode(&kdf.salt).map_err(|_| Error::InvalidArgument("keystore salt is not hex"))?;
+    if kdf.log_n > MAX_LOG_N || kdf.r > MAX_R || kdf.p > MAX_P || (128 * kdf.r as u64) << kdf.log_n > MAX_MEMORY {
+        return Err(Error::InvalidArgument("keystore scrypt parameters exceed limits"));
+    }
+    let params = ScryptParams::new(kdf.log_n, kdf.r, kdf.p).map_err(|_| Error::InvalidArgument("invalid keystore scrypt parameters"))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(password.as_bytes(), salt.as_slice(), &params, &mut key[..]).expect("32 bytes is a valid scrypt output length");
+    Ok(Aes256Gcm::new(*GenericArray::from_slice(&key[..])))
+}
+
+fn keyroot(seed: &[u8], network: Network) -> Result<ExtendedPubKey, Error> {
+    let master = SecretMaster::from(ExtendedPrivKey::new_master(network, seed).map_err(|_| Error::InvalidArgument("invalid seed"))?);
+    Ok(ExtendedPubKey::from_private(&Secp256k1::signing_only(), &master))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use crate::error::Error;
+
+    use super::{export, import, Keystore, VERSION};
+
+    #[test]
+    fn export_import() {
+        let seed = [7u8; 64];
+        let document = export(&seed[..], Network::Testnet, 1567260002, "keystore password").unwrap();
+        let keystore = serde_json::from_str::<Keystore>(document.as_str()).unwrap();
+        assert_eq!(keystore.version, VERSION);
+        assert!(!document.contains(hex::encode(&seed[..]).as_str()));
+
+        let (imported, network, _, birth) = import(document.as_str(), "keystore password").unwrap();
+        assert_eq!(*imported, seed.to_vec());
+        assert_eq!(network, Network::Testnet);
+        assert_eq!(birth, 1567260002);
+        match import(document.as_str(), "wrong password") {
+            Err(Error::WrongPassphrase) => {}
+            _ => panic!("decrypted with a wrong password")
+        }
+        let future = document.replace("\"version\":1", "\"version\":2");
+        assert!(import(future.as_str(), "keystore password").is_err());
+    }
+
+    #[test]
+    fn scrypt_limits() {
+        let document = export(&[7u8; 64], Network::Testnet, 1567260002, "keystore password").unwrap();
+        let keystore = serde_json::from_str::<Keystore>(document.as_str()).unwrap();
+        for (log_n, r, p) in &[(21, 8, 1), (15, 17, 1), (15, 8, 17), (20, 16, 1)] {
+            let mut costly = keystore.clone();
+            costly.kdf.log_n = *log_n;
+            costly.kdf.r = *r;
+            costly.kdf.p = *p;
+            match import(serde_json::to_string(&costly).unwrap().as_str(), "keystore password") {
+                Err(Error::InvalidArgument(_)) => {}
+                _ => panic!("accepted scrypt parameters beyond limits")
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! version of the wallet files
+//!
+//! the files of a work dir carry the version of their layout, so an older library refuses
+//! files it does not understand and a newer one upgrades files of earlier versions
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::db::DB;
+use crate::error::Error;
+
+/// layout written by this library
+pub const LAYOUT_VERSION: u32 = 2;
+const VERSION_FILE_NAME: &str = "bdk.version";
+const DB_FILE_NAME: &str = "bdk.db";
+
+/// mark the files at config_path as of the current layout
+pub fn write(config_path: &Path) -> Result<(), Error> {
+    let file_path = version_file(config_path);
+    let temp_path = file_path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(LAYOUT_VERSION.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, file_path)?;
+    Ok(())
+}
+
+/// layout version of the files at config_path, None if not marked
+pub fn read(config_path: &Path) -> Result<Option<u32>, Error> {
+    let mut file = match File::open(version_file(config_path)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into())
+    };
+    let mut version = String::new();
+    file.read_to_string(&mut version)?;
+    version.trim().parse::<u32>().map(Some).map_err(|_| Error::InvalidConfig("wallet version file is malformed"))
+}
+
+/// refuse files of a newer layout and upgrade those of an older one.
+/// Files written before versions were marked are of version 0
+pub fn check(config_path: &Path) -> Result<(), Error> {
+    let version = match read(config_path)? {
+        Some(version) => version,
+        None if db_file(config_path).exists() => 0,
+        // no wallet
+        None => return Ok(())
+    };
+    if version > LAYOUT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    if version < LAYOUT_VERSION {
+        upgrade(config_path, version)?;
+    }
+    Ok(())
+}
+
+fn upgrade(config_path: &Path, from: u32) -> Result<(), Error> {
+    for version in from..LAYOUT_VERSION {
+        info!("upgrading wallet files at {} from version {}", config_path.to_string_lossy(), version);
+        match version {
+            0 => {
+                // tables added to the db before versions were marked
+                let mut db = DB::new(db_file(config_path).as_path())?;
+                let mut tx = db.transaction();
+                tx.create_tables();
+                tx.commit();
+            }
+            1 => {
+                // vaults with their own cancel key or of a retired seed
+                let mut db = DB::new(db_file(config_path).as_path())?;
+                let mut tx = db.transaction();
+                tx.add_column("vault", "cancel_key", "text")?;
+                tx.add_column("vault", "retired", "number")?;
+                tx.commit();
+            }
+            _ => {}
+        }
+    }
+    write(config_path)
+}
+
+fn version_file(config_path: &Path) -> PathBuf {
+    config_path.join(VERSION_FILE_NAME)
+}
+
+fn db_file(config_path: &Path) -> PathBuf {
+    config_path.join(DB_FILE_NAME)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rusqlite::Connection;
+
+    use crate::db::DB;
+    use crate::error::Error;
+
+    use super::{check, LAYOUT_VERSION, read, write};
+
+    #[test]
+    fn versions() {
+        let config_path = PathBuf::from("./test-layout");
+        fs::create_dir_all(&config_path).unwrap();
+        // no wallet
+        check(&config_path).unwrap();
+        assert_eq!(read(&config_path).unwrap(), None);
+
+        // a wallet written before versions were marked is upgraded
+        DB::new(config_path.join("bdk.db").as_path()).unwrap();
+        check(&config_path).unwrap();
+        assert_eq!(read(&config_path).unwrap(), Some(LAYOUT_VERSION));
+
+        write(&config_path).unwrap();
+        check(&config_path).unwrap();
+
+        // vaults of version 1 had no cancel key
+        Connection::open(config_path.join("bdk.db")).unwrap().execute_batch(r#"
+            drop table vault;
+            create table vault (txid text, vout number, value number, lock_time number, delay number, ix number,
+                state number, unvault blob, unvault_block text, primary key(txid, vout)) without rowid;
+        "#).unwrap();
+        fs::write(config_path.join("bdk.version"), "1").unwrap();
+        check(&config_path).unwrap();
+        assert_eq!(read(&config_path).unwrap(), Some(LAYOUT_VERSION));
+        assert!(DB::new(config_path.join("bdk.db").as_path()).unwrap().transaction().read_vaults().unwrap().is_empty());
+
+        fs::write(config_path.join("bdk.version"), (LAYOUT_VERSION + 1).to_string()).unwrap();
+        match check(&config_path) {
+            Err(Error::UnsupportedVersion(version)) => assert_eq!(version, LAYOUT_VERSION + 1),
+            _ => panic!("files of a newer layout were accepted")
+        }
+        fs::write(config_path.join("bdk.version"), "one").unwrap();
+        assert!(check(&config_path).is_err());
+        fs::remove_dir_all(&config_path).unwrap();
+    }
+}
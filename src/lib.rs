@@ -20,16 +20,58 @@
 #[macro_use]
 extern crate serde_derive;
 
+#[cfg(feature = "node")]
 pub mod api;
+#[cfg(feature = "node")]
+pub mod bandwidth;
+pub mod bip47;
+#[cfg(feature = "node")]
 pub mod blockdownload;
+#[cfg(feature = "node")]
 pub mod config;
+#[cfg(feature = "node")]
+pub mod consolidate;
+pub mod custody;
+#[cfg(feature = "node")]
 pub mod db;
 pub mod error;
+#[cfg(feature = "node")]
+pub mod esplora;
+pub mod history;
+pub mod imported;
+pub mod kdf;
+pub mod keystore;
+#[cfg(feature = "node")]
+pub mod layout;
+#[cfg(feature = "node")]
+pub mod logging;
+#[cfg(feature = "node")]
 pub mod p2p_bitcoin;
+#[cfg(feature = "node")]
+pub mod peers;
+#[cfg(feature = "node")]
+pub mod publish;
+#[cfg(feature = "node")]
+pub mod rates;
+#[cfg(feature = "node")]
+pub mod rpc;
+pub mod secret;
+#[cfg(feature = "node")]
 pub mod sendtx;
+#[cfg(all(feature = "node", any(test, feature = "testutil")))]
+pub mod simulation;
+#[cfg(feature = "node")]
 pub mod store;
+pub mod syncstate;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
 pub mod trunk;
+pub mod txsize;
+pub mod vault;
 pub mod wallet;
+pub mod watch;
+#[cfg(feature = "node")]
+pub mod webhook;
 
 #[cfg(any(feature = "java", feature = "android"))]
 pub mod jni;
\ No newline at end of file
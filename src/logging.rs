@@ -0,0 +1,212 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! logging configuration
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+use crate::error::Error;
+
+/// where log records are written, this includes the logs of the murmel p2p layer
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogTarget {
+    /// standard error
+    Console,
+    /// a file rotated once it exceeds max_size bytes, keeping max_files older files
+    File { path: PathBuf, max_size: u64, max_files: u32 },
+    /// android logcat
+    #[cfg(feature = "android")]
+    Logcat,
+}
+
+static LOGGER: Lazy<BdkLogger> = Lazy::new(|| BdkLogger { sink: Mutex::new(None) });
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// set logging level and target, can be called again to change them
+pub fn set_logging(level: LevelFilter, target: LogTarget) -> Result<(), Error> {
+    let sink = Sink::new(target)?;
+    *LOGGER.sink.lock().map_err(|_| Error::Lock("logger"))? = Some(sink);
+    if !INSTALLED.swap(true, Ordering::SeqCst) {
+        if log::set_logger(&*LOGGER).is_err() {
+            INSTALLED.store(false, Ordering::SeqCst);
+            return Err(Error::Unsupported("an other logger is already installed"));
+        }
+    }
+    log::set_max_level(level);
+    Ok(())
+}
+
+struct BdkLogger {
+    sink: Mutex<Option<Sink>>
+}
+
+impl Log for BdkLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            if let Ok(mut sink) = self.sink.lock() {
+                if let Some(ref mut sink) = *sink {
+                    sink.log(record);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            if let Some(ref mut sink) = *sink {
+                sink.flush();
+            }
+        }
+    }
+}
+
+enum Sink {
+    Console,
+    File(RotatingFile),
+    #[cfg(feature = "android")]
+    Logcat(android_logger::AndroidLogger),
+}
+
+impl Sink {
+    fn new(target: LogTarget) -> Result<Sink, Error> {
+        match target {
+            LogTarget::Console => Ok(Sink::Console),
+            LogTarget::File { path, max_size, max_files } => Ok(Sink::File(RotatingFile::new(path, max_size, max_files)?)),
+            #[cfg(feature = "android")]
+            LogTarget::Logcat => Ok(Sink::Logcat(android_logger::AndroidLogger::new(
+                android_logger::Config::default().with_min_level(log::Level::Trace).with_tag("BDK")))),
+        }
+    }
+
+    fn log(&mut self, record: &Record) {
+        match *self {
+            Sink::Console => {
+                eprintln!("{}", format_record(record));
+            }
+            Sink::File(ref mut file) => {
+                if let Err(e) = file.write_line(format_record(record).as_str()) {
+                    eprintln!("can not write log file: {}", e);
+                }
+            }
+            #[cfg(feature = "android")]
+            Sink::Logcat(ref logger) => logger.log(record),
+        }
+    }
+
+    fn flush(&mut self) {
+        match *self {
+            Sink::Console => {}
+            Sink::File(ref mut file) => { file.file.flush().ok(); }
+            #[cfg(feature = "android")]
+            Sink::Logcat(ref logger) => logger.flush(),
+        }
+    }
+}
+
+fn format_record(record: &Record) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    format!("[{}][{}][{}] {}", now, record.target(), record.level(), record.args())
+}
+
+/// a log file that is renamed to path.1, path.2 ... once it exceeds its maximum size
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    file: File,
+    size: u64
+}
+
+impl RotatingFile {
+    fn new(path: PathBuf, max_size: u64, max_files: u32) -> Result<RotatingFile, Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, max_size, max_files, file, size })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), io::Error> {
+        if self.max_size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::RotatingFile;
+
+    #[test]
+    fn rotate() {
+        let dir = PathBuf::from("./test_logging");
+        let mut path = dir.clone();
+        path.push("bdk.log");
+        let mut file = RotatingFile::new(path.clone(), 100, 2).unwrap();
+        for _ in 0..10 {
+            file.write_line("0123456789012345678901234567890123456789").unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() <= 100);
+        assert!(file.rotated(1).exists());
+        assert!(file.rotated(2).exists());
+        assert!(!file.rotated(3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
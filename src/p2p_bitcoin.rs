@@ -18,13 +18,14 @@
 use std::{
     collections::HashSet,
     net::SocketAddr,
-    sync::{Arc, atomic::AtomicUsize, mpsc, Mutex},
+    sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc, Mutex, RwLock},
     thread,
     time::SystemTime
 };
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::pin::Pin;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use bitcoin::{
     Block, BlockHeader,
@@ -40,53 +41,250 @@ use bitcoin_hashes::sha256d;
 use futures::{
     executor::ThreadPool,
     future,
+    future::Either,
     Future,
     FutureExt, Poll as Async,
     StreamExt,
     task::{Context, SpawnExt}
 };
-use futures_timer::Interval;
-use log::debug;
+use futures_timer::{Delay, Interval};
+use log::{debug, info};
+use lru_cache::LruCache;
 use murmel::{
     chaindb::SharedChainDB,
     dispatcher::Dispatcher,
     dns::dns_seed,
     downstream::Downstream,
     p2p::{
-        BitcoinP2PConfig, P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender,
+        BitcoinP2PConfig, P2PControl, P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender,
         PeerSource
     },
     p2p::P2P,
-    ping::Ping,
     timeout::Timeout
 };
 use murmel::p2p::PeerId;
 use rand::{RngCore, thread_rng};
 
+use crate::bandwidth::{Bandwidth, BandwidthMeter, BandwidthUsage, SharedBandwidth};
 use crate::blockdownload::BlockDownload;
+use crate::config::Config;
 use crate::db::SharedDB;
-use crate::sendtx::SendTx;
+use crate::error::Error;
+use crate::peers::{PeerInfo, PeerMonitor, Pings, SharedPings};
+use crate::sendtx::{BroadcastStatus, CACHE_SIZE, SendTx, SharedBroadcasts};
 use crate::store::SharedContentStore;
 use crate::trunk::Trunk;
 
 const MAX_PROTOCOL_VERSION: u32 = 70001;
 
+/// user agent announced in the version message
+const USER_AGENT: &str = "bdk 0.1.0";
+/// default timeout to connect and handshake with a peer
+pub const CONNECT_TIMEOUT: u64 = 10;
+/// default delay before reconnecting a failed peer for the first time
+pub const RECONNECT_DELAY: u64 = 5;
+/// default maximum delay before reconnecting a failed peer
+pub const MAX_RECONNECT_DELAY: u64 = 600;
+/// default maximum number of inbound connections if listening
+pub const MAX_INBOUND: usize = 8;
+
+/// peer settings of the p2p layer that can be changed while it runs
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerSettings {
+    pub connections: usize,
+    pub peers: Vec<SocketAddr>,
+    pub discovery: bool,
+    pub connect_timeout: Duration,
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    pub max_inbound: usize,
+    pub broadcast_peers: Option<usize>,
+    pub broadcast_fallback: Option<String>
+}
+
+impl PeerSettings {
+    fn from_config(config: &Config) -> PeerSettings {
+        // outbound connections are kept within the cap first, inbound ones get what is left
+        let cap = config.max_connections.unwrap_or(usize::max_value());
+        let connections = std::cmp::min(config.bitcoin_connections, cap);
+        PeerSettings {
+            connections,
+            peers: config.bitcoin_peers.clone(),
+            discovery: config.bitcoin_discovery,
+            connect_timeout: Duration::from_secs(config.bitcoin_connect_timeout.unwrap_or(CONNECT_TIMEOUT)),
+            reconnect_delay: Duration::from_secs(config.bitcoin_reconnect_delay.unwrap_or(RECONNECT_DELAY)),
+            max_reconnect_delay: Duration::from_secs(config.bitcoin_max_reconnect_delay.unwrap_or(MAX_RECONNECT_DELAY)),
+            max_inbound: std::cmp::min(config.bitcoin_max_inbound.unwrap_or(MAX_INBOUND), cap - connections),
+            broadcast_peers: config.bitcoin_broadcast_peers,
+            broadcast_fallback: config.bitcoin_broadcast_fallback.clone()
+        }
+    }
+}
+
+pub type SharedPeerSettings = Arc<RwLock<PeerSettings>>;
+
+/// peers currently connected
+pub type SharedConnectedPeers = Arc<RwLock<HashMap<PeerId, SocketAddr>>>;
+
+/// addresses of outbound connections being established or established
+type SharedDialed = Arc<Mutex<HashSet<SocketAddr>>>;
+
+type BitcoinP2P = P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>;
+
 pub struct P2PBitcoin {
-    connections: usize,
-    peers: Vec<SocketAddr>,
+    settings: SharedPeerSettings,
+    connected: SharedConnectedPeers,
+    dialed: SharedDialed,
+    backoff: SharedBackoff,
+    bandwidth: SharedBandwidth,
+    pings: SharedPings,
+    broadcasts: SharedBroadcasts,
     chain_db: SharedChainDB,
     network: Network,
     db: SharedDB,
     content_store: SharedContentStore,
-    discovery: bool,
-    birth: u64
+    birth: u64,
+    listen: Option<SocketAddr>,
+    running: Mutex<Option<Running>>,
+    /// set by shutdown, no new connections are made
+    stopping: Arc<AtomicBool>,
+    /// blocks are downloaded and scanned, otherwise only headers are followed
+    scanning: Arc<AtomicBool>,
+    max_block_memory: Option<u64>
+}
+
+/// handles to the running p2p layer
+struct Running {
+    p2p: Arc<BitcoinP2P>,
+    p2p_control: P2PControlSender<NetworkMessage>,
+    keep_connected: KeepConnected
 }
 
 impl P2PBitcoin {
-    pub fn new (network: Network, connections: usize, peers: Vec<SocketAddr>, discovery: bool, chain_db: SharedChainDB, db: SharedDB, content_store: SharedContentStore, birth: u64) -> P2PBitcoin {
-        P2PBitcoin {connections, peers, chain_db, network, db, content_store, discovery, birth}
+    pub fn new (config: &Config, chain_db: SharedChainDB, db: SharedDB, content_store: SharedContentStore) -> P2PBitcoin {
+        let settings = Arc::new(RwLock::new(PeerSettings::from_config(config)));
+        let bandwidth = Arc::new(RwLock::new(Bandwidth::new(config.network, config.bitcoin_max_download_rate)));
+        P2PBitcoin {settings, connected: Arc::new(RwLock::new(HashMap::new())), dialed: Arc::new(Mutex::new(HashSet::new())),
+            backoff: Arc::new(Mutex::new(Backoff::new())), bandwidth, pings: Arc::new(RwLock::new(Pings::new())), broadcasts: Arc::new(Mutex::new(LruCache::new(CACHE_SIZE))), chain_db, network: config.network, db, content_store,
+            birth: config.birth, listen: config.bitcoin_listen, running: Mutex::new(None), stopping: Arc::new(AtomicBool::new(false)),
+            scanning: Arc::new(AtomicBool::new(!config.headers_only.unwrap_or(false))), max_block_memory: config.max_block_memory}
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// download and scan blocks, or only follow headers. Blocks deferred are downloaded once scanning is enabled
+    pub fn set_scanning(&self, scanning: bool) {
+        info!("{} block download", if scanning { "enable" } else { "defer" });
+        self.scanning.store(scanning, Ordering::SeqCst);
+    }
+
+    pub fn is_scanning(&self) -> bool {
+        self.scanning.load(Ordering::SeqCst)
     }
+
+    /// number of connected peers
+    pub fn peer_count(&self) -> usize {
+        self.connected.read().unwrap().len()
+    }
+
+    /// connected peers with what they told at connect, latency and bytes exchanged
+    pub fn list_peers(&self) -> Vec<PeerInfo> {
+        let running = self.running.lock().unwrap();
+        let usage = self.bandwidth.read().unwrap().usage();
+        let pings = self.pings.read().unwrap();
+        self.connected.read().unwrap().iter().map(|(pid, address)| {
+            let version = running.as_ref().and_then(|r| r.p2p_control.peer_version(*pid));
+            PeerInfo {
+                address: *address,
+                user_agent: version.as_ref().map(|v| v.user_agent.clone()).unwrap_or_default(),
+                start_height: version.as_ref().map(|v| v.start_height as u32).unwrap_or(0),
+                ping: pings.latency(*pid),
+                usage: usage.peers.iter().find(|(a, _)| a == address).map(|(_, u)| *u).unwrap_or_default(),
+            }
+        }).collect()
+    }
+
+    /// bytes exchanged with peers since start
+    pub fn bandwidth_usage(&self) -> BandwidthUsage {
+        self.bandwidth.read().unwrap().usage()
+    }
+
+    /// propagation of one of our recent transactions, None if it was not broadcast since start
+    pub fn broadcast_status(&self, txid: &sha256d::Hash) -> Option<BroadcastStatus> {
+        self.broadcasts.lock().unwrap().get_mut(txid).map(|b| b.status())
+    }
+
+    /// apply changed peer settings of a config to the running p2p layer
+    /// peers no longer configured are disconnected, newly configured peers are connected
+    /// and the connection target is adjusted, a changed listen address needs a restart
+    pub fn apply_config(&self, config: &Config) {
+        let new_settings = PeerSettings::from_config(config);
+        self.bandwidth.write().unwrap().set_max_download_rate(config.bitcoin_max_download_rate);
+        let old_settings = self.settings.read().unwrap().clone();
+        if old_settings == new_settings {
+            return;
+        }
+        *self.settings.write().unwrap() = new_settings.clone();
+        info!("applied peer settings connections={} peers={:?} discovery={}", new_settings.connections, new_settings.peers, new_settings.discovery);
+
+        if let Some(ref running) = *self.running.lock().unwrap() {
+            let removed = old_settings.peers.iter().filter(|a| !new_settings.peers.contains(a)).collect::<HashSet<_>>();
+            for (pid, addr) in self.connected.read().unwrap().iter() {
+                if removed.contains(addr) {
+                    debug!("disconnect peer {} removed from config peer={}", addr, pid);
+                    running.p2p_control.send(P2PControl::Disconnect(*pid));
+                }
+            }
+            let connected = running.p2p.connected_peers();
+            let mut keep_connected = running.keep_connected.clone();
+            for addr in new_settings.peers.iter().filter(|a| !old_settings.peers.contains(a) && !connected.contains(a)) {
+                debug!("connect peer {} added to config", addr);
+                self.backoff.lock().unwrap().succeeded(addr);
+                keep_connected.connect(*addr);
+            }
+        }
+    }
+
+    /// connect a peer now, it is not added to the config and not reconnected if it disconnects
+    pub fn add_peer(&self, address: SocketAddr) -> Result<(), Error> {
+        match *self.running.lock().unwrap() {
+            Some(ref running) => {
+                if running.p2p.connected_peers().contains(&address) || self.dialed.lock().unwrap().contains(&address) {
+                    return Ok(());
+                }
+                info!("connect peer {} on request", address);
+                self.backoff.lock().unwrap().succeeded(&address);
+                running.keep_connected.clone().connect(address);
+                Ok(())
+            }
+            None => Err(Error::NotRunning)
+        }
+    }
+
+    /// disconnect a peer now, a configured peer is not reconnected until the config is applied again
+    pub fn disconnect_peer(&self, address: SocketAddr) -> Result<(), Error> {
+        match *self.running.lock().unwrap() {
+            Some(ref running) => {
+                let pids = self.connected.read().unwrap().iter()
+                    .filter_map(|(pid, a)| if *a == address { Some(*pid) } else { None }).collect::<Vec<_>>();
+                if pids.is_empty() {
+                    return Err(Error::InvalidArgument("peer is not connected"));
+                }
+                self.settings.write().unwrap().peers.retain(|a| *a != address);
+                for pid in pids {
+                    info!("disconnect peer {} on request peer={}", address, pid);
+                    running.p2p_control.send(P2PControl::Disconnect(pid));
+                }
+                Ok(())
+            }
+            None => Err(Error::NotRunning)
+        }
+    }
+
     pub fn start(&self, executor: &mut ThreadPool) {
+        self.stopping.store(false, Ordering::SeqCst);
         let (sender, receiver) = mpsc::sync_channel(100);
 
         let mut dispatcher = Dispatcher::new(receiver);
@@ -103,8 +301,8 @@ impl P2PBitcoin {
             nonce: thread_rng().next_u64(),
             network: self.network,
             max_protocol_version: MAX_PROTOCOL_VERSION,
-            user_agent: "bdk 0.1.0".to_string(),
-            server: false,
+            user_agent: USER_AGENT.to_string(),
+            server: self.listen.is_some(),
             height
         };
 
@@ -113,6 +311,11 @@ impl P2PBitcoin {
             PeerMessageSender::new(sender),
             10);
 
+        if let Some(listen) = self.listen {
+            info!("accept inbound connections on {}", listen);
+            p2p_control.send(P2PControl::Bind(listen));
+        }
+
         let downstream = Arc::new(Mutex::new(BitcoinDriver{store: self.content_store.clone()}));
 
         let processed_block;
@@ -146,23 +349,16 @@ impl P2PBitcoin {
 
         let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone())));
 
-        if self.discovery {
-            dispatcher.add_listener(AddressPoolMaintainer::new(p2p_control.clone(), self.db.clone(), murmel::p2p::SERVICE_BLOCKS));
-        }
-        dispatcher.add_listener(BlockDownload::new(self.chain_db.clone(), p2p_control.clone(), timeout.clone(), downstream, processed_block, self.birth));
-        dispatcher.add_listener(Ping::new(p2p_control.clone(), timeout.clone()));
+        dispatcher.add_listener(ConnectedPeers::new(p2p_control.clone(), self.connected.clone(), self.dialed.clone(), self.backoff.clone(), self.settings.clone(), self.db.clone()));
+        dispatcher.add_listener(BandwidthMeter::new(p2p_control.clone(), self.bandwidth.clone(), USER_AGENT));
+        dispatcher.add_listener(AddressPoolMaintainer::new(p2p_control.clone(), self.db.clone(), self.settings.clone(), self.dialed.clone(), murmel::p2p::SERVICE_BLOCKS));
+        dispatcher.add_listener(BlockDownload::new(self.chain_db.clone(), p2p_control.clone(), timeout.clone(), downstream, processed_block, self.birth, self.bandwidth.clone(), self.scanning.clone(), self.max_block_memory));
+        dispatcher.add_listener(PeerMonitor::new(p2p_control.clone(), timeout.clone(), self.pings.clone(), self.bandwidth.clone()));
 
-        let sendtx = SendTx::new(p2p_control.clone(), self.db.clone());
+        let sendtx = SendTx::new(p2p_control.clone(), self.db.clone(), self.bandwidth.clone(), self.settings.clone(), self.broadcasts.clone());
         dispatcher.add_listener(sendtx.clone());
         self.content_store.write().unwrap().set_tx_sender(sendtx);
 
-        let mut earlier = HashSet::new();
-        let p2p = p2p.clone();
-        for addr in &self.peers {
-            earlier.insert(addr.clone());
-            executor.spawn(p2p.add_peer("bitcoin", PeerSource::Outgoing(addr.clone())).map(|_|())).expect("can not spawn task for peers");
-        }
-
         let dns = dns_seed(self.network);
         {
             let mut db = self.db.lock().unwrap();
@@ -174,13 +370,19 @@ impl P2PBitcoin {
         }
 
         let keep_connected = KeepConnected {
-            min_connections: self.connections,
+            settings: self.settings.clone(),
             p2p: p2p.clone(),
-            earlier: Arc::new(Mutex::new(earlier)),
+            dialed: self.dialed.clone(),
+            backoff: self.backoff.clone(),
             db: self.db.clone(),
             dns,
-            cex: executor.clone()
+            cex: executor.clone(),
+            stopping: self.stopping.clone()
         };
+
+        *self.running.lock().unwrap() = Some(Running { p2p: p2p.clone(), p2p_control: p2p_control.clone(), keep_connected: keep_connected.clone() });
+
+        executor.spawn(keep_connected.clone()).expect("can not connect");
         executor.spawn(Interval::new(Duration::new(10, 0)).for_each(move |_| keep_connected.clone())).expect("can not keep connected");
 
         let p2p = p2p.clone();
@@ -192,8 +394,63 @@ impl P2PBitcoin {
         })).expect("can not spawn bitcoin event loop");
     }
 
+    /// close peer connections and flush the header chain, call after the content store stopped processing blocks
     pub fn shutdown(&self) {
-        self.chain_db.write().unwrap().shutdown()
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(running) = self.running.lock().unwrap().take() {
+            for (pid, addr) in self.connected.read().unwrap().iter() {
+                debug!("disconnect peer {} at shutdown peer={}", addr, pid);
+                running.p2p_control.send(P2PControl::Disconnect(*pid));
+            }
+        }
+        self.dialed.lock().unwrap().clear();
+        self.chain_db.write().unwrap().shutdown();
+        info!("p2p layer shut down");
+    }
+}
+
+pub type SharedBackoff = Arc<Mutex<Backoff>>;
+
+/// reconnect schedule of peers that failed to connect or disconnected
+/// the delay doubles with every failure up to a maximum, randomized by jitter
+pub struct Backoff {
+    peers: HashMap<SocketAddr, (u32, Instant)>
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { peers: HashMap::new() }
+    }
+
+    /// record a failure and schedule the next attempt
+    pub fn failed(&mut self, addr: SocketAddr, settings: &PeerSettings) -> Duration {
+        let failures = self.peers.get(&addr).map(|(f, _)| *f).unwrap_or(0) + 1;
+        let delay = Self::delay(failures, settings.reconnect_delay, settings.max_reconnect_delay);
+        self.peers.insert(addr, (failures, Instant::now() + delay));
+        delay
+    }
+
+    pub fn succeeded(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    /// true if the peer can be tried now
+    pub fn can_try(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr).map(|(_, next)| *next <= Instant::now()).unwrap_or(true)
+    }
+
+    /// peers that must not be tried now
+    pub fn waiting(&self) -> HashSet<SocketAddr> {
+        let now = Instant::now();
+        self.peers.iter().filter_map(|(a, (_, next))| if *next > now { Some(*a) } else { None }).collect()
+    }
+
+    fn delay(failures: u32, base: Duration, max: Duration) -> Duration {
+        let base = base.as_millis() as u64;
+        let max = max.as_millis() as u64;
+        let delay = std::cmp::min(max, base.saturating_mul(1u64 << std::cmp::min(failures - 1, 32)));
+        // jitter spreads reconnects between half and full delay
+        Duration::from_millis(delay / 2 + thread_rng().next_u64() % (delay / 2 + 1))
     }
 }
 
@@ -202,34 +459,74 @@ struct KeepConnected {
     cex: ThreadPool,
     dns: Vec<SocketAddr>,
     db: SharedDB,
-    earlier: Arc<Mutex<HashSet<SocketAddr>>>,
-    p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
-    min_connections: usize
+    dialed: SharedDialed,
+    backoff: SharedBackoff,
+    p2p: Arc<BitcoinP2P>,
+    settings: SharedPeerSettings,
+    stopping: Arc<AtomicBool>
+}
+
+impl KeepConnected {
+    /// connect to a peer, the attempt fails if it does not complete within the connect timeout
+    fn connect(&mut self, addr: SocketAddr) {
+        let timeout = self.settings.read().unwrap().connect_timeout;
+        self.dialed.lock().unwrap().insert(addr);
+        let dialed = self.dialed.clone();
+        let backoff = self.backoff.clone();
+        let settings = self.settings.clone();
+        let attempt = future::select(
+            self.p2p.add_peer("bitcoin", PeerSource::Outgoing(addr)).boxed(),
+            Delay::new(timeout))
+            .map(move |result| {
+                let failure = match result {
+                    Either::Left((Ok(_), _)) => None,
+                    Either::Left((Err(e), _)) => Some(e.to_string()),
+                    Either::Right(_) => Some("timeout".to_string())
+                };
+                if let Some(failure) = failure {
+                    dialed.lock().unwrap().remove(&addr);
+                    let delay = backoff.lock().unwrap().failed(addr, &settings.read().unwrap());
+                    debug!("connection to {} failed ({}), retry in {}s", addr, failure, delay.as_secs());
+                }
+            });
+        self.cex.spawn(attempt).expect("can not add peer for outgoing connection");
+    }
 }
 
 impl Future for KeepConnected {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Async<Self::Output> {
-        if self.p2p.n_connected_peers() < self.min_connections {
-            let choice;
-            {
-                self.p2p.connected_peers().iter().for_each(|a| {self.earlier.lock().unwrap().insert(a.clone());} );
-                choice = self.db.lock().unwrap().transaction().get_an_address("bitcoin", self.earlier.clone()).expect("can not read addresses from db")
+        if self.stopping.load(Ordering::SeqCst) {
+            return Async::Ready(());
+        }
+        let settings = self.settings.read().unwrap().clone();
+        let connected = self.p2p.connected_peers();
+
+        // configured peers first
+        for addr in &settings.peers {
+            let eligible = !connected.contains(addr) && !self.dialed.lock().unwrap().contains(addr) && self.backoff.lock().unwrap().can_try(addr);
+            if eligible {
+                self.connect(*addr);
             }
+        }
+
+        // inbound connections do not count towards the target
+        if self.dialed.lock().unwrap().len() < settings.connections {
+            let mut other_than = self.backoff.lock().unwrap().waiting();
+            other_than.extend(connected.iter().cloned());
+            other_than.extend(self.dialed.lock().unwrap().iter().cloned());
+            let other_than = Arc::new(Mutex::new(other_than));
+            let choice = self.db.lock().unwrap().transaction().get_an_address("bitcoin", other_than.clone()).expect("can not read addresses from db");
             if let Some(choice) = choice {
-                self.earlier.lock().unwrap().insert(choice);
-                let add = self.p2p.add_peer("bitcoin", PeerSource::Outgoing(choice)).map(|_| ());
-                self.cex.spawn(add).expect("can not add peer for outgoing connection");
+                self.connect(choice);
             }
             else {
-                let eligible = self.dns.iter().cloned().filter(|a| !self.earlier.lock().unwrap().contains(&a)).collect::<Vec<_>>();
+                let eligible = self.dns.iter().cloned().filter(|a| !other_than.lock().unwrap().contains(&a)).collect::<Vec<_>>();
                 if eligible.len() > 0 {
                     let mut rng = thread_rng();
                     let choice = eligible[(rng.next_u32() as usize) % eligible.len()];
-                    self.earlier.lock().unwrap().insert(choice);
-                    let add = self.p2p.add_peer("bitcoin", PeerSource::Outgoing(choice)).map(|_| ());
-                    self.cex.spawn(add).expect("can not add peer for outgoing connection");
+                    self.connect(choice);
                 }
             }
         }
@@ -237,16 +534,120 @@ impl Future for KeepConnected {
     }
 }
 
+/// keeps track of connected peers and their addresses,
+/// schedules reconnects of disconnected outbound peers
+/// and enforces the inbound limit and bans on inbound peers
+struct ConnectedPeers {
+    p2p: P2PControlSender<NetworkMessage>,
+    connected: SharedConnectedPeers,
+    dialed: SharedDialed,
+    directions: Directions<PeerId>,
+    backoff: SharedBackoff,
+    settings: SharedPeerSettings,
+    db: SharedDB
+}
+
+impl ConnectedPeers {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, connected: SharedConnectedPeers, dialed: SharedDialed, backoff: SharedBackoff, settings: SharedPeerSettings, db: SharedDB) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+        let mut c = ConnectedPeers { p2p, connected, dialed, directions: Directions::new(), backoff, settings, db };
+
+        thread::Builder::new().name("connected peers".to_string()).spawn(move || { c.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        while let Ok(msg) = receiver.recv() {
+            match msg {
+                PeerMessage::Connected(pid, Some(address)) => {
+                    self.connected.write().unwrap().insert(pid, address);
+                    if self.dialed.lock().unwrap().contains(&address) {
+                        self.directions.dialed(pid);
+                        self.backoff.lock().unwrap().succeeded(&address);
+                    }
+                    else {
+                        self.inbound_connected(pid, address);
+                    }
+                }
+                PeerMessage::Disconnected(pid, _) => {
+                    if let Some(address) = self.connected.write().unwrap().remove(&pid) {
+                        // only outbound peers are scheduled for a reconnect, rejected inbound peers are not failures
+                        if self.directions.disconnected(&pid) == Some(Direction::Outbound) {
+                            self.dialed.lock().unwrap().remove(&address);
+                            self.backoff.lock().unwrap().failed(address, &self.settings.read().unwrap());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn inbound_connected(&mut self, pid: PeerId, address: SocketAddr) {
+        let banned = self.db.lock().unwrap().transaction().is_banned("bitcoin", &address.ip()).expect("can not read addresses from db");
+        if banned {
+            debug!("disconnect banned inbound peer {} peer={}", address, pid);
+            self.p2p.send(P2PControl::Disconnect(pid));
+            return;
+        }
+        if self.directions.inbound() >= self.settings.read().unwrap().max_inbound {
+            debug!("disconnect inbound peer {} over limit peer={}", address, pid);
+            self.p2p.send(P2PControl::Disconnect(pid));
+            return;
+        }
+        debug!("accepted inbound peer {} peer={}", address, pid);
+        self.directions.accepted(pid);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Outbound,
+    Inbound
+}
+
+/// direction of connected peers, peers in neither direction were rejected
+struct Directions<P> {
+    peers: HashMap<P, Direction>
+}
+
+impl<P: Hash + Eq> Directions<P> {
+    fn new() -> Directions<P> {
+        Directions { peers: HashMap::new() }
+    }
+
+    fn dialed(&mut self, peer: P) {
+        self.peers.insert(peer, Direction::Outbound);
+    }
+
+    fn accepted(&mut self, peer: P) {
+        self.peers.insert(peer, Direction::Inbound);
+    }
+
+    /// number of accepted inbound peers
+    fn inbound(&self) -> usize {
+        self.peers.values().filter(|d| **d == Direction::Inbound).count()
+    }
+
+    /// direction of a disconnected peer, None if it was rejected
+    fn disconnected(&mut self, peer: &P) -> Option<Direction> {
+        self.peers.remove(peer)
+    }
+}
+
 struct AddressPoolMaintainer {
     db: SharedDB,
     addresses: HashMap<PeerId, SocketAddr>,
+    settings: SharedPeerSettings,
+    dialed: SharedDialed,
     needed_services: u64
 }
 
 impl AddressPoolMaintainer {
-    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedDB, needed_services: u64) -> PeerMessageSender<NetworkMessage>  {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedDB, settings: SharedPeerSettings, dialed: SharedDialed, needed_services: u64) -> PeerMessageSender<NetworkMessage>  {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
-        let mut m = AddressPoolMaintainer { db, addresses: HashMap::new(), needed_services };
+        let mut m = AddressPoolMaintainer { db, addresses: HashMap::new(), settings, dialed, needed_services };
 
         thread::Builder::new().name("address pool".to_string()).spawn(move || { m.run(receiver) }).unwrap();
 
@@ -259,6 +660,10 @@ impl AddressPoolMaintainer {
                 PeerMessage::Connected(pid, addr) => {
                     if let Some(address) = addr {
                         self.addresses.insert(pid, address);
+                        // inbound peers connect from ephemeral ports, those are not worth connecting to
+                        if !self.dialed.lock().unwrap().contains(&address) {
+                            continue;
+                        }
                         let mut db = self.db.lock().unwrap();
                         let mut tx = db.transaction();
                         debug!("store successful connection to {} peer={}", &address, pid);
@@ -284,6 +689,9 @@ impl AddressPoolMaintainer {
                 PeerMessage::Incoming(pid, msg) => {
                     match msg {
                         NetworkMessage::Addr(av) => {
+                            if !self.settings.read().unwrap().discovery {
+                                continue;
+                            }
                             let mut db = self.db.lock().unwrap();
                             let mut tx = db.transaction();
                             for (last_seen, a) in &av {
@@ -366,3 +774,70 @@ impl Trunk for ChainDBTrunk {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bitcoin::Network;
+
+    use crate::config::Config;
+
+    use super::{Backoff, Direction, Directions, MAX_INBOUND, PeerSettings};
+
+    #[test]
+    fn connection_quota() {
+        let mut config = Config::new("", "", 10, 0, Network::Testnet);
+        config.bitcoin_connections = 4;
+        assert_eq!(PeerSettings::from_config(&config).max_inbound, MAX_INBOUND);
+        config.max_connections = Some(6);
+        let settings = PeerSettings::from_config(&config);
+        assert_eq!((settings.connections, settings.max_inbound), (4, 2));
+        config.max_connections = Some(3);
+        let settings = PeerSettings::from_config(&config);
+        assert_eq!((settings.connections, settings.max_inbound), (3, 0));
+    }
+
+    #[test]
+    fn backoff() {
+        let settings = PeerSettings {
+            connections: 1,
+            peers: vec!(),
+            discovery: false,
+            connect_timeout: Duration::from_secs(10),
+            reconnect_delay: Duration::from_secs(5),
+            max_reconnect_delay: Duration::from_secs(60),
+            max_inbound: 0,
+            broadcast_peers: None,
+            broadcast_fallback: None
+        };
+        let addr = "127.0.0.1:18444".parse().unwrap();
+        let mut backoff = Backoff::new();
+        assert!(backoff.can_try(&addr));
+
+        for failures in 1..10u32 {
+            let delay = backoff.failed(addr, &settings);
+            let full = std::cmp::min(Duration::from_secs(5 * (1 << (failures - 1))), Duration::from_secs(60));
+            assert!(delay >= full / 2 && delay <= full);
+        }
+        assert!(!backoff.can_try(&addr));
+        assert!(backoff.waiting().contains(&addr));
+
+        backoff.succeeded(&addr);
+        assert!(backoff.can_try(&addr));
+        assert!(backoff.waiting().is_empty());
+    }
+
+    #[test]
+    fn rejected_inbound_is_no_failure() {
+        let mut directions = Directions::new();
+        directions.dialed(1);
+        directions.accepted(2);
+        assert_eq!(directions.inbound(), 1);
+        // peer 3 connected inbound but was rejected
+        assert_eq!(directions.disconnected(&3), None);
+        assert_eq!(directions.disconnected(&2), Some(Direction::Inbound));
+        assert_eq!(directions.disconnected(&1), Some(Direction::Outbound));
+        assert_eq!(directions.inbound(), 0);
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! connected peers, their ping latency and what they told about themselves
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, mpsc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::network::message::NetworkMessage;
+use log::trace;
+use murmel::p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use murmel::timeout::{ExpectedReply, SharedTimeout};
+use rand::{RngCore, thread_rng};
+
+use crate::bandwidth::{SharedBandwidth, Usage};
+
+/// peers are pinged this often
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type SharedPings = Arc<RwLock<Pings<PeerId>>>;
+
+/// a connected peer
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    pub user_agent: String,
+    /// the peer's chain height when connected
+    pub start_height: u32,
+    /// round trip of the last answered ping
+    pub ping: Option<Duration>,
+    /// bytes exchanged with the address since start
+    pub usage: Usage,
+}
+
+/// outstanding pings and measured latency per peer
+pub struct Pings<P> {
+    asked: HashMap<P, (u64, Instant)>,
+    latency: HashMap<P, Duration>,
+}
+
+impl<P: Hash + Eq> Pings<P> {
+    pub fn new() -> Pings<P> {
+        Pings { asked: HashMap::new(), latency: HashMap::new() }
+    }
+
+    /// round trip of the last answered ping of a peer
+    pub fn latency(&self, peer: P) -> Option<Duration> {
+        self.latency.get(&peer).cloned()
+    }
+
+    /// a ping is due if none is outstanding and the last one was sent at least interval ago
+    fn due(&self, peer: P, interval: Duration) -> bool {
+        self.asked.get(&peer).map(|(_, sent)| sent.elapsed() >= interval).unwrap_or(true)
+    }
+
+    fn sent(&mut self, peer: P, nonce: u64) {
+        self.asked.insert(peer, (nonce, Instant::now()));
+    }
+
+    /// true if the pong answers our last ping
+    fn pong(&mut self, peer: P, nonce: u64) -> bool {
+        if let Some((asked, sent)) = self.asked.get(&peer) {
+            if *asked == nonce {
+                self.latency.insert(peer, sent.elapsed());
+                return true;
+            }
+        }
+        false
+    }
+
+    fn disconnected(&mut self, peer: P) {
+        self.asked.remove(&peer);
+        self.latency.remove(&peer);
+    }
+}
+
+/// dispatcher listener answering pings and pinging peers to keep connections alive and measure latency
+pub struct PeerMonitor {
+    p2p: P2PControlSender<NetworkMessage>,
+    timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+    pings: SharedPings,
+    bandwidth: SharedBandwidth,
+    connected: Vec<PeerId>,
+}
+
+impl PeerMonitor {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, pings: SharedPings, bandwidth: SharedBandwidth) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+        let mut monitor = PeerMonitor { p2p, timeout, pings, bandwidth, connected: Vec::new() };
+
+        thread::Builder::new().name("peer monitor".to_string()).spawn(move || { monitor.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                match msg {
+                    PeerMessage::Connected(pid, _) => {
+                        self.connected.push(pid);
+                        self.ping(pid);
+                    }
+                    PeerMessage::Disconnected(pid, _) => {
+                        self.connected.retain(|p| *p != pid);
+                        self.pings.write().unwrap().disconnected(pid);
+                    }
+                    PeerMessage::Incoming(pid, NetworkMessage::Ping(nonce)) => {
+                        self.send(pid, NetworkMessage::Pong(nonce));
+                    }
+                    PeerMessage::Incoming(pid, NetworkMessage::Pong(nonce)) => {
+                        if self.pings.write().unwrap().pong(pid, nonce) {
+                            self.timeout.lock().unwrap().received(pid, 1, ExpectedReply::Pong);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            self.timeout.lock().unwrap().check(vec!(ExpectedReply::Pong));
+            let due = self.connected.iter().cloned()
+                .filter(|pid| self.pings.read().unwrap().due(*pid, PING_INTERVAL)).collect::<Vec<_>>();
+            for pid in due {
+                self.ping(pid);
+            }
+        }
+    }
+
+    fn ping(&mut self, pid: PeerId) {
+        let nonce = thread_rng().next_u64();
+        trace!("ping peer={}", pid);
+        self.pings.write().unwrap().sent(pid, nonce);
+        self.timeout.lock().unwrap().expect(pid, 1, ExpectedReply::Pong);
+        self.send(pid, NetworkMessage::Ping(nonce));
+    }
+
+    fn send(&self, pid: PeerId, msg: NetworkMessage) {
+        self.bandwidth.write().unwrap().sent_to_peer(pid, &msg);
+        self.p2p.send_network(pid, msg);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Pings;
+
+    #[test]
+    fn latency() {
+        let mut pings = Pings::new();
+        assert!(pings.due(1, Duration::from_secs(60)));
+        pings.sent(1, 42);
+        assert!(!pings.due(1, Duration::from_secs(60)));
+        assert!(pings.due(1, Duration::from_secs(0)));
+        assert!(!pings.pong(1, 43));
+        assert!(pings.latency(1).is_none());
+        assert!(pings.pong(1, 42));
+        assert!(pings.latency(1).unwrap() < Duration::from_secs(1));
+        pings.disconnected(1);
+        assert!(pings.latency(1).is_none());
+    }
+}
@@ -0,0 +1,154 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! local event socket
+//!
+//! subscribers connect over TCP and receive every wallet and chain event as a frame of
+//! a 4 byte big endian length followed by the JSON payload also posted to webhooks
+
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use log::{debug, info, warn};
+
+use crate::error::Error;
+use crate::webhook::{payload, WalletEvent};
+
+/// how often new subscribers are accepted while no event is published
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+/// a subscriber not taking a frame within this time is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// publishes events to subscribers of a local socket, in the background
+pub struct Publisher {
+    sender: mpsc::Sender<WalletEvent>,
+    address: SocketAddr,
+}
+
+impl Publisher {
+    /// listen for subscribers at address, a loopback address as events are not authenticated.
+    /// The socket is closed when the publisher is dropped
+    pub fn bind(address: SocketAddr) -> Result<Publisher, Error> {
+        if !address.ip().is_loopback() {
+            return Err(Error::InvalidArgument("event socket must be on a loopback address"));
+        }
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel::<WalletEvent>();
+        thread::Builder::new().name("event socket".to_string()).spawn(move || {
+            let mut subscribers: Vec<TcpStream> = Vec::new();
+            let mut sequence = 0u64;
+            loop {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, peer)) => {
+                            if stream.set_nonblocking(false).and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT))).is_ok() {
+                                debug!("event subscriber {} connected", peer);
+                                subscribers.push(stream);
+                            }
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            warn!("can not accept event subscriber {}", e);
+                            break;
+                        }
+                    }
+                }
+                match receiver.recv_timeout(ACCEPT_INTERVAL) {
+                    Ok(event) => {
+                        sequence += 1;
+                        let frame = frame(payload(sequence, &event).as_str());
+                        subscribers.retain(|mut s| match s.write_all(frame.as_slice()) {
+                            Ok(()) => true,
+                            Err(e) => {
+                                debug!("event subscriber dropped {}", e);
+                                false
+                            }
+                        });
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+            }
+            debug!("event socket closed");
+        }).expect("can not start event socket");
+        info!("publishing events at {}", address);
+        Ok(Publisher { sender, address })
+    }
+
+    /// the address subscribers connect to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// queue an event for subscribers
+    pub fn publish(&self, event: WalletEvent) {
+        if self.sender.send(event).is_err() {
+            warn!("event socket is not running");
+        }
+    }
+}
+
+fn frame(payload: &str) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.write_u32::<BigEndian>(payload.len() as u32).expect("can not write to vector");
+    frame.extend_from_slice(payload.as_bytes());
+    frame
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use bitcoin_hashes::sha256d;
+    use byteorder::{BigEndian, ReadBytesExt};
+
+    use crate::webhook::WalletEvent;
+
+    use super::Publisher;
+
+    #[test]
+    fn subscribe() {
+        let publisher = Publisher::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut subscriber = TcpStream::connect(publisher.local_addr()).unwrap();
+        // let the publisher accept the subscriber
+        thread::sleep(Duration::from_millis(500));
+        publisher.publish(WalletEvent::Block { hash: sha256d::Hash::default(), height: 1 });
+        publisher.publish(WalletEvent::Broadcast { txid: sha256d::Hash::default() });
+        subscriber.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for (sequence, event) in [(1, "block"), (2, "broadcast")].iter() {
+            let len = subscriber.read_u32::<BigEndian>().unwrap();
+            let mut payload = vec!(0u8; len as usize);
+            subscriber.read_exact(payload.as_mut_slice()).unwrap();
+            let payload = serde_json::from_slice::<serde_json::Value>(payload.as_slice()).unwrap();
+            assert_eq!(payload["sequence"], *sequence);
+            assert_eq!(payload["event"], *event);
+        }
+        drop(publisher);
+        let mut rest = Vec::new();
+        assert_eq!(subscriber.read_to_end(&mut rest).unwrap(), 0);
+
+        assert!(Publisher::bind("0.0.0.0:0".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! exchange rates for fiat amounts
+//!
+//! rates are provided by the application or fetched over HTTP from a CoinGecko compatible API.
+//! Fetched rates are cached in the db, current rates for a few minutes, historical ones by day.
+
+use crate::error::Error;
+
+/// timeout of a request in milliseconds
+const TIMEOUT: u64 = 30000;
+
+/// seconds a current rate is reused
+pub const CURRENT_RATE_AGE: u32 = 600;
+
+const DAY: u32 = 24 * 60 * 60;
+
+const SATOSHIS_PER_BITCOIN: f64 = 100_000_000.0;
+
+/// price of a bitcoin in a fiat currency
+pub trait RateProvider: Send + Sync {
+    /// the rate at time (seconds since the epoch) or the current rate if None. Currency is a lower case code e.g. usd
+    fn rate(&self, currency: &str, time: Option<u32>) -> Result<f64, Error>;
+}
+
+/// rates of a CoinGecko compatible API e.g. https://api.coingecko.com/api/v3
+pub struct HttpRateProvider {
+    endpoint: String,
+}
+
+impl HttpRateProvider {
+    pub fn new(endpoint: &str) -> HttpRateProvider {
+        HttpRateProvider { endpoint: endpoint.trim_end_matches('/').to_string() }
+    }
+}
+
+impl RateProvider for HttpRateProvider {
+    fn rate(&self, currency: &str, time: Option<u32>) -> Result<f64, Error> {
+        let currency = currency.to_lowercase();
+        if let Some(time) = time {
+            let url = format!("{}/coins/bitcoin/history?date={}&localization=false", self.endpoint, date(time));
+            parse_historical_rate(get(url.as_str())?.as_str(), currency.as_str())
+        } else {
+            let url = format!("{}/simple/price?ids=bitcoin&vs_currencies={}", self.endpoint, currency);
+            parse_current_rate(get(url.as_str())?.as_str(), currency.as_str())
+        }
+    }
+}
+
+/// the time a rate is cached for: the day of historical rates, an interval of CURRENT_RATE_AGE of the current one
+pub fn rate_slot(time: Option<u32>, now: u32) -> u32 {
+    match time {
+        Some(time) => time - time % DAY,
+        None => now - now % CURRENT_RATE_AGE
+    }
+}
+
+/// fiat value of satoshis at rate
+pub fn fiat(satoshis: i64, rate: f64) -> f64 {
+    satoshis as f64 / SATOSHIS_PER_BITCOIN * rate
+}
+
+fn get(url: &str) -> Result<String, Error> {
+    let response = ureq::get(url)
+        .timeout_connect(TIMEOUT)
+        .timeout_read(TIMEOUT)
+        .call();
+    if let Some(err) = response.synthetic_error() {
+        return Err(Error::from_request(err));
+    }
+    if !response.ok() {
+        return Err(Error::Http(format!("{} {}", response.status(), response.status_text())));
+    }
+    response.into_string().map_err(|e| Error::Http(e.to_string()))
+}
+
+fn parse_current_rate(body: &str, currency: &str) -> Result<f64, Error> {
+    let response = serde_json::from_str::<serde_json::Value>(body).map_err(|_| Error::Http("unexpected rate response".to_string()))?;
+    response["bitcoin"][currency].as_f64().ok_or_else(|| Error::Http(format!("no rate for {}", currency)))
+}
+
+fn parse_historical_rate(body: &str, currency: &str) -> Result<f64, Error> {
+    let response = serde_json::from_str::<serde_json::Value>(body).map_err(|_| Error::Http("unexpected rate response".to_string()))?;
+    response["market_data"]["current_price"][currency].as_f64().ok_or_else(|| Error::Http(format!("no rate for {}", currency)))
+}
+
+// dd-mm-yyyy of a time stamp, in UTC
+fn date(time: u32) -> String {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (time / DAY) as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:02}-{:02}-{}", day, month, year)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{date, fiat, parse_current_rate, parse_historical_rate, rate_slot};
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(parse_current_rate(r#"{"bitcoin":{"usd":9500.5}}"#, "usd").unwrap(), 9500.5);
+        assert!(parse_current_rate(r#"{"bitcoin":{"usd":9500.5}}"#, "eur").is_err());
+        assert_eq!(parse_historical_rate(r#"{"id":"bitcoin","market_data":{"current_price":{"eur":8400}}}"#, "eur").unwrap(), 8400.0);
+        assert!(parse_historical_rate("not json", "eur").is_err());
+    }
+
+    #[test]
+    fn dates_and_slots() {
+        assert_eq!(date(0), "01-01-1970");
+        assert_eq!(date(1590969600 + 3600), "01-06-2020");
+        assert_eq!(date(951782400), "29-02-2000");
+        assert_eq!(rate_slot(Some(1590969600 + 3600), 0), 1590969600);
+        assert_eq!(rate_slot(None, 1590969600 + 601), 1590969600 + 600);
+        assert_eq!(fiat(-50_000_000, 9000.0), -4500.0);
+    }
+}
@@ -0,0 +1,375 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! a trusted bitcoind as chain backend
+//!
+//! blocks are fetched over the JSON-RPC interface of a full node and own transactions are sent
+//! with sendrawtransaction, instead of talking to the p2p network. Headers are looked up at the node
+//! and cached, the node's best chain is the trunk
+
+use std::collections::HashMap;
+use std::sync::{Arc, mpsc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use bitcoin::{BitcoinHash, Block, BlockHeader, Transaction};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::network::message::NetworkMessage;
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256d;
+use log::{debug, info, warn};
+use murmel::p2p::{PeerMessage, PeerMessageSender};
+use serde_json::{json, Value};
+
+use crate::db::SharedDB;
+use crate::error::Error;
+use crate::store::SharedContentStore;
+use crate::trunk::Trunk;
+
+/// timeout of a request in milliseconds
+const TIMEOUT: u64 = 30000;
+/// the node is asked for new blocks this often
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// block times may be this many seconds off, scanning starts this much before the wallet birth
+const MAX_TIME_DRIFT: u32 = 2 * 60 * 60;
+
+/// JSON-RPC client of a bitcoind
+#[derive(Clone)]
+pub struct RpcClient {
+    url: String,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+/// a header of the node's chain
+#[derive(Clone, Debug)]
+pub struct HeaderInfo {
+    pub header: BlockHeader,
+    pub height: u32,
+    /// on the node's best chain
+    pub on_trunk: bool,
+}
+
+impl RpcClient {
+    /// a client of the node at url, e.g. http://127.0.0.1:8332, with basic authentication if user is given
+    pub fn new(url: &str, user: Option<&str>, password: Option<&str>) -> RpcClient {
+        RpcClient { url: url.to_string(), user: user.map(|u| u.to_string()), password: password.map(|p| p.to_string()) }
+    }
+
+    /// call method with params
+    pub fn call(&self, method: &str, params: Value) -> Result<Value, Error> {
+        let mut request = ureq::post(self.url.as_str());
+        request.timeout_connect(TIMEOUT).timeout_read(TIMEOUT);
+        if let Some(ref user) = self.user {
+            request.auth(user.as_str(), self.password.as_ref().map(|p| p.as_str()).unwrap_or(""));
+        }
+        let response = request.send_json(json!({"jsonrpc": "1.0", "id": "bdk", "method": method, "params": params}));
+        if let Some(err) = response.synthetic_error() {
+            return Err(Error::from_request(err));
+        }
+        if response.status() == 401 {
+            return Err(Error::Http("bitcoind refused the RPC credentials".to_string()));
+        }
+        // bitcoind answers errors with 404 or 500 and a JSON body
+        let body = response.into_string().map_err(|e| Error::Http(e.to_string()))?;
+        parse_response(body.as_str())
+    }
+
+    pub fn block_count(&self) -> Result<u32, Error> {
+        self.call("getblockcount", json!([]))?.as_u64().map(|n| n as u32).ok_or_else(|| unexpected("getblockcount"))
+    }
+
+    /// hash of the block at height of the best chain
+    pub fn block_hash(&self, height: u32) -> Result<sha256d::Hash, Error> {
+        let hash = self.call("getblockhash", json!([height]))?;
+        hash.as_str().and_then(|h| sha256d::Hash::from_hex(h).ok()).ok_or_else(|| unexpected("getblockhash"))
+    }
+
+    pub fn block(&self, hash: &sha256d::Hash) -> Result<Block, Error> {
+        let block = self.call("getblock", json!([hash.to_string(), 0]))?;
+        let block: Block = decode(&block).ok_or_else(|| unexpected("getblock"))?;
+        if block.bitcoin_hash() != *hash {
+            return Err(unexpected("getblock"));
+        }
+        Ok(block)
+    }
+
+    /// header with its height, None if the node does not know the block
+    pub fn header(&self, hash: &sha256d::Hash) -> Result<Option<HeaderInfo>, Error> {
+        let info = match self.call("getblockheader", json!([hash.to_string(), true])) {
+            Ok(info) => info,
+            // block not found
+            Err(Error::Http(ref message)) if message.starts_with("-5 ") => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        let header: BlockHeader = decode(&self.call("getblockheader", json!([hash.to_string(), false]))?).ok_or_else(|| unexpected("getblockheader"))?;
+        if header.bitcoin_hash() != *hash {
+            return Err(unexpected("getblockheader"));
+        }
+        Ok(Some(header_info(header, &info)?))
+    }
+
+    pub fn send_raw_transaction(&self, tx: &Transaction) -> Result<sha256d::Hash, Error> {
+        let txid = self.call("sendrawtransaction", json!([hex::encode(serialize(tx))]))?;
+        txid.as_str().and_then(|t| sha256d::Hash::from_hex(t).ok()).ok_or_else(|| unexpected("sendrawtransaction"))
+    }
+
+    /// height of the first block of the best chain with a time at or after time, None if there is none
+    pub fn first_block_after(&self, time: u32) -> Result<Option<u32>, Error> {
+        let mut low = 0;
+        let mut high = self.block_count()? + 1;
+        while low < high {
+            let mid = (low + high) / 2;
+            let hash = self.block_hash(mid)?;
+            let header = self.header(&hash)?.ok_or_else(|| unexpected("getblockheader"))?.header;
+            if header.time < time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(if low > self.block_count()? { None } else { Some(low) })
+    }
+}
+
+fn unexpected(method: &str) -> Error {
+    Error::Http(format!("unexpected {} response", method))
+}
+
+fn parse_response(body: &str) -> Result<Value, Error> {
+    let mut response = serde_json::from_str::<Value>(body).map_err(|_| Error::Http(format!("not a JSON-RPC response: {}", body.trim())))?;
+    if let Some(error) = response.get("error").filter(|e| !e.is_null()) {
+        return Err(Error::Http(format!("{} {}", error["code"], error["message"].as_str().unwrap_or(""))));
+    }
+    Ok(response["result"].take())
+}
+
+fn decode<T: bitcoin::consensus::Decodable>(value: &Value) -> Option<T> {
+    value.as_str().and_then(|s| hex::decode(s).ok()).and_then(|b| deserialize(b.as_slice()).ok())
+}
+
+fn header_info(header: BlockHeader, info: &Value) -> Result<HeaderInfo, Error> {
+    let height = info["height"].as_u64().ok_or_else(|| unexpected("getblockheader"))? as u32;
+    // -1 if not on the best chain
+    let confirmations = info["confirmations"].as_i64().ok_or_else(|| unexpected("getblockheader"))?;
+    Ok(HeaderInfo { header, height, on_trunk: confirmations >= 0 })
+}
+
+/// the best chain of the node, headers are cached as they are looked up
+pub struct RpcTrunk {
+    client: RpcClient,
+    chain: RwLock<Chain>,
+}
+
+#[derive(Default)]
+struct Chain {
+    /// headers on the trunk by hash
+    headers: HashMap<sha256d::Hash, (BlockHeader, u32)>,
+    by_height: HashMap<u32, sha256d::Hash>,
+    tip: Option<(BlockHeader, u32)>,
+}
+
+impl RpcTrunk {
+    pub fn new(client: RpcClient) -> Result<RpcTrunk, Error> {
+        let trunk = RpcTrunk { client, chain: RwLock::new(Chain::default()) };
+        trunk.update_tip()?;
+        Ok(trunk)
+    }
+
+    pub fn client(&self) -> &RpcClient {
+        &self.client
+    }
+
+    /// follow the node's tip, cached headers are dropped if the tip does not extend the known one
+    pub fn update_tip(&self) -> Result<(), Error> {
+        let height = self.client.block_count()?;
+        let hash = self.client.block_hash(height)?;
+        let info = self.client.header(&hash)?.ok_or_else(|| unexpected("getblockheader"))?;
+        let mut chain = self.chain.write().unwrap();
+        let extends = chain.tip.as_ref().map(|(tip, tip_height)| {
+            *tip_height <= height && self.client.block_hash(*tip_height).ok() == Some(tip.bitcoin_hash())
+        }).unwrap_or(true);
+        if !extends {
+            debug!("node re-organized, dropping cached headers");
+            *chain = Chain::default();
+        }
+        chain.headers.insert(hash, (info.header.clone(), height));
+        chain.by_height.insert(height, hash);
+        chain.tip = Some((info.header, height));
+        Ok(())
+    }
+
+    fn lookup(&self, hash: &sha256d::Hash) -> Option<(BlockHeader, u32)> {
+        if let Some(known) = self.chain.read().unwrap().headers.get(hash) {
+            return Some(known.clone());
+        }
+        match self.client.header(hash) {
+            Ok(Some(info)) if info.on_trunk => {
+                let mut chain = self.chain.write().unwrap();
+                chain.headers.insert(*hash, (info.header.clone(), info.height));
+                chain.by_height.insert(info.height, *hash);
+                Some((info.header, info.height))
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("can not look up header {} at the node: {}", hash, e);
+                None
+            }
+        }
+    }
+}
+
+impl Trunk for RpcTrunk {
+    fn is_on_trunk(&self, block_hash: &sha256d::Hash) -> bool {
+        self.lookup(block_hash).is_some()
+    }
+
+    fn get_header(&self, block_hash: &sha256d::Hash) -> Option<BlockHeader> {
+        self.lookup(block_hash).map(|(header, _)| header)
+    }
+
+    fn get_header_for_height(&self, height: u32) -> Option<BlockHeader> {
+        let known = self.chain.read().unwrap().by_height.get(&height).cloned();
+        match known {
+            Some(hash) => self.lookup(&hash),
+            None => self.client.block_hash(height).ok().and_then(|hash| self.lookup(&hash))
+        }.map(|(header, _)| header)
+    }
+
+    fn get_height(&self, block_hash: &sha256d::Hash) -> Option<u32> {
+        self.lookup(block_hash).map(|(_, height)| height)
+    }
+
+    fn get_tip(&self) -> Option<BlockHeader> {
+        self.chain.read().unwrap().tip.as_ref().map(|(header, _)| header.clone())
+    }
+
+    fn len(&self) -> u32 {
+        self.chain.read().unwrap().tip.as_ref().map(|(_, height)| height + 1).unwrap_or(0)
+    }
+}
+
+/// drive the store with blocks of the node until it is stopped, starting after the last processed block
+/// or at the wallet birth. Transactions sent by the store go to the node
+pub fn attach(trunk: Arc<RpcTrunk>, db: SharedDB, store: SharedContentStore, birth: u64) -> Result<(), Error> {
+    let (sender, receiver) = mpsc::sync_channel(100);
+    let client = trunk.client().clone();
+    thread::Builder::new().name("bitcoind sendtx".to_string()).spawn(move || {
+        while let Ok(msg) = receiver.recv() {
+            if let PeerMessage::Outgoing(NetworkMessage::Tx(tx)) = msg {
+                match client.send_raw_transaction(&tx) {
+                    Ok(txid) => info!("sent our transaction {} to bitcoind", txid),
+                    Err(e) => warn!("bitcoind did not take our transaction {}: {}", tx.txid(), e)
+                }
+            }
+        }
+    })?;
+    store.write().map_err(|_| Error::Lock("content store"))?.set_tx_sender(PeerMessageSender::new(sender));
+    thread::Builder::new().name("bitcoind sync".to_string()).spawn(move || {
+        while !store.read().unwrap().get_stopped() {
+            if let Err(e) = sync(&trunk, &db, &store, birth) {
+                warn!("can not sync with bitcoind: {}", e);
+            }
+            let mut waited = Duration::from_secs(0);
+            while waited < POLL_INTERVAL && !store.read().unwrap().get_stopped() {
+                thread::sleep(Duration::from_secs(1));
+                waited += Duration::from_secs(1);
+            }
+        }
+        debug!("stop syncing with bitcoind");
+    })?;
+    Ok(())
+}
+
+// unwind processed blocks the node left, then connect its blocks up to its tip
+fn sync(trunk: &RpcTrunk, db: &SharedDB, store: &SharedContentStore, birth: u64) -> Result<(), Error> {
+    trunk.update_tip()?;
+    let client = trunk.client();
+    let mut next = None;
+    loop {
+        let processed = db.lock().unwrap().transaction().read_processed()?;
+        let processed = match processed {
+            Some(processed) => processed,
+            None => break
+        };
+        match client.header(&processed)? {
+            Some(ref info) if info.on_trunk => {
+                next = Some(info.height + 1);
+                break;
+            }
+            Some(info) => {
+                info!("bitcoind left block {}, unwinding", processed);
+                let mut store = store.write().unwrap();
+                if store.get_stopped() {
+                    return Ok(());
+                }
+                store.unwind_tip(&info.header)?;
+            }
+            None => return Err(Error::Unsupported("bitcoind does not know the last processed block"))
+        }
+    }
+    let next = match next {
+        Some(next) => next,
+        None => match client.first_block_after((birth as u32).saturating_sub(MAX_TIME_DRIFT))? {
+            Some(first) => first,
+            None => return Ok(())
+        }
+    };
+    let tip = trunk.len().saturating_sub(1);
+    for height in next..=tip {
+        let hash = client.block_hash(height)?;
+        let block = client.block(&hash)?;
+        let mut store = store.write().unwrap();
+        if store.get_stopped() {
+            break;
+        }
+        store.add_header(height, &block.header)?;
+        store.block_connected(&block, height)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{BitcoinHash, Block, Network};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use serde_json::json;
+
+    use crate::error::Error;
+
+    use super::{decode, header_info, parse_response};
+
+    #[test]
+    fn responses() {
+        assert_eq!(parse_response(r#"{"result":700000,"error":null,"id":"bdk"}"#).unwrap(), json!(700000));
+        match parse_response(r#"{"result":null,"error":{"code":-5,"message":"Block not found"},"id":"bdk"}"#) {
+            Err(Error::Http(message)) => assert_eq!(message, "-5 Block not found"),
+            _ => panic!("error response not recognized")
+        }
+        assert!(parse_response("Unauthorized").is_err());
+
+        let genesis = genesis_block(Network::Testnet);
+        let block: Block = decode(&json!(hex::encode(serialize(&genesis)))).unwrap();
+        assert_eq!(block.bitcoin_hash(), genesis.bitcoin_hash());
+        assert!(decode::<Block>(&json!("00")).is_none());
+
+        let info = header_info(genesis.header, &json!({"height": 0, "confirmations": 10})).unwrap();
+        assert!(info.on_trunk);
+        assert_eq!(info.height, 0);
+        assert!(!header_info(genesis.header, &json!({"height": 0, "confirmations": -1})).unwrap().on_trunk);
+    }
+}
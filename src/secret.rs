@@ -0,0 +1,136 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! secrets zeroized when dropped
+//!
+//! Keys are decrypted by bitcoin-wallet's Unlocker, which neither zeroizes its copy of the master key
+//! and the keys it derives nor the decrypted seed. Unlockers are therefore only held while signing, keys
+//! taken out of them are kept in a SecretMaster, but their own copies remain in memory until overwritten
+
+use std::{fmt, ptr};
+use std::ops::Deref;
+use std::sync::atomic;
+
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::util::bip32::{ChainCode, ExtendedPrivKey};
+use zeroize::Zeroize;
+
+/// a passphrase, zeroized on drop and never shown by Debug
+#[derive(Clone, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Secret {
+        Secret(secret)
+    }
+}
+
+impl<'a> From<&'a str> for Secret {
+    fn from(secret: &'a str) -> Secret {
+        Secret(secret.to_string())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+/// an extended private key, overwritten on drop and never shown by Debug
+pub struct SecretMaster(ExtendedPrivKey);
+
+impl From<ExtendedPrivKey> for SecretMaster {
+    fn from(master: ExtendedPrivKey) -> SecretMaster {
+        SecretMaster(master)
+    }
+}
+
+impl Deref for SecretMaster {
+    type Target = ExtendedPrivKey;
+
+    fn deref(&self) -> &ExtendedPrivKey {
+        &self.0
+    }
+}
+
+impl Drop for SecretMaster {
+    fn drop(&mut self) {
+        wipe(&mut self.0.private_key.key);
+        unsafe {
+            ptr::write_volatile(&mut self.0.chain_code, ChainCode::from(&[0u8; 32][..]));
+        }
+        atomic::compiler_fence(atomic::Ordering::SeqCst);
+    }
+}
+
+/// overwrite a private key, secp256k1 keys do not implement Zeroize so a valid dummy key is written
+pub fn wipe(key: &mut SecretKey) {
+    let dummy = SecretKey::from_slice(&[1u8; 32]).expect("valid key");
+    unsafe {
+        ptr::write_volatile(key, dummy);
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
+impl fmt::Debug for SecretMaster {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretMaster(..)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+    use bitcoin::util::bip32::ExtendedPrivKey;
+
+    use super::{Secret, SecretMaster};
+
+    #[test]
+    fn hidden() {
+        let secret = Secret::from("whatever");
+        assert_eq!(secret.as_str(), "whatever");
+        assert_eq!(format!("{:?}", secret), "Secret(..)");
+    }
+
+    #[test]
+    fn hidden_master() {
+        let key = ExtendedPrivKey::new_master(Network::Testnet, &[7u8; 32]).unwrap();
+        let master = SecretMaster::from(key);
+        assert_eq!(*master, key);
+        assert_eq!(format!("{:?}", master), "SecretMaster(..)");
+    }
+}
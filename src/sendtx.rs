@@ -15,8 +15,8 @@
  * limitations under the License.
  */
 use std::{
-    collections::HashMap,
-    sync::mpsc,
+    collections::{HashMap, HashSet},
+    sync::{Arc, mpsc, Mutex},
     thread,
     time::SystemTime
 };
@@ -25,22 +25,71 @@ use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message_blockdata::{Inventory, InvType};
 use bitcoin::Transaction;
 use bitcoin_hashes::sha256d;
-use log::debug;
+use log::{debug, info, warn};
 use lru_cache::LruCache;
-use murmel::p2p::{P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use murmel::p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
 
+use crate::bandwidth::SharedBandwidth;
 use crate::db::SharedDB;
+use crate::esplora;
+use crate::p2p_bitcoin::SharedPeerSettings;
+
+pub type SharedBroadcasts = Arc<Mutex<LruCache<sha256d::Hash, Broadcast>>>;
+
+/// how widely one of our transactions propagated
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BroadcastStatus {
+    /// peers we announced the transaction to
+    pub announced: usize,
+    /// peers that requested the transaction from us
+    pub requested: usize,
+    /// peers that announced the transaction back, whether we announced to them or not
+    pub seen: usize,
+    /// the transaction was posted to the HTTP fallback
+    pub posted: bool
+}
+
+/// peers involved in broadcasting one of our transactions
+#[derive(Default)]
+pub struct Broadcast {
+    announced_to: HashSet<PeerId>,
+    requested_by: HashSet<PeerId>,
+    seen_by: HashSet<PeerId>,
+    posted: bool
+}
+
+impl Broadcast {
+    pub fn status(&self) -> BroadcastStatus {
+        BroadcastStatus {
+            announced: self.announced_to.len(),
+            requested: self.requested_by.len(),
+            seen: self.seen_by.len(),
+            posted: self.posted
+        }
+    }
+
+    /// a peer took the transaction: it asked for it or announced it back
+    pub fn propagated(&self) -> bool {
+        !self.requested_by.is_empty() || !self.seen_by.is_empty()
+    }
+}
 
 pub struct SendTx {
     p2p: P2PControlSender<NetworkMessage>,
     db: SharedDB,
+    bandwidth: SharedBandwidth,
+    settings: SharedPeerSettings,
+    broadcasts: SharedBroadcasts,
+    peers: HashSet<PeerId>,
     cache: LruCache<sha256d::Hash, Transaction>
 }
 
-const CACHE_SIZE: usize=1000;
+pub const CACHE_SIZE: usize=1000;
 
 impl SendTx {
-    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedDB) -> PeerMessageSender<NetworkMessage> {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedDB, bandwidth: SharedBandwidth, settings: SharedPeerSettings, broadcasts: SharedBroadcasts) -> PeerMessageSender<NetworkMessage> {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
 
         let mut own_unconfirmed = HashMap::new();
@@ -52,25 +101,91 @@ impl SendTx {
             }
         }
 
-        let mut txsender = SendTx { p2p, db, cache: LruCache::new(CACHE_SIZE) };
+        let mut txsender = SendTx { p2p, db, bandwidth, settings, broadcasts, peers: HashSet::new(), cache: LruCache::new(CACHE_SIZE) };
 
         thread::Builder::new().name("sendtx".to_string()).spawn(move || { txsender.run(receiver) }).unwrap();
 
         PeerMessageSender::new(sender)
     }
 
+    fn send_network(&self, peer: PeerId, msg: NetworkMessage) {
+        self.bandwidth.write().unwrap().sent_to_peer(peer, &msg);
+        self.p2p.send_network(peer, msg);
+    }
+
+    fn send_random_network(&self, msg: NetworkMessage) -> Option<PeerId> {
+        let counted = msg.clone();
+        let peer = self.p2p.send_random_network(msg);
+        if let Some(peer) = peer {
+            self.bandwidth.write().unwrap().sent_to_peer(peer, &counted);
+        }
+        peer
+    }
+
+    /// announce one of our transactions to all or the configured number of connected peers,
+    /// post it to the HTTP fallback if no peer is connected
+    fn broadcast(&mut self, transaction: &Transaction) {
+        let txid = transaction.txid();
+        let mut peers = self.peers.iter().cloned().collect::<Vec<_>>();
+        if let Some(n) = self.settings.read().unwrap().broadcast_peers {
+            peers.shuffle(&mut thread_rng());
+            peers.truncate(n);
+        }
+        for peer in &peers {
+            self.send_network(*peer, NetworkMessage::Inv(vec!(Inventory { hash: txid, inv_type: InvType::Transaction })));
+        }
+        debug!("announced our transaction {} to {} peers", txid, peers.len());
+        let mut broadcasts = self.broadcasts.lock().unwrap();
+        if !broadcasts.contains_key(&txid) {
+            broadcasts.insert(txid, Broadcast::default());
+        }
+        let broadcast = broadcasts.get_mut(&txid).expect("inserted above");
+        if peers.is_empty() {
+            self.post_fallback(broadcast, transaction);
+        }
+        broadcast.announced_to.extend(peers);
+    }
+
+    /// post one of our transactions to the HTTP fallback once, if configured
+    fn post_fallback(&self, broadcast: &mut Broadcast, transaction: &Transaction) {
+        if broadcast.posted {
+            return;
+        }
+        if let Some(endpoint) = self.settings.read().unwrap().broadcast_fallback.clone() {
+            broadcast.posted = true;
+            let transaction = transaction.clone();
+            thread::Builder::new().name("broadcast fallback".to_string()).spawn(move || {
+                match esplora::post_tx(endpoint.as_str(), &transaction) {
+                    Ok(()) => info!("posted our transaction {} to {}", transaction.txid(), endpoint),
+                    Err(e) => warn!("can not post our transaction {} to {}: {}", transaction.txid(), endpoint, e)
+                }
+            }).expect("can not spawn broadcast fallback");
+        }
+    }
+
+    /// true if a peer asked for or announced one of our transactions
+    fn propagated(&self, txid: &sha256d::Hash) -> bool {
+        self.broadcasts.lock().unwrap().get_mut(txid).map(|b| b.propagated()).unwrap_or(false)
+    }
+
     fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
         let mut last_announcement = SystemTime::now();
         while let Ok(msg) = receiver.recv() {
             match msg {
+                PeerMessage::Connected(pid, _) => {
+                    self.peers.insert(pid);
+                }
+                PeerMessage::Disconnected(pid, _) => {
+                    self.peers.remove(&pid);
+                }
                 PeerMessage::Incoming(pid, msg) => {
                     match msg {
                         NetworkMessage::GetData(ref inv) => {
                             let txs = inv.iter().filter_map(|i| if i.inv_type == InvType::Transaction { Some(i.hash) } else { None }).collect::<Vec<_>>();
                             if !txs.is_empty() {
                                 let txs = txs.iter().filter_map(|h| {
-                                    if let Some(cached) = self.cache.get_mut(h) {
-                                        self.p2p.send_network(pid, NetworkMessage::Tx(cached.clone()));
+                                    if let Some(cached) = self.cache.get_mut(h).map(|t| t.clone()) {
+                                        self.send_network(pid, NetworkMessage::Tx(cached));
                                         None
                                     } else {
                                         Some(*h)
@@ -81,21 +196,38 @@ impl SendTx {
                                     let mut db = self.db.lock().unwrap();
                                     let tx = db.transaction();
                                     for (t, _) in tx.read_unconfirmed().expect("can not read unconfirmed transactions").iter().filter(|(t, _)| txs.contains(&t.txid())) {
-                                        self.p2p.send_network(pid, NetworkMessage::Tx(t.clone()));
+                                        self.send_network(pid, NetworkMessage::Tx(t.clone()));
+                                        if let Some(broadcast) = self.broadcasts.lock().unwrap().get_mut(&t.txid()) {
+                                            broadcast.requested_by.insert(pid);
+                                        }
                                         debug!("sent our transaction {} at request of peer={}", t.txid(), pid);
                                     }
                                 }
                             }
                         }
                         NetworkMessage::Inv(ref inv) => {
-                            let have_not = inv.iter().filter(|i| i.inv_type == InvType::Transaction && !self.cache.contains_key(&i.hash)).cloned().collect::<Vec<_>>();
+                            // our own transactions announced back show propagation, also by peers we announced to
+                            // as they do so once they accepted the transaction
+                            let mut own = HashSet::new();
+                            {
+                                let mut broadcasts = self.broadcasts.lock().unwrap();
+                                for i in inv.iter().filter(|i| i.inv_type == InvType::Transaction) {
+                                    if let Some(broadcast) = broadcasts.get_mut(&i.hash) {
+                                        if broadcast.seen_by.insert(pid) {
+                                            debug!("our transaction {} was announced by peer={}", i.hash, pid);
+                                        }
+                                        own.insert(i.hash);
+                                    }
+                                }
+                            }
+                            let have_not = inv.iter().filter(|i| i.inv_type == InvType::Transaction && !own.contains(&i.hash) && !self.cache.contains_key(&i.hash)).cloned().collect::<Vec<_>>();
                             if !have_not.is_empty() {
-                                self.p2p.send_network(pid, NetworkMessage::GetData(have_not));
+                                self.send_network(pid, NetworkMessage::GetData(have_not));
                             }
                         }
                         NetworkMessage::Tx(ref tx) => {
                             if self.cache.insert(tx.txid(), tx.clone()).is_none() {
-                                self.p2p.send_random_network(NetworkMessage::Inv(vec!(Inventory { inv_type: InvType::Transaction, hash: tx.txid() })));
+                                self.send_random_network(NetworkMessage::Inv(vec!(Inventory { inv_type: InvType::Transaction, hash: tx.txid() })));
                             }
                         }
                         _ => {}
@@ -104,8 +236,7 @@ impl SendTx {
                 PeerMessage::Outgoing(msg) => {
                     match msg {
                         NetworkMessage::Tx(ref transaction) => {
-                            let txid = transaction.txid();
-                            self.p2p.send_random_network(NetworkMessage::Inv(vec!(Inventory { hash: txid, inv_type: InvType::Transaction })));
+                            self.broadcast(transaction);
                         },
                         _ => {}
                     }
@@ -115,11 +246,19 @@ impl SendTx {
             if SystemTime::now().duration_since(last_announcement).unwrap().as_secs() > 60 {
                 let mut db = self.db.lock().unwrap();
                 let tx = db.transaction();
-                for (transaction, _) in tx.read_unconfirmed().expect("can not read unconfirmed transactions") {
-                    if !self.cache.contains_key(&transaction.txid()) {
-                        if let Some(peer) = self.p2p.send_random_network(NetworkMessage::Inv(vec!(Inventory { hash: transaction.txid(), inv_type: InvType::Transaction }))) {
-                            debug!("announced our transaction {} to peer={}", transaction.txid(), peer);
+                let unconfirmed = tx.read_unconfirmed().expect("can not read unconfirmed transactions");
+                drop(tx);
+                drop(db);
+                for (transaction, _) in unconfirmed {
+                    let txid = transaction.txid();
+                    if !self.cache.contains_key(&txid) && !self.propagated(&txid) {
+                        // no peer took the transaction since it was last announced
+                        if let Some(broadcast) = self.broadcasts.lock().unwrap().get_mut(&txid) {
+                            if !broadcast.announced_to.is_empty() && broadcast.requested_by.is_empty() {
+                                self.post_fallback(broadcast, &transaction);
+                            }
                         }
+                        self.broadcast(&transaction);
                     }
                 }
                 last_announcement = SystemTime::now();
@@ -0,0 +1,226 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! a scripted chain backend
+//!
+//! drives the content store like the p2p layer does, but blocks are mined, deposits are sent
+//! and re-orgs are triggered by the caller. Transactions sent by the wallet are kept in a
+//! mempool and confirmed by the next mined block.
+//! available with the testutil feature, use a work dir separate from that of a real chain
+
+use std::sync::{Arc, mpsc, Mutex};
+use std::thread;
+
+use bitcoin::{Address, BitcoinHash, Block, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin_hashes::{Hash, sha256d};
+use log::info;
+use murmel::p2p::{PeerMessage, PeerMessageSender};
+
+use crate::error::Error;
+use crate::store::SharedContentStore;
+use crate::testutil::{add_tx, mine, TestTrunk};
+use crate::trunk::Trunk;
+
+pub type SharedSimulation = Arc<Simulation>;
+
+/// a scripted in-memory chain
+pub struct Simulation {
+    trunk: Arc<TestTrunk>,
+    miner: Address,
+    state: Mutex<State>
+}
+
+struct State {
+    blocks: Vec<Block>,
+    mempool: Arc<Mutex<Vec<Transaction>>>,
+    store: Option<SharedContentStore>,
+    nonce: u32
+}
+
+impl Simulation {
+    /// a chain with only the genesis block of the network
+    pub fn new(network: Network) -> Simulation {
+        let genesis = genesis_block(network);
+        let trunk = Arc::new(TestTrunk::new());
+        trunk.extend(&genesis.header);
+        // coinbase outputs are burned
+        let miner = Address::p2wsh(&Builder::new().push_opcode(all::OP_RETURN).into_script(), network);
+        Simulation {
+            trunk,
+            miner,
+            state: Mutex::new(State { blocks: vec!(genesis), mempool: Arc::new(Mutex::new(Vec::new())), store: None, nonce: 0 })
+        }
+    }
+
+    pub fn trunk(&self) -> Arc<TestTrunk> {
+        self.trunk.clone()
+    }
+
+    /// height of the tip
+    pub fn height(&self) -> u32 {
+        self.trunk.len() - 1
+    }
+
+    /// transactions waiting for the next block
+    pub fn mempool(&self) -> Vec<Transaction> {
+        self.state.lock().unwrap().mempool.lock().unwrap().clone()
+    }
+
+    /// drive the store, blocks mined so far are connected,
+    /// transactions sent by the store go to the mempool
+    pub fn attach(&self, store: SharedContentStore) -> Result<(), Error> {
+        let mut state = self.state.lock().map_err(|_| Error::Lock("simulation"))?;
+        let (sender, receiver) = mpsc::sync_channel(100);
+        let mempool = state.mempool.clone();
+        thread::Builder::new().name("simulated mempool".to_string()).spawn(move || {
+            while let Ok(msg) = receiver.recv() {
+                if let PeerMessage::Outgoing(NetworkMessage::Tx(tx)) = msg {
+                    info!("simulated mempool accepted {}", tx.txid());
+                    mempool.lock().unwrap().push(tx);
+                }
+            }
+        })?;
+        {
+            let mut store = store.write().map_err(|_| Error::Lock("content store"))?;
+            store.set_tx_sender(PeerMessageSender::new(sender));
+            for (height, block) in state.blocks.iter().enumerate() {
+                store.add_header(height as u32, &block.header)?;
+                store.block_connected(block, height as u32)?;
+            }
+        }
+        state.store = Some(store);
+        Ok(())
+    }
+
+    /// queue a transaction paying amount to address, it confirms with the next mined block
+    pub fn send(&self, address: &Address, amount: u64) -> sha256d::Hash {
+        let mut state = self.state.lock().unwrap();
+        state.nonce += 1;
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn {
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                previous_output: OutPoint { txid: sha256d::Hash::hash(&state.nonce.to_le_bytes()), vout: 0 },
+                script_sig: Script::new(),
+            }),
+            output: vec!(TxOut {
+                value: amount,
+                script_pubkey: address.script_pubkey(),
+            }),
+        };
+        let txid = tx.txid();
+        state.mempool.lock().unwrap().push(tx);
+        txid
+    }
+
+    /// mine n blocks, the first one confirms the mempool
+    pub fn mine(&self, n: u32) -> Result<Vec<sha256d::Hash>, Error> {
+        let mut state = self.state.lock().map_err(|_| Error::Lock("simulation"))?;
+        let mut mined = Vec::new();
+        for _ in 0..n {
+            let txs = state.mempool.lock().unwrap().drain(..).collect::<Vec<_>>();
+            mined.push(self.mine_block(&mut state, txs)?);
+        }
+        Ok(mined)
+    }
+
+    /// replace the top k blocks with k + 1 empty blocks,
+    /// transactions of the replaced blocks go back to the mempool
+    pub fn reorg(&self, k: u32) -> Result<Vec<sha256d::Hash>, Error> {
+        let mut state = self.state.lock().map_err(|_| Error::Lock("simulation"))?;
+        if k as usize >= state.blocks.len() {
+            return Err(Error::InvalidArgument("can not re-org the genesis block"));
+        }
+        for _ in 0..k {
+            let block = state.blocks.pop().expect("checked above");
+            self.trunk.unwind();
+            if let Some(ref store) = state.store {
+                store.write().map_err(|_| Error::Lock("content store"))?.unwind_tip(&block.header)?;
+            }
+            let mut mempool = state.mempool.lock().unwrap();
+            for tx in block.txdata.into_iter().skip(1).rev() {
+                mempool.insert(0, tx);
+            }
+        }
+        let mut mined = Vec::new();
+        for _ in 0..k + 1 {
+            mined.push(self.mine_block(&mut state, vec!())?);
+        }
+        Ok(mined)
+    }
+
+    fn mine_block(&self, state: &mut State, txs: Vec<Transaction>) -> Result<sha256d::Hash, Error> {
+        let height = self.trunk.len();
+        let mut block = mine(&state.blocks.last().expect("genesis").bitcoin_hash(), height, &self.miner);
+        for tx in txs {
+            add_tx(&mut block, tx);
+        }
+        // blocks replacing others at the same height must differ
+        state.nonce += 1;
+        block.header.nonce = state.nonce;
+        self.trunk.extend(&block.header);
+        if let Some(ref store) = state.store {
+            let mut store = store.write().map_err(|_| Error::Lock("content store"))?;
+            store.add_header(height, &block.header)?;
+            store.block_connected(&block, height)?;
+        }
+        let hash = block.bitcoin_hash();
+        state.blocks.push(block);
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use bitcoin::Network;
+
+    use crate::testutil::new_store;
+
+    use super::Simulation;
+
+    #[test]
+    fn deposit_and_reorg() {
+        let simulation = Simulation::new(Network::Testnet);
+        let store = Arc::new(RwLock::new(new_store(simulation.trunk())));
+        simulation.attach(store.clone()).unwrap();
+        assert_eq!(simulation.height(), 0);
+
+        let deposit = store.write().unwrap().deposit_address();
+        simulation.send(&deposit, 100000);
+        assert_eq!(simulation.mempool().len(), 1);
+        let mined = simulation.mine(2).unwrap();
+        assert!(simulation.mempool().is_empty());
+        assert_eq!(store.read().unwrap().balance()[0], 100000);
+        assert_eq!(store.read().unwrap().get_tip(), Some(mined[1]));
+
+        let replaced = simulation.reorg(2).unwrap();
+        assert_eq!(simulation.height(), 3);
+        assert_eq!(simulation.mempool().len(), 1);
+        assert_eq!(store.read().unwrap().get_tip(), Some(replaced[2]));
+
+        simulation.mine(1).unwrap();
+        assert_eq!(store.read().unwrap().balance()[0], 100000);
+        assert!(simulation.reorg(5).is_err());
+    }
+}
@@ -17,9 +17,11 @@
 
 //! store
 
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use bitcoin::{Address, BitcoinHash, Block, BlockHeader, PublicKey, Script, Transaction};
+use bitcoin::{Address, BitcoinHash, Block, BlockHeader, OutPoint, PublicKey, Script, Transaction};
 use bitcoin::{
     blockdata::{
         opcodes::all,
@@ -28,38 +30,123 @@ use bitcoin::{
     network::constants::Network,
 };
 use bitcoin::network::message::NetworkMessage;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::util::psbt::PartiallySignedTransaction;
 use bitcoin_hashes::{sha256, sha256d};
-use log::{debug, info};
+use bitcoin_wallet::account::Unlocker;
+use log::{debug, info, warn};
 use murmel::p2p::{PeerMessage, PeerMessageSender};
 
-use crate::db::SharedDB;
+use crate::bip47::PaymentCode;
+use crate::consolidate::{ConsolidationEvent, ConsolidationPolicy};
+use crate::db::{SharedDB, TX};
+use crate::history::{self, HistoryRecord};
 use crate::error::Error;
+use crate::imported::{self, ImportedKey};
+use crate::publish::Publisher;
+use crate::secret::{Secret, SecretMaster};
 use crate::trunk::Trunk;
-use crate::wallet::Wallet;
+use crate::vault::{LOCKTIME_THRESHOLD, MAX_DELAY, Vault, VaultKeys, VaultState};
+use crate::wallet::{AddressInfo, ExternalInput, FeeLimits, TransactionInfo, TxOptions, Wallet};
+use crate::watch::Watched;
+use crate::webhook::{Notifier, WalletEvent};
 
 pub type SharedContentStore = Arc<RwLock<ContentStore>>;
 
+/// a header tip older than this in seconds means more blocks are to be downloaded
+pub const MAX_TIP_AGE: u64 = 24 * 60 * 60;
+
+/// peer addresses kept if the db exceeds the storage quota
+const KEPT_ADDRESSES: usize = 100;
+
+/// progress of a seed rotation
+#[derive(Clone, Debug, PartialEq)]
+pub enum RotationEvent {
+    /// available coins of the retiring wallet were sent to the new one
+    Swept { txid: sha256d::Hash, amount: u64 },
+    /// coins of the retiring wallet that are still locked
+    Locked { amount: u64 },
+    /// locked coins of the retiring wallet became available, sweep them with its passphrase
+    Sweepable { amount: u64 },
+    /// the retiring wallet is empty and was removed
+    Retired,
+}
+
 /// the distributed content storage
 pub struct ContentStore {
     trunk: Arc<dyn Trunk + Send + Sync>,
     db: SharedDB,
     wallet: Wallet,
+    /// wallet replaced by a seed rotation, until its coins are swept
+    retiring: Option<Wallet>,
+    rotation_events: Vec<RotationEvent>,
+    vaults: Vec<Vault>,
+    /// scripts watched without keys
+    watched: Vec<Watched>,
     txout: Option<PeerMessageSender<NetworkMessage>>,
-    stopped: bool
+    stopped: bool,
+    /// height of the last processed block
+    processed: Option<u32>,
+    /// blocks processed since created
+    processed_blocks: u32,
+    /// blocks to process before stopping
+    block_budget: Option<u32>,
+    /// bounds of the fee rate of our transactions
+    min_fee_rate: Option<u64>,
+    max_fee_rate: Option<u64>,
+    /// limits of the fee of our transactions unless the caller chooses others
+    fee_limits: FeeLimits,
+    /// spend unconfirmed change unless the caller chooses otherwise
+    spend_unconfirmed_change: bool,
+    consolidation_policy: Option<ConsolidationPolicy>,
+    /// held to sign consolidations while they are enabled
+    consolidation_passphrase: Option<Secret>,
+    consolidation_events: Vec<ConsolidationEvent>,
+    /// reports wallet events to a webhook
+    notifier: Option<Notifier>,
+    /// publishes wallet and chain events on a local socket
+    publisher: Option<Publisher>,
+    /// bytes the db may use
+    storage_quota: Option<u64>,
+    over_storage_quota: bool,
 }
 
 impl ContentStore {
     /// new content store
     pub fn new(db: SharedDB, trunk: Arc<dyn Trunk + Send + Sync>, wallet: Wallet) -> Result<ContentStore, Error> {
+        let vaults = db.lock().unwrap().transaction().read_vaults()?;
+        let watched = db.lock().unwrap().transaction().read_watched()?;
+        let processed = db.lock().unwrap().transaction().read_processed()?.and_then(|block| trunk.get_height(&block));
         Ok(ContentStore {
             trunk,
             db,
             wallet,
+            retiring: None,
+            rotation_events: Vec::new(),
+            vaults,
+            watched,
             txout: None,
-            stopped: false
+            stopped: false,
+            processed,
+            processed_blocks: 0,
+            block_budget: None,
+            min_fee_rate: None,
+            max_fee_rate: None,
+            fee_limits: FeeLimits::default(),
+            spend_unconfirmed_change: false,
+            consolidation_policy: None,
+            consolidation_passphrase: None,
+            consolidation_events: Vec::new(),
+            notifier: None,
+            publisher: None,
+            storage_quota: None,
+            over_storage_quota: false,
         })
     }
 
+    /// stop processing blocks. A block being processed is finished first as it holds the store,
+    /// later blocks are left to the next start which resumes after the last processed block
     pub fn set_stopped(&mut self, stopped: bool) {
         self.stopped = stopped;
     }
@@ -68,10 +155,143 @@ impl ContentStore {
         self.stopped
     }
 
+    /// refuse transactions paying a fee rate outside the bounds, None for no bound
+    pub fn set_fee_rate_bounds(&mut self, min_fee_rate: Option<u64>, max_fee_rate: Option<u64>) {
+        self.min_fee_rate = min_fee_rate;
+        self.max_fee_rate = max_fee_rate;
+    }
+
+    /// refuse to sign transactions with a fee above the limits unless the caller allows it
+    pub fn set_fee_limits(&mut self, fee_limits: FeeLimits) {
+        self.fee_limits = fee_limits;
+    }
+
+    /// spend unconfirmed change of our own transactions if confirmed coins are insufficient, unless the caller chooses otherwise
+    pub fn set_spend_unconfirmed_change(&mut self, spend: bool) {
+        self.spend_unconfirmed_change = spend;
+    }
+
+    // the caller's options with the configured fee limits and change spending if it did not choose others
+    fn limited(&self, options: &TxOptions) -> TxOptions {
+        let mut options = options.clone();
+        if options.fee_limits.is_none() {
+            options.fee_limits = Some(self.fee_limits);
+        }
+        if options.spend_unconfirmed_change.is_none() {
+            options.spend_unconfirmed_change = Some(self.spend_unconfirmed_change);
+        }
+        options
+    }
+
+    fn check_fee_rate(&self, fee_per_vbyte: u64) -> Result<(), Error> {
+        if self.min_fee_rate.map(|min| fee_per_vbyte < min).unwrap_or(false) {
+            return Err(Error::InvalidArgument("fee rate is below the configured minimum"));
+        }
+        if self.max_fee_rate.map(|max| fee_per_vbyte > max).unwrap_or(false) {
+            return Err(Error::InvalidArgument("fee rate exceeds the configured maximum"));
+        }
+        Ok(())
+    }
+
+    /// stop after max_blocks more blocks, None for no limit
+    pub fn set_block_budget(&mut self, max_blocks: Option<u32>) {
+        self.block_budget = max_blocks;
+        if max_blocks == Some(0) {
+            self.stopped = true;
+        }
+    }
+
+    /// cap the size of the db in bytes, None for no limit
+    pub fn set_storage_quota(&mut self, max_storage: Option<u64>) {
+        self.storage_quota = max_storage;
+    }
+
+    // prune peer addresses if the db outgrew the quota, wallet data is never dropped. True if still over it
+    fn enforce_storage_quota(quota: Option<u64>, was_over: bool, tx: &mut TX) -> Result<bool, Error> {
+        if let Some(quota) = quota {
+            if tx.used_size()? > quota {
+                let pruned = tx.prune_addresses(KEPT_ADDRESSES)?;
+                if tx.used_size()? > quota {
+                    if !was_over {
+                        warn!("wallet db exceeds the storage quota of {} bytes", quota);
+                    }
+                    return Ok(true);
+                }
+                info!("pruned {} peer addresses to stay within the storage quota", pruned);
+            }
+        }
+        Ok(false)
+    }
+
+    /// true if the db could not be kept within the storage quota
+    pub fn is_over_storage_quota(&self) -> bool {
+        self.over_storage_quota
+    }
+
+    /// blocks processed since created
+    pub fn processed_blocks(&self) -> u32 {
+        self.processed_blocks
+    }
+
+    /// height of the last processed block
+    pub fn processed_height(&self) -> Option<u32> {
+        self.processed
+    }
+
+    /// height of the header tip
+    pub fn header_height(&self) -> u32 {
+        self.trunk.get_tip().and_then(|tip| self.trunk.get_height(&tip.bitcoin_hash())).unwrap_or(0)
+    }
+
+    /// processed up to the header tip and that is at most MAX_TIP_AGE old
+    pub fn is_synced(&self) -> bool {
+        match self.trunk.get_tip() {
+            Some(tip) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                self.processed == self.trunk.get_height(&tip.bitcoin_hash()) && (tip.time as u64) + MAX_TIP_AGE >= now
+            }
+            None => false
+        }
+    }
+
     pub fn set_tx_sender(&mut self, txout: PeerMessageSender<NetworkMessage>) {
         self.txout = Some(txout);
     }
 
+    /// report wallet events to a webhook, None to stop reporting
+    pub fn set_notifier(&mut self, notifier: Option<Notifier>) {
+        self.notifier = notifier;
+    }
+
+    /// publish wallet and chain events on a local socket, None to stop publishing
+    pub fn set_publisher(&mut self, publisher: Option<Publisher>) {
+        self.publisher = publisher;
+    }
+
+    fn notify(&self, event: WalletEvent) {
+        if let Some(ref publisher) = self.publisher {
+            publisher.publish(event.clone());
+        }
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify(event);
+        }
+    }
+
+    // blocks and headers are of no interest to webhooks
+    fn publish(&self, event: WalletEvent) {
+        if let Some(ref publisher) = self.publisher {
+            publisher.publish(event);
+        }
+    }
+
+    // hand one of our transactions to the peers
+    fn broadcast(&self, transaction: &Transaction) {
+        if let Some(ref txout) = self.txout {
+            txout.send(PeerMessage::Outgoing(NetworkMessage::Tx(transaction.clone())));
+            self.notify(WalletEvent::Broadcast { txid: transaction.txid() });
+        }
+    }
+
     pub fn balance(&self) -> Vec<u64> {
         vec!(self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)))
     }
@@ -81,17 +301,215 @@ impl ContentStore {
             .next_key().expect("can not generate receiver address in 0/0").address.clone()
     }
 
-    pub fn fund(&mut self, id: &sha256::Hash, term: u16, amount: u64, fee_per_vbyte: u64, passpharse: String) -> Result<(Transaction, PublicKey, u64), Error> {
-        let (transaction, funder, fee) = self.wallet.fund(id, term, passpharse, fee_per_vbyte, amount, self.trunk.clone(),
+    /// addresses derived in the receive and change chains of an account
+    pub fn list_addresses(&self, account: u32) -> Result<Vec<AddressInfo>, Error> {
+        self.wallet.list_addresses(account)
+    }
+
+    /// a transaction of the wallet with its effect on our balance, None if not stored
+    pub fn get_transaction(&self, txid: &sha256d::Hash) -> Result<Option<TransactionInfo>, Error> {
+        let transaction = self.db.lock().unwrap().transaction().read_txout(txid)?;
+        let mut stored = match transaction {
+            Some(ref transaction) => self.stored_inputs(transaction)?,
+            None => HashMap::new()
+        };
+        if let Some(transaction) = transaction {
+            stored.insert(transaction.txid(), transaction);
+        }
+        Ok(self.wallet.transaction_info(txid, |t| stored.get(t).cloned(), |h| self.trunk.get_height(h)))
+    }
+
+    /// decode a transaction not necessarily known to the wallet, marking what is ours
+    pub fn decode_tx(&self, transaction: &Transaction) -> Result<TransactionInfo, Error> {
+        let stored = self.stored_inputs(transaction)?;
+        Ok(self.wallet.decode(transaction, |t| stored.get(t).cloned()))
+    }
+
+    // own transactions spent by the inputs of a transaction
+    fn stored_inputs(&self, transaction: &Transaction) -> Result<HashMap<sha256d::Hash, Transaction>, Error> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction();
+        let mut stored = HashMap::new();
+        for input in transaction.input.iter() {
+            if let Some(previous) = tx.read_txout(&input.previous_output.txid)? {
+                stored.insert(previous.txid(), previous);
+            }
+        }
+        Ok(stored)
+    }
+
+    /// label a transaction for the history export, an empty label removes it
+    pub fn set_label(&mut self, txid: &sha256d::Hash, label: &str) -> Result<(), Error> {
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction();
+        tx.store_label(txid, label)?;
+        tx.commit();
+        Ok(())
+    }
+
+    /// confirmed transactions with a block time stamp in [from, to] with running balance and labels,
+    /// followed by those of each watched script
+    pub fn history_records(&self, from: Option<u32>, to: Option<u32>) -> Result<Vec<HistoryRecord>, Error> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction();
+        let labels = tx.read_labels()?;
+        let mut records = history::records(&tx.read_history()?, &labels, from, to);
+        for watched in self.watched.iter() {
+            records.extend(history::watched_records(watched, |height| self.trunk.get_header_for_height(height).map(|h| h.time), &labels, from, to));
+        }
+        Ok(records)
+    }
+
+    /// a cached exchange rate, see rates::rate_slot
+    pub fn cached_rate(&self, currency: &str, slot: u32) -> Result<Option<f64>, Error> {
+        let rate = self.db.lock().unwrap().transaction().read_rate(currency, slot)?;
+        Ok(rate)
+    }
+
+    pub fn cache_rate(&mut self, currency: &str, slot: u32, rate: f64) -> Result<(), Error> {
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction();
+        tx.store_rate(currency, slot, rate)?;
+        tx.commit();
+        Ok(())
+    }
+
+    /// watch a script without keys, its coins are tracked from the next processed block, or from the wallet birth after a rescan
+    pub fn watch_script(&mut self, script: Script, address: Option<Address>) -> Result<(), Error> {
+        if self.watched.iter().any(|w| w.script == script) {
+            return Ok(());
+        }
+        let watched = Watched::new(script, address);
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction();
+        tx.store_watched(&watched)?;
+        tx.commit();
+        self.watched.push(watched);
+        Ok(())
+    }
+
+    /// scripts watched without keys
+    pub fn watched(&self) -> Vec<Watched> {
+        self.watched.clone()
+    }
+
+    /// import a WIF private key, its coins are tracked from the next processed block, or from the wallet birth after a rescan
+    pub fn import_wif(&mut self, passphrase: &Secret, wif: &str) -> Result<Vec<ImportedKey>, Error> {
+        let added = self.wallet.import_wif(passphrase.as_str(), wif)?;
+        self.store_imported()?;
+        Ok(added)
+    }
+
+    /// import the first keys of an Electrum seed
+    pub fn import_electrum(&mut self, passphrase: &Secret, words: &str, seed_passphrase: &str) -> Result<Vec<ImportedKey>, Error> {
+        let added = self.wallet.import_electrum(passphrase.as_str(), words, seed_passphrase)?;
+        self.store_imported()?;
+        Ok(added)
+    }
+
+    /// keys imported from other wallets with their coins
+    pub fn imported(&self) -> Vec<ImportedKey> {
+        self.wallet.imported.keys.clone()
+    }
+
+    /// sweep coins of imported keys with public key, or of all imported keys if None, to a deposit address.
+    /// Coins are those found by block processing and additional coins of the keys given as script, outpoint and value
+    pub fn sweep_imported(&mut self, passphrase: &Secret, public: Option<&PublicKey>, additional: Vec<(Script, OutPoint, u64)>, fee_per_vbyte: u64) -> Result<(Transaction, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let keys = self.wallet.imported.keys.iter().filter(|k| public.map(|p| k.public == *p).unwrap_or(true)).collect::<Vec<_>>();
+        let mut coins = Vec::new();
+        for key in &keys {
+            for coin in key.watched.coins.iter().filter(|c| c.spent.is_none()) {
+                coins.push(((*key).clone(), coin.outpoint, coin.value));
+            }
+        }
+        for (script, outpoint, value) in additional {
+            if coins.iter().any(|(_, o, _)| *o == outpoint) {
+                continue;
+            }
+            if let Some(key) = keys.iter().find(|k| k.watched.script == script) {
+                coins.push(((*key).clone(), outpoint, value));
+            }
+        }
+        let master = {
+            let unlocker = Unlocker::new_for_master(&self.wallet.master, passphrase.as_str())?;
+            SecretMaster::from(*unlocker.master_private())
+        };
+        let address = self.deposit_address();
+        let (transaction, fee) = imported::sweep(coins.as_slice(), &master, &address, fee_per_vbyte)?;
+        self.fee_limits.check(fee, coins.iter().map(|(_, _, v)| *v).sum())?;
+        {
+            let mut db = self.db.lock().unwrap();
+            let mut tx = db.transaction();
+            tx.store_account(&self.wallet.master.get((0, 0)).unwrap())?;
+            tx.store_txout(&transaction, None)?;
+            tx.commit();
+        }
+        self.broadcast(&transaction);
+        self.wallet.coins.process_unconfirmed_transaction(&mut self.wallet.master, &transaction);
+        info!("swept {} satoshis of imported keys", transaction.output[0].value);
+        Ok((transaction, fee))
+    }
+
+    fn store_imported(&self) -> Result<(), Error> {
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction();
+        tx.store_imported(&self.wallet.imported)?;
+        tx.commit();
+        Ok(())
+    }
+
+    /// our BIP47 payment code
+    pub fn payment_code(&self, passphrase: &str) -> Result<PaymentCode, Error> {
+        self.wallet.payment_code(passphrase)
+    }
+
+    /// the address of the index-th payment to the owner of a payment code
+    pub fn payment_address(&self, passphrase: &str, theirs: &PaymentCode, index: u32) -> Result<Address, Error> {
+        self.wallet.payment_address(passphrase, theirs, index)
+    }
+
+    /// send our payment code to the owner of theirs in a notification transaction, before paying to their code
+    pub fn notify_payment_code(&mut self, passphrase: &Secret, theirs: &PaymentCode, fee_per_vbyte: u64, options: &TxOptions) -> Result<(Transaction, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let options = self.limited(options);
+        let (transaction, fee) = self.wallet.notify_payment_code(passphrase, theirs, fee_per_vbyte, &options, self.trunk.clone())?;
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction();
+        tx.store_account(&self.wallet.master.get((0, 1)).unwrap())?;
+        tx.store_txout(&transaction, None)?;
+        tx.commit();
+        self.broadcast(&transaction);
+        info!("notified {} of our payment code", theirs);
+        Ok((transaction, fee))
+    }
+
+    /// watch our notification address and import keys receiving payments of senders that notified us.
+    /// Until the wallet stops, senders notifying us and keys beyond those paid to are imported while processing blocks.
+    /// Payments that arrived before their keys were imported are found by a rescan
+    pub fn receive_payment_codes(&mut self, passphrase: &Secret) -> Result<Vec<ImportedKey>, Error> {
+        let notifications = self.db.lock().unwrap().transaction().read_notifications()?;
+        let added = self.wallet.receive_payment_codes(passphrase.as_str(), notifications.as_slice())?;
+        self.store_imported()?;
+        Ok(added)
+    }
+
+    /// payment codes of senders that notified us
+    pub fn payment_code_senders(&self) -> Vec<PaymentCode> {
+        self.wallet.payment_code_senders()
+    }
+
+    pub fn fund(&mut self, id: &sha256::Hash, term: u16, amount: u64, fee_per_vbyte: u64, passphrase: &Secret, options: &TxOptions) -> Result<(Transaction, PublicKey, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let options = self.limited(options);
+        let (transaction, funder, fee) = self.wallet.fund(id, term, passphrase, fee_per_vbyte, amount, &options, self.trunk.clone(),
                                                           |pk, term| Self::funding_script(pk, term.unwrap()))?;
         let mut db = self.db.lock().unwrap();
         let mut tx = db.transaction();
         tx.store_account(&self.wallet.master.get((1, 0)).unwrap())?;
         tx.store_txout(&transaction, Some((&funder, id, term))).expect("can not store outgoing transaction");
         tx.commit();
-        if let Some(ref txout) = self.txout {
-            txout.send(PeerMessage::Outgoing(NetworkMessage::Tx(transaction.clone())));
-        }
+        self.broadcast(&transaction);
         info!("Wallet balance: {} satoshis {} available", self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)));
         Ok((transaction, funder, fee))
     }
@@ -110,20 +528,355 @@ impl ContentStore {
         Address::p2wsh(&Self::funding_script(tweaked, term), Network::Bitcoin)
     }
 
-    pub fn withdraw(&mut self, passphrase: String, address: Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<(Transaction, u64), Error> {
-        let (transaction, fee) = self.wallet.withdraw(passphrase, address, fee_per_vbyte, amount, self.trunk.clone())?;
+    pub fn withdraw(&mut self, passphrase: &Secret, address: Address, fee_per_vbyte: u64, amount: Option<u64>, options: &TxOptions) -> Result<(Transaction, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let options = self.limited(options);
+        let (transaction, fee) = self.wallet.withdraw(passphrase, address, fee_per_vbyte, amount, &options, self.trunk.clone())?;
         let mut db = self.db.lock().unwrap();
         let mut tx = db.transaction();
         tx.store_account(&self.wallet.master.get((0, 1)).unwrap())?;
         tx.store_txout(&transaction, None).expect("can not store outgoing transaction");
         tx.commit();
-        if let Some(ref txout) = self.txout {
-            txout.send(PeerMessage::Outgoing(NetworkMessage::Tx(transaction.clone())));
-        }
+        self.broadcast(&transaction);
         info!("Wallet balance: {} satoshis {} available", self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)));
         Ok((transaction, fee))
     }
 
+    /// a PSBT paying amount with our coins and the external inputs, for the other party to complete and broadcast
+    pub fn collaborate(&mut self, passphrase: &Secret, address: Address, fee_per_vbyte: u64, amount: u64, external: &[ExternalInput], options: &TxOptions) -> Result<(PartiallySignedTransaction, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let options = self.limited(options);
+        let (psbt, fee) = self.wallet.collaborate(passphrase, address, fee_per_vbyte, amount, external, &options, self.trunk.clone())?;
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction();
+        tx.store_account(&self.wallet.master.get((0, 1)).unwrap())?;
+        tx.commit();
+        Ok((psbt, fee))
+    }
+
+    /// continue with the master key encrypted with an other passphrase, if it is the key of our wallet.
+    /// Enabled consolidations sign with the new passphrase from then on
+    pub fn set_encrypted(&mut self, public_master_key: &ExtendedPubKey, encrypted: &[u8], passphrase: &Secret) {
+        if self.wallet.master_public() == public_master_key {
+            self.wallet.set_encrypted(encrypted);
+            if self.consolidation_passphrase.is_some() {
+                self.consolidation_passphrase = Some(passphrase.clone());
+            }
+        }
+    }
+
+    pub fn set_retiring(&mut self, retiring: Wallet) {
+        self.retiring = Some(retiring);
+    }
+
+    /// replace the wallet by new, the current one is retiring until its coins are swept and its vaults are closed.
+    /// Imported keys are encrypted under the current seed, so they must be swept before and are dropped.
+    /// sealed is the encrypted current seed as sealed by the key storage
+    pub fn rotate(&mut self, new: Wallet, passphrase: &str, sealed: &[u8]) -> Result<(), Error> {
+        if self.retiring.is_some() {
+            return Err(Error::Unsupported("a seed rotation is in progress"));
+        }
+        if self.wallet.imported.balance() > 0 {
+            return Err(Error::Unsupported("sweep imported keys before rotating the seed"));
+        }
+        Unlocker::new_for_master(&self.wallet.master, passphrase)?;
+        {
+            let mut db = self.db.lock().unwrap();
+            let mut tx = db.transaction();
+            tx.drop_imported()?;
+            tx.retire_master(&self.wallet.master, sealed)?;
+            tx.store_master(&new.master)?;
+            tx.store_coins(new.coins())?;
+            // keys of open vaults stay with the retiring seed
+            for vault in self.vaults.iter_mut().filter(|v| v.state != VaultState::Closed) {
+                vault.retired = true;
+                tx.store_vault(vault)?;
+            }
+            tx.commit();
+        }
+        let retiring = std::mem::replace(&mut self.wallet, new);
+        info!("seed rotated, retiring wallet holds {} satoshis", retiring.balance());
+        self.retiring = Some(retiring);
+        Ok(())
+    }
+
+    /// sweep available coins of the retiring wallet to the current one
+    pub fn sweep_retiring(&mut self, passphrase: &Secret, fee_per_vbyte: u64) -> Result<(), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let trunk = self.trunk.clone();
+        let options = self.limited(&TxOptions::default());
+        let retiring = self.retiring.as_mut().ok_or(Error::InvalidArgument("no seed rotation in progress"))?;
+        let available = retiring.available_balance(trunk.len(), |h| trunk.get_height(h));
+        let locked = retiring.balance().saturating_sub(available);
+        if available > 0 {
+            let address = self.wallet.master.get_mut((0, 0)).expect("can not find 0/0 account")
+                .next_key().expect("can not generate receiver address in 0/0").address.clone();
+            let (transaction, fee) = retiring.withdraw(passphrase, address, fee_per_vbyte, Some(available), &options, trunk.clone())?;
+            {
+                let mut db = self.db.lock().unwrap();
+                let mut tx = db.transaction();
+                tx.store_retiring_account(&retiring.master.get((0, 1)).unwrap())?;
+                tx.store_account(&self.wallet.master.get((0, 0)).unwrap())?;
+                tx.store_txout(&transaction, None)?;
+                tx.commit();
+            }
+            self.broadcast(&transaction);
+            self.wallet.coins.process_unconfirmed_transaction(&mut self.wallet.master, &transaction);
+            info!("swept {} satoshis of the retiring wallet", available - fee);
+            self.rotation_events.push(RotationEvent::Swept { txid: transaction.txid(), amount: available - fee });
+        }
+        if locked > 0 {
+            self.rotation_events.push(RotationEvent::Locked { amount: locked });
+        }
+        Ok(())
+    }
+
+    /// consolidate small coins by policy, None to never consolidate
+    pub fn set_consolidation_policy(&mut self, policy: Option<ConsolidationPolicy>) {
+        self.consolidation_policy = policy;
+    }
+
+    /// allow consolidations to sign with passphrase, None to forget it
+    pub fn set_consolidation_passphrase(&mut self, passphrase: Option<Secret>) -> Result<(), Error> {
+        if let Some(ref passphrase) = passphrase {
+            Unlocker::new_for_master(&self.wallet.master, passphrase.as_str())?;
+        }
+        self.consolidation_passphrase = passphrase;
+        Ok(())
+    }
+
+    /// merge small coins if the policy allows at the current low priority fee rate
+    pub fn consolidate(&mut self, fee_per_vbyte: u64) -> Result<Option<ConsolidationEvent>, Error> {
+        let (policy, passphrase) = match (self.consolidation_policy, self.consolidation_passphrase.clone()) {
+            (Some(policy), Some(passphrase)) => (policy, passphrase),
+            _ => return Ok(None)
+        };
+        let small = self.wallet.small_coins(policy.max_value, self.trunk.as_ref());
+        let merged = match policy.select(fee_per_vbyte, small.as_slice()) {
+            Some(merged) => merged,
+            None => return Ok(None)
+        };
+        let options = self.limited(&TxOptions::default());
+        let result = self.check_fee_rate(fee_per_vbyte)
+            .and_then(|_| self.wallet.consolidate(&passphrase, merged.as_slice(), fee_per_vbyte, &options, self.trunk.clone()));
+        let (transaction, fee) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.consolidation_events.push(ConsolidationEvent::Failed { reason: e.to_string() });
+                return Err(e);
+            }
+        };
+        {
+            let mut db = self.db.lock().unwrap();
+            let mut tx = db.transaction();
+            tx.store_account(&self.wallet.master.get((0, 1)).unwrap())?;
+            tx.store_txout(&transaction, None)?;
+            tx.commit();
+        }
+        self.broadcast(&transaction);
+        self.wallet.coins.process_unconfirmed_transaction(&mut self.wallet.master, &transaction);
+        let amount = transaction.output[0].value;
+        info!("consolidated {} coins into {} satoshis", merged.len(), amount);
+        let event = ConsolidationEvent::Consolidated { txid: transaction.txid(), merged, amount, fee };
+        self.consolidation_events.push(event.clone());
+        Ok(Some(event))
+    }
+
+    /// consolidations since the last call
+    pub fn consolidation_events(&mut self) -> Vec<ConsolidationEvent> {
+        std::mem::replace(&mut self.consolidation_events, Vec::new())
+    }
+
+    /// progress of a seed rotation since the last call
+    pub fn rotation_events(&mut self) -> Vec<RotationEvent> {
+        std::mem::replace(&mut self.rotation_events, Vec::new())
+    }
+
+    /// all vaults, including closed ones
+    pub fn vaults(&self) -> Vec<Vault> {
+        self.vaults.clone()
+    }
+
+    /// output descriptors of open vaults of the seed passphrase unlocks, the current or the retiring one,
+    /// to find their coins with the seed
+    pub fn vault_descriptors(&self, passphrase: &Secret) -> Result<Vec<String>, Error> {
+        let retired = match self.wallet.vault_keys(passphrase.as_str(), 0) {
+            Ok(_) => false,
+            Err(e) => match self.retiring {
+                Some(ref retiring) if retiring.vault_keys(passphrase.as_str(), 0).is_ok() => true,
+                _ => return Err(e)
+            }
+        };
+        let mut descriptors = Vec::new();
+        for vault in self.vaults.iter().filter(|v| v.retired == retired) {
+            let keys = match self.retiring {
+                Some(ref retiring) if retired => retiring.vault_keys(passphrase.as_str(), vault.index)?,
+                _ => self.wallet.vault_keys(passphrase.as_str(), vault.index)?
+            };
+            descriptors.extend(vault.descriptor(&keys));
+        }
+        Ok(descriptors)
+    }
+
+    /// lock amount in a new vault until block height lock_time, its coins can be spent
+    /// delay blocks after unvaulting. Unvaulting is cancelled with cancel_key if given, else with our own key
+    pub fn vault(&mut self, passphrase: &Secret, amount: u64, lock_time: u32, delay: u16, fee_per_vbyte: u64, cancel_key: Option<PublicKey>) -> Result<(Transaction, u64), Error> {
+        if lock_time >= LOCKTIME_THRESHOLD || lock_time < self.trunk.len() {
+            return Err(Error::InvalidArgument("vault lock time must be a future block height"));
+        }
+        if delay == 0 || delay > MAX_DELAY {
+            return Err(Error::InvalidArgument("unvault delay must be between 1 and 4320 blocks"));
+        }
+        let index = self.next_vault_index();
+        let address = self.wallet.vault_keys(passphrase.as_str(), index)?.vault_address(lock_time, self.network());
+        let (transaction, fee) = self.withdraw(passphrase, address.clone(), fee_per_vbyte, Some(amount), &TxOptions::default())?;
+        let vout = transaction.output.iter().position(|o| o.script_pubkey == address.script_pubkey()).expect("vault output") as u32;
+        self.add_vault(Vault {
+            outpoint: OutPoint { txid: transaction.txid(), vout },
+            value: transaction.output[vout as usize].value,
+            lock_time,
+            delay,
+            index,
+            cancel_key,
+            retired: false,
+            state: VaultState::Locked,
+            unvault: None,
+            unvault_block: None,
+        })?;
+        Ok((transaction, fee))
+    }
+
+    /// start unvaulting once the lock time passed.
+    /// A vault of the retiring seed is unlocked with retiring_passphrase
+    pub fn unvault(&mut self, passphrase: &Secret, retiring_passphrase: Option<&Secret>, vault: &OutPoint, fee_per_vbyte: u64) -> Result<(Transaction, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let mut vault = self.find_vault(vault)?;
+        if vault.lock_time >= self.trunk.len() {
+            return Err(Error::InvalidArgument("vault is still locked"));
+        }
+        let keys = self.vault_keys(passphrase, retiring_passphrase, &vault)?;
+        let (transaction, fee) = vault.unvault(&keys, fee_per_vbyte, self.network(), &self.fee_limits)?;
+        vault.state = VaultState::Unvaulting;
+        vault.unvault = Some(transaction.clone());
+        self.update_vault(vault, &transaction)?;
+        Ok((transaction, fee))
+    }
+
+    /// send unvaulting coins to a new vault locked until lock_time, possible until they are spent.
+    /// cancel_key is needed for a vault with its own cancel key, the new vault keeps it.
+    /// The new vault is of the current seed, a vault of the retiring seed is unlocked with retiring_passphrase
+    pub fn cancel_unvault(&mut self, passphrase: &Secret, retiring_passphrase: Option<&Secret>, vault: &OutPoint, lock_time: u32, fee_per_vbyte: u64, cancel_key: Option<&SecretKey>) -> Result<(Transaction, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        if lock_time >= LOCKTIME_THRESHOLD || lock_time < self.trunk.len() {
+            return Err(Error::InvalidArgument("vault lock time must be a future block height"));
+        }
+        let mut vault = self.find_vault(vault)?;
+        let index = self.next_vault_index();
+        let address = self.wallet.vault_keys(passphrase.as_str(), index)?.vault_address(lock_time, self.network());
+        let keys = self.vault_keys(passphrase, retiring_passphrase, &vault)?;
+        let (transaction, fee) = vault.cancel(&keys, cancel_key, &address, fee_per_vbyte, &self.fee_limits)?;
+        let delay = vault.delay;
+        let cancel_key = vault.cancel_key;
+        vault.state = VaultState::Closed;
+        self.update_vault(vault, &transaction)?;
+        self.add_vault(Vault {
+            outpoint: OutPoint { txid: transaction.txid(), vout: 0 },
+            value: transaction.output[0].value,
+            lock_time,
+            delay,
+            index,
+            cancel_key,
+            retired: false,
+            state: VaultState::Locked,
+            unvault: None,
+            unvault_block: None,
+        })?;
+        Ok((transaction, fee))
+    }
+
+    /// spend unvaulted coins to address once the unvault transaction is confirmed for the delay.
+    /// A vault of the retiring seed is unlocked with retiring_passphrase
+    pub fn withdraw_vault(&mut self, passphrase: &Secret, retiring_passphrase: Option<&Secret>, vault: &OutPoint, address: Address, fee_per_vbyte: u64) -> Result<(Transaction, u64), Error> {
+        self.check_fee_rate(fee_per_vbyte)?;
+        let mut vault = self.find_vault(vault)?;
+        let confirmed = vault.unvault_block.and_then(|b| self.trunk.get_height(&b));
+        match confirmed {
+            Some(height) if self.trunk.len() - height >= vault.delay as u32 => {}
+            _ => return Err(Error::InvalidArgument("unvaulted coins are not yet spendable"))
+        }
+        let keys = self.vault_keys(passphrase, retiring_passphrase, &vault)?;
+        let (transaction, fee) = vault.withdraw(&keys, &address, fee_per_vbyte, &self.fee_limits)?;
+        vault.state = VaultState::Closed;
+        self.update_vault(vault, &transaction)?;
+        Ok((transaction, fee))
+    }
+
+    fn network(&self) -> Network {
+        self.wallet.master.master_public().network
+    }
+
+    /// keys of a vault, those of the retiring seed if the vault was created before a seed rotation.
+    /// Vaults of the retiring seed are unlocked with retiring_passphrase, passphrase still has to unlock the current seed
+    fn vault_keys(&self, passphrase: &Secret, retiring_passphrase: Option<&Secret>, vault: &Vault) -> Result<VaultKeys, Error> {
+        if vault.retired {
+            let retiring = self.retiring.as_ref().ok_or(Error::Unsupported("the retired seed of the vault is gone"))?;
+            let retiring_passphrase = retiring_passphrase.ok_or(Error::InvalidArgument("the vault is of the retiring seed, its passphrase is needed"))?;
+            Unlocker::new_for_master(&self.wallet.master, passphrase.as_str())?;
+            retiring.vault_keys(retiring_passphrase.as_str(), vault.index)
+        } else {
+            self.wallet.vault_keys(passphrase.as_str(), vault.index)
+        }
+    }
+
+    /// vaults of the retiring seed are not closed
+    fn retired_vaults_open(&self) -> bool {
+        self.vaults.iter().any(|v| v.retired && v.state != VaultState::Closed)
+    }
+
+    fn next_vault_index(&self) -> u32 {
+        self.vaults.iter().map(|v| v.index + 1).max().unwrap_or(0)
+    }
+
+    fn find_vault(&self, outpoint: &OutPoint) -> Result<Vault, Error> {
+        self.vaults.iter().find(|v| v.outpoint == *outpoint).cloned().ok_or(Error::InvalidArgument("unknown vault"))
+    }
+
+    fn add_vault(&mut self, vault: Vault) -> Result<(), Error> {
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction();
+        tx.store_vault(&vault)?;
+        tx.commit();
+        self.vaults.push(vault);
+        Ok(())
+    }
+
+    /// store the changed vault and send the transaction spending it
+    fn update_vault(&mut self, vault: Vault, transaction: &Transaction) -> Result<(), Error> {
+        {
+            let mut db = self.db.lock().unwrap();
+            let mut tx = db.transaction();
+            tx.store_vault(&vault)?;
+            tx.store_txout(transaction, None)?;
+            tx.commit();
+        }
+        self.broadcast(&transaction);
+        if let Some(known) = self.vaults.iter_mut().find(|v| v.outpoint == vault.outpoint) {
+            *known = vault;
+        }
+        Ok(())
+    }
+
+    /// the header tip of the trunk with its height
+    pub fn tip_header(&self) -> Option<(u32, BlockHeader)> {
+        let tip = self.trunk.get_tip()?;
+        self.trunk.get_height(&tip.bitcoin_hash()).map(|height| (height, tip))
+    }
+
+    /// the trunk header at height
+    pub fn header_for_height(&self, height: u32) -> Option<BlockHeader> {
+        self.trunk.get_header_for_height(height)
+    }
+
     pub fn get_tip(&self) -> Option<sha256d::Hash> {
         if let Some(header) = self.trunk.get_tip() {
             return Some(header.bitcoin_hash());
@@ -131,165 +884,853 @@ impl ContentStore {
         None
     }
 
+    // deposits, confirmations of own transactions and matured coins of a processed block
+    fn wallet_events(&self, block: &Block, height: u32, confirmed: &HashSet<OutPoint>, available: &HashSet<OutPoint>, own: &HashSet<sha256d::Hash>) -> Vec<WalletEvent> {
+        let network = self.wallet.master.master_public().network;
+        let mut events = Vec::new();
+        for transaction in block.txdata.iter().filter(|t| own.contains(&t.txid())) {
+            events.push(WalletEvent::Confirmed { txid: transaction.txid(), height });
+        }
+        for (point, coin) in self.wallet.coins.confirmed() {
+            let derivation = &coin.derivation;
+            if !confirmed.contains(point) && !own.contains(&point.txid) && derivation.account == 0 && derivation.sub == 0 {
+                events.push(WalletEvent::Deposit {
+                    txid: point.txid,
+                    vout: point.vout,
+                    address: Address::from_script(&coin.output.script_pubkey, network).map(|a| a.to_string()),
+                    amount: coin.output.value,
+                    height,
+                });
+            }
+        }
+        let trunk = self.trunk.clone();
+        for (point, coin, _) in self.wallet.available_coins(trunk.len(), |h| trunk.get_height(h)) {
+            if confirmed.contains(&point) && !available.contains(&point) {
+                events.push(WalletEvent::Matured { txid: point.txid, vout: point.vout, amount: coin.output.value, height });
+            }
+        }
+        events
+    }
+
     pub fn block_connected(&mut self, block: &Block, height: u32) -> Result<(), Error> {
+        if self.stopped {
+            debug!("stopped, leave block {} {} to the next start", height, block.header.bitcoin_hash());
+            return Ok(());
+        }
         debug!("processing block {} {}", height, block.header.bitcoin_hash());
         // let newly_confirmed_publication;
+        let mut events = Vec::new();
         {
             let mut db = self.db.lock().unwrap();
             let mut tx = db.transaction();
 
-            if self.wallet.process(block) {
+            // coins and own transactions before the block, to report what it changed
+            let before = if self.notifier.is_some() || self.publisher.is_some() {
+                let trunk = self.trunk.clone();
+                let confirmed = self.wallet.coins.confirmed().keys().cloned().collect::<HashSet<_>>();
+                let available = self.wallet.available_coins(trunk.len().saturating_sub(1), |h| trunk.get_height(h)).into_iter().map(|(p, _, _)| p).collect::<HashSet<_>>();
+                let own = tx.read_unconfirmed()?.into_iter().map(|(t, _)| t.txid()).collect::<HashSet<_>>();
+                Some((confirmed, available, own))
+            } else {
+                None
+            };
+            // previous transactions of our unconfirmed ones, that the block may confirm
+            let mut stored = HashMap::new();
+            for (transaction, _) in tx.read_unconfirmed()? {
+                for input in transaction.input.iter() {
+                    if let Some(previous) = tx.read_txout(&input.previous_output.txid)? {
+                        stored.insert(previous.txid(), previous);
+                    }
+                }
+            }
+            for entry in self.wallet.history(block, height, |t| stored.get(t).cloned()) {
+                tx.store_history(&entry)?;
+            }
+            for notification in self.wallet.notifications(block) {
+                info!("payment code notification {}", notification.txid());
+                tx.store_notification(notification)?;
+            }
+            if self.wallet.process(block, height) {
                 tx.store_coins(&self.wallet.coins())?;
+                tx.store_imported(&self.wallet.imported)?;
                 info!("New wallet balance {} satoshis {} available", self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)));
             }
+            if let Some((confirmed, available, own)) = before {
+                events = self.wallet_events(block, height, &confirmed, &available, &own);
+            }
+            let retired_vaults_open = self.retired_vaults_open();
+            if let Some(ref mut retiring) = self.retiring {
+                let trunk = self.trunk.clone();
+                let available_before = retiring.available_balance(trunk.len().saturating_sub(1), |h| trunk.get_height(h));
+                if retiring.process(block, height) {
+                    tx.store_retiring_coins(retiring.coins())?;
+                }
+                let available = retiring.available_balance(trunk.len(), |h| trunk.get_height(h));
+                if retiring.balance() == 0 && !retired_vaults_open {
+                    info!("retiring wallet is empty");
+                    tx.drop_retiring()?;
+                    self.rotation_events.push(RotationEvent::Retired);
+                } else if available > available_before {
+                    self.rotation_events.push(RotationEvent::Sweepable { amount: available });
+                }
+            }
+            if self.retiring.as_ref().map(|r| r.balance() == 0).unwrap_or(false) && !retired_vaults_open {
+                self.retiring = None;
+            }
+            // any spend of an open vault, ours or one signed with a stolen spend key
+            for vault in self.vaults.iter_mut().filter(|v| v.state != VaultState::Closed && v.unvault_block.is_none()) {
+                let spending = match block.txdata.iter().find(|t| t.input.iter().any(|i| i.previous_output == vault.outpoint)) {
+                    Some(spending) => spending,
+                    None => continue
+                };
+                if vault.unvault.as_ref().map(|t| t.txid()) == Some(spending.txid()) {
+                    info!("unvault of vault {}:{} confirmed", vault.outpoint.txid, vault.outpoint.vout);
+                } else {
+                    warn!("vault {}:{} spent by {} we did not send", vault.outpoint.txid, vault.outpoint.vout, spending.txid());
+                    vault.unvault = Some(spending.clone());
+                    events.push(WalletEvent::VaultSpent { txid: vault.outpoint.txid, vout: vault.outpoint.vout, spent_by: spending.txid(), height });
+                }
+                vault.state = VaultState::Unvaulting;
+                vault.unvault_block = Some(block.header.bitcoin_hash());
+                tx.store_vault(vault)?;
+            }
+            for watched in self.watched.iter_mut() {
+                if watched.process(block, height) {
+                    info!("watched script {:?} balance {} satoshis", watched.script, watched.balance());
+                    tx.store_watched(watched)?;
+                }
+            }
             tx.store_processed(&block.header.bitcoin_hash())?;
+            self.over_storage_quota = Self::enforce_storage_quota(self.storage_quota, self.over_storage_quota, &mut tx)?;
             tx.commit();
         }
+        for event in events {
+            self.notify(event);
+        }
+        self.publish(WalletEvent::Block { hash: block.header.bitcoin_hash(), height });
+        self.processed = Some(height);
+        self.processed_blocks += 1;
+        if let Some(left) = self.block_budget {
+            self.block_budget = Some(left.saturating_sub(1));
+            if left <= 1 {
+                info!("block budget used, stopping");
+                self.stopped = true;
+            }
+        }
         Ok(())
     }
 
     /// add a header to the tip of the chain
     pub fn add_header(&mut self, height: u32, header: &BlockHeader) -> Result<(), Error> {
         info!("new chain tip at height {} {}", height, header.bitcoin_hash());
+        self.publish(WalletEvent::Header { hash: header.bitcoin_hash(), height });
         Ok(())
     }
 
     /// unwind the tip
     pub fn unwind_tip(&mut self, header: &BlockHeader) -> Result<(), Error> {
+        if self.stopped {
+            // the re-org is detected again at the next start
+            debug!("stopped, leave unwind of {} to the next start", header.bitcoin_hash());
+            return Ok(());
+        }
         info!("unwind tip {}", header.bitcoin_hash());
         // let mut deleted_some = false;
         let mut db = self.db.lock().unwrap();
         let mut tx = db.transaction();
         tx.store_processed(&header.prev_blockhash)?;
+        for vault in self.vaults.iter_mut().filter(|v| v.unvault_block == Some(header.bitcoin_hash())) {
+            vault.unvault_block = None;
+            tx.store_vault(vault)?;
+        }
+        let height = self.trunk.get_height(&header.prev_blockhash).map(|h| h + 1).unwrap_or(0);
+        for watched in self.watched.iter_mut() {
+            if watched.unwind(height) {
+                tx.store_watched(watched)?;
+            }
+        }
+        self.wallet.unwind_tip(&header.bitcoin_hash(), height);
+        tx.store_imported(&self.wallet.imported)?;
+        tx.unwind_history(height)?;
         tx.commit();
-        self.wallet.unwind_tip(&header.bitcoin_hash());
+        self.processed = self.trunk.get_height(&header.prev_blockhash);
+        if let Some(ref mut retiring) = self.retiring {
+            retiring.unwind_tip(&header.bitcoin_hash(), height);
+        }
+        // deposits and confirmations of the block were posted to the webhook, so is their revert
+        self.notify(WalletEvent::Unwound { hash: header.bitcoin_hash(), height });
         return Ok(());
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{
-        str::FromStr,
-        sync::{Arc, Mutex},
-    };
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
 
-    use bitcoin::{Address, BitcoinHash, Block, blockdata::opcodes::all, BlockHeader, network::constants::Network, OutPoint, Transaction, TxIn, TxOut, util::bip32::ExtendedPubKey};
+    use bitcoin::{Address, BitcoinHash, OutPoint, PrivateKey, Script, Transaction, TxIn, TxOut};
     use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::opcodes::all;
     use bitcoin::blockdata::script::Builder;
-    use bitcoin::util::hash::MerkleRoot;
-    use bitcoin_hashes::sha256d;
-    use bitcoin_wallet::account::{Account, AccountAddressType, Unlocker};
+    use bitcoin::consensus::serialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+    use byteorder::{BigEndian, ReadBytesExt};
+
+    use crate::bip47::{self, PaymentCode};
+    use crate::consolidate::{ConsolidationEvent, ConsolidationPolicy};
+    use crate::error::Error;
+    use crate::history::{self, ExportFormat};
+    use crate::publish::Publisher;
+    use crate::secret::Secret;
+    use crate::testutil::{add_tx, coin_base, connect, funded_store, mine, new_store, NEW_COINS, PASSPHRASE, TestTrunk};
+    use crate::vault::VaultState;
+    use crate::wallet::{ExternalInput, FeeLimits, TxOptions, Wallet};
+    use crate::webhook::{Notifier, WalletEvent};
 
-    use crate::db::DB;
-    use crate::trunk::Trunk;
-    use crate::wallet::Wallet;
+    use super::{ContentStore, RotationEvent};
 
-    use super::ContentStore;
+    #[test]
+    fn rotate_seed() {
+        let (trunk, mut store, block) = funded_store();
 
-    const NEW_COINS: u64 = 5000000000;
-    const PASSPHRASE: &str = "whatever";
+        let (_, _, new) = Wallet::new(Network::Testnet, "new passphrase", None);
+        let sealed = store.wallet.encrypted().clone();
+        assert!(store.rotate(Wallet::new(Network::Testnet, "new passphrase", None).2, "wrong passphrase", sealed.as_slice()).is_err());
+        store.rotate(new, PASSPHRASE, sealed.as_slice()).unwrap();
+        assert!(store.rotate(Wallet::new(Network::Testnet, "new passphrase", None).2, PASSPHRASE, sealed.as_slice()).is_err());
+        store.sweep_retiring(&Secret::from(PASSPHRASE), 1).unwrap();
+        let events = store.rotation_events();
+        let (txid, amount) = match events.as_slice() {
+            [RotationEvent::Swept { txid, amount }] => (*txid, *amount),
+            _ => panic!("unexpected events {:?}", events)
+        };
+        assert!(amount < NEW_COINS && amount > NEW_COINS - 10000);
 
-    struct TestTrunk {
-        trunk: Arc<Mutex<Vec<BlockHeader>>>
+        let sweep = store.db.lock().unwrap().transaction().read_unconfirmed().unwrap()
+            .into_iter().find(|(t, _)| t.txid() == txid).unwrap().0;
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut next = mine(&block.bitcoin_hash(), 2, &burn);
+        add_tx(&mut next, sweep);
+        connect(&mut store, &trunk, &next);
+        assert_eq!(store.rotation_events(), vec!(RotationEvent::Retired));
+        assert!(store.retiring.is_none());
+        assert_eq!(store.balance()[0], amount);
+        assert!(store.sweep_retiring(&Secret::from(PASSPHRASE), 1).is_err());
     }
 
-    impl TestTrunk {
-        fn extend(&self, header: &BlockHeader) {
-            self.trunk.lock().unwrap().push(header.clone());
+    #[test]
+    fn rotate_with_open_vault() {
+        let (trunk, mut store, block) = funded_store();
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut tip = block.bitcoin_hash();
+        let mut next = |store: &mut ContentStore, txs: Vec<Transaction>| {
+            let mut block = mine(&tip, trunk.len(), &burn);
+            for tx in txs {
+                add_tx(&mut block, tx);
+            }
+            connect(store, &trunk, &block);
+            tip = block.bitcoin_hash();
+        };
+
+        let passphrase = Secret::from(PASSPHRASE);
+        let (vault_tx, _) = store.vault(&passphrase, 100000, 10, 2, 1, None).unwrap();
+        let outpoint = store.vaults()[0].outpoint;
+        let descriptors = store.vault_descriptors(&passphrase).unwrap();
+        assert_eq!(descriptors.len(), 1);
+        next(&mut store, vec!(vault_tx));
+
+        let sealed = store.wallet.encrypted().clone();
+        store.rotate(Wallet::new(Network::Testnet, "new passphrase", None).2, PASSPHRASE, sealed.as_slice()).unwrap();
+        assert!(store.vaults()[0].retired);
+        assert!(store.db.lock().unwrap().transaction().read_vaults().unwrap()[0].retired);
+        assert_eq!(store.db.lock().unwrap().transaction().read_retiring_master().unwrap().unwrap().0, sealed);
+        // the vault is still found with the retiring seed
+        assert_eq!(store.vault_descriptors(&passphrase).unwrap(), descriptors);
+        assert!(store.vault_descriptors(&Secret::from("new passphrase")).unwrap().is_empty());
+
+        store.sweep_retiring(&passphrase, 1).unwrap();
+        let txid = match store.rotation_events().as_slice() {
+            [RotationEvent::Swept { txid, .. }] => *txid,
+            events => panic!("unexpected events {:?}", events)
+        };
+        let sweep = store.db.lock().unwrap().transaction().read_unconfirmed().unwrap()
+            .into_iter().find(|(t, _)| t.txid() == txid).unwrap().0;
+        next(&mut store, vec!(sweep));
+        // the empty retiring wallet is kept for its vault
+        assert_eq!(store.retiring.as_ref().unwrap().balance(), 0);
+        assert!(!store.rotation_events().contains(&RotationEvent::Retired));
+
+        while trunk.len() <= 10 {
+            next(&mut store, vec!());
         }
+        let new_passphrase = Secret::from("new passphrase");
+        let (unvault, _) = store.unvault(&new_passphrase, Some(&passphrase), &outpoint, 1).unwrap();
+        next(&mut store, vec!(unvault));
+        next(&mut store, vec!());
+        let (withdraw, _) = store.withdraw_vault(&new_passphrase, Some(&passphrase), &outpoint, burn.clone(), 1).unwrap();
+        next(&mut store, vec!(withdraw));
+        assert!(store.rotation_events().contains(&RotationEvent::Retired));
+        assert!(store.retiring.is_none());
     }
 
-    impl Trunk for TestTrunk {
-        fn is_on_trunk(&self, block_hash: &sha256d::Hash) -> bool {
-            self.trunk.lock().unwrap().iter().any(|h| h.bitcoin_hash() == *block_hash)
-        }
+    #[test]
+    fn cancel_retired_vault() {
+        let (trunk, mut store, block) = funded_store();
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut tip = block.bitcoin_hash();
+        let mut next = |store: &mut ContentStore, txs: Vec<Transaction>| {
+            let mut block = mine(&tip, trunk.len(), &burn);
+            for tx in txs {
+                add_tx(&mut block, tx);
+            }
+            connect(store, &trunk, &block);
+            tip = block.bitcoin_hash();
+        };
 
-        fn get_header(&self, block_hash: &sha256d::Hash) -> Option<BlockHeader> {
-            self.trunk.lock().unwrap().iter().find(|h| h.bitcoin_hash() == *block_hash).map(|h| h.clone())
+        let passphrase = Secret::from(PASSPHRASE);
+        let (closed_tx, _) = store.vault(&passphrase, 100000, 5, 2, 1, None).unwrap();
+        let closed = store.vaults()[0].outpoint;
+        next(&mut store, vec!(closed_tx));
+        let (vault_tx, _) = store.vault(&passphrase, 100000, 5, 2, 1, None).unwrap();
+        let outpoint = store.vaults()[1].outpoint;
+        next(&mut store, vec!(vault_tx));
+        while trunk.len() <= 5 {
+            next(&mut store, vec!());
         }
+        let (unvault, _) = store.unvault(&passphrase, None, &closed, 1).unwrap();
+        next(&mut store, vec!(unvault));
+        next(&mut store, vec!());
+        let (withdraw, _) = store.withdraw_vault(&passphrase, None, &closed, burn.clone(), 1).unwrap();
+        next(&mut store, vec!(withdraw));
+        let (unvault, _) = store.unvault(&passphrase, None, &outpoint, 1).unwrap();
+        next(&mut store, vec!(unvault));
 
-        fn get_header_for_height(&self, height: u32) -> Option<BlockHeader> {
-            self.trunk.lock().unwrap().get(height as usize).map(|h| h.clone())
-        }
+        let new_passphrase = Secret::from("new passphrase");
+        let sealed = store.wallet.encrypted().clone();
+        store.rotate(Wallet::new(Network::Testnet, new_passphrase.as_str(), None).2, PASSPHRASE, sealed.as_slice()).unwrap();
+        // only the open vault stays with the retiring seed
+        assert!(!store.vaults()[0].retired);
+        assert!(store.vaults()[1].retired);
 
-        fn get_height(&self, block_hash: &sha256d::Hash) -> Option<u32> {
-            self.trunk.lock().unwrap().iter().enumerate().find_map(|(i, h)| if h.bitcoin_hash() == *block_hash { Some(i as u32) } else { None })
-        }
+        let lock_time = trunk.len() + 10;
+        assert!(store.cancel_unvault(&new_passphrase, None, &outpoint, lock_time, 1, None).is_err());
+        assert!(store.cancel_unvault(&new_passphrase, Some(&new_passphrase), &outpoint, lock_time, 1, None).is_err());
+        assert!(store.cancel_unvault(&passphrase, Some(&passphrase), &outpoint, lock_time, 1, None).is_err());
+        let (cancel, _) = store.cancel_unvault(&new_passphrase, Some(&passphrase), &outpoint, lock_time, 1, None).unwrap();
 
-        fn get_tip(&self) -> Option<BlockHeader> {
-            let len = self.trunk.lock().unwrap().len();
-            if len > 0 {
-                self.trunk.lock().unwrap().get(len - 1).map(|h| h.clone())
-            } else {
-                None
+        // the replacement vault is of the current seed
+        let replacement = store.vaults()[2].clone();
+        assert!(!replacement.retired);
+        assert_eq!(replacement.outpoint, OutPoint { txid: cancel.txid(), vout: 0 });
+        let address = store.wallet.vault_keys(new_passphrase.as_str(), replacement.index).unwrap().vault_address(lock_time, Network::Testnet);
+        assert_eq!(cancel.output[0].script_pubkey, address.script_pubkey());
+        next(&mut store, vec!(cancel));
+        assert_eq!(store.vault_descriptors(&new_passphrase).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn vault_spent_by_foreign_tx() {
+        let (trunk, mut store, block) = funded_store();
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut tip = block.bitcoin_hash();
+        let mut next = |store: &mut ContentStore, txs: Vec<Transaction>| {
+            let mut block = mine(&tip, trunk.len(), &burn);
+            for tx in txs {
+                add_tx(&mut block, tx);
             }
-        }
+            connect(store, &trunk, &block);
+            tip = block.bitcoin_hash();
+        };
 
-        fn len(&self) -> u32 {
-            self.trunk.lock().unwrap().len() as u32
+        let passphrase = Secret::from(PASSPHRASE);
+        let (vault_tx, _) = store.vault(&passphrase, 100000, 5, 10, 1, None).unwrap();
+        let vault = store.vaults()[0].clone();
+        next(&mut store, vec!(vault_tx));
+        while trunk.len() <= 5 {
+            next(&mut store, vec!());
         }
+
+        // a thief with the spend key unvaults to the unvault script
+        let unvault_address = store.wallet.vault_keys(PASSPHRASE, vault.index).unwrap().unvault_address(vault.delay, None, Network::Testnet);
+        let foreign = Transaction {
+            version: 2,
+            lock_time: 5,
+            input: vec!(TxIn { previous_output: vault.outpoint, script_sig: Script::new(), sequence: 0xfffffffe, witness: vec!(vec!(0x30u8; 71)) }),
+            output: vec!(TxOut { value: vault.value - 1000, script_pubkey: unvault_address.script_pubkey() }),
+        };
+        let (notifier, events) = Notifier::capture();
+        store.set_notifier(Some(notifier));
+        let height = trunk.len();
+        next(&mut store, vec!(foreign.clone()));
+        let spent = events.try_iter().collect::<Vec<_>>();
+        assert!(spent.contains(&WalletEvent::VaultSpent { txid: vault.outpoint.txid, vout: vault.outpoint.vout, spent_by: foreign.txid(), height }));
+        let spent = store.vaults()[0].clone();
+        assert_eq!(spent.state, VaultState::Unvaulting);
+        assert_eq!(spent.unvault, Some(foreign.clone()));
+        assert!(spent.unvault_block.is_some());
+        assert_eq!(store.db.lock().unwrap().transaction().read_vaults().unwrap()[0], spent);
+
+        // the stolen coins are sent back to a new vault within the delay
+        let (cancel, _) = store.cancel_unvault(&passphrase, None, &vault.outpoint, trunk.len() + 10, 1, None).unwrap();
+        assert_eq!(cancel.input[0].previous_output, OutPoint { txid: foreign.txid(), vout: 0 });
+        assert_eq!(store.vaults()[0].state, VaultState::Closed);
     }
 
-    fn new_store(trunk: Arc<TestTrunk>) -> ContentStore {
-        let mut memdb = DB::memory().unwrap();
-        {
-            let mut tx = memdb.transaction();
-            tx.create_tables();
-            tx.commit();
-        }
-        let mut wallet = Wallet::from_encrypted(
-            hex::decode("0e05ba48bb0fdc7285dc9498202aeee5e1777ac4f55072b30f15f6a8632ad0f3fde1c41d9e162dbe5d3153282eaebd081cf3b3312336fc56f5dd18a2df6ea48c1cdd11a1ed11281cd2e0f864f02e5bed5ab03326ed24e43b8a184acff9cb4e730db484e33f2b24295a97b2ca87871a69384eb64d4160ce8b3e8b4d90234040970e531d4333a8979dbe533c2b2668bf43b6607b2d24c5b42765ebfdd075fd173c").unwrap().as_slice(),
-            ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4XKz4vgwBmnnVmA7EgWhnXvimQ4krq94yUgcSSbroi4uC1xbZ3UGMxG9M2utmaPjdpMrWW2uKRY9Mj4DZWrrY8M4pry8shsK").unwrap(),
-            1567260002);
-        let mut unlocker = Unlocker::new_for_master(&wallet.master, PASSPHRASE).unwrap();
-        wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap());
-        wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap());
-        wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 1, 0, 0).unwrap());
+    #[test]
+    fn stopped_store_resumes() {
+        let (trunk, mut store, first) = funded_store();
+        let deposit = store.deposit_address();
+        assert_eq!(store.balance()[0], NEW_COINS);
 
-        ContentStore::new(Arc::new(Mutex::new(memdb)), trunk, wallet).unwrap()
+        store.set_stopped(true);
+        let second = mine(&first.bitcoin_hash(), 2, &deposit);
+        connect(&mut store, &trunk, &second);
+        assert_eq!(store.balance()[0], NEW_COINS);
+        assert_eq!(store.db.lock().unwrap().transaction().read_processed().unwrap(), Some(first.bitcoin_hash()));
+
+        // the next start processes the block after the last processed one
+        store.set_stopped(false);
+        store.block_connected(&second, 2).unwrap();
+        assert_eq!(store.balance()[0], 2 * NEW_COINS);
+        assert_eq!(store.db.lock().unwrap().transaction().read_processed().unwrap(), Some(second.bitcoin_hash()));
     }
 
-    fn new_block(prev: &sha256d::Hash) -> Block {
-        Block {
-            header: BlockHeader {
-                version: 1,
-                time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
-                nonce: 0,
-                bits: 0x1d00ffff,
-                prev_blockhash: prev.clone(),
-                merkle_root: sha256d::Hash::default(),
-            },
-            txdata: Vec::new(),
-        }
+    #[test]
+    fn fee_rate_bounds() {
+        let (_, mut store, _) = funded_store();
+
+        store.set_fee_rate_bounds(Some(2), Some(50));
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let passphrase = Secret::from(PASSPHRASE);
+        let options = TxOptions::default();
+        assert!(store.withdraw(&passphrase, burn.clone(), 1, Some(100000), &options).is_err());
+        assert!(store.withdraw(&passphrase, burn.clone(), 51, Some(100000), &options).is_err());
+        assert!(store.withdraw(&passphrase, burn, 50, Some(100000), &options).is_ok());
     }
 
-    fn coin_base(miner: &Address, height: u32) -> Transaction {
-        Transaction {
+    #[test]
+    fn watch_script() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        connect(&mut store, &trunk, &genesis);
+
+        let cold = Address::p2wsh(&Script::new(), Network::Testnet);
+        store.watch_script(cold.script_pubkey(), Some(cold.clone())).unwrap();
+        store.watch_script(cold.script_pubkey(), None).unwrap();
+        let block = mine(&genesis.bitcoin_hash(), 1, &cold);
+        connect(&mut store, &trunk, &block);
+        let watched = store.watched();
+        assert_eq!(watched.len(), 1);
+        assert_eq!(watched[0].address, Some(cold));
+        assert_eq!(watched[0].balance(), NEW_COINS);
+        assert_eq!(watched[0].history().len(), 1);
+        // watched coins are not the wallet's
+        assert_eq!(store.balance()[0], 0);
+        let records = store.history_records(None, None).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].direction, records[0].amount, records[0].balance), ("watched in", NEW_COINS, NEW_COINS as i64));
+        assert_eq!(records[0].watched, Some(cold.to_string()));
+        assert_eq!(records[0].timestamp, block.header.time);
+        assert_eq!(store.db.lock().unwrap().transaction().read_watched().unwrap(), watched);
+
+        store.unwind_tip(&block.header).unwrap();
+        assert_eq!(store.watched()[0].balance(), 0);
+    }
+
+    #[test]
+    fn receive_payment_codes() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        connect(&mut store, &trunk, &genesis);
+        let passphrase = Secret::from(PASSPHRASE);
+        let bob = store.payment_code(PASSPHRASE).unwrap();
+        // watches the notification address
+        assert_eq!(store.receive_payment_codes(&passphrase).unwrap().len(), 1);
+        assert!(store.payment_code_senders().is_empty());
+
+        let secp = Secp256k1::new();
+        let alice_account = bip47::account_key(&ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap()).unwrap();
+        let alice = PaymentCode::from_account(&ExtendedPubKey::from_private(&secp, &alice_account));
+        let designated = bip47::child_key(&alice_account, 3).unwrap();
+        let funding = OutPoint { txid: genesis.txdata[0].txid(), vout: 0 };
+        let payload = bip47::blind(&alice, &bob, &designated, &funding, Network::Testnet).unwrap();
+        let notification = Transaction {
             version: 2,
             lock_time: 0,
             input: vec!(TxIn {
+                previous_output: funding,
+                script_sig: Script::new(),
                 sequence: 0xffffffff,
-                witness: Vec::new(),
-                previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
-                script_sig: Builder::new().push_int(height as i64).into_script(),
-            }),
-            output: vec!(TxOut {
-                value: NEW_COINS,
-                script_pubkey: miner.script_pubkey(),
+                witness: vec!(vec!(0x30u8; 71), bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &designated).serialize().to_vec()),
             }),
+            output: vec!(
+                TxOut { value: 546, script_pubkey: bob.notification_address(Network::Testnet).unwrap().script_pubkey() },
+                TxOut { value: 0, script_pubkey: Builder::new().push_opcode(all::OP_RETURN).push_slice(&payload[..]).into_script() }
+            ),
+        };
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut block = mine(&genesis.bitcoin_hash(), 1, &burn);
+        add_tx(&mut block, notification);
+        connect(&mut store, &trunk, &block);
+
+        // the sender is accepted and keys of its payments imported while processing the block
+        assert_eq!(store.payment_code_senders(), vec!(alice));
+        let receiving = |store: &ContentStore| store.imported().iter().filter(|k| bip47::parse_receive_origin(k.origin.as_str()).is_some()).count() as u32;
+        assert_eq!(receiving(&store), bip47::RECEIVE_GAP);
+        assert!(store.receive_payment_codes(&passphrase).unwrap().is_empty());
+        assert_eq!(store.db.lock().unwrap().transaction().read_imported().unwrap().keys, store.imported());
+
+        // alice pays bob to her first payment address and to one beyond those imported before the block
+        let alice_designated = bip47::child_key(&alice_account, 0).unwrap();
+        let paid = bip47::send_address(&alice_designated, &bob, 0, Network::Testnet).unwrap();
+        let paid10 = bip47::send_address(&alice_designated, &bob, bip47::RECEIVE_GAP, Network::Testnet).unwrap();
+        let mut payment = mine(&block.bitcoin_hash(), 2, &paid);
+        add_tx(&mut payment, coin_base(&paid10, 2));
+        connect(&mut store, &trunk, &payment);
+        for index in &[0, bip47::RECEIVE_GAP] {
+            let origin = bip47::receive_origin(&alice, *index);
+            assert_eq!(store.imported().iter().find(|k| k.origin == origin).unwrap().watched.balance(), NEW_COINS);
+        }
+        // keys beyond the last one paid to
+        assert_eq!(receiving(&store), 2 * bip47::RECEIVE_GAP + 1);
+        assert!(store.receive_payment_codes(&passphrase).unwrap().is_empty());
+    }
+
+    #[test]
+    fn notify_payment_code() {
+        let (_, mut store, _) = funded_store();
+        let passphrase = Secret::from(PASSPHRASE);
+        let secp = Secp256k1::new();
+        let bob_account = bip47::account_key(&ExtendedPrivKey::new_master(Network::Testnet, &[2u8; 32]).unwrap()).unwrap();
+        let bob = PaymentCode::from_account(&ExtendedPubKey::from_private(&secp, &bob_account));
+
+        let options = TxOptions::default();
+        let (tx, fee) = store.notify_payment_code(&passphrase, &bob, 5, &options).unwrap();
+        assert_eq!(tx.output[0], TxOut { value: bip47::NOTIFICATION_VALUE, script_pubkey: bob.notification_address(Network::Testnet).unwrap().script_pubkey() });
+        // bob unblinds our code with the key of his notification address
+        assert_eq!(bip47::notified_code(&tx, &bip47::child_key(&bob_account, 0).unwrap()).unwrap(), store.payment_code(PASSPHRASE).unwrap());
+        assert_eq!(tx.output.iter().map(|o| o.value).sum::<u64>() + fee, NEW_COINS);
+
+        let data = TxOptions { data: Some(vec!(1u8; 4)), ..TxOptions::default() };
+        assert!(store.notify_payment_code(&passphrase, &bob, 5, &data).is_err());
+    }
+
+    #[test]
+    fn import_wif() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        connect(&mut store, &trunk, &genesis);
+
+        let key = PrivateKey { compressed: true, network: Network::Testnet, key: SecretKey::from_slice(&[3u8; 32]).unwrap() };
+        let passphrase = Secret::from(PASSPHRASE);
+        assert!(store.import_wif(&Secret::from("wrong passphrase"), key.to_wif().as_str()).is_err());
+        let added = store.import_wif(&passphrase, key.to_wif().as_str()).unwrap();
+        assert_eq!(added.len(), 3);
+        assert!(store.import_wif(&passphrase, key.to_wif().as_str()).unwrap().is_empty());
+
+        let block = mine(&genesis.bitcoin_hash(), 1, added[0].address());
+        connect(&mut store, &trunk, &block);
+        assert_eq!(store.imported()[0].watched.balance(), NEW_COINS);
+        assert_eq!(store.balance()[0], 0);
+        let stored = store.db.lock().unwrap().transaction().read_imported().unwrap();
+        assert_eq!(stored.keys, store.imported());
+        assert!(store.rotate(Wallet::new(Network::Testnet, "new passphrase", None).2, PASSPHRASE, &[]).is_err());
+
+        store.unwind_tip(&block.header).unwrap();
+        assert_eq!(store.imported()[0].watched.balance(), 0);
+
+        let public = added[0].public;
+        let external = vec!((added[1].watched.script.clone(), OutPoint { txid: Default::default(), vout: 1 }, 100000));
+        assert!(store.sweep_imported(&passphrase, Some(&public), vec!(), 5).is_err());
+        let (sweep, fee) = store.sweep_imported(&passphrase, Some(&public), external, 5).unwrap();
+        assert_eq!(sweep.input.len(), 1);
+        assert_eq!(sweep.output[0].value, 100000 - fee);
+        assert_eq!(store.balance()[0], 100000 - fee);
+    }
+
+    #[test]
+    fn fee_limits() {
+        let (_, mut store, _) = funded_store();
+
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let passphrase = Secret::from(PASSPHRASE);
+        // about 15000 satoshis fee for 20000
+        match store.withdraw(&passphrase, burn.clone(), 100, Some(20000), &TxOptions::default()) {
+            Err(Error::FeeTooHigh(_)) => {}
+            _ => panic!("signed a transaction paying most of its amount as fee")
+        }
+        let options = TxOptions { allow_high_fee: true, ..Default::default() };
+        assert!(store.withdraw(&passphrase, burn.clone(), 100, Some(20000), &options).is_ok());
+
+        store.set_fee_limits(FeeLimits { max_fee_percent: None, max_fee: Some(1000) });
+        assert!(store.withdraw(&passphrase, burn.clone(), 10, Some(1000000), &TxOptions::default()).is_err());
+        assert!(store.withdraw(&passphrase, burn, 1, Some(1000000), &TxOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn consolidate() {
+        let (trunk, mut store, mut tip) = funded_store();
+        let passphrase = Secret::from(PASSPHRASE);
+        let miner = Address::p2wsh(&Script::new(), Network::Testnet);
+        for height in 2..5 {
+            let deposit = store.deposit_address();
+            let (tx, _) = store.withdraw(&passphrase, deposit, 1, Some(20000), &TxOptions::default()).unwrap();
+            let mut block = mine(&tip.bitcoin_hash(), height, &miner);
+            add_tx(&mut block, tx);
+            connect(&mut store, &trunk, &block);
+            tip = block;
+        }
+
+        store.set_consolidation_policy(Some(ConsolidationPolicy { fee_rate: 5, min_coins: 3, max_value: 100000 }));
+        assert_eq!(store.consolidate(2).unwrap(), None);
+        assert!(store.set_consolidation_passphrase(Some(Secret::from("wrong"))).is_err());
+        store.set_consolidation_passphrase(Some(passphrase)).unwrap();
+        assert_eq!(store.consolidate(10).unwrap(), None);
+        // consolidations continue after the passphrase changed
+        let public = store.wallet.master_public().clone();
+        let encrypted = Wallet::reencrypt(store.wallet.encrypted(), &public, PASSPHRASE, "new passphrase").unwrap();
+        store.set_encrypted(&public, encrypted.as_slice(), &Secret::from("new passphrase"));
+        match store.consolidate(2).unwrap() {
+            Some(ConsolidationEvent::Consolidated { merged, amount, fee, .. }) => {
+                assert_eq!(merged.len(), 3);
+                assert_eq!(amount + fee, 60000);
+            }
+            _ => panic!("small coins were not consolidated")
+        }
+        assert_eq!(store.consolidation_events().len(), 1);
+        assert!(store.consolidation_events().is_empty());
+    }
+
+    #[test]
+    fn get_transaction() {
+        let (trunk, mut store, first) = funded_store();
+        let deposit = store.deposit_address();
+        let coinbase = store.get_transaction(&first.txdata[0].txid()).unwrap().unwrap();
+        assert_eq!(coinbase.net, NEW_COINS as i64);
+        assert_eq!((coinbase.block, coinbase.height, coinbase.fee), (Some(first.bitcoin_hash()), Some(1), None));
+        assert_eq!(coinbase.outputs[0].address, Some(deposit.to_string()));
+        assert!(coinbase.outputs[0].ours);
+
+        let other = Address::p2wsh(&Script::new(), Network::Testnet);
+        let (transaction, fee) = store.withdraw(&Secret::from(PASSPHRASE), other.clone(), 1, Some(20000), &TxOptions::default()).unwrap();
+        let withdrawal = store.get_transaction(&transaction.txid()).unwrap().unwrap();
+        assert_eq!(withdrawal.hex, hex::encode(serialize(&transaction)));
+        assert_eq!((withdrawal.block, withdrawal.fee), (None, Some(fee)));
+        assert_eq!(withdrawal.net, -(20000 + fee as i64));
+        assert!(withdrawal.inputs.iter().all(|i| i.ours && i.value == Some(NEW_COINS)));
+        let paid = withdrawal.outputs.iter().find(|o| !o.ours).unwrap();
+        assert_eq!((paid.value, paid.address.clone()), (20000, Some(other.to_string())));
+
+        let mut second = mine(&first.bitcoin_hash(), 2, &other);
+        add_tx(&mut second, transaction.clone());
+        connect(&mut store, &trunk, &second);
+        let confirmed = store.get_transaction(&transaction.txid()).unwrap().unwrap();
+        assert_eq!((confirmed.block, confirmed.height), (Some(second.bitcoin_hash()), Some(2)));
+        assert_eq!(confirmed.net, withdrawal.net);
+        assert!(store.get_transaction(&second.txdata[0].txid()).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_tx() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        let deposit = store.deposit_address();
+        let other = Address::p2wsh(&Script::new(), Network::Testnet);
+        let transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xffffffff, witness: vec!() }),
+            output: vec!(TxOut { value: 1000, script_pubkey: other.script_pubkey() },
+                         TxOut { value: 2000, script_pubkey: deposit.script_pubkey() }),
+        };
+        let decoded = store.decode_tx(&transaction).unwrap();
+        assert_eq!(decoded.txid, transaction.txid());
+        assert_eq!((decoded.net, decoded.fee, decoded.block), (2000, None, None));
+        assert_eq!(decoded.outputs.iter().map(|o| o.ours).collect::<Vec<_>>(), vec!(false, true));
+        assert_eq!(decoded.outputs[1].address, Some(deposit.to_string()));
+        assert!(!decoded.inputs[0].ours);
+    }
+
+    #[test]
+    fn chain_headers() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        assert!(store.tip_header().is_none());
+        let genesis = genesis_block(Network::Testnet);
+        connect(&mut store, &trunk, &genesis);
+        let block = mine(&genesis.bitcoin_hash(), 1, &store.deposit_address());
+        connect(&mut store, &trunk, &block);
+        assert_eq!(store.tip_header(), Some((1, block.header.clone())));
+        assert_eq!(store.header_for_height(0), Some(genesis.header.clone()));
+        assert!(store.header_for_height(2).is_none());
+    }
+
+    #[test]
+    fn deterministic_transactions() {
+        let options = TxOptions { seed: Some(42), ..TxOptions::default() };
+        let other = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut transactions = Vec::new();
+        for _ in 0..2 {
+            let trunk = Arc::new(TestTrunk::new());
+            let mut store = new_store(trunk.clone());
+            let genesis = genesis_block(Network::Testnet);
+            connect(&mut store, &trunk, &genesis);
+            let mut tip = genesis.bitcoin_hash();
+            for height in 1..4 {
+                let block = mine(&tip, height, &store.deposit_address());
+                connect(&mut store, &trunk, &block);
+                tip = block.bitcoin_hash();
+            }
+            let (transaction, _) = store.withdraw(&Secret::from(PASSPHRASE), other.clone(), 1, Some(NEW_COINS + 10000), &options).unwrap();
+            assert_eq!(transaction.input.len(), 2);
+            transactions.push(serialize(&transaction));
         }
+        assert_eq!(transactions[0], transactions[1]);
+    }
+
+    #[test]
+    fn collaborate() {
+        let (_, mut store, _) = funded_store();
+
+        let key = PrivateKey { compressed: true, network: Network::Testnet, key: SecretKey::from_slice(&[3u8; 32]).unwrap() };
+        let public = key.public_key(&Secp256k1::new());
+        let external = ExternalInput {
+            outpoint: OutPoint { txid: Transaction { version: 2, lock_time: 0, input: vec![], output: vec![] }.txid(), vout: 0 },
+            script_pubkey: Address::p2wpkh(&public, Network::Testnet).script_pubkey(),
+            value: 10000,
+            partial_sig: Some((public, vec![1u8; 72])),
+        };
+        let other = Address::p2wsh(&Script::new(), Network::Testnet);
+        let passphrase = Secret::from(PASSPHRASE);
+        assert!(store.collaborate(&passphrase, other.clone(), 1, 20000, &[], &TxOptions::default()).is_err());
+        let (psbt, fee) = store.collaborate(&passphrase, other.clone(), 1, 20000, &[external.clone()], &TxOptions::default()).unwrap();
+        let unsigned = &psbt.global.unsigned_tx;
+        assert_eq!(unsigned.input.len(), 2);
+        let payment = unsigned.output.iter().find(|o| o.script_pubkey == other.script_pubkey()).unwrap();
+        assert_eq!(payment.value, 20000 - fee + 10000);
+        for (input, psbt_input) in unsigned.input.iter().zip(psbt.inputs.iter()) {
+            if input.previous_output == external.outpoint {
+                assert!(psbt_input.final_script_witness.is_none());
+                assert_eq!(psbt_input.partial_sigs.get(&public), Some(&vec![1u8; 72]));
+            } else {
+                assert!(psbt_input.final_script_witness.is_some());
+            }
+        }
+        // our coin is not spent until the other party broadcasts
+        assert_eq!(store.balance()[0], NEW_COINS);
+    }
+
+    #[test]
+    fn export_history() {
+        let (trunk, mut store, block) = funded_store();
+
+        let other = Address::p2wsh(&Script::new(), Network::Testnet);
+        let (transaction, fee) = store.withdraw(&Secret::from(PASSPHRASE), other.clone(), 1, Some(10000), &TxOptions::default()).unwrap();
+        let mut next = mine(&block.bitcoin_hash(), 2, &other);
+        add_tx(&mut next, transaction.clone());
+        connect(&mut store, &trunk, &next);
+        store.set_label(&transaction.txid(), "coffee").unwrap();
+
+        let csv = history::export(&store.history_records(None, None).unwrap(), ExportFormat::Csv);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], format!("{},{},in,{},,{},", block.header.time, block.txdata[0].txid(), NEW_COINS, NEW_COINS));
+        assert_eq!(lines[2], format!("{},{},out,10000,{},{},coffee", next.header.time, transaction.txid(), fee, NEW_COINS - 10000));
+
+        // history of an unwound block is forgotten
+        store.unwind_tip(&trunk.unwind().unwrap()).unwrap();
+        assert_eq!(store.history_records(None, None).unwrap().len(), 1);
+
+        assert_eq!(store.cached_rate("usd", 86400).unwrap(), None);
+        store.cache_rate("usd", 86400, 9000.5).unwrap();
+        assert_eq!(store.cached_rate("usd", 86400).unwrap(), Some(9000.5));
     }
 
-    fn add_tx(block: &mut Block, tx: Transaction) {
-        block.txdata.push(tx);
-        block.header.merkle_root = block.merkle_root();
+    #[test]
+    fn header_events() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        let publisher = Publisher::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut subscriber = TcpStream::connect(publisher.local_addr()).unwrap();
+        store.set_publisher(Some(publisher));
+        // let the publisher accept the subscriber
+        thread::sleep(Duration::from_millis(500));
+        let genesis = genesis_block(Network::Testnet);
+        trunk.extend(&genesis.header);
+        store.add_header(0, &genesis.header).unwrap();
+        subscriber.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let len = subscriber.read_u32::<BigEndian>().unwrap();
+        let mut payload = vec!(0u8; len as usize);
+        subscriber.read_exact(payload.as_mut_slice()).unwrap();
+        let payload = serde_json::from_slice::<serde_json::Value>(payload.as_slice()).unwrap();
+        assert_eq!(payload["event"], "header");
+        assert_eq!(payload["hash"], genesis.bitcoin_hash().to_string());
+        assert_eq!(payload["height"], 0);
     }
 
-    fn mine(store: &ContentStore, height: u32, miner: &Address) -> Block {
-        let mut block = new_block(&store.trunk.get_tip().unwrap().bitcoin_hash());
-        add_tx(&mut block, coin_base(miner, height));
-        block
+    #[test]
+    fn webhook_events() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        let (notifier, events) = Notifier::capture();
+        store.set_notifier(Some(notifier));
+        let genesis = genesis_block(Network::Testnet);
+        connect(&mut store, &trunk, &genesis);
+        let deposit = store.deposit_address();
+        let first = mine(&genesis.bitcoin_hash(), 1, &deposit);
+        connect(&mut store, &trunk, &first);
+        match events.try_recv().unwrap() {
+            WalletEvent::Deposit { txid, vout, address, amount, height } => {
+                assert_eq!((txid, vout, amount, height), (first.txdata[0].txid(), 0, NEW_COINS, 1));
+                assert_eq!(address, Some(deposit.to_string()));
+            }
+            e => panic!("unexpected {:?}", e)
+        }
+
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        let (spend, _) = store.withdraw(&Secret::from(PASSPHRASE), burn.clone(), 1, Some(20000), &TxOptions::default()).unwrap();
+        let mut second = mine(&first.bitcoin_hash(), 2, &burn);
+        add_tx(&mut second, spend.clone());
+        connect(&mut store, &trunk, &second);
+        assert_eq!(events.try_recv().unwrap(), WalletEvent::Confirmed { txid: spend.txid(), height: 2 });
+        assert!(events.try_recv().is_err());
+
+        // deposits and confirmations of an unwound block are reverted
+        store.unwind_tip(&trunk.unwind().unwrap()).unwrap();
+        assert_eq!(events.try_recv().unwrap(), WalletEvent::Unwound { hash: second.bitcoin_hash(), height: 2 });
+        assert!(events.try_recv().is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn block_budget() {
+        let trunk = Arc::new(TestTrunk::new());
+        let mut store = new_store(trunk.clone());
+        store.set_block_budget(Some(2));
+        let genesis = genesis_block(Network::Testnet);
+        connect(&mut store, &trunk, &genesis);
+        assert!(!store.get_stopped());
+        assert!(!store.is_synced());
+        let deposit = store.deposit_address();
+        let first = mine(&genesis.bitcoin_hash(), 1, &deposit);
+        connect(&mut store, &trunk, &first);
+        assert!(store.get_stopped());
+        assert_eq!(store.processed_blocks(), 2);
+        assert_eq!(store.processed_height(), Some(1));
+        assert_eq!(store.header_height(), 1);
+        assert!(store.is_synced());
+
+        let second = mine(&first.bitcoin_hash(), 2, &deposit);
+        connect(&mut store, &trunk, &second);
+        assert_eq!(store.processed_blocks(), 2);
+        assert_eq!(store.header_height(), 2);
+        assert!(!store.is_synced());
+    }
+}
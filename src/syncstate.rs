@@ -0,0 +1,365 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! snapshots of the synchronization state
+//!
+//! the header chain and the last block scanned by the wallet, to restore a wallet without downloading headers again.
+//! Headers are stored without the hash of their predecessor and with version and bits only where they change,
+//! proof of work and difficulty of imported headers are checked
+
+use bitcoin::{BitcoinHash, BlockHeader, Network};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::util::uint::Uint256;
+use bitcoin_hashes::sha256d;
+
+use crate::error::Error;
+
+const MAGIC: &[u8; 4] = b"bdks";
+/// format version written by serialize
+pub const VERSION: u8 = 2;
+/// format of full 80 byte headers, still read
+const FULL_HEADERS: u8 = 1;
+const HEADER_LEN: usize = 80;
+/// flags of a compact header
+const NEW_VERSION: u8 = 1;
+const NEW_BITS: u8 = 2;
+/// blocks between difficulty adjustments
+const DIFFCHANGE_INTERVAL: usize = 2016;
+const TARGET_TIMESPAN: u32 = 14 * 24 * 3600;
+const TARGET_SPACING: u32 = 600;
+
+/// the header chain from genesis to the tip and the position of the block scan
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncState {
+    pub network: Network,
+    /// height and hash of the last block processed by the wallet
+    pub processed: Option<(u32, sha256d::Hash)>,
+    /// the trunk, genesis first
+    pub headers: Vec<BlockHeader>,
+}
+
+impl SyncState {
+    /// height and hash of the header tip
+    pub fn tip(&self) -> Option<(u32, sha256d::Hash)> {
+        self.headers.last().map(|h| (self.headers.len() as u32 - 1, h.bitcoin_hash()))
+    }
+
+    /// the header at height
+    pub fn header(&self, height: u32) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    /// magic, version, network magic, processed height and hash (height u32::MAX if none), header count,
+    /// then for each header flags, version if new, merkle root, time, bits if new and nonce
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut blob = Vec::with_capacity(49 + 41 * self.headers.len());
+        blob.extend_from_slice(&MAGIC[..]);
+        blob.push(VERSION);
+        blob.extend_from_slice(&self.network.magic().to_le_bytes());
+        match self.processed {
+            Some((height, ref hash)) => {
+                blob.extend_from_slice(&height.to_le_bytes());
+                blob.extend_from_slice(&hash[..]);
+            }
+            None => {
+                blob.extend_from_slice(&u32::max_value().to_le_bytes());
+                blob.extend_from_slice(&[0u8; 32]);
+            }
+        }
+        blob.extend_from_slice(&(self.headers.len() as u32).to_le_bytes());
+        let mut prev: Option<&BlockHeader> = None;
+        for header in &self.headers {
+            let mut flags = 0;
+            if prev.map(|p| p.version != header.version).unwrap_or(true) {
+                flags |= NEW_VERSION;
+            }
+            if prev.map(|p| p.bits != header.bits).unwrap_or(true) {
+                flags |= NEW_BITS;
+            }
+            blob.push(flags);
+            if flags & NEW_VERSION != 0 {
+                blob.extend_from_slice(&(header.version as u32).to_le_bytes());
+            }
+            blob.extend_from_slice(&header.merkle_root[..]);
+            blob.extend_from_slice(&header.time.to_le_bytes());
+            if flags & NEW_BITS != 0 {
+                blob.extend_from_slice(&header.bits.to_le_bytes());
+            }
+            blob.extend_from_slice(&header.nonce.to_le_bytes());
+            prev = Some(header);
+        }
+        blob
+    }
+
+    pub fn deserialize(blob: &[u8]) -> Result<SyncState, Error> {
+        if blob.len() < 49 || &blob[0..4] != &MAGIC[..] {
+            return Err(Error::InvalidArgument("not a sync state"));
+        }
+        if blob[4] != VERSION && blob[4] != FULL_HEADERS {
+            return Err(Error::Unsupported("unknown sync state version"));
+        }
+        let network = Network::from_magic(read_u32(&blob[5..9])).ok_or(Error::InvalidArgument("unknown network of sync state"))?;
+        let height = read_u32(&blob[9..13]);
+        let processed = if height == u32::max_value() {
+            None
+        } else {
+            Some((height, deserialize::<sha256d::Hash>(&blob[13..45]).expect("32 bytes are a hash")))
+        };
+        let count = read_u32(&blob[45..49]) as usize;
+        let headers = if blob[4] == FULL_HEADERS {
+            full_headers(&blob[49..], count)?
+        } else {
+            compact_headers(&blob[49..], count)?
+        };
+        check_work(network, headers.as_slice())?;
+        let state = SyncState { network, processed, headers };
+        if let Some((height, hash)) = state.processed {
+            if state.header(height).map(|h| h.bitcoin_hash()) != Some(hash) {
+                return Err(Error::InvalidArgument("processed block of sync state is not on its chain"));
+            }
+        }
+        Ok(state)
+    }
+}
+
+fn full_headers(blob: &[u8], count: usize) -> Result<Vec<BlockHeader>, Error> {
+    if blob.len() != count * HEADER_LEN {
+        return Err(Error::InvalidArgument("sync state has wrong length"));
+    }
+    let mut headers = Vec::with_capacity(count);
+    for chunk in blob.chunks(HEADER_LEN) {
+        let header = deserialize::<BlockHeader>(chunk).map_err(|_| Error::InvalidArgument("invalid header in sync state"))?;
+        if let Some(prev) = headers.last().map(|h: &BlockHeader| h.bitcoin_hash()) {
+            if header.prev_blockhash != prev {
+                return Err(Error::InvalidArgument("headers of sync state do not form a chain"));
+            }
+        }
+        headers.push(header);
+    }
+    Ok(headers)
+}
+
+fn compact_headers(blob: &[u8], count: usize) -> Result<Vec<BlockHeader>, Error> {
+    let mut reader = Reader { blob, pos: 0 };
+    let mut headers: Vec<BlockHeader> = Vec::new();
+    for _ in 0..count {
+        let flags = reader.take(1)?[0];
+        let prev = headers.last();
+        let version = match prev {
+            Some(prev) if flags & NEW_VERSION == 0 => prev.version,
+            None if flags & NEW_VERSION == 0 => return Err(Error::InvalidArgument("first header of sync state has no version")),
+            _ => read_u32(reader.take(4)?) as _
+        };
+        let merkle_root = deserialize::<sha256d::Hash>(reader.take(32)?).expect("32 bytes are a hash");
+        let time = read_u32(reader.take(4)?);
+        let bits = match prev {
+            Some(prev) if flags & NEW_BITS == 0 => prev.bits,
+            None if flags & NEW_BITS == 0 => return Err(Error::InvalidArgument("first header of sync state has no bits")),
+            _ => read_u32(reader.take(4)?)
+        };
+        let nonce = read_u32(reader.take(4)?);
+        let prev_blockhash = prev.map(|p| p.bitcoin_hash()).unwrap_or_default();
+        headers.push(BlockHeader { version, prev_blockhash, merkle_root, time, bits, nonce });
+    }
+    if reader.pos != blob.len() {
+        return Err(Error::InvalidArgument("sync state has wrong length"));
+    }
+    Ok(headers)
+}
+
+struct Reader<'a> {
+    blob: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.pos + len > self.blob.len() {
+            return Err(Error::InvalidArgument("sync state has wrong length"));
+        }
+        self.pos += len;
+        Ok(&self.blob[self.pos - len..self.pos])
+    }
+}
+
+/// checks the genesis, proof of work and difficulty of headers, genesis first
+fn check_work(network: Network, headers: &[BlockHeader]) -> Result<(), Error> {
+    if let Some(genesis) = headers.first() {
+        if *genesis != genesis_block(network).header {
+            return Err(Error::InvalidArgument("sync state does not start with the genesis of its network"));
+        }
+    }
+    let limit = target(limit_bits(network));
+    for (height, header) in headers.iter().enumerate().skip(1) {
+        let target = header.target();
+        if target > limit || header.validate_pow(&target).is_err() {
+            return Err(Error::InvalidArgument("header of sync state has insufficient proof of work"));
+        }
+        if header.bits != required_bits(network, &headers[..height], header) {
+            return Err(Error::InvalidArgument("header of sync state has wrong difficulty"));
+        }
+    }
+    Ok(())
+}
+
+/// bits of the lowest difficulty of network
+fn limit_bits(network: Network) -> u32 {
+    match network {
+        Network::Regtest => 0x207fffff,
+        _ => 0x1d00ffff
+    }
+}
+
+fn target(bits: u32) -> Uint256 {
+    BlockHeader { bits, ..genesis_block(Network::Bitcoin).header }.target()
+}
+
+/// bits header must have on top of chain, as required by bitcoind
+fn required_bits(network: Network, chain: &[BlockHeader], header: &BlockHeader) -> u32 {
+    let height = chain.len();
+    let prev = chain.last().expect("genesis precedes");
+    let limit = limit_bits(network);
+    if height % DIFFCHANGE_INTERVAL != 0 {
+        if network == Network::Bitcoin {
+            return prev.bits;
+        }
+        // test networks accept blocks of lowest difficulty 20 minutes after their predecessor,
+        // others have the difficulty of the last block not mined at lowest difficulty
+        if header.time as u64 > prev.time as u64 + 2 * TARGET_SPACING as u64 {
+            return limit;
+        }
+        return chain.iter().enumerate().rev()
+            .find(|(h, b)| h % DIFFCHANGE_INTERVAL == 0 || b.bits != limit)
+            .map(|(_, b)| b.bits).unwrap_or(limit);
+    }
+    if network == Network::Regtest {
+        return prev.bits;
+    }
+    let first = &chain[height - DIFFCHANGE_INTERVAL];
+    let timespan = (prev.time as i64 - first.time as i64)
+        .max((TARGET_TIMESPAN / 4) as i64)
+        .min((TARGET_TIMESPAN * 4) as i64) as u32;
+    let mut adjusted = target(prev.bits).mul_u32(timespan) / Uint256::from_u64(TARGET_TIMESPAN as u64).expect("fits");
+    if adjusted > target(limit) {
+        adjusted = target(limit);
+    }
+    BlockHeader::compact_target_from_u256(&adjusted)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{BitcoinHash, BlockHeader};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    use super::{DIFFCHANGE_INTERVAL, required_bits, SyncState, TARGET_TIMESPAN};
+
+    /// a header on top of prev with bits, mined
+    fn mined(prev: &BlockHeader, bits: u32) -> BlockHeader {
+        let mut header = BlockHeader { prev_blockhash: prev.bitcoin_hash(), time: prev.time + 600, bits, nonce: 0, ..*prev };
+        while header.validate_pow(&header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn serialize_deserialize() {
+        let genesis = genesis_block(Network::Regtest).header;
+        let first = mined(&genesis, genesis.bits);
+        let second = mined(&first, genesis.bits);
+        let state = SyncState { network: Network::Regtest, processed: Some((1, first.bitcoin_hash())), headers: vec!(genesis, first, second) };
+        let blob = state.serialize();
+        // previous hashes are left out, version and bits written once
+        assert_eq!(blob.len(), 49 + 49 + 2 * 41);
+        let restored = SyncState::deserialize(blob.as_slice()).unwrap();
+        assert_eq!(restored, state);
+        assert_eq!(restored.tip(), Some((2, second.bitcoin_hash())));
+
+        let empty = SyncState { network: Network::Regtest, processed: None, headers: vec!() };
+        assert_eq!(SyncState::deserialize(empty.serialize().as_slice()).unwrap(), empty);
+
+        assert!(SyncState::deserialize(&blob[..blob.len() - 1]).is_err());
+        let broken = SyncState { network: Network::Regtest, processed: Some((1, first.bitcoin_hash())), headers: vec!(genesis, second) };
+        assert!(SyncState::deserialize(broken.serialize().as_slice()).is_err());
+    }
+
+    #[test]
+    fn full_headers() {
+        let genesis = genesis_block(Network::Regtest).header;
+        let first = mined(&genesis, genesis.bits);
+        let mut blob = SyncState { network: Network::Regtest, processed: None, headers: vec!() }.serialize();
+        blob[4] = 1;
+        blob[45..49].copy_from_slice(&2u32.to_le_bytes());
+        blob.extend_from_slice(bitcoin::consensus::serialize(&genesis).as_slice());
+        blob.extend_from_slice(bitcoin::consensus::serialize(&first).as_slice());
+        assert_eq!(SyncState::deserialize(blob.as_slice()).unwrap().headers, vec!(genesis, first));
+    }
+
+    #[test]
+    fn proof_of_work() {
+        let genesis = genesis_block(Network::Regtest).header;
+        let first = mined(&genesis, genesis.bits);
+
+        let mut unmined = mined(&first, genesis.bits);
+        while unmined.validate_pow(&unmined.target()).is_ok() {
+            unmined.nonce += 1;
+        }
+        let state = SyncState { network: Network::Regtest, processed: None, headers: vec!(genesis, first, unmined) };
+        assert!(SyncState::deserialize(state.serialize().as_slice()).is_err());
+
+        // harder than required is the wrong difficulty too
+        let harder = mined(&first, 0x2007ffff);
+        let state = SyncState { network: Network::Regtest, processed: None, headers: vec!(genesis, first, harder) };
+        assert!(SyncState::deserialize(state.serialize().as_slice()).is_err());
+
+        let other_genesis = SyncState { network: Network::Regtest, processed: None, headers: vec!(genesis_block(Network::Testnet).header) };
+        assert!(SyncState::deserialize(other_genesis.serialize().as_slice()).is_err());
+    }
+
+    #[test]
+    fn difficulty_adjustment() {
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let mut chain = (0..DIFFCHANGE_INTERVAL as u32).map(|i| BlockHeader { time: genesis.time + i * 300, ..genesis }).collect::<Vec<_>>();
+        let next = BlockHeader { time: chain.last().unwrap().time + 600, ..genesis };
+        // twice as fast halves the target
+        chain.last_mut().unwrap().time = genesis.time + TARGET_TIMESPAN / 2;
+        assert_eq!(required_bits(Network::Bitcoin, chain.as_slice(), &next), 0x1c7fff80);
+        // at most by a factor of four
+        chain.last_mut().unwrap().time = genesis.time + 60;
+        assert_eq!(required_bits(Network::Bitcoin, chain.as_slice(), &next), 0x1c3fffc0);
+        // never below the lowest difficulty
+        chain.last_mut().unwrap().time = genesis.time + 4 * TARGET_TIMESPAN;
+        assert_eq!(required_bits(Network::Bitcoin, chain.as_slice(), &next), 0x1d00ffff);
+        // unchanged between adjustments
+        assert_eq!(required_bits(Network::Bitcoin, &chain[..100], &next), 0x1d00ffff);
+
+        // test networks fall back to the lowest difficulty after 20 minutes
+        let hard = BlockHeader { bits: 0x1c7fff80, ..genesis };
+        let chain = vec!(genesis, hard);
+        assert_eq!(required_bits(Network::Testnet, chain.as_slice(), &BlockHeader { time: hard.time + 600, ..hard }), 0x1c7fff80);
+        assert_eq!(required_bits(Network::Testnet, chain.as_slice(), &BlockHeader { time: hard.time + 1201, ..hard }), 0x1d00ffff);
+        let chain = vec!(genesis, hard, genesis);
+        assert_eq!(required_bits(Network::Testnet, chain.as_slice(), &BlockHeader { time: genesis.time + 600, ..hard }), 0x1c7fff80);
+    }
+}
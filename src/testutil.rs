@@ -0,0 +1,223 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! helpers to test against an in-memory chain
+//!
+//! available with the testutil feature
+
+#[cfg(feature = "node")]
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::{Address, BitcoinHash, Block, BlockHeader, OutPoint, Transaction, TxIn, TxOut};
+#[cfg(feature = "node")]
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Builder;
+#[cfg(feature = "node")]
+use bitcoin::network::constants::Network;
+#[cfg(feature = "node")]
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::util::hash::MerkleRoot;
+use bitcoin_hashes::sha256d;
+#[cfg(feature = "node")]
+use bitcoin_wallet::account::{Account, AccountAddressType, Unlocker};
+
+#[cfg(feature = "node")]
+use crate::db::DB;
+#[cfg(feature = "node")]
+use crate::store::ContentStore;
+use crate::trunk::Trunk;
+#[cfg(feature = "node")]
+use crate::wallet::Wallet;
+
+/// value of a coinbase output
+pub const NEW_COINS: u64 = 5000000000;
+/// passphrase of the wallet created by new_store
+pub const PASSPHRASE: &str = "whatever";
+
+#[cfg(feature = "node")]
+const ENCRYPTED: &str = "0e05ba48bb0fdc7285dc9498202aeee5e1777ac4f55072b30f15f6a8632ad0f3fde1c41d9e162dbe5d3153282eaebd081cf3b3312336fc56f5dd18a2df6ea48c1cdd11a1ed11281cd2e0f864f02e5bed5ab03326ed24e43b8a184acff9cb4e730db484e33f2b24295a97b2ca87871a69384eb64d4160ce8b3e8b4d90234040970e531d4333a8979dbe533c2b2668bf43b6607b2d24c5b42765ebfdd075fd173c";
+#[cfg(feature = "node")]
+const KEYROOT: &str = "tpubD6NzVbkrYhZ4XKz4vgwBmnnVmA7EgWhnXvimQ4krq94yUgcSSbroi4uC1xbZ3UGMxG9M2utmaPjdpMrWW2uKRY9Mj4DZWrrY8M4pry8shsK";
+
+/// an in-memory trunk extended by the test
+pub struct TestTrunk {
+    trunk: Arc<Mutex<Vec<BlockHeader>>>
+}
+
+impl TestTrunk {
+    pub fn new() -> TestTrunk {
+        TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// add a header to the tip
+    pub fn extend(&self, header: &BlockHeader) {
+        self.trunk.lock().unwrap().push(header.clone());
+    }
+
+    /// remove the tip
+    pub fn unwind(&self) -> Option<BlockHeader> {
+        self.trunk.lock().unwrap().pop()
+    }
+}
+
+impl Trunk for TestTrunk {
+    fn is_on_trunk(&self, block_hash: &sha256d::Hash) -> bool {
+        self.trunk.lock().unwrap().iter().any(|h| h.bitcoin_hash() == *block_hash)
+    }
+
+    fn get_header(&self, block_hash: &sha256d::Hash) -> Option<BlockHeader> {
+        self.trunk.lock().unwrap().iter().find(|h| h.bitcoin_hash() == *block_hash).map(|h| h.clone())
+    }
+
+    fn get_header_for_height(&self, height: u32) -> Option<BlockHeader> {
+        self.trunk.lock().unwrap().get(height as usize).map(|h| h.clone())
+    }
+
+    fn get_height(&self, block_hash: &sha256d::Hash) -> Option<u32> {
+        self.trunk.lock().unwrap().iter().enumerate().find_map(|(i, h)| if h.bitcoin_hash() == *block_hash { Some(i as u32) } else { None })
+    }
+
+    fn get_tip(&self) -> Option<BlockHeader> {
+        self.trunk.lock().unwrap().last().map(|h| h.clone())
+    }
+
+    fn len(&self) -> u32 {
+        self.trunk.lock().unwrap().len() as u32
+    }
+}
+
+/// a content store with an in-memory db and a testnet wallet unlocked by PASSPHRASE
+#[cfg(feature = "node")]
+pub fn new_store(trunk: Arc<TestTrunk>) -> ContentStore {
+    let mut memdb = DB::memory().unwrap();
+    {
+        let mut tx = memdb.transaction();
+        tx.create_tables();
+        tx.commit();
+    }
+    ContentStore::new(Arc::new(Mutex::new(memdb)), trunk, new_wallet()).unwrap()
+}
+
+// a testnet wallet unlocked by PASSPHRASE with receive, change and funding accounts
+#[cfg(feature = "node")]
+fn new_wallet() -> Wallet {
+    let mut wallet = Wallet::from_encrypted(
+        hex::decode(ENCRYPTED).unwrap().as_slice(),
+        ExtendedPubKey::from_str(KEYROOT).unwrap(),
+        1567260002);
+    let mut unlocker = Unlocker::new_for_master(&wallet.master, PASSPHRASE).unwrap();
+    wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap());
+    wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap());
+    wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 1, 0, 0).unwrap());
+    wallet
+}
+
+/// a store on a trunk of the genesis block and block 1, whose coinbase paid NEW_COINS to the store
+#[cfg(feature = "node")]
+pub fn funded_store() -> (Arc<TestTrunk>, ContentStore, Block) {
+    let trunk = Arc::new(TestTrunk::new());
+    let mut store = new_store(trunk.clone());
+    let genesis = genesis_block(Network::Testnet);
+    connect(&mut store, &trunk, &genesis);
+    let deposit = store.deposit_address();
+    let block = mine(&genesis.bitcoin_hash(), 1, &deposit);
+    connect(&mut store, &trunk, &block);
+    (trunk, store, block)
+}
+
+/// a wallet on a trunk of the genesis block and block 1, whose coinbase paid NEW_COINS to the wallet
+#[cfg(feature = "node")]
+pub fn funded_wallet() -> (Arc<TestTrunk>, Wallet, Block) {
+    let trunk = Arc::new(TestTrunk::new());
+    let mut wallet = new_wallet();
+    let genesis = genesis_block(Network::Testnet);
+    trunk.extend(&genesis.header);
+    wallet.process(&genesis, 0);
+    let miner = wallet.master.get_mut((0, 0)).unwrap().next_key().unwrap().address.clone();
+    let block = mine(&genesis.bitcoin_hash(), 1, &miner);
+    trunk.extend(&block.header);
+    wallet.process(&block, 1);
+    (trunk, wallet, block)
+}
+
+/// an empty block on top of prev
+pub fn new_block(prev: &sha256d::Hash) -> Block {
+    Block {
+        header: BlockHeader {
+            version: 1,
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
+            nonce: 0,
+            bits: 0x1d00ffff,
+            prev_blockhash: prev.clone(),
+            merkle_root: sha256d::Hash::default(),
+        },
+        txdata: Vec::new(),
+    }
+}
+
+/// a coinbase paying NEW_COINS to miner
+pub fn coin_base(miner: &Address, height: u32) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec!(TxIn {
+            sequence: 0xffffffff,
+            witness: Vec::new(),
+            previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
+            script_sig: Builder::new().push_int(height as i64).into_script(),
+        }),
+        output: vec!(TxOut {
+            value: NEW_COINS,
+            script_pubkey: miner.script_pubkey(),
+        }),
+    }
+}
+
+/// add a transaction and update the merkle root
+pub fn add_tx(block: &mut Block, tx: Transaction) {
+    block.txdata.push(tx);
+    block.header.merkle_root = block.merkle_root();
+}
+
+/// a block on top of tip with a coinbase paying to miner
+pub fn mine(tip: &sha256d::Hash, height: u32, miner: &Address) -> Block {
+    let mut block = new_block(tip);
+    add_tx(&mut block, coin_base(miner, height));
+    block
+}
+
+/// extend the trunk with the block and process it in the store, as the p2p layer would
+#[cfg(feature = "node")]
+pub fn connect(store: &mut ContentStore, trunk: &TestTrunk, block: &Block) {
+    trunk.extend(&block.header);
+    store.block_connected(block, trunk.len() - 1).unwrap();
+}
+
+#[cfg(all(test, feature = "node"))]
+mod test {
+    use bitcoin::BitcoinHash;
+
+    use super::{funded_store, NEW_COINS};
+
+    #[test]
+    fn confirm_deposit() {
+        let (_, store, block) = funded_store();
+        assert_eq!(store.balance()[0], NEW_COINS);
+        assert_eq!(store.get_tip(), Some(block.bitcoin_hash()));
+    }
+}
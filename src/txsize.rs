@@ -0,0 +1,287 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! size and weight estimates of transactions
+//!
+//! computed from the script types of inputs and outputs, without building and signing a transaction
+
+use std::str::FromStr;
+
+use bitcoin::{Address, Script};
+
+use crate::error::Error;
+
+/// version and lock time
+const TX_FIXED: usize = 4 + 4;
+/// segwit marker and flag, counted in weight units
+const SEGWIT_MARKER: usize = 2;
+/// previous outpoint and sequence
+const INPUT_FIXED: usize = 36 + 4;
+/// value
+const OUTPUT_FIXED: usize = 8;
+/// DER signature with sighash byte, worst case
+pub const SIGNATURE: usize = 72;
+const PUBLIC_KEY: usize = 33;
+
+/// spent output types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    /// a single signature for a witness script of this length, as of vaults and term deposits.
+    /// branch if an IF branch is selected by a witness item
+    P2wsh { script: usize, branch: bool },
+}
+
+/// created output types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    /// OP_RETURN with this many data bytes
+    Data(usize),
+}
+
+/// estimated size of a transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxSize {
+    pub weight: usize,
+    /// weight / 4 rounded up
+    pub vsize: usize,
+}
+
+impl TxSize {
+    /// fee at a fee rate, rounded as the wallet does
+    pub fn fee(&self, fee_per_vbyte: u64) -> u64 {
+        (self.weight as u64 * fee_per_vbyte + 3) / 4
+    }
+}
+
+impl InputType {
+    /// script sig length
+    fn script_sig(&self) -> usize {
+        match self {
+            InputType::P2pkh => 1 + SIGNATURE + 1 + PUBLIC_KEY,
+            // push of the witness program
+            InputType::P2shP2wpkh => 1 + 22,
+            InputType::P2wpkh | InputType::P2wsh { .. } => 0
+        }
+    }
+
+    /// witness length, item count included
+    fn witness(&self) -> usize {
+        match self {
+            InputType::P2pkh => 0,
+            InputType::P2shP2wpkh | InputType::P2wpkh => 1 + 1 + SIGNATURE + 1 + PUBLIC_KEY,
+            InputType::P2wsh { script, branch } =>
+                1 + 1 + SIGNATURE + (if *branch { 1 + 1 } else { 0 }) + var_int_len(*script) + script
+        }
+    }
+
+    /// type of a spent script pubkey, P2SH is assumed to wrap P2WPKH. None if not one of the known types,
+    /// P2WSH needs its witness script
+    pub fn from_script(script: &Script) -> Option<InputType> {
+        if script.is_p2pkh() {
+            Some(InputType::P2pkh)
+        } else if script.is_p2sh() {
+            Some(InputType::P2shP2wpkh)
+        } else if script.is_v0_p2wpkh() {
+            Some(InputType::P2wpkh)
+        } else {
+            None
+        }
+    }
+
+    fn weight(&self) -> usize {
+        let script_sig = self.script_sig();
+        (INPUT_FIXED + var_int_len(script_sig) + script_sig) * 4 + self.witness()
+    }
+
+    /// weight an input of a segwit transaction gains by signing it
+    pub fn signature_weight(&self) -> usize {
+        let script_sig = self.script_sig();
+        // empty script sig and witness item count of the unsigned input
+        (var_int_len(script_sig) + script_sig - 1) * 4 + self.witness().saturating_sub(1)
+    }
+}
+
+/// p2pkh, p2sh-p2wpkh, p2wpkh or p2wsh:<script length> with :branch if the script branches
+impl FromStr for InputType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<InputType, Error> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["p2pkh"] => Ok(InputType::P2pkh),
+            ["p2sh-p2wpkh"] => Ok(InputType::P2shP2wpkh),
+            ["p2wpkh"] => Ok(InputType::P2wpkh),
+            ["p2wsh", script] => Ok(InputType::P2wsh { script: parse_len(script)?, branch: false }),
+            ["p2wsh", script, "branch"] => Ok(InputType::P2wsh { script: parse_len(script)?, branch: true }),
+            _ => Err(Error::InvalidArgument("unknown input type"))
+        }
+    }
+}
+
+/// p2pkh, p2sh, p2wpkh, p2wsh or data:<data length>
+impl FromStr for OutputType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputType, Error> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["p2pkh"] => Ok(OutputType::P2pkh),
+            ["p2sh"] => Ok(OutputType::P2sh),
+            ["p2wpkh"] => Ok(OutputType::P2wpkh),
+            ["p2wsh"] => Ok(OutputType::P2wsh),
+            ["data", len] => Ok(OutputType::Data(parse_len(len)?)),
+            _ => Err(Error::InvalidArgument("unknown output type"))
+        }
+    }
+}
+
+fn parse_len(len: &str) -> Result<usize, Error> {
+    match len.parse::<usize>() {
+        Ok(len) if len <= 10000 => Ok(len),
+        _ => Err(Error::InvalidArgument("script length is not a number up to 10000"))
+    }
+}
+
+impl OutputType {
+    /// type of a script pubkey, None if not one of the known types
+    pub fn from_script(script: &Script) -> Option<OutputType> {
+        if script.is_p2pkh() {
+            Some(OutputType::P2pkh)
+        } else if script.is_p2sh() {
+            Some(OutputType::P2sh)
+        } else if script.is_v0_p2wpkh() {
+            Some(OutputType::P2wpkh)
+        } else if script.is_v0_p2wsh() {
+            Some(OutputType::P2wsh)
+        } else if script.is_op_return() {
+            let pushed = script.len() - 1;
+            Some(OutputType::Data(pushed.saturating_sub(push_len(pushed.saturating_sub(1)))))
+        } else {
+            None
+        }
+    }
+
+    pub fn from_address(address: &Address) -> Option<OutputType> {
+        Self::from_script(&address.script_pubkey())
+    }
+
+    /// script pubkey length
+    fn script(&self) -> usize {
+        match self {
+            OutputType::P2pkh => 25,
+            OutputType::P2sh => 23,
+            OutputType::P2wpkh => 22,
+            OutputType::P2wsh => 34,
+            OutputType::Data(len) => 1 + push_len(*len) + len
+        }
+    }
+
+    fn weight(&self) -> usize {
+        let script = self.script();
+        (OUTPUT_FIXED + var_int_len(script) + script) * 4
+    }
+}
+
+/// estimate size and weight of a transaction spending inputs to outputs
+pub fn estimate(inputs: &[InputType], outputs: &[OutputType]) -> TxSize {
+    let mut weight = (TX_FIXED + var_int_len(inputs.len()) + var_int_len(outputs.len())) * 4;
+    if inputs.iter().any(|i| i.witness() > 0) {
+        weight += SEGWIT_MARKER;
+        // inputs without witness still need an empty item count
+        weight += inputs.iter().filter(|i| i.witness() == 0).count();
+    }
+    weight += inputs.iter().map(|i| i.weight()).sum::<usize>();
+    weight += outputs.iter().map(|o| o.weight()).sum::<usize>();
+    TxSize { weight, vsize: (weight + 3) / 4 }
+}
+
+fn var_int_len(n: usize) -> usize {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x10000..=0xffffffff => 5,
+        _ => 9
+    }
+}
+
+/// length of the push opcodes in front of len data bytes
+fn push_len(len: usize) -> usize {
+    match len {
+        0..=75 => 1,
+        76..=0xff => 2,
+        _ => 3
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network, Script};
+
+    use std::str::FromStr;
+
+    use super::{estimate, InputType, OutputType};
+
+    #[test]
+    fn estimates() {
+        // well known sizes of single key spends
+        let legacy = estimate(&[InputType::P2pkh], &[OutputType::P2pkh, OutputType::P2pkh]);
+        assert_eq!(legacy.vsize, 226);
+        assert_eq!(legacy.weight, 904);
+        let segwit = estimate(&[InputType::P2wpkh], &[OutputType::P2wpkh, OutputType::P2wpkh]);
+        assert_eq!(segwit.weight, 562);
+        assert_eq!(segwit.vsize, 141);
+        assert_eq!(segwit.fee(2), 281);
+        let nested = estimate(&[InputType::P2shP2wpkh], &[OutputType::P2sh]);
+        assert_eq!(nested.vsize, 134);
+
+        let mixed = estimate(&[InputType::P2pkh, InputType::P2wpkh], &[OutputType::P2wsh]);
+        assert_eq!(mixed.weight, 40 + 2 + 1 + 592 + 272 + 172);
+
+        let data = estimate(&[InputType::P2wpkh], &[OutputType::Data(80)]);
+        assert_eq!(data.weight - estimate(&[InputType::P2wpkh], &[]).weight, (8 + 1 + 83) * 4);
+
+        let burn = Address::p2wsh(&Script::new(), Network::Testnet);
+        assert_eq!(OutputType::from_address(&burn), Some(OutputType::P2wsh));
+        assert_eq!(OutputType::from_script(&Script::new()), None);
+
+        let key = Address::p2wpkh(&"0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".parse().unwrap(), Network::Testnet);
+        assert_eq!(InputType::from_script(&key.script_pubkey()), Some(InputType::P2wpkh));
+        assert_eq!(InputType::from_script(&burn.script_pubkey()), None);
+        assert_eq!(InputType::P2wpkh.signature_weight(), 1 + 72 + 1 + 33);
+    }
+
+    #[test]
+    fn script_inputs() {
+        let p2wsh = InputType::P2wsh { script: 41, branch: false };
+        // a signature and the witness script
+        assert_eq!(estimate(&[p2wsh], &[]).weight - estimate(&[InputType::P2wpkh], &[]).weight, 41 + 1 - 33 - 1);
+        let branch = InputType::P2wsh { script: 41, branch: true };
+        assert_eq!(branch.signature_weight() - p2wsh.signature_weight(), 2);
+
+        assert_eq!(InputType::from_str("p2wsh:41:branch").unwrap(), branch);
+        assert_eq!(InputType::from_str("p2sh-p2wpkh").unwrap(), InputType::P2shP2wpkh);
+        assert!(InputType::from_str("p2wsh").is_err());
+        assert_eq!(OutputType::from_str("data:80").unwrap(), OutputType::Data(80));
+        assert!(OutputType::from_str("p2tr").is_err());
+    }
+}
@@ -0,0 +1,500 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! vaults: coins locked until a block height, then unvaulted to an output
+//! that can only be spent after a delay but can be sent back to a new vault until then.
+//! Vault scripts are miniscript, their descriptors carry the origin of our keys in the seed
+
+use std::str::FromStr;
+
+use bitcoin::{Address, Network, OutPoint, PublicKey, Script, SigHashType, Transaction, TxIn, TxOut};
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, Fingerprint};
+use bitcoin_hashes::sha256d;
+
+use crate::error::Error;
+use crate::secret::{self, SecretMaster};
+use crate::txsize::{self, InputType};
+use crate::wallet::FeeLimits;
+
+/// purpose of vault key derivation m/purpose'/coin'/index'
+const VAULT_PURPOSE: u32 = 0x7661;
+/// lock times from here on are unix times, vaults only lock until a block height
+pub const LOCKTIME_THRESHOLD: u32 = 500000000;
+/// maximum delay of unvaulted coins, approx. one month
+pub const MAX_DELAY: u16 = 6 * 24 * 30;
+/// non final, so the lock time is enforced
+const NON_FINAL: u32 = 0xffffffff - 1;
+const DUST: u64 = 546;
+const MIN_FEE_PER_VBYTE: u64 = 1;
+const MAX_FEE_PER_VBYTE: u64 = 100;
+const GENERATOR: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const DESCRIPTOR_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VaultState {
+    /// locked until lock_time
+    Locked,
+    /// unvault transaction sent, waiting for the delay
+    Unvaulting,
+    /// unvaulted coins were spent or sent to a new vault
+    Closed,
+}
+
+impl VaultState {
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            VaultState::Locked => 0,
+            VaultState::Unvaulting => 1,
+            VaultState::Closed => 2,
+        }
+    }
+
+    pub fn from_u32(n: u32) -> Option<VaultState> {
+        match n {
+            0 => Some(VaultState::Locked),
+            1 => Some(VaultState::Unvaulting),
+            2 => Some(VaultState::Closed),
+            _ => None
+        }
+    }
+}
+
+/// coins in a vault output
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vault {
+    /// the vault output
+    pub outpoint: OutPoint,
+    pub value: u64,
+    /// block height the vault is locked until
+    pub lock_time: u32,
+    /// blocks the unvault transaction must be confirmed before the coins can be spent
+    pub delay: u16,
+    /// index of the vault keys
+    pub index: u32,
+    /// key that cancels unvaulting instead of our own cancel key, e.g. one kept offline
+    pub cancel_key: Option<PublicKey>,
+    /// the keys are of the seed retired by a seed rotation
+    pub retired: bool,
+    pub state: VaultState,
+    /// the transaction unvaulting, its first output holds the coins
+    pub unvault: Option<Transaction>,
+    /// the block that confirmed the unvault transaction
+    pub unvault_block: Option<sha256d::Hash>,
+}
+
+/// keys of a vault, spend unlocks the vault and unvaulted coins after the delay,
+/// cancel sends unvaulted coins back to a vault before that
+pub struct VaultKeys {
+    spend: SecretKey,
+    cancel: SecretKey,
+    /// origin of the keys, fingerprint of the master key and path m/purpose'/coin'/index'
+    fingerprint: Fingerprint,
+    path: Vec<ChildNumber>,
+}
+
+impl VaultKeys {
+    /// keys m/purpose'/coin'/index'/0 and /1
+    pub fn new(master: &ExtendedPrivKey, index: u32) -> Result<VaultKeys, Error> {
+        let secp = Secp256k1::signing_only();
+        let coin = if master.network == Network::Bitcoin { 0 } else { 1 };
+        let path = vec!(
+            ChildNumber::from_hardened_idx(VAULT_PURPOSE).expect("valid index"),
+            ChildNumber::from_hardened_idx(coin).expect("valid index"),
+            ChildNumber::from_hardened_idx(index).map_err(|_| Error::InvalidArgument("vault index is out of range"))?);
+        let vault = SecretMaster::from(master.derive_priv(&secp, &DerivationPath::from(path.clone())).map_err(|_| Error::Unsupported("can not derive vault keys"))?);
+        let spend = vault.ckd_priv(&secp, ChildNumber::from_normal_idx(0).expect("valid index"))
+            .map_err(|_| Error::Unsupported("can not derive vault keys"))?.private_key.key;
+        let cancel = vault.ckd_priv(&secp, ChildNumber::from_normal_idx(1).expect("valid index"))
+            .map_err(|_| Error::Unsupported("can not derive vault keys"))?.private_key.key;
+        Ok(VaultKeys { spend, cancel, fingerprint: master.fingerprint(&secp), path })
+    }
+
+    fn public(key: &SecretKey) -> PublicKey {
+        PublicKey { compressed: true, key: bitcoin::secp256k1::PublicKey::from_secret_key(&Secp256k1::signing_only(), key) }
+    }
+
+    /// the public key that cancels unvaulting, cancel_key if given else ours
+    pub fn cancel_public(&self, cancel_key: Option<&PublicKey>) -> PublicKey {
+        cancel_key.cloned().unwrap_or_else(|| Self::public(&self.cancel))
+    }
+
+    /// the address of a vault locked until lock_time
+    pub fn vault_address(&self, lock_time: u32, network: Network) -> Address {
+        Address::p2wsh(&vault_script(&Self::public(&self.spend), lock_time), network)
+    }
+
+    /// the address of unvaulted coins, cancel_key replaces our cancel key if given
+    pub fn unvault_address(&self, delay: u16, cancel_key: Option<&PublicKey>, network: Network) -> Address {
+        Address::p2wsh(&unvault_script(&Self::public(&self.spend), &self.cancel_public(cancel_key), delay), network)
+    }
+
+    /// output descriptor of a vault locked until lock_time
+    pub fn vault_descriptor(&self, lock_time: u32) -> String {
+        with_checksum(format!("wsh(and_v(v:after({}),pk({})))", lock_time, self.origin(0, &self.spend)))
+    }
+
+    /// output descriptor of unvaulted coins, cancel_key replaces our cancel key if given
+    pub fn unvault_descriptor(&self, delay: u16, cancel_key: Option<&PublicKey>) -> String {
+        let cancel = match cancel_key {
+            Some(key) => key.to_string(),
+            None => self.origin(1, &self.cancel)
+        };
+        with_checksum(format!("wsh(c:or_i(and_v(v:older({}),pk_k({})),pk_k({})))", delay, self.origin(0, &self.spend), cancel))
+    }
+
+    /// key expression with origin [fingerprint/purpose'/coin'/index'/child]
+    fn origin(&self, child: u32, key: &SecretKey) -> String {
+        let mut origin = self.fingerprint.to_string();
+        for n in self.path.iter() {
+            origin.push_str(format!("/{}", n).as_str());
+        }
+        format!("[{}/{}]{}", origin, child, Self::public(key))
+    }
+}
+
+impl Drop for VaultKeys {
+    fn drop(&mut self) {
+        secret::wipe(&mut self.spend);
+        secret::wipe(&mut self.cancel);
+    }
+}
+
+/// spendable by key once the lock time passed, miniscript and_v(v:after(lock_time),pk(key))
+pub fn vault_script(key: &PublicKey, lock_time: u32) -> Script {
+    Builder::new()
+        .push_int(lock_time as i64)
+        .push_opcode(all::OP_CLTV)
+        .push_opcode(all::OP_VERIFY)
+        .push_slice(key.to_bytes().as_slice())
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+/// spendable by key after delay blocks, or by cancel at any time,
+/// miniscript c:or_i(and_v(v:older(delay),pk_k(key)),pk_k(cancel))
+pub fn unvault_script(key: &PublicKey, cancel: &PublicKey, delay: u16) -> Script {
+    Builder::new()
+        .push_opcode(all::OP_IF)
+        .push_int(delay as i64)
+        .push_opcode(all::OP_CSV)
+        .push_opcode(all::OP_VERIFY)
+        .push_slice(key.to_bytes().as_slice())
+        .push_opcode(all::OP_ELSE)
+        .push_slice(cancel.to_bytes().as_slice())
+        .push_opcode(all::OP_ENDIF)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+impl Vault {
+    /// transaction moving the coins of the vault to the unvault address
+    pub fn unvault(&self, keys: &VaultKeys, fee_per_vbyte: u64, network: Network, limits: &FeeLimits) -> Result<(Transaction, u64), Error> {
+        if self.state != VaultState::Locked {
+            return Err(Error::InvalidArgument("vault is not locked"));
+        }
+        let script = vault_script(&VaultKeys::public(&keys.spend), self.lock_time);
+        spend(self.outpoint, self.value, &script, &keys.spend, None, NON_FINAL, self.lock_time,
+              keys.unvault_address(self.delay, self.cancel_key.as_ref(), network).script_pubkey(), fee_per_vbyte, limits)
+    }
+
+    /// transaction spending the unvaulted coins to address, valid once unvault is confirmed for delay blocks
+    pub fn withdraw(&self, keys: &VaultKeys, address: &Address, fee_per_vbyte: u64, limits: &FeeLimits) -> Result<(Transaction, u64), Error> {
+        let (outpoint, value) = self.unvaulted()?;
+        let script = unvault_script(&VaultKeys::public(&keys.spend), &keys.cancel_public(self.cancel_key.as_ref()), self.delay);
+        spend(outpoint, value, &script, &keys.spend, Some(true), self.delay as u32, 0,
+              address.script_pubkey(), fee_per_vbyte, limits)
+    }
+
+    /// transaction sending the unvaulted coins to an other vault at once,
+    /// cancel_key is the private key of a vault with its own cancel key
+    pub fn cancel(&self, keys: &VaultKeys, cancel_key: Option<&SecretKey>, vault: &Address, fee_per_vbyte: u64, limits: &FeeLimits) -> Result<(Transaction, u64), Error> {
+        let (outpoint, value) = self.unvaulted()?;
+        let key = match (&self.cancel_key, cancel_key) {
+            (None, _) => &keys.cancel,
+            (Some(public), Some(key)) if VaultKeys::public(key) == *public => key,
+            (Some(_), _) => return Err(Error::InvalidArgument("the cancel key of the vault is needed"))
+        };
+        let script = unvault_script(&VaultKeys::public(&keys.spend), &keys.cancel_public(self.cancel_key.as_ref()), self.delay);
+        spend(outpoint, value, &script, key, Some(false), NON_FINAL, 0,
+              vault.script_pubkey(), fee_per_vbyte, limits)
+    }
+
+    /// input type of the coins of the vault while not closed, to estimate the size of transactions spending them
+    pub fn input_type(&self) -> Option<InputType> {
+        // scripts have the same length with any compressed key
+        let key = PublicKey::from_str(GENERATOR).expect("valid key");
+        match self.state {
+            VaultState::Locked => Some(InputType::P2wsh { script: vault_script(&key, self.lock_time).len(), branch: false }),
+            VaultState::Unvaulting => Some(InputType::P2wsh { script: unvault_script(&key, &key, self.delay).len(), branch: true }),
+            VaultState::Closed => None
+        }
+    }
+
+    /// output descriptor of the coins of the vault while not closed
+    pub fn descriptor(&self, keys: &VaultKeys) -> Option<String> {
+        match self.state {
+            VaultState::Locked => Some(keys.vault_descriptor(self.lock_time)),
+            VaultState::Unvaulting => Some(keys.unvault_descriptor(self.delay, self.cancel_key.as_ref())),
+            VaultState::Closed => None
+        }
+    }
+
+    fn unvaulted(&self) -> Result<(OutPoint, u64), Error> {
+        match (self.state, &self.unvault) {
+            (VaultState::Unvaulting, Some(unvault)) =>
+                Ok((OutPoint { txid: unvault.txid(), vout: 0 }, unvault.output[0].value)),
+            _ => Err(Error::InvalidArgument("vault is not unvaulting"))
+        }
+    }
+}
+
+/// spend a single P2WSH output to destination, the fee is paid from its value and checked against limits before signing.
+/// branch selects the IF (true) or ELSE (false) branch of the script
+fn spend(input: OutPoint, value: u64, script: &Script, key: &SecretKey, branch: Option<bool>, sequence: u32, lock_time: u32,
+         destination: Script, fee_per_vbyte: u64, limits: &FeeLimits) -> Result<(Transaction, u64), Error> {
+    if fee_per_vbyte > MAX_FEE_PER_VBYTE {
+        return Err(Error::InvalidArgument("vault fee rate exceeds 100 sat/vbyte"));
+    }
+    let fee_per_vbyte = std::cmp::max(MIN_FEE_PER_VBYTE, fee_per_vbyte);
+    // sized with the longest signature
+    let mut tx = Transaction {
+        input: vec!(TxIn { previous_output: input, script_sig: Script::new(), sequence, witness: witness(vec!(0u8; txsize::SIGNATURE), script, branch) }),
+        output: vec!(TxOut { value, script_pubkey: destination }),
+        version: 2,
+        lock_time,
+    };
+    let fee = (tx.get_weight() as u64 * fee_per_vbyte + 3) / 4;
+    if value < fee + DUST {
+        return Err(Error::Unsupported("vault value is less than the fees needed (+DUST limit)"));
+    }
+    limits.check(fee, value)?;
+    tx.output[0].value = value - fee;
+    sign(&mut tx, value, script, key, branch);
+    Ok((tx, fee))
+}
+
+fn sign(tx: &mut Transaction, value: u64, script: &Script, key: &SecretKey, branch: Option<bool>) {
+    let secp = Secp256k1::signing_only();
+    let sighash = SighashComponents::new(tx).sighash_all(&tx.input[0], script, value);
+    let signature = secp.sign(&Message::from_slice(&sighash[..]).expect("sighash is 32 bytes"), key);
+    let mut sig = signature.serialize_der().to_vec();
+    sig.push(SigHashType::All as u8);
+    tx.input[0].witness = witness(sig, script, branch);
+}
+
+fn witness(sig: Vec<u8>, script: &Script, branch: Option<bool>) -> Vec<Vec<u8>> {
+    let mut witness = vec!(sig);
+    match branch {
+        Some(true) => witness.push(vec!(1)),
+        Some(false) => witness.push(Vec::new()),
+        None => {}
+    }
+    witness.push(script.to_bytes());
+    witness
+}
+
+/// append the checksum of BIP380 to a descriptor
+fn with_checksum(descriptor: String) -> String {
+    fn polymod(c: u64, value: u64) -> u64 {
+        let top = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ value;
+        for (bit, generator) in [0xf5dee51989u64, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd].iter().enumerate() {
+            if top & (1 << bit) != 0 {
+                c ^= generator;
+            }
+        }
+        c
+    }
+    let mut c = 1u64;
+    let mut class = 0u64;
+    let mut count = 0;
+    for ch in descriptor.chars() {
+        let position = DESCRIPTOR_CHARSET.find(ch).expect("descriptor character") as u64;
+        c = polymod(c, position & 31);
+        class = class * 3 + (position >> 5);
+        count += 1;
+        if count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            count = 0;
+        }
+    }
+    if count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    let checksum = (0..8).map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char).collect::<String>();
+    format!("{}#{}", descriptor, checksum)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, OutPoint, PublicKey};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+
+    use crate::error::Error;
+    use crate::txsize::{self, OutputType};
+    use crate::wallet::FeeLimits;
+
+    use super::{Vault, VaultKeys, VaultState, with_checksum};
+
+    const NO_LIMITS: FeeLimits = FeeLimits { max_fee_percent: None, max_fee: None };
+
+    fn locked(value: u64, cancel_key: Option<PublicKey>) -> Vault {
+        Vault {
+            outpoint: OutPoint::default(),
+            value,
+            lock_time: 1000,
+            delay: 144,
+            index: 0,
+            cancel_key,
+            retired: false,
+            state: VaultState::Locked,
+            unvault: None,
+            unvault_block: None,
+        }
+    }
+
+    #[test]
+    fn unvault_withdraw_cancel() {
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let keys = VaultKeys::new(&master, 0).unwrap();
+        let mut vault = locked(100000, None);
+        assert!(vault.withdraw(&keys, &keys.vault_address(2000, Network::Testnet), 1, &FeeLimits::default()).is_err());
+
+        let (unvault, fee) = vault.unvault(&keys, 1, Network::Testnet, &FeeLimits::default()).unwrap();
+        assert_eq!(unvault.lock_time, 1000);
+        assert!(unvault.input[0].sequence < 0xffffffff);
+        assert_eq!(unvault.input[0].witness.len(), 2);
+        assert_eq!(unvault.output[0].value + fee, 100000);
+        assert_eq!(unvault.output[0].script_pubkey, keys.unvault_address(144, None, Network::Testnet).script_pubkey());
+
+        vault.state = VaultState::Unvaulting;
+        vault.unvault = Some(unvault.clone());
+        let address = keys.vault_address(2000, Network::Testnet);
+        let (withdraw, _) = vault.withdraw(&keys, &address, 1, &FeeLimits::default()).unwrap();
+        assert_eq!(withdraw.input[0].previous_output.txid, unvault.txid());
+        assert_eq!(withdraw.input[0].sequence, 144);
+        assert_eq!(withdraw.input[0].witness[1], vec!(1u8));
+
+        let (cancel, _) = vault.cancel(&keys, None, &address, 1, &FeeLimits::default()).unwrap();
+        assert!(cancel.input[0].witness[1].is_empty());
+        assert_eq!(cancel.output[0].script_pubkey, address.script_pubkey());
+        assert_ne!(cancel.input[0].witness[0], withdraw.input[0].witness[0]);
+    }
+
+    #[test]
+    fn separate_cancel_key() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let keys = VaultKeys::new(&master, 0).unwrap();
+        let cold = SecretKey::from_slice(&[2u8; 32]).unwrap();
+        let cold_public = PublicKey { compressed: true, key: bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &cold) };
+        let mut vault = locked(100000, Some(cold_public));
+        let (unvault, _) = vault.unvault(&keys, 1, Network::Testnet, &FeeLimits::default()).unwrap();
+        assert_eq!(unvault.output[0].script_pubkey, keys.unvault_address(144, Some(&cold_public), Network::Testnet).script_pubkey());
+        assert_ne!(unvault.output[0].script_pubkey, keys.unvault_address(144, None, Network::Testnet).script_pubkey());
+
+        vault.state = VaultState::Unvaulting;
+        vault.unvault = Some(unvault);
+        let address = keys.vault_address(2000, Network::Testnet);
+        // our own cancel key can not cancel
+        assert!(vault.cancel(&keys, None, &address, 1, &FeeLimits::default()).is_err());
+        assert!(vault.cancel(&keys, Some(&SecretKey::from_slice(&[3u8; 32]).unwrap()), &address, 1, &FeeLimits::default()).is_err());
+        let (cancel, _) = vault.cancel(&keys, Some(&cold), &address, 1, &FeeLimits::default()).unwrap();
+        assert!(cancel.input[0].witness[1].is_empty());
+        // the spend key still withdraws
+        assert!(vault.withdraw(&keys, &address, 1, &FeeLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn fee_rate_limit() {
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let keys = VaultKeys::new(&master, 0).unwrap();
+        let vault = locked(100000, None);
+        match vault.unvault(&keys, 101, Network::Testnet, &NO_LIMITS) {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("fee rate above the limit was accepted")
+        }
+        assert!(vault.unvault(&keys, 100, Network::Testnet, &NO_LIMITS).is_ok());
+    }
+
+    #[test]
+    fn fee_limits() {
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let keys = VaultKeys::new(&master, 0).unwrap();
+        let vault = locked(100000, None);
+        let (_, fee) = vault.unvault(&keys, 10, Network::Testnet, &NO_LIMITS).unwrap();
+        // refused with the fee of the transaction that would have been signed
+        match vault.unvault(&keys, 10, Network::Testnet, &FeeLimits { max_fee_percent: None, max_fee: Some(fee - 1) }) {
+            Err(Error::FeeTooHigh(high)) => assert_eq!(high, fee),
+            _ => panic!("fee above the limit was accepted")
+        }
+        assert!(vault.unvault(&keys, 10, Network::Testnet, &FeeLimits { max_fee_percent: None, max_fee: Some(fee) }).is_ok());
+        // at 100 sat/vbyte the fee is more than 10% of the vault
+        assert!(vault.unvault(&keys, 100, Network::Testnet, &FeeLimits::default()).is_err());
+    }
+
+    #[test]
+    fn input_types() {
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let keys = VaultKeys::new(&master, 0).unwrap();
+        let mut vault = locked(100000, None);
+        let (unvault, fee) = vault.unvault(&keys, 1, Network::Testnet, &FeeLimits::default()).unwrap();
+        assert_eq!(txsize::estimate(&[vault.input_type().unwrap()], &[OutputType::P2wsh]).fee(1), fee);
+
+        vault.state = VaultState::Unvaulting;
+        vault.unvault = Some(unvault);
+        let address = keys.vault_address(2000, Network::Testnet);
+        let (_, fee) = vault.withdraw(&keys, &address, 1, &FeeLimits::default()).unwrap();
+        assert_eq!(txsize::estimate(&[vault.input_type().unwrap()], &[OutputType::P2wsh]).fee(1), fee);
+        vault.state = VaultState::Closed;
+        assert_eq!(vault.input_type(), None);
+    }
+
+    #[test]
+    fn descriptors() {
+        assert_eq!(with_checksum("raw(deadbeef)".to_string()), "raw(deadbeef)#89f8spxm");
+        assert_eq!(with_checksum("addr(mkmZxiEcEd8ZqjQWVZuC6so5dFMKEFpN2j)".to_string()), "addr(mkmZxiEcEd8ZqjQWVZuC6so5dFMKEFpN2j)#02wpgw69");
+
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32]).unwrap();
+        let keys = VaultKeys::new(&master, 5).unwrap();
+        // keys are found again in the seed with the origin of the descriptor
+        let spend = master.derive_priv(&secp, &"m/30305'/1'/5'/0".parse::<DerivationPath>().unwrap()).unwrap().private_key.public_key(&secp);
+        let cancel = master.derive_priv(&secp, &"m/30305'/1'/5'/1".parse::<DerivationPath>().unwrap()).unwrap().private_key.public_key(&secp);
+        let origin = format!("[{}/30305'/1'/5'", master.fingerprint(&secp));
+        let descriptor = keys.vault_descriptor(1000);
+        assert!(descriptor.starts_with(format!("wsh(and_v(v:after(1000),pk({}/0]{})))#", origin, spend).as_str()));
+        let descriptor = keys.unvault_descriptor(144, None);
+        assert!(descriptor.starts_with(format!("wsh(c:or_i(and_v(v:older(144),pk_k({}/0]{})),pk_k({}/1]{})))#", origin, spend, origin, cancel).as_str()));
+        assert!(keys.unvault_descriptor(144, Some(&spend)).contains(format!(",pk_k({})", spend).as_str()));
+
+        let mut vault = locked(100000, None);
+        assert_eq!(vault.descriptor(&keys), Some(keys.vault_descriptor(1000)));
+        vault.state = VaultState::Closed;
+        assert_eq!(vault.descriptor(&keys), None);
+    }
+}
@@ -14,36 +14,263 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use bitcoin::{Address, Block, PublicKey, Script, SigHashType, Transaction, TxIn, TxOut};
+use bitcoin::{Address, Block, OutPoint, PrivateKey, PublicKey, Script, SigHashType, Transaction, TxIn, TxOut};
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Builder;
 use bitcoin::consensus::serialize;
 use bitcoin::network::constants::Network;
-use bitcoin::util::bip32::ExtendedPubKey;
-use bitcoin_hashes::{sha256, sha256d};
-use bitcoin_wallet::account::{Account, AccountAddressType, MasterAccount, Unlocker};
-use bitcoin_wallet::coins::Coins;
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin_hashes::{Hash, HashEngine, sha256, sha256d};
+use bitcoin_wallet::account::{Account, AccountAddressType, MasterAccount, Seed, Unlocker};
+use bitcoin_wallet::coins::{Coin, Coins};
 use bitcoin_wallet::mnemonic::Mnemonic;
 use bitcoin_wallet::proved::ProvedTransaction;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use rand::{RngCore, thread_rng};
+use zeroize::{Zeroize, Zeroizing};
 
+use crate::bip47::{self, PaymentCode};
 use crate::error::Error;
+use crate::history::HistoryEntry;
+use crate::imported::{self, Imported, ImportedKey};
+use crate::keystore;
+use crate::secret::{Secret, SecretMaster};
 use crate::trunk::Trunk;
+use crate::txsize::{self, InputType, OutputType};
+use crate::vault::{LOCKTIME_THRESHOLD, VaultKeys};
 
 pub const KEY_LOOK_AHEAD: u32 = 10;
 const KEY_PURPOSE: u32 = 0xb1ad;
-const DUST: u64 = 546;
+/// outputs below this are not relayed
+pub const DUST: u64 = 546;
 const MAX_FEE_PER_VBYTE: u64 = 100;
 const MIN_FEE_PER_VBYTE: u64 = 1;
 const MAX_TERM: u16 = 6 * 24 * 30;
 // approx. one month.
 const RBF: u32 = 0xffffffff - 2;
+/// a tip older than this is considered out of sync for anti fee sniping
+const MAX_TIP_AGE: u64 = 8 * 60 * 60;
+const FINAL_SEQUENCE: u32 = 0xffffffff;
+/// BIP68: a sequence with this flag has no relative lock time
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// BIP68: a relative lock time with this flag is in units of 512 seconds, otherwise in blocks
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0xffff;
+/// standard limit of data in an OP_RETURN output
+pub const MAX_DATA: usize = 80;
+/// fee limit as percentage of the amount if none is configured
+pub const DEFAULT_MAX_FEE_PERCENT: u32 = 10;
+
+/// a transaction is not signed if its fee exceeds these
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeLimits {
+    /// percentage of the amount sent, None for no limit
+    pub max_fee_percent: Option<u32>,
+    /// satoshis, None for no limit
+    pub max_fee: Option<u64>,
+}
+
+impl Default for FeeLimits {
+    fn default() -> FeeLimits {
+        FeeLimits { max_fee_percent: Some(DEFAULT_MAX_FEE_PERCENT), max_fee: None }
+    }
+}
+
+impl FeeLimits {
+    pub fn check(&self, fee: u64, amount: u64) -> Result<(), Error> {
+        if self.max_fee_percent.map(|p| fee as u128 * 100 > p as u128 * amount as u128).unwrap_or(false) ||
+            self.max_fee.map(|max| fee > max).unwrap_or(false) {
+            return Err(Error::FeeTooHigh(fee));
+        }
+        Ok(())
+    }
+}
+
+/// caller choices for lock time and sequences of a new transaction
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TxOptions {
+    /// absolute lock time, a block height below 500000000 otherwise a unix time, None for anti fee sniping.
+    /// It must be satisfied by the next block
+    pub lock_time: Option<u32>,
+    /// sequence of all inputs, None to signal replace-by-fee
+    pub sequence: Option<u32>,
+    /// sequence of specific inputs, overrides sequence. Outpoints must be spent by the transaction and
+    /// relative lock times satisfied by the confirmations of the coins.
+    /// inputs spending term deposits keep the sequence their script requires
+    pub input_sequences: HashMap<OutPoint, u32>,
+    /// data of up to MAX_DATA bytes carried in an OP_RETURN output
+    pub data: Option<Vec<u8>>,
+    /// fee limits, None for the configured limits
+    pub fee_limits: Option<FeeLimits>,
+    /// sign even if the fee exceeds the limits
+    pub allow_high_fee: bool,
+    /// spend unconfirmed change of our own transactions if confirmed coins are insufficient,
+    /// None for the configured setting
+    pub spend_unconfirmed_change: Option<bool>,
+    /// seed of coin selection order, change position and lock time offset, so the same coins and options
+    /// always give the same transaction. None for random choices
+    pub seed: Option<u64>,
+}
+
+impl TxOptions {
+    fn sequence(&self, point: &OutPoint) -> u32 {
+        self.input_sequences.get(point).cloned().or(self.sequence).unwrap_or(RBF)
+    }
+
+    fn lock_time(&self, trunk: &dyn Trunk) -> u32 {
+        self.lock_time.unwrap_or_else(|| anti_fee_sniping_locktime(trunk, self.random(b"lock time")))
+    }
+
+    // a random number, derived from the seed and purpose if a seed is given
+    fn random(&self, purpose: &[u8]) -> u32 {
+        match self.seed {
+            Some(seed) => {
+                let mut engine = sha256::Hash::engine();
+                engine.input(&seed.to_le_bytes());
+                engine.input(purpose);
+                let hash = sha256::Hash::from_engine(engine);
+                u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]])
+            }
+            None => thread_rng().next_u32()
+        }
+    }
+
+    // order coins by the seed, if one is given
+    fn order_coins(&self, coins: &mut Vec<(OutPoint, Coin, u32)>) {
+        if self.seed.is_some() {
+            coins.sort_by_cached_key(|(point, _, _)| (self.random(serialize(point).as_slice()), *point));
+        }
+    }
+
+    fn data_output(&self) -> Option<TxOut> {
+        self.data.as_ref().map(|data| TxOut {
+            value: 0,
+            script_pubkey: Builder::new().push_opcode(all::OP_RETURN).push_slice(data.as_slice()).into_script(),
+        })
+    }
+
+    fn check_fee(&self, fee: u64, amount: u64) -> Result<(), Error> {
+        if self.allow_high_fee {
+            return Ok(());
+        }
+        self.fee_limits.unwrap_or_default().check(fee, amount)
+    }
+
+    // check that tx spending coins may be included in the next block
+    fn validate(&self, tx: &Transaction, coins: &[(OutPoint, Coin, u32)], trunk: &dyn Trunk) -> Result<(), Error> {
+        let height = trunk.len();
+        if tx.lock_time != 0 {
+            // lock time is only enforced if an input is not final
+            if tx.input.iter().all(|i| i.sequence == FINAL_SEQUENCE) {
+                return Err(Error::InvalidArgument("lock time needs an input with a non final sequence"));
+            }
+            let next = if tx.lock_time < LOCKTIME_THRESHOLD { height } else { median_time_past(trunk, height) };
+            if tx.lock_time >= next {
+                return Err(Error::InvalidArgument("lock time is beyond the next block"));
+            }
+        }
+        if self.input_sequences.keys().any(|point| !tx.input.iter().any(|i| i.previous_output == *point)) {
+            return Err(Error::InvalidArgument("input sequence for an outpoint not spent"));
+        }
+        // BIP68 relative lock times of our coins, confirmed at their height or unconfirmed at height
+        for input in tx.input.iter().filter(|i| tx.version >= 2 && i.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0) {
+            if let Some((_, _, confirmed)) = coins.iter().find(|(point, _, _)| *point == input.previous_output) {
+                let lock = input.sequence & SEQUENCE_LOCKTIME_MASK;
+                let passed = if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                    median_time_past(trunk, height).saturating_sub(median_time_past(trunk, *confirmed)) >> 9
+                } else {
+                    height.saturating_sub(*confirmed)
+                };
+                if passed < lock {
+                    return Err(Error::InvalidArgument("relative lock time of an input is not satisfied by its confirmations"));
+                }
+            }
+        }
+        if self.data.as_ref().map(|d| d.len() > MAX_DATA).unwrap_or(false) {
+            return Err(Error::InvalidArgument("data exceeds 80 bytes"));
+        }
+        Ok(())
+    }
+}
+
+/// an input of an other party to a collaborative transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternalInput {
+    pub outpoint: OutPoint,
+    /// script of the spent output
+    pub script_pubkey: Script,
+    /// value of the spent output
+    pub value: u64,
+    /// signature of the other party for the input, with its key
+    pub partial_sig: Option<(PublicKey, Vec<u8>)>,
+}
+
+/// a derived address of the wallet
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddressInfo {
+    pub address: Address,
+    /// BIP32 derivation path from the master key
+    pub path: String,
+    /// 0 for receive, 1 for change
+    pub sub: u32,
+    pub kix: u32,
+    /// true if a known transaction paid to the address
+    pub received: bool,
+    /// value of unspent coins at the address
+    pub balance: u64,
+}
+
+/// an input of a wallet transaction
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct InputInfo {
+    pub outpoint: OutPoint,
+    /// value of the spent output, if its transaction is known to the wallet
+    pub value: Option<u64>,
+    pub address: Option<String>,
+    /// true if the spent output was ours
+    pub ours: bool,
+}
+
+/// an output of a wallet transaction
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OutputInfo {
+    pub vout: u32,
+    pub value: u64,
+    pub address: Option<String>,
+    /// true if paid to us
+    pub ours: bool,
+}
+
+/// a wallet relevant transaction, decoded
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TransactionInfo {
+    pub txid: sha256d::Hash,
+    /// hex of the consensus serialized transaction
+    pub hex: String,
+    /// block of the transaction, None if unconfirmed
+    pub block: Option<sha256d::Hash>,
+    pub height: Option<u32>,
+    pub inputs: Vec<InputInfo>,
+    pub outputs: Vec<OutputInfo>,
+    /// received by us less spent by us, in satoshis
+    pub net: i64,
+    /// known if the values of all spent outputs are known
+    pub fee: Option<u64>,
+}
 
 pub struct Wallet {
     pub coins: Coins,
     pub master: MasterAccount,
+    /// keys imported from other wallets
+    pub imported: Imported,
+    /// key of our payment code account while receiving payments to our payment code
+    payment_code_account: Option<SecretMaster>,
 }
 
 impl Wallet {
@@ -80,34 +307,476 @@ impl Wallet {
         self.coins.available_balance(height, height_for_block)
     }
 
-    pub fn unwind_tip(&mut self, block_hash: &sha256d::Hash) {
-        self.coins.unwind_tip(block_hash)
+    /// confirmed coins spendable at height
+    pub fn available_coins<H>(&self, height: u32, height_for_block: H) -> Vec<(OutPoint, Coin, u32)>
+        where H: Fn(&sha256d::Hash) -> Option<u32> + Copy {
+        self.coins.choose_inputs(self.available_balance(height, height_for_block), height, height_for_block)
+    }
+
+    /// unconfirmed change outputs of our own transactions
+    pub fn unconfirmed_change(&self) -> Vec<(OutPoint, Coin)> {
+        self.coins.unconfirmed().into_iter()
+            .filter(|(_, c)| c.derivation.account == 0 && c.derivation.sub == 1 && c.derivation.csv.is_none())
+            .map(|(p, c)| (p.clone(), c.clone()))
+            .collect()
+    }
+
+    /// available balance, with unconfirmed change if options allow it
+    pub fn spendable_balance(&self, options: &TxOptions, trunk: &dyn Trunk) -> u64 {
+        let available = self.available_balance(trunk.len(), |h| trunk.get_height(h));
+        if options.spend_unconfirmed_change.unwrap_or(false) {
+            available + self.unconfirmed_change().iter().map(|(_, c)| c.output.value).sum::<u64>()
+        } else {
+            available
+        }
+    }
+
+    // confirmed coins for amount, topped up with unconfirmed change, largest first, if they are insufficient and options allow it
+    fn choose_coins(&self, amount: u64, options: &TxOptions, trunk: &dyn Trunk) -> Vec<(OutPoint, Coin, u32)> {
+        let height = trunk.len();
+        if options.seed.is_some() {
+            // confirmed coins, then unconfirmed change if allowed, each in the order of the seed
+            let mut candidates = self.available_coins(height, |h| trunk.get_height(h));
+            options.order_coins(&mut candidates);
+            if options.spend_unconfirmed_change.unwrap_or(false) {
+                let mut change = self.unconfirmed_change().into_iter().map(|(p, c)| (p, c, height)).collect::<Vec<_>>();
+                options.order_coins(&mut change);
+                candidates.extend(change);
+            }
+            let mut total = 0u64;
+            return candidates.into_iter().take_while(|(_, c, _)| {
+                let needed = total < amount;
+                total += c.output.value;
+                needed
+            }).collect();
+        }
+        let mut coins = self.coins.choose_inputs(amount, height, |h| trunk.get_height(h));
+        let mut total = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
+        if total < amount && options.spend_unconfirmed_change.unwrap_or(false) {
+            let mut change = self.unconfirmed_change();
+            change.sort_by_key(|(_, c)| std::cmp::Reverse(c.output.value));
+            for (point, coin) in change {
+                if total >= amount {
+                    break;
+                }
+                total += coin.output.value;
+                coins.push((point, coin, height));
+            }
+        }
+        coins
+    }
+
+    pub fn unwind_tip(&mut self, block_hash: &sha256d::Hash, height: u32) {
+        self.coins.unwind_tip(block_hash);
+        self.imported.unwind(height);
     }
 
     pub fn rescan(&mut self) {
         self.coins = Coins::new();
+        for key in self.imported.keys.iter_mut() {
+            key.watched.coins.clear();
+        }
     }
 
-    pub fn process(&mut self, block: &Block) -> bool {
-        self.coins.process(&mut self.master, block)
+    /// process a block at height, true if coins of our or imported keys changed.
+    /// While receiving payments to our payment code, senders notifying us in the block are accepted
+    /// and keys beyond those paid to are imported
+    pub fn process(&mut self, block: &Block, height: u32) -> bool {
+        let notifications = self.notifications(block).into_iter().cloned().collect::<Vec<_>>();
+        let mut imported = !self.accept_payment_codes(notifications.as_slice()).is_empty();
+        imported |= self.imported.process(block, height);
+        // keys added beyond those paid to may be paid in the same block
+        loop {
+            let added = self.accept_payment_codes(&[]);
+            if added.is_empty() {
+                break;
+            }
+            imported = true;
+            for key in self.imported.keys.iter_mut().filter(|k| added.iter().any(|a| a.watched.script == k.watched.script)) {
+                key.watched.process(block, height);
+            }
+        }
+        self.coins.process(&mut self.master, block) || imported
+    }
+
+    /// import a WIF private key, returns keys not yet imported
+    pub fn import_wif(&mut self, passphrase: &str, wif: &str) -> Result<Vec<ImportedKey>, Error> {
+        let keys = imported::import_wif(wif, &self.unlocked_master(passphrase)?)?;
+        Ok(self.imported.add(keys))
+    }
+
+    /// import the first keys of an Electrum seed, returns keys not yet imported
+    pub fn import_electrum(&mut self, passphrase: &str, words: &str, seed_passphrase: &str) -> Result<Vec<ImportedKey>, Error> {
+        let keys = imported::import_electrum(words, seed_passphrase, &self.unlocked_master(passphrase)?)?;
+        Ok(self.imported.add(keys))
+    }
+
+    // the master private key, wiped on drop unlike the copy of the Unlocker taken from
+    fn unlocked_master(&self, passphrase: &str) -> Result<SecretMaster, Error> {
+        let unlocker = Unlocker::new(
+            self.master.encrypted(), passphrase,
+            self.master.master_public().network, Some(self.master.master_public()))?;
+        Ok(SecretMaster::from(*unlocker.master_private()))
     }
 
     pub fn prove(&self, txid: &sha256d::Hash) -> Option<&ProvedTransaction> {
         self.coins.proofs().get(txid)
     }
 
-    pub fn fund<W>(&mut self, id: &sha256::Hash, mut term: u16, passpharse: String, mut fee_per_vbyte: u64, amount: u64, trunk: Arc<dyn Trunk>, scripter: W) -> Result<(Transaction, PublicKey, u64), Error>
-        where W: FnOnce(&PublicKey, Option<u16>) -> Script {
+    /// decode a transaction of the wallet. Own transactions not yet in a block are looked up with stored
+    pub fn transaction_info<S, H>(&self, txid: &sha256d::Hash, stored: S, height_for_block: H) -> Option<TransactionInfo>
+        where S: Fn(&sha256d::Hash) -> Option<Transaction>,
+              H: Fn(&sha256d::Hash) -> Option<u32> {
+        let transaction = self.prove(txid).map(|p| p.get_transaction().clone()).or_else(|| stored(txid))?;
+        let mut info = self.decode(&transaction, stored);
+        info.block = self.prove(txid).map(|p| *p.get_block_hash());
+        info.height = info.block.as_ref().and_then(|b| height_for_block(b));
+        Some(info)
+    }
+
+    /// decode any transaction, marking what is ours. Spent outputs are looked up in known transactions and with stored
+    pub fn decode<S>(&self, transaction: &Transaction, stored: S) -> TransactionInfo
+        where S: Fn(&sha256d::Hash) -> Option<Transaction> {
+        let lookup = |txid: &sha256d::Hash| self.prove(txid).map(|p| p.get_transaction().clone()).or_else(|| stored(txid));
+        describe(transaction, self.master.master_public().network, &self.own_scripts(), lookup)
+    }
+
+    /// balance changes by the transactions of a block, call before the block is processed.
+    /// Own transactions spent by its inputs are looked up with stored
+    pub fn history<S>(&self, block: &Block, height: u32, stored: S) -> Vec<HistoryEntry>
+        where S: Fn(&sha256d::Hash) -> Option<Transaction> {
+        let ours = self.own_scripts();
+        let in_block = block.txdata.iter().map(|t| (t.txid(), t)).collect::<HashMap<_, _>>();
+        let previous = |point: &OutPoint| {
+            let vout = point.vout as usize;
+            if let Some(t) = in_block.get(&point.txid) {
+                t.output.get(vout).cloned()
+            } else if let Some(p) = self.prove(&point.txid) {
+                p.get_transaction().output.get(vout).cloned()
+            } else {
+                stored(&point.txid).and_then(|t| t.output.get(vout).cloned())
+            }
+        };
+        let mut history = Vec::new();
+        for transaction in block.txdata.iter() {
+            let received = transaction.output.iter().filter(|o| ours.contains(&o.script_pubkey)).map(|o| o.value).sum::<u64>();
+            let mut spent = 0u64;
+            let mut input_value = Some(0u64);
+            for input in transaction.input.iter() {
+                let output = previous(&input.previous_output);
+                if let Some(ref o) = output {
+                    if ours.contains(&o.script_pubkey) {
+                        spent += o.value;
+                    }
+                }
+                input_value = input_value.and_then(|v| output.map(|o| v + o.value));
+            }
+            if received == 0 && spent == 0 {
+                continue;
+            }
+            let output_value = transaction.output.iter().map(|o| o.value).sum::<u64>();
+            history.push(HistoryEntry {
+                txid: transaction.txid(),
+                height,
+                time: block.header.time,
+                net: received as i64 - spent as i64,
+                fee: if spent > 0 { input_value.map(|v| v.saturating_sub(output_value)) } else { None },
+            });
+        }
+        history
+    }
+
+    // scripts of our addresses and coins
+    fn own_scripts(&self) -> HashSet<Script> {
+        let mut scripts = HashSet::new();
+        for sub in 0..2 {
+            if let Some(chain) = self.master.get((0, sub)) {
+                scripts.extend(chain.instantiated().iter().map(|k| k.address.script_pubkey()));
+            }
+        }
+        scripts.extend(self.coins.confirmed().into_iter().map(|(_, c)| c.output.script_pubkey.clone()));
+        scripts.extend(self.coins.unconfirmed().into_iter().map(|(_, c)| c.output.script_pubkey.clone()));
+        scripts
+    }
+
+    /// addresses derived in the receive and change chains of an account, look ahead included
+    pub fn list_addresses(&self, account: u32) -> Result<Vec<AddressInfo>, Error> {
+        let mut received = HashSet::new();
+        for proof in self.coins.proofs().values() {
+            received.extend(proof.get_transaction().output.iter().map(|o| o.script_pubkey.clone()));
+        }
+        let mut balance = HashMap::new();
+        let outputs = self.coins.confirmed().into_iter().map(|(_, c)| c.output.clone())
+            .chain(self.coins.unconfirmed().into_iter().map(|(_, c)| c.output.clone()));
+        for output in outputs {
+            *balance.entry(output.script_pubkey.clone()).or_insert(0u64) += output.value;
+            received.insert(output.script_pubkey);
+        }
+        let coin_type = if self.master.master_public().network == Network::Bitcoin { 0 } else { 1 };
+        let mut addresses = Vec::new();
+        for sub in 0..2 {
+            let chain = self.master.get((account, sub)).ok_or(Error::InvalidArgument("unknown account"))?;
+            let purpose = match chain.address_type() {
+                AccountAddressType::P2PKH => 44,
+                AccountAddressType::P2SHWPKH => 49,
+                AccountAddressType::P2WPKH => 84,
+                AccountAddressType::P2WSH(purpose) => purpose
+            };
+            for (kix, key) in chain.instantiated().iter().enumerate() {
+                let script = key.address.script_pubkey();
+                addresses.push(AddressInfo {
+                    address: key.address.clone(),
+                    path: format!("m/{}'/{}'/{}'/{}/{}", purpose, coin_type, account, sub, kix),
+                    sub,
+                    kix: kix as u32,
+                    received: received.contains(&script),
+                    balance: balance.get(&script).cloned().unwrap_or(0),
+                });
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// our BIP47 payment code
+    pub fn payment_code(&self, passphrase: &str) -> Result<PaymentCode, Error> {
+        let account = self.payment_code_account(passphrase)?;
+        Ok(PaymentCode::from_account(&ExtendedPubKey::from_private(&bitcoin::secp256k1::Secp256k1::new(), &account)))
+    }
+
+    /// the address of the index-th payment to the owner of a payment code,
+    /// our code must have been sent to them in a notification transaction before
+    pub fn payment_address(&self, passphrase: &str, theirs: &PaymentCode, index: u32) -> Result<Address, Error> {
+        let network = self.master.master_public().network;
+        let designated = bip47::child_key(&self.payment_code_account(passphrase)?, 0)?;
+        bip47::send_address(&designated, theirs, index, network)
+    }
+
+    /// a notification transaction sending our payment code to the owner of theirs, so they find our payments.
+    /// It pays NOTIFICATION_VALUE to their notification address, the key of its first input blinds our code
+    pub fn notify_payment_code(&mut self, passphrase: &Secret, theirs: &PaymentCode, mut fee_per_vbyte: u64, options: &TxOptions, trunk: Arc<dyn Trunk>) -> Result<(Transaction, u64), Error> {
+        if options.data.is_some() {
+            return Err(Error::InvalidArgument("a notification transaction carries no other data"));
+        }
         let network = self.master.master_public().network;
+        let height = trunk.len();
+        fee_per_vbyte = fee_rate(fee_per_vbyte)?;
+        let address = theirs.notification_address(network)?;
+        // coins for the notification and the estimated fee of a transaction spending them
+        let mut needed = bip47::NOTIFICATION_VALUE;
+        let mut coins = loop {
+            let coins = self.choose_coins(needed, options, trunk.as_ref());
+            let total = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
+            if total < needed {
+                return Err(Error::InsufficientFunds);
+            }
+            let inputs = coins.iter().map(|(_, c, _)| InputType::from_script(&c.output.script_pubkey).unwrap_or(InputType::P2wpkh)).collect::<Vec<_>>();
+            let fee = txsize::estimate(inputs.as_slice(), &[OutputType::P2pkh, OutputType::Data(bip47::PAYLOAD_LEN), OutputType::P2wpkh]).fee(fee_per_vbyte);
+            if total >= bip47::NOTIFICATION_VALUE + fee {
+                break coins;
+            }
+            needed = bip47::NOTIFICATION_VALUE + fee;
+        };
+        let designated_coin = coins.iter().position(|(_, c, _)| c.derivation.account == 0 && c.derivation.csv.is_none())
+            .ok_or(Error::Unsupported("a notification needs a coin of our receive or change keys"))?;
+        coins.swap(0, designated_coin);
+        let total_input = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
+        let change_address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
+        let mut tx = Transaction {
+            input: coins.iter().map(|(point, coin, h)|
+                TxIn {
+                    previous_output: point.clone(),
+                    script_sig: Script::new(),
+                    sequence: if let Some(csv) = coin.derivation.csv {
+                        std::cmp::min(csv as u32, height - *h)
+                    } else { options.sequence(point) },
+                    witness: vec![],
+                }).collect(),
+            output: Vec::new(),
+            version: 2,
+            lock_time: options.lock_time(trunk.as_ref()),
+        };
+        options.validate(&tx, coins.as_slice(), trunk.as_ref())?;
+        let secp = Secp256k1::new();
+        // decrypted keys are only held while signing
         let mut unlocker = Unlocker::new(
-            self.master.encrypted(), passpharse.as_str(),
+            self.master.encrypted(), passphrase.as_str(),
             network, Some(self.master.master_public()))?;
-        fee_per_vbyte = std::cmp::min(MAX_FEE_PER_VBYTE, std::cmp::max(MIN_FEE_PER_VBYTE, fee_per_vbyte));
+        let (payload, designated) = {
+            let account = SecretMaster::from(bip47::account_key(unlocker.master_private())?);
+            let ours = PaymentCode::from_account(&ExtendedPubKey::from_private(&secp, &account));
+            let designated = self.coin_key(unlocker.master_private(), &coins[0].1)?;
+            (bip47::blind(&ours, theirs, &designated, &coins[0].0, network)?, bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &designated))
+        };
+        let mut fee = 0;
+        loop {
+            if total_input < bip47::NOTIFICATION_VALUE + fee {
+                return Err(Error::InsufficientFunds);
+            }
+            tx.output.clear();
+            tx.output.push(TxOut { value: bip47::NOTIFICATION_VALUE, script_pubkey: address.script_pubkey() });
+            tx.output.push(TxOut {
+                value: 0,
+                script_pubkey: Builder::new().push_opcode(all::OP_RETURN).push_slice(&payload[..]).into_script(),
+            });
+            let change = total_input - bip47::NOTIFICATION_VALUE - fee;
+            if change > DUST {
+                tx.output.push(TxOut { value: change, script_pubkey: change_address.script_pubkey() });
+            }
+            if self.master.sign(&mut tx, SigHashType::All,
+                                &|point| {
+                                    coins.iter().find(|(o, _, _)| *o == *point).map(|(_, c, _)| c.output.clone())
+                                }, &mut unlocker)?
+                != coins.len() {
+                error!("could not sign all inputs of our notification {:?} {}", tx, hex::encode(serialize(&tx)));
+                return Err(Error::Unsupported("could not sign for all inputs"));
+            }
+            if fee == 0 {
+                fee = (tx.get_weight() as u64 * fee_per_vbyte + 3) / 4;
+                options.check_fee(fee, total_input)?;
+            } else {
+                break;
+            }
+        }
+        drop(unlocker);
+        // the receiver unblinds our code with the key the first input reveals
+        if bip47::designated_input(&tx).map(|(_, key)| key) != Some(designated) {
+            return Err(Error::Unsupported("the first input of the notification does not reveal its key"));
+        }
+        self.coins.process_unconfirmed_transaction(&mut self.master, &tx);
+        Ok((tx, fee))
+    }
+
+    // the private key of a coin of our receive or change keys
+    fn coin_key(&self, master: &ExtendedPrivKey, coin: &Coin) -> Result<SecretKey, Error> {
+        let d = &coin.derivation;
+        let purpose = match self.master.get((d.account, d.sub)).map(|a| a.address_type()) {
+            Some(AccountAddressType::P2PKH) => 44,
+            Some(AccountAddressType::P2SHWPKH) => 49,
+            Some(AccountAddressType::P2WPKH) => 84,
+            _ => return Err(Error::Unsupported("not a coin of a single key"))
+        };
+        let coin_type = if master.network == Network::Bitcoin { 0 } else { 1 };
+        let path = DerivationPath::from(vec!(
+            ChildNumber::from_hardened_idx(purpose).expect("valid index"),
+            ChildNumber::from_hardened_idx(coin_type).expect("valid index"),
+            ChildNumber::from_hardened_idx(d.account).map_err(|_| Error::Unsupported("account out of range"))?,
+            ChildNumber::from_normal_idx(d.sub).map_err(|_| Error::Unsupported("sub account out of range"))?,
+            ChildNumber::from_normal_idx(d.kix).map_err(|_| Error::Unsupported("key index out of range"))?));
+        let key = SecretMaster::from(master.derive_priv(&Secp256k1::signing_only(), &path).map_err(|_| Error::Unsupported("can not derive the key of a coin"))?);
+        Ok(key.private_key.key)
+    }
+
+    /// start receiving payments to our payment code and accept senders of notification transactions.
+    /// Our notification address is watched from then on, for each sender the keys of its next payments
+    /// are imported. The key of our payment code account is held until the wallet is dropped, so
+    /// notifications and payments are found by process. Returns keys not yet imported
+    pub fn receive_payment_codes(&mut self, passphrase: &str, notifications: &[Transaction]) -> Result<Vec<ImportedKey>, Error> {
+        let network = self.master.master_public().network;
+        let account = self.payment_code_account(passphrase)?;
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        let notification = PrivateKey { compressed: true, network, key: bip47::child_key(&account, 0)? }.public_key(&secp);
+        let mut added = self.imported.add(vec!(imported::payment_code_key(notification, InputType::P2pkh, bip47::NOTIFICATION_ORIGIN.to_string(), network)?));
+        self.payment_code_account = Some(account);
+        added.extend(self.accept_payment_codes(notifications));
+        Ok(added)
+    }
+
+    // accept senders of notifications and import the keys of their next payments, while receiving payments to our payment code.
+    // Returns keys not yet imported
+    fn accept_payment_codes(&mut self, notifications: &[Transaction]) -> Vec<ImportedKey> {
+        let account = match self.payment_code_account {
+            Some(ref account) => account,
+            None => return Vec::new()
+        };
+        let network = self.master.master_public().network;
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        let mut senders = self.payment_code_senders();
+        let notification = match bip47::child_key(account, 0) {
+            Ok(notification) => notification,
+            Err(e) => {
+                warn!("can not derive our notification key: {}", e);
+                return Vec::new();
+            }
+        };
+        for transaction in notifications {
+            match bip47::notified_code(transaction, &notification) {
+                Ok(code) => if !senders.contains(&code) {
+                    info!("payment code {} notified us", code);
+                    senders.push(code);
+                },
+                Err(e) => debug!("ignore notification {}: {}", transaction.txid(), e)
+            }
+        }
+        let mut keys = Vec::new();
+        for sender in senders {
+            let paid = self.imported.keys.iter()
+                .filter(|k| !k.watched.coins.is_empty())
+                .filter_map(|k| bip47::parse_receive_origin(k.origin.as_str()))
+                .filter(|(code, _)| *code == sender)
+                .map(|(_, index)| index + 1)
+                .max().unwrap_or(0);
+            for index in 0..paid + bip47::RECEIVE_GAP {
+                let origin = bip47::receive_origin(&sender, index);
+                if self.imported.keys.iter().any(|k| k.origin == origin) {
+                    continue;
+                }
+                // an index without a valid key is skipped by the sender too
+                let key = match bip47::child_key(account, index).and_then(|child| bip47::receive_key(&child, &sender, network)) {
+                    Ok(key) => PrivateKey { compressed: true, network, key }.public_key(&secp),
+                    Err(e) => {
+                        debug!("skip payment {} of {}: {}", index, sender, e);
+                        continue;
+                    }
+                };
+                debug!("receive payments from {} at {}", sender, Address::p2pkh(&key, network));
+                keys.push(imported::payment_code_key(key, InputType::P2pkh, origin, network).expect("P2PKH is a single key type"));
+            }
+        }
+        self.imported.add(keys)
+    }
+
+    /// payment codes of senders that notified us
+    pub fn payment_code_senders(&self) -> Vec<PaymentCode> {
+        let mut senders = Vec::new();
+        for (code, _) in self.imported.keys.iter().filter_map(|k| bip47::parse_receive_origin(k.origin.as_str())) {
+            if !senders.contains(&code) {
+                senders.push(code);
+            }
+        }
+        senders
+    }
+
+    /// transactions of a block paying to our notification address, once payment codes are received
+    pub fn notifications<'a>(&self, block: &'a Block) -> Vec<&'a Transaction> {
+        match self.imported.keys.iter().find(|k| k.origin == bip47::NOTIFICATION_ORIGIN) {
+            Some(key) => block.txdata.iter()
+                .filter(|t| t.output.iter().any(|o| o.script_pubkey == key.watched.script))
+                .collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// keys of the index-th vault
+    pub fn vault_keys(&self, passphrase: &str, index: u32) -> Result<VaultKeys, Error> {
+        let unlocker = Unlocker::new(
+            self.master.encrypted(), passphrase,
+            self.master.master_public().network, Some(self.master.master_public()))?;
+        VaultKeys::new(unlocker.master_private(), index)
+    }
+
+    fn payment_code_account(&self, passphrase: &str) -> Result<SecretMaster, Error> {
+        Ok(SecretMaster::from(bip47::account_key(&self.unlocked_master(passphrase)?)?))
+    }
+
+    pub fn fund<W>(&mut self, id: &sha256::Hash, mut term: u16, passphrase: &Secret, mut fee_per_vbyte: u64, amount: u64, options: &TxOptions, trunk: Arc<dyn Trunk>, scripter: W) -> Result<(Transaction, PublicKey, u64), Error>
+        where W: FnOnce(&PublicKey, Option<u16>) -> Script {
+        let network = self.master.master_public().network;
+        fee_per_vbyte = fee_rate(fee_per_vbyte)?;
         term = std::cmp::min(MAX_TERM, term);
         let mut fee = 0;
         let change_address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
         let height = trunk.len();
-        let coins = self.coins.choose_inputs(amount, height, |h| trunk.get_height(h));
+        let coins = self.choose_coins(amount, options, trunk.as_ref());
         let total_input = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
         let contract_address;
         let funder;
@@ -118,7 +787,7 @@ impl Wallet {
             funder = commit_account.compute_base_public_key(kix).expect("can not compute base public key");
         }
         if amount > total_input {
-            return Err(Error::Unsupported("insufficient funds"));
+            return Err(Error::InsufficientFunds);
         }
         let mut tx = Transaction {
             input: coins.iter().map(|(point, coin, h)|
@@ -127,13 +796,18 @@ impl Wallet {
                     script_sig: Script::new(),
                     sequence: if let Some(csv) = coin.derivation.csv {
                         std::cmp::min(csv as u32, height - *h)
-                    } else { RBF },
+                    } else { options.sequence(point) },
                     witness: vec![],
                 }).collect(),
             output: Vec::new(),
             version: 2,
-            lock_time: 0,
+            lock_time: options.lock_time(trunk.as_ref()),
         };
+        options.validate(&tx, coins.as_slice(), trunk.as_ref())?;
+        // decrypted keys are only held while signing
+        let mut unlocker = Unlocker::new(
+            self.master.encrypted(), passphrase.as_str(),
+            network, Some(self.master.master_public()))?;
         loop {
             tx.output.clear();
             if amount - fee > DUST {
@@ -145,11 +819,15 @@ impl Wallet {
                 return Err(Error::Unsupported("withdraw amount is less than the fees needed (+DUST limit)"));
             }
             if total_input > amount && (total_input - amount) > DUST {
-                tx.output.insert((thread_rng().next_u32() % 2) as usize, TxOut {
+                tx.output.insert((options.random(b"change") % 2) as usize, TxOut {
                     value: total_input - amount,
                     script_pubkey: change_address.script_pubkey(),
                 });
             }
+            // part of the transaction weight, so paid for by the fee
+            if let Some(data) = options.data_output() {
+                tx.output.push(data);
+            }
             if self.master.sign(&mut tx, SigHashType::All,
                                 &|point| {
                                     coins.iter().find(|(o, _, _)| *o == *point).map(|(_, c, _)| c.output.clone())
@@ -160,6 +838,7 @@ impl Wallet {
             }
             if fee == 0 {
                 fee = (tx.get_weight() as u64 * fee_per_vbyte + 3) / 4;
+                options.check_fee(fee, amount)?;
             } else {
                 debug!("compiled transaction to withdraw {} fee {}", amount, fee);
                 #[cfg(feature = "bitcoinconsensus")]
@@ -175,25 +854,112 @@ impl Wallet {
                 break;
             }
         }
+        drop(unlocker);
         self.coins.process_unconfirmed_transaction(&mut self.master, &tx);
         Ok((tx, funder, fee))
     }
 
-    pub fn withdraw(&mut self, passphrase: String, address: Address, mut fee_per_vbyte: u64, amount: Option<u64>, trunk: Arc<dyn Trunk>) -> Result<(Transaction, u64), Error> {
+    pub fn withdraw(&mut self, passphrase: &Secret, address: Address, fee_per_vbyte: u64, amount: Option<u64>, options: &TxOptions, trunk: Arc<dyn Trunk>) -> Result<(Transaction, u64), Error> {
+        let amount = amount.unwrap_or_else(|| self.spendable_balance(options, trunk.as_ref()));
+        let change_address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
+        let coins = self.choose_coins(amount, options, trunk.as_ref());
+        self.spend(passphrase, address, change_address, fee_per_vbyte, amount, coins, options, trunk)
+    }
+
+    /// available coins of our receive and change keys of at most max_value, smallest first
+    pub fn small_coins(&self, max_value: u64, trunk: &dyn Trunk) -> Vec<(OutPoint, u64)> {
+        let height = trunk.len();
+        let available = self.available_balance(height, |h| trunk.get_height(h));
+        let mut coins = self.coins.choose_inputs(available, height, |h| trunk.get_height(h)).into_iter()
+            .filter(|(_, c, _)| c.derivation.account == 0 && c.derivation.csv.is_none() && c.output.value <= max_value)
+            .map(|(p, c, _)| (p, c.output.value))
+            .collect::<Vec<_>>();
+        coins.sort_by_key(|(_, v)| *v);
+        coins
+    }
+
+    /// merge available coins into one output to a change address, the fee is paid from them
+    pub fn consolidate(&mut self, passphrase: &Secret, outpoints: &[OutPoint], fee_per_vbyte: u64, options: &TxOptions, trunk: Arc<dyn Trunk>) -> Result<(Transaction, u64), Error> {
+        let height = trunk.len();
+        let available = self.available_balance(height, |h| trunk.get_height(h));
+        let mut coins = self.coins.choose_inputs(available, height, |h| trunk.get_height(h)).into_iter()
+            .filter(|(p, _, _)| outpoints.contains(p))
+            .collect::<Vec<_>>();
+        if coins.len() != outpoints.len() {
+            return Err(Error::InvalidArgument("a coin to consolidate is not available"));
+        }
+        options.order_coins(&mut coins);
+        let amount = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
+        let address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
+        self.spend(passphrase, address.clone(), address, fee_per_vbyte, amount, coins, options, trunk)
+    }
+
+    /// pay amount to address from our coins together with inputs of an other party, whose value is added to
+    /// the payment as a payjoin receiver expects. The fee is paid from amount. Our inputs are signed, the other
+    /// party completes the returned PSBT
+    pub fn collaborate(&mut self, passphrase: &Secret, address: Address, fee_per_vbyte: u64, amount: u64, external: &[ExternalInput], options: &TxOptions, trunk: Arc<dyn Trunk>) -> Result<(PartiallySignedTransaction, u64), Error> {
+        if external.is_empty() {
+            return Err(Error::InvalidArgument("no external input"));
+        }
+        let change_address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
+        let coins = self.choose_coins(amount, options, trunk.as_ref());
+        if external.iter().any(|e| coins.iter().any(|(p, _, _)| *p == e.outpoint)) {
+            return Err(Error::InvalidArgument("an external input spends our coin"));
+        }
+        let (mut tx, fee) = self.build(passphrase, address, change_address, fee_per_vbyte, amount, coins.clone(), external, options, trunk)?;
+
+        // a PSBT is built of the unsigned transaction, our inputs are final
+        let signed = tx.input.iter().map(|i| (i.script_sig.clone(), i.witness.clone())).collect::<Vec<_>>();
+        for input in tx.input.iter_mut() {
+            input.script_sig = Script::new();
+            input.witness = Vec::new();
+        }
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).map_err(|_| Error::Unsupported("can not create PSBT"))?;
+        for (i, (script_sig, witness)) in signed.into_iter().enumerate() {
+            let point = psbt.global.unsigned_tx.input[i].previous_output;
+            let input = &mut psbt.inputs[i];
+            if let Some((_, coin, _)) = coins.iter().find(|(p, _, _)| *p == point) {
+                input.witness_utxo = Some(coin.output.clone());
+                if !script_sig.is_empty() {
+                    input.final_script_sig = Some(script_sig);
+                }
+                if !witness.is_empty() {
+                    input.final_script_witness = Some(witness);
+                }
+            } else if let Some(e) = external.iter().find(|e| e.outpoint == point) {
+                input.witness_utxo = Some(TxOut { value: e.value, script_pubkey: e.script_pubkey.clone() });
+                if let Some((ref key, ref signature)) = e.partial_sig {
+                    input.partial_sigs.insert(*key, signature.clone());
+                }
+            }
+        }
+        Ok((psbt, fee))
+    }
+
+    fn spend(&mut self, passphrase: &Secret, address: Address, change_address: Address, fee_per_vbyte: u64, amount: u64, coins: Vec<(OutPoint, Coin, u32)>, options: &TxOptions, trunk: Arc<dyn Trunk>) -> Result<(Transaction, u64), Error> {
+        let (tx, fee) = self.build(passphrase, address, change_address, fee_per_vbyte, amount, coins, &[], options, trunk)?;
+        self.coins.process_unconfirmed_transaction(&mut self.master, &tx);
+        Ok((tx, fee))
+    }
+
+    // a transaction paying amount less fee to address and the rest of coins to change_address.
+    // The value of external inputs is added to the payment, only our inputs are signed
+    fn build(&mut self, passphrase: &Secret, address: Address, change_address: Address, mut fee_per_vbyte: u64, amount: u64, coins: Vec<(OutPoint, Coin, u32)>, external: &[ExternalInput], options: &TxOptions, trunk: Arc<dyn Trunk>) -> Result<(Transaction, u64), Error> {
         let network = self.master.master_public().network;
-        let mut unlocker = Unlocker::new(
-            self.master.encrypted(), passphrase.as_str(),
-            network, Some(self.master.master_public()))?;
         let height = trunk.len();
-        let balance = self.available_balance(height, |h| trunk.get_height(h));
-        let amount = amount.unwrap_or(balance);
-        fee_per_vbyte = std::cmp::min(MAX_FEE_PER_VBYTE, std::cmp::max(MIN_FEE_PER_VBYTE, fee_per_vbyte));
+        fee_per_vbyte = fee_rate(fee_per_vbyte)?;
         let mut fee = 0;
-        let change_address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
-        let coins = self.coins.choose_inputs(amount, height, |h| trunk.get_height(h));
         let total_input = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
         if amount > total_input {
-            return Err(Error::Unsupported("insufficient funds"));
+            return Err(Error::InsufficientFunds);
+        }
+        let external_value = external.iter().map(|e| e.value).sum::<u64>();
+        // weight of the signatures still missing
+        let mut external_weight = 0;
+        for input in external {
+            external_weight += InputType::from_script(&input.script_pubkey)
+                .ok_or(Error::Unsupported("script type of an external input is not supported"))?
+                .signature_weight();
         }
         let mut tx = Transaction {
             input: coins.iter().map(|(point, coin, h)|
@@ -202,43 +968,59 @@ impl Wallet {
                     script_sig: Script::new(),
                     sequence: if let Some(csv) = coin.derivation.csv {
                         std::cmp::min(csv as u32, height - *h)
-                    } else { RBF },
+                    } else { options.sequence(point) },
                     witness: vec![],
-                }).collect(),
+                }).chain(external.iter().map(|e|
+                TxIn {
+                    previous_output: e.outpoint,
+                    script_sig: Script::new(),
+                    sequence: options.sequence(&e.outpoint),
+                    witness: vec![],
+                })).collect(),
             output: Vec::new(),
             version: 2,
-            lock_time: 0,
+            lock_time: options.lock_time(trunk.as_ref()),
         };
+        options.validate(&tx, coins.as_slice(), trunk.as_ref())?;
+        // decrypted keys are only held while signing
+        let mut unlocker = Unlocker::new(
+            self.master.encrypted(), passphrase.as_str(),
+            network, Some(self.master.master_public()))?;
         loop {
             tx.output.clear();
             if amount - fee > DUST {
                 tx.output.push(TxOut {
-                    value: amount - fee,
+                    value: amount - fee + external_value,
                     script_pubkey: address.script_pubkey(),
                 });
             } else {
                 return Err(Error::Unsupported("withdraw amount is less than the fees needed (+DUST limit)"));
             }
             if total_input > amount && (total_input - amount) > DUST {
-                tx.output.insert((thread_rng().next_u32() % 2) as usize, TxOut {
+                tx.output.insert((options.random(b"change") % 2) as usize, TxOut {
                     value: total_input - amount,
                     script_pubkey: change_address.script_pubkey(),
                 });
             }
+            // part of the transaction weight, so paid for by the fee
+            if let Some(data) = options.data_output() {
+                tx.output.push(data);
+            }
             if self.master.sign(&mut tx, SigHashType::All,
                                 &|point| {
                                     coins.iter().find(|(o, _, _)| *o == *point).map(|(_, c, _)| c.output.clone())
                                 }, &mut unlocker)?
-                != tx.input.len() {
+                != coins.len() {
                 error!("could not sign all inputs of our transaction {:?} {}", tx, hex::encode(serialize(&tx)));
                 return Err(Error::Unsupported("could not sign for all inputs"));
             }
             if fee == 0 {
-                fee = (tx.get_weight() as u64 * fee_per_vbyte + 3) / 4;
+                fee = ((tx.get_weight() + external_weight) as u64 * fee_per_vbyte + 3) / 4;
+                options.check_fee(fee, amount)?;
             } else {
                 debug!("compiled transaction to withdraw {} fee {}", amount, fee);
                 #[cfg(feature = "bitcoinconsensus")]
-                    {
+                    if external.is_empty() {
                         match tx.verify(|o| coins.iter().find_map(|(p, c, _)| if *p == *o { Some(c.output.clone()) } else { None })) {
                             Ok(()) => {}
                             Err(e) => {
@@ -250,10 +1032,29 @@ impl Wallet {
                 break;
             }
         }
-        self.coins.process_unconfirmed_transaction(&mut self.master, &tx);
+        drop(unlocker);
         Ok((tx, fee))
     }
 
+    /// the master key encrypted with new_passphrase instead of passphrase
+    pub fn reencrypt(encrypted: &[u8], public_master_key: &ExtendedPubKey, passphrase: &str, new_passphrase: &str) -> Result<Vec<u8>, Error> {
+        // checks the passphrase
+        Unlocker::new(encrypted, passphrase, public_master_key.network, Some(public_master_key))?;
+        let mut seed = Seed::decrypt(encrypted, passphrase)?;
+        let reencrypted = seed.encrypt(new_passphrase);
+        seed.0.zeroize();
+        Ok(reencrypted?)
+    }
+
+    /// continue with the master key encrypted with an other passphrase
+    pub fn set_encrypted(&mut self, encrypted: &[u8]) {
+        let mut master = MasterAccount::from_encrypted(encrypted, self.master.master_public().clone(), self.master.birth());
+        for (_, account) in self.master.accounts().iter() {
+            master.add_account(account.clone());
+        }
+        self.master = master;
+    }
+
     pub fn from_storage(coins: Coins, mut master: MasterAccount) -> Wallet {
         for (_, coin) in coins.confirmed() {
             let ref d = coin.derivation;
@@ -263,12 +1064,12 @@ impl Wallet {
             let ref d = coin.derivation;
             master.get_mut((d.account, d.sub)).unwrap().do_look_ahead(Some(d.kix)).expect("can not look ahead of storage");
         }
-        Wallet { coins: coins, master }
+        Wallet { coins: coins, master, imported: Imported::new(), payment_code_account: None }
     }
 
     pub fn from_encrypted(encrypted: &[u8], public_master_key: ExtendedPubKey, birth: u64) -> Wallet {
         let master = MasterAccount::from_encrypted(encrypted, public_master_key, birth);
-        Wallet { coins: Coins::new(), master }
+        Wallet { coins: Coins::new(), master, imported: Imported::new(), payment_code_account: None }
     }
 
     pub fn new(bitcoin_network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> (Mnemonic, Address, Wallet) {
@@ -276,8 +1077,34 @@ impl Wallet {
         let mut entropy = [0u8; 16];
         thread_rng().fill_bytes(&mut entropy);
         let mnemonic = Mnemonic::new(&entropy).expect("can not create mnemonic");
-        let mut master = MasterAccount::from_mnemonic(&mnemonic, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                                                      bitcoin_network, passphrase, pd_passphrase).expect("can not generate wallet");
+        let master = MasterAccount::from_mnemonic(&mnemonic, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                                                  bitcoin_network, passphrase, pd_passphrase).expect("can not generate wallet");
+        let (deposit_address, wallet) = Self::with_accounts(master, passphrase);
+        (mnemonic, deposit_address, wallet)
+    }
+
+    /// the seed encrypted with password in a keystore document
+    pub fn export_keystore(&self, passphrase: &str, password: &str) -> Result<String, Error> {
+        let public = self.master.master_public();
+        // checks the passphrase
+        Unlocker::new(self.master.encrypted(), passphrase, public.network, Some(public))?;
+        let seed = Zeroizing::new(Seed::decrypt(self.master.encrypted(), passphrase)?.0);
+        keystore::export(seed.as_slice(), public.network, self.birth(), password)
+    }
+
+    /// a new wallet with the seed of a keystore document, protected by passphrase
+    pub fn import_keystore(document: &str, password: &str, passphrase: &str) -> Result<(Address, Wallet), Error> {
+        let (seed, _, keyroot, birth) = keystore::import(document, password)?;
+        let mut seed = Seed(seed.to_vec());
+        let encrypted = seed.encrypt(passphrase);
+        seed.0.zeroize();
+        let encrypted = encrypted?;
+        let master = MasterAccount::from_encrypted(encrypted.as_slice(), keyroot, birth);
+        Ok(Self::with_accounts(master, passphrase))
+    }
+
+    fn with_accounts(mut master: MasterAccount, passphrase: &str) -> (Address, Wallet) {
+        let bitcoin_network = master.master_public().network;
         let mut unlocker = Unlocker::new(master.encrypted().as_slice(),
                                          passphrase, bitcoin_network,
                                          Some(&master.master_public())).expect("Internal error in wallet generation");
@@ -292,75 +1119,109 @@ impl Wallet {
         master.add_account(commitments);
         let deposit_address = master.get((0, 0)).unwrap().get_key(0).unwrap().address.clone();
 
-        (mnemonic, deposit_address, Wallet {
+        (deposit_address, Wallet {
             master,
             coins: Coins::new(),
+            imported: Imported::new(),
+            payment_code_account: None,
         })
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::{
-        str::FromStr,
-        sync::{Arc, Mutex},
-    };
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    use bitcoin::{Address, BitcoinHash, Block, blockdata::opcodes::all, BlockHeader, network::constants::Network, OutPoint, PublicKey, Transaction, TxIn, TxOut, util::bip32::ExtendedPubKey};
-    use bitcoin::blockdata::constants::genesis_block;
-    use bitcoin::blockdata::script::Builder;
-    use bitcoin::util::hash::MerkleRoot;
-    use bitcoin_hashes::{sha256, sha256d};
-    use bitcoin_wallet::account::{Account, AccountAddressType, Unlocker};
-
-    use crate::store::ContentStore;
-    use crate::trunk::Trunk;
-    use crate::wallet::Wallet;
-
-    const NEW_COINS: u64 = 1000000000;
-    const PASSPHRASE: &str = "whatever";
+// the fee rate to sign with, rates above MAX_FEE_PER_VBYTE are rejected rather than capped
+fn fee_rate(fee_per_vbyte: u64) -> Result<u64, Error> {
+    if fee_per_vbyte > MAX_FEE_PER_VBYTE {
+        return Err(Error::InvalidArgument("fee rate exceeds 100 sat/vbyte"));
+    }
+    Ok(std::cmp::max(MIN_FEE_PER_VBYTE, fee_per_vbyte))
+}
 
-    struct TestTrunk {
-        trunk: Arc<Mutex<Vec<BlockHeader>>>
+/// lock time of new transactions: the tip height, sometimes up to 100 blocks back,
+/// as Bitcoin Core does to discourage fee sniping re-orgs. Zero if the trunk is out of sync
+fn anti_fee_sniping_locktime(trunk: &dyn Trunk, random: u32) -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    match trunk.get_tip() {
+        Some(tip) => locktime_for_tip(trunk.len() - 1, tip.time as u64, now, random),
+        None => 0
     }
+}
 
-    impl TestTrunk {
-        fn extend(&self, header: &BlockHeader) {
-            self.trunk.lock().unwrap().push(header.clone());
-        }
+fn locktime_for_tip(height: u32, tip_time: u64, now: u64, random: u32) -> u32 {
+    if tip_time + MAX_TIP_AGE < now {
+        return 0;
+    }
+    if random % 10 == 0 {
+        return height.saturating_sub((random / 10) % 100);
     }
+    height
+}
 
-    impl Trunk for TestTrunk {
-        fn is_on_trunk(&self, block_hash: &sha256d::Hash) -> bool {
-            self.trunk.lock().unwrap().iter().any(|h| h.bitcoin_hash() == *block_hash)
-        }
+// BIP113 median time of the eleven blocks before height
+fn median_time_past(trunk: &dyn Trunk, height: u32) -> u32 {
+    let mut times = (height.saturating_sub(11)..height).filter_map(|h| trunk.get_header_for_height(h)).map(|h| h.time).collect::<Vec<_>>();
+    times.sort();
+    times.get(times.len() / 2).cloned().unwrap_or(0)
+}
 
-        fn get_header(&self, block_hash: &sha256d::Hash) -> Option<BlockHeader> {
-            self.trunk.lock().unwrap().iter().find(|h| h.bitcoin_hash() == *block_hash).map(|h| h.clone())
+/// decode a transaction, outputs paying to scripts in ours and inputs spending them are marked as ours.
+/// Values of spent outputs are known if lookup finds their transaction
+pub fn describe<L>(transaction: &Transaction, network: Network, ours: &HashSet<Script>, lookup: L) -> TransactionInfo
+    where L: Fn(&sha256d::Hash) -> Option<Transaction> {
+    let address = |script: &Script| Address::from_script(script, network).map(|a| a.to_string());
+    let mut spent = 0u64;
+    let mut fee = Some(0u64);
+    let mut inputs = Vec::new();
+    for input in transaction.input.iter() {
+        let previous = lookup(&input.previous_output.txid)
+            .and_then(|t| t.output.get(input.previous_output.vout as usize).cloned());
+        let is_ours = previous.as_ref().map(|o| ours.contains(&o.script_pubkey)).unwrap_or(false);
+        if is_ours {
+            spent += previous.as_ref().unwrap().value;
         }
-
-        fn get_header_for_height(&self, height: u32) -> Option<BlockHeader> {
-            self.trunk.lock().unwrap().get(height as usize).map(|h| h.clone())
+        fee = fee.and_then(|f| previous.as_ref().map(|o| f + o.value));
+        inputs.push(InputInfo {
+            outpoint: input.previous_output,
+            value: previous.as_ref().map(|o| o.value),
+            address: previous.as_ref().and_then(|o| address(&o.script_pubkey)),
+            ours: is_ours,
+        });
+    }
+    let mut received = 0u64;
+    let mut outputs = Vec::new();
+    for (vout, output) in transaction.output.iter().enumerate() {
+        let is_ours = ours.contains(&output.script_pubkey);
+        if is_ours {
+            received += output.value;
         }
+        outputs.push(OutputInfo { vout: vout as u32, value: output.value, address: address(&output.script_pubkey), ours: is_ours });
+    }
+    TransactionInfo {
+        txid: transaction.txid(),
+        hex: hex::encode(serialize(transaction)),
+        block: None,
+        height: None,
+        inputs,
+        outputs,
+        net: received as i64 - spent as i64,
+        fee: fee.and_then(|f| f.checked_sub(transaction.output.iter().map(|o| o.value).sum::<u64>())),
+    }
+}
 
-        fn get_height(&self, block_hash: &sha256d::Hash) -> Option<u32> {
-            self.trunk.lock().unwrap().iter().enumerate().find_map(|(i, h)| if h.bitcoin_hash() == *block_hash { Some(i as u32) } else { None })
-        }
+#[cfg(all(test, feature = "node"))]
+mod test {
+    use std::sync::Arc;
 
-        fn get_tip(&self) -> Option<BlockHeader> {
-            let len = self.trunk.lock().unwrap().len();
-            if len > 0 {
-                self.trunk.lock().unwrap().get(len - 1).map(|h| h.clone())
-            } else {
-                None
-            }
-        }
+    use bitcoin::{Address, BitcoinHash, blockdata::opcodes::all, network::constants::Network, OutPoint, PublicKey};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin_hashes::sha256;
+    use bitcoin_wallet::account::{Account, AccountAddressType, Unlocker};
 
-        fn len(&self) -> u32 {
-            self.trunk.lock().unwrap().len() as u32
-        }
-    }
+    use crate::secret::Secret;
+    use crate::store::ContentStore;
+    use crate::testutil::{add_tx, funded_wallet, mine, NEW_COINS, PASSPHRASE, TestTrunk};
+    use crate::trunk::Trunk;
+    use crate::wallet::{locktime_for_tip, MAX_TIP_AGE, TxOptions, Wallet};
 
     fn new_wallet() -> Wallet {
         // let mut wallet = Wallet::from_encrypted(
@@ -375,76 +1236,33 @@ mod test {
         wallet
     }
 
-    fn new_block(prev: &sha256d::Hash) -> Block {
-        Block {
-            header: BlockHeader {
-                version: 1,
-                time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
-                nonce: 0,
-                bits: 0x1d00ffff,
-                prev_blockhash: prev.clone(),
-                merkle_root: sha256d::Hash::default(),
-            },
-            txdata: Vec::new(),
-        }
-    }
-
-    fn coin_base(miner: &Address, height: u32) -> Transaction {
-        Transaction {
-            version: 2,
-            lock_time: 0,
-            input: vec!(TxIn {
-                sequence: 0xffffffff,
-                witness: Vec::new(),
-                previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
-                script_sig: Builder::new().push_int(height as i64).into_script(),
-            }),
-            output: vec!(TxOut {
-                value: NEW_COINS,
-                script_pubkey: miner.script_pubkey(),
-            }),
-        }
-    }
-
-    fn add_tx(block: &mut Block, tx: Transaction) {
-        block.txdata.push(tx);
-        block.header.merkle_root = block.merkle_root();
-    }
-
-    fn mine(tip: &sha256d::Hash, height: u32, miner: &Address) -> Block {
-        let mut block = new_block(tip);
-        add_tx(&mut block, coin_base(miner, height));
-        block
-    }
-
-
     #[test]
     pub fn process_blocks_balance() {
-        let trunk = Arc::new(
-            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let trunk = Arc::new(TestTrunk::new());
         let mut wallet = new_wallet();
         let genesis = genesis_block(Network::Testnet);
         let miner = wallet.master.get_mut((0, 0)).unwrap().next_key().unwrap().address.clone();
 
         trunk.extend(&genesis.header);
-        wallet.process(&genesis);
+        wallet.process(&genesis, 0);
 
         let next = mine(&genesis.bitcoin_hash(), 1, &miner);
         trunk.extend(&next.header);
-        wallet.process(&next);
+        wallet.process(&next, 1);
 
         assert_eq!(wallet.balance(), NEW_COINS);
 
         let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
-        let (burn_half, _) = wallet.withdraw(PASSPHRASE.to_string(), burn, 1, Some(NEW_COINS / 2), trunk.clone()).unwrap();
+        let (burn_half, _) = wallet.withdraw(&Secret::from(PASSPHRASE), burn, 1, Some(NEW_COINS / 2), &TxOptions::default(), trunk.clone()).unwrap();
+        assert!(burn_half.lock_time <= 1 && burn_half.input.iter().all(|i| i.sequence < 0xffffffff));
 
         let mut next = mine(&next.bitcoin_hash(), 2, &miner);
         add_tx(&mut next, burn_half);
         trunk.extend(&next.header);
-        wallet.process(&next);
+        wallet.process(&next, 2);
         assert_eq!(wallet.balance(), NEW_COINS + NEW_COINS / 2);
 
-        let (fund, _, fee) = wallet.fund(&sha256::Hash::default(), 1, PASSPHRASE.to_string(), 5, NEW_COINS / 10, trunk.clone(),
+        let (fund, _, fee) = wallet.fund(&sha256::Hash::default(), 1, &Secret::from(PASSPHRASE), 5, NEW_COINS / 10, &TxOptions::default(), trunk.clone(),
                                          |pk: &PublicKey, term: Option<u16>| {
                                              ContentStore::funding_script(pk, term.unwrap())
                                          }).unwrap();
@@ -452,14 +1270,120 @@ mod test {
         let mut next = mine(&next.bitcoin_hash(), 3, &miner);
         add_tx(&mut next, fund);
         trunk.extend(&next.header);
-        wallet.process(&next);
+        wallet.process(&next, 3);
         assert_eq!(wallet.balance(), 2 * NEW_COINS + NEW_COINS / 2 - fee);
         assert_eq!(wallet.available_balance(3, |h| trunk.get_height(h)), 2 * NEW_COINS + NEW_COINS / 2 - NEW_COINS / 10);
 
         let next = mine(&next.bitcoin_hash(), 4, &miner);
         trunk.extend(&next.header);
-        wallet.process(&next);
+        wallet.process(&next, 4);
         assert_eq!(wallet.balance(), 3 * NEW_COINS + NEW_COINS / 2 - fee);
         assert_eq!(wallet.available_balance(4, |h| trunk.get_height(h)), 3 * NEW_COINS + NEW_COINS / 2 - fee);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn list_addresses() {
+        let (_, wallet, next) = funded_wallet();
+        let miner = Address::from_script(&next.txdata[0].output[0].script_pubkey, Network::Testnet).unwrap();
+
+        let addresses = wallet.list_addresses(0).unwrap();
+        assert!(addresses.iter().any(|a| a.sub == 1));
+        let mined = addresses.iter().find(|a| a.address == miner).unwrap();
+        assert_eq!(mined.path, format!("m/49'/1'/0'/0/{}", mined.kix));
+        assert!(mined.received);
+        assert_eq!(mined.balance, wallet.balance());
+        assert_eq!(addresses.iter().filter(|a| a.received).count(), 1);
+        assert!(wallet.list_addresses(7).is_err());
+    }
+
+    #[test]
+    fn anti_fee_sniping() {
+        let now = 1600000000;
+        assert_eq!(locktime_for_tip(1000, now, now, 1), 1000);
+        assert_eq!(locktime_for_tip(1000, now, now, 10 * 42), 958);
+        assert_eq!(locktime_for_tip(10, now, now, 10 * 42), 0);
+        assert_eq!(locktime_for_tip(1000, now - MAX_TIP_AGE - 1, now, 1), 0);
+    }
+
+    #[test]
+    fn fee_rate_limit() {
+        let (trunk, mut wallet, _) = funded_wallet();
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+        assert!(wallet.withdraw(&Secret::from(PASSPHRASE), burn.clone(), 101, Some(NEW_COINS / 2), &TxOptions::default(), trunk.clone()).is_err());
+        assert!(wallet.withdraw(&Secret::from(PASSPHRASE), burn, 100, Some(NEW_COINS / 2), &TxOptions::default(), trunk).is_ok());
+    }
+
+    #[test]
+    fn caller_locktime_and_sequence() {
+        let (trunk, mut wallet, next) = funded_wallet();
+        let coinbase = OutPoint { txid: next.txdata[0].txid(), vout: 0 };
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+
+        let options = TxOptions { lock_time: Some(100), sequence: Some(0xffffffff), ..Default::default() };
+        assert!(wallet.withdraw(&Secret::from(PASSPHRASE), burn.clone(), 1, Some(NEW_COINS / 2), &options, trunk.clone()).is_err());
+
+        // the next block is at height 2
+        let options = TxOptions { lock_time: Some(2), sequence: Some(0xfffffffe), ..Default::default() };
+        assert!(wallet.withdraw(&Secret::from(PASSPHRASE), burn.clone(), 1, Some(NEW_COINS / 2), &options, trunk.clone()).is_err());
+        let mut options = TxOptions { lock_time: Some(1), sequence: Some(0xfffffffe), ..Default::default() };
+        options.input_sequences.insert(OutPoint { txid: coinbase.txid, vout: 1 }, 0xfffffff0);
+        assert!(wallet.withdraw(&Secret::from(PASSPHRASE), burn.clone(), 1, Some(NEW_COINS / 2), &options, trunk.clone()).is_err());
+        // the coin has one confirmation
+        let mut options = TxOptions { lock_time: Some(1), sequence: Some(0xfffffffe), ..Default::default() };
+        options.input_sequences.insert(coinbase, 2);
+        assert!(wallet.withdraw(&Secret::from(PASSPHRASE), burn.clone(), 1, Some(NEW_COINS / 2), &options, trunk.clone()).is_err());
+        options.input_sequences.insert(coinbase, 1);
+        let (delayed, _) = wallet.withdraw(&Secret::from(PASSPHRASE), burn, 1, Some(NEW_COINS / 2), &options, trunk.clone()).unwrap();
+        assert_eq!(delayed.lock_time, 1);
+        assert_eq!(delayed.input.len(), 1);
+        assert_eq!(delayed.input[0].previous_output, coinbase);
+        assert_eq!(delayed.input[0].sequence, 1);
+    }
+
+    #[test]
+    fn data_output() {
+        let (trunk, mut wallet, _) = funded_wallet();
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+
+        let options = TxOptions { data: Some(vec!(0u8; 81)), ..Default::default() };
+        assert!(wallet.withdraw(&Secret::from(PASSPHRASE), burn.clone(), 1, Some(NEW_COINS / 2), &options, trunk.clone()).is_err());
+
+        let options = TxOptions { data: Some(b"commitment".to_vec()), ..Default::default() };
+        let (with_data, fee) = wallet.withdraw(&Secret::from(PASSPHRASE), burn, 1, Some(NEW_COINS / 2), &options, trunk.clone()).unwrap();
+        let data = with_data.output.iter().find(|o| o.script_pubkey.is_op_return()).unwrap();
+        assert_eq!(data.value, 0);
+        assert_eq!(&data.script_pubkey[2..], b"commitment");
+        assert!(fee >= (with_data.get_weight() as u64 + 3) / 4);
+    }
+
+    #[test]
+    fn spend_unconfirmed_change() {
+        let (trunk, mut wallet, _) = funded_wallet();
+
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+        let passphrase = Secret::from(PASSPHRASE);
+        let (burn_half, _) = wallet.withdraw(&passphrase, burn.clone(), 1, Some(NEW_COINS / 2), &TxOptions::default(), trunk.clone()).unwrap();
+        wallet.coins.process_unconfirmed_transaction(&mut wallet.master, &burn_half);
+        assert_eq!(wallet.unconfirmed_change().len(), 1);
+
+        assert!(wallet.withdraw(&passphrase, burn.clone(), 1, Some(NEW_COINS / 4), &TxOptions::default(), trunk.clone()).is_err());
+        let options = TxOptions { spend_unconfirmed_change: Some(true), ..Default::default() };
+        assert_eq!(wallet.spendable_balance(&options, trunk.as_ref()), NEW_COINS / 2);
+        let (spend_change, _) = wallet.withdraw(&passphrase, burn, 1, Some(NEW_COINS / 4), &options, trunk.clone()).unwrap();
+        assert_eq!(spend_change.input.len(), 1);
+        assert_eq!(spend_change.input[0].previous_output.txid, burn_half.txid());
+    }
+
+    #[test]
+    fn change_passphrase() {
+        let mut wallet = new_wallet();
+        let public = wallet.master_public().clone();
+        assert!(Wallet::reencrypt(wallet.encrypted(), &public, "wrong passphrase", "new passphrase").is_err());
+        let encrypted = Wallet::reencrypt(wallet.encrypted(), &public, PASSPHRASE, "new passphrase").unwrap();
+        wallet.set_encrypted(encrypted.as_slice());
+        assert!(Unlocker::new_for_master(&wallet.master, PASSPHRASE).is_err());
+        assert!(Unlocker::new_for_master(&wallet.master, "new passphrase").is_ok());
+        assert_eq!(wallet.master_public(), &public);
+        assert!(wallet.master.get((0, 1)).is_some());
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! scripts watched without keys
+//!
+//! confirmed coins of scripts outside the wallet, e.g. a cold storage address, are tracked apart from the wallet's own
+
+use bitcoin::{Address, Block, OutPoint, Script};
+use bitcoin_hashes::sha256d;
+
+/// a coin of a watched script
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchedCoin {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    /// height of the block that confirmed it
+    pub height: u32,
+    /// spending transaction and height of its block
+    pub spent: Option<(sha256d::Hash, u32)>,
+}
+
+/// a transaction of a watched script
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchedTx {
+    pub txid: sha256d::Hash,
+    pub height: u32,
+    /// paid to the script
+    pub received: u64,
+    /// spent from coins of the script
+    pub sent: u64,
+}
+
+/// a script watched without keys and its coins since it is watched
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watched {
+    pub script: Script,
+    /// the address the script was given as, if any
+    pub address: Option<Address>,
+    pub coins: Vec<WatchedCoin>,
+}
+
+impl Watched {
+    pub fn new(script: Script, address: Option<Address>) -> Watched {
+        Watched { script, address, coins: Vec::new() }
+    }
+
+    /// value of confirmed unspent coins
+    pub fn balance(&self) -> u64 {
+        self.coins.iter().filter(|c| c.spent.is_none()).map(|c| c.value).sum()
+    }
+
+    /// transactions paying to or spending from the script, oldest first
+    pub fn history(&self) -> Vec<WatchedTx> {
+        let mut history: Vec<WatchedTx> = Vec::new();
+        let mut add = |txid: sha256d::Hash, height: u32, received: u64, sent: u64| {
+            if let Some(known) = history.iter_mut().find(|t| t.txid == txid) {
+                known.received += received;
+                known.sent += sent;
+            } else {
+                history.push(WatchedTx { txid, height, received, sent });
+            }
+        };
+        for coin in &self.coins {
+            add(coin.outpoint.txid, coin.height, coin.value, 0);
+            if let Some((txid, height)) = coin.spent {
+                add(txid, height, 0, coin.value);
+            }
+        }
+        history.sort_by_key(|t| t.height);
+        history
+    }
+
+    /// track coins created and spent by a block, true if any changed
+    pub fn process(&mut self, block: &Block, height: u32) -> bool {
+        let mut changed = false;
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            for input in &tx.input {
+                if let Some(coin) = self.coins.iter_mut().find(|c| c.outpoint == input.previous_output && c.spent.is_none()) {
+                    coin.spent = Some((txid, height));
+                    changed = true;
+                }
+            }
+            for (vout, output) in tx.output.iter().enumerate() {
+                if output.script_pubkey == self.script {
+                    let outpoint = OutPoint { txid, vout: vout as u32 };
+                    if !self.coins.iter().any(|c| c.outpoint == outpoint) {
+                        self.coins.push(WatchedCoin { outpoint, value: output.value, height, spent: None });
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    /// forget what the block at height did, true if any changed
+    pub fn unwind(&mut self, height: u32) -> bool {
+        let before = self.coins.clone();
+        self.coins.retain(|c| c.height < height);
+        for coin in self.coins.iter_mut() {
+            if coin.spent.map(|(_, h)| h >= height).unwrap_or(false) {
+                coin.spent = None;
+            }
+        }
+        self.coins != before
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, BitcoinHash, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+    use bitcoin::blockdata::constants::genesis_block;
+
+    use crate::testutil::{add_tx, mine};
+
+    use super::Watched;
+
+    #[test]
+    fn process_unwind() {
+        let cold = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut watched = Watched::new(cold.script_pubkey(), Some(cold.clone()));
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.bitcoin_hash(), 1, &cold);
+        assert!(watched.process(&first, 1));
+        let received = watched.balance();
+        assert!(received > 0);
+
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn { previous_output: OutPoint { txid: first.txdata[0].txid(), vout: 0 }, script_sig: Script::new(), sequence: 0xffffffff, witness: vec!() }),
+            output: vec!(TxOut { value: received - 1000, script_pubkey: Script::new() }),
+        };
+        let mut second = mine(&first.bitcoin_hash(), 2, &Address::p2wsh(&Script::from(vec!(1u8)), Network::Testnet));
+        add_tx(&mut second, spend.clone());
+        assert!(watched.process(&second, 2));
+        assert_eq!(watched.balance(), 0);
+        let history = watched.history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].txid, spend.txid());
+        assert_eq!(history[1].sent, received);
+
+        assert!(watched.unwind(2));
+        assert_eq!(watched.balance(), received);
+        assert!(watched.unwind(1));
+        assert!(watched.history().is_empty());
+        assert!(!watched.unwind(1));
+    }
+}
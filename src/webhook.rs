@@ -0,0 +1,175 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! webhook notifications
+//!
+//! wallet events are posted as JSON to a configured url, so a backend learns of them without polling the wallet.
+//! Events wait for delivery in memory only, those not yet posted are lost if the wallet stops.
+//! A backend should reconcile with the wallet's history after a restart
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin_hashes::{Hash, HashEngine, Hmac, HmacEngine, sha256, sha256d};
+use log::{debug, warn};
+
+use crate::error::Error;
+
+/// header carrying the hex HMAC-SHA256 of the body under the configured secret
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+/// attempts to deliver an event before it is dropped
+const MAX_ATTEMPTS: u32 = 6;
+/// wait before the first retry, doubled with every further one
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// timeout of a request in milliseconds
+const TIMEOUT: u64 = 10000;
+
+/// a wallet or chain event, Block and Header events are only published to the event socket
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WalletEvent {
+    /// a coin paid to one of our receive addresses was confirmed
+    Deposit { txid: sha256d::Hash, vout: u32, address: Option<String>, amount: u64, height: u32 },
+    /// one of our own transactions was confirmed
+    Confirmed { txid: sha256d::Hash, height: u32 },
+    /// a confirmed coin became spendable, e.g. a coinbase or a term deposit
+    Matured { txid: sha256d::Hash, vout: u32, amount: u64, height: u32 },
+    /// one of our own transactions was handed to peers
+    Broadcast { txid: sha256d::Hash },
+    /// a vault was spent by a transaction we did not send, cancel it before its delay passes
+    VaultSpent { txid: sha256d::Hash, vout: u32, spent_by: sha256d::Hash, height: u32 },
+    /// a block was processed
+    Block { hash: sha256d::Hash, height: u32 },
+    /// a processed block was unwound by a re-org, deposits and confirmations at its height are reverted
+    Unwound { hash: sha256d::Hash, height: u32 },
+    /// a header extended the chain, blocks may not be downloaded yet
+    Header { hash: sha256d::Hash, height: u32 },
+}
+
+/// body posted for an event
+#[derive(Serialize)]
+struct Payload<'a> {
+    /// increases with every event of a notifier, so retried deliveries can be recognized
+    sequence: u64,
+    /// unix time the event was raised
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a WalletEvent,
+}
+
+/// posts wallet events in order, in the background
+pub struct Notifier {
+    sender: mpsc::Sender<WalletEvent>,
+}
+
+impl Notifier {
+    /// start posting events to url, signed with secret if given. The thread ends when the notifier is dropped
+    pub fn start(url: String, secret: Option<String>) -> Notifier {
+        let (sender, receiver) = mpsc::channel::<WalletEvent>();
+        thread::Builder::new().name("webhook".to_string()).spawn(move || {
+            let mut sequence = 0u64;
+            while let Ok(event) = receiver.recv() {
+                sequence += 1;
+                let body = payload(sequence, &event);
+                let mut delay = RETRY_DELAY;
+                for attempt in 1..=MAX_ATTEMPTS {
+                    match post(url.as_str(), secret.as_ref().map(|s| s.as_str()), body.as_str()) {
+                        Ok(()) => {
+                            debug!("posted {:?} to webhook", event);
+                            break;
+                        }
+                        Err(e) if attempt < MAX_ATTEMPTS => {
+                            debug!("webhook failed {}, retry in {:?}", e, delay);
+                            thread::sleep(delay);
+                            delay *= 2;
+                        }
+                        Err(e) => warn!("dropped {:?} after {} webhook attempts: {}", event, MAX_ATTEMPTS, e)
+                    }
+                }
+            }
+            debug!("webhook notifier stopped");
+        }).expect("can not start webhook notifier");
+        Notifier { sender }
+    }
+
+    /// a notifier handing events to the receiver instead of posting them
+    #[cfg(any(test, feature = "testutil"))]
+    pub fn capture() -> (Notifier, mpsc::Receiver<WalletEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        (Notifier { sender }, receiver)
+    }
+
+    /// queue an event for posting
+    pub fn notify(&self, event: WalletEvent) {
+        if self.sender.send(event).is_err() {
+            warn!("webhook notifier is not running");
+        }
+    }
+}
+
+/// JSON of an event with its sequence number and time
+pub fn payload(sequence: u64, event: &WalletEvent) -> String {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    serde_json::to_string(&Payload { sequence, timestamp, event }).expect("can not serialize wallet event")
+}
+
+/// hex HMAC-SHA256 of body under secret
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    hex::encode(&Hmac::<sha256::Hash>::from_engine(engine)[..])
+}
+
+fn post(url: &str, secret: Option<&str>, body: &str) -> Result<(), Error> {
+    let mut request = ureq::post(url);
+    request.timeout_connect(TIMEOUT).timeout_read(TIMEOUT).set("Content-Type", "application/json");
+    if let Some(secret) = secret {
+        request.set(SIGNATURE_HEADER, sign(secret, body).as_str());
+    }
+    let response = request.send_string(body);
+    if let Some(err) = response.synthetic_error() {
+        return Err(Error::from_request(err));
+    }
+    if !response.ok() {
+        return Err(Error::Http(format!("{} {}", response.status(), response.status_text())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin_hashes::sha256d;
+
+    use super::{payload, sign, WalletEvent};
+
+    #[test]
+    fn payloads() {
+        let event = WalletEvent::Confirmed { txid: sha256d::Hash::default(), height: 7 };
+        let body = serde_json::from_str::<serde_json::Value>(payload(3, &event).as_str()).unwrap();
+        assert_eq!(body["event"], "confirmed");
+        assert_eq!(body["sequence"], 3);
+        assert_eq!(body["height"], 7);
+        assert_eq!(body["txid"], sha256d::Hash::default().to_string());
+        assert!(body["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn signature() {
+        // RFC 4231 test case 2
+        assert_eq!(sign("Jefe", "what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+}
@@ -0,0 +1,15 @@
+package org.bdk.jni;
+
+// the exception thrown by the native library, as the java side defines it, for the jni tests
+public class BdkException extends Exception {
+    private final int code;
+
+    public BdkException(int code, String message) {
+        super(message);
+        this.code = code;
+    }
+
+    public int getCode() {
+        return code;
+    }
+}